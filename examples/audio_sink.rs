@@ -4,25 +4,27 @@ use std::iter::zip;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::Arc;
+use std::task::{Poll, Waker};
 use std::time::Duration;
 
 use anyhow::Context;
 use bluefang::a2dp::sbc::SbcMediaCodecInformation;
 use bluefang::a2dp::sdp::A2dpSinkServiceRecord;
 use bluefang::avdtp::capabilities::{Capability, MediaCodecCapability};
-use bluefang::avdtp::{AvdtpBuilder, LocalEndpoint, StreamHandler, StreamHandlerFactory, MediaType, StreamEndpointType};
+use bluefang::avdtp::{AvdtpBuilder, LocalEndpoint, MediaPacket, StreamHandler, StreamHandlerFactory, MediaType, StreamEndpointType};
 use bluefang::avrcp::notifications::CurrentTrack;
 use bluefang::avrcp::sdp::{AvrcpControllerServiceRecord, AvrcpTargetServiceRecord};
 use bluefang::avrcp::{Avrcp, AvrcpSession, Event, MediaAttributeId, Notification};
 use bluefang::firmware::{FolderFileProvider, RealTekFirmwareLoader};
 use bluefang::hci::connection::ConnectionManagerBuilder;
 use bluefang::hci::consts::{AudioVideoClass, ClassOfDevice, DeviceClass, MajorServiceClasses};
+use bluefang::hci::eir::ExtendedInquiryResponse;
+use bluefang::hci::link_keys::FileLinkKeyStore;
 use bluefang::hci::{FirmwareLoader, Hci};
 use bluefang::host::usb::UsbController;
 use bluefang::l2cap::L2capServerBuilder;
 use bluefang::sdp::SdpBuilder;
 use bluefang::utils::{select2, Either2};
-use bytes::Bytes;
 use console::{Key, Term};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{default_host, SampleFormat, Stream, StreamConfig};
@@ -30,7 +32,7 @@ use enum_iterator::{all, Sequence};
 use portable_atomic::AtomicF32;
 use ringbuf::consumer::Consumer;
 use ringbuf::producer::Producer;
-use ringbuf::traits::Split;
+use ringbuf::traits::{Observer, Split};
 use ringbuf::{HeapProd, HeapRb};
 use rubato::{FastFixedIn, PolynomialDegree, Resampler};
 use sbc_rs::BufferedDecoder;
@@ -78,19 +80,21 @@ async fn main() -> anyhow::Result<()> {
     let host = Arc::new(Hci::new(usb).await?);
     info!("Local BD_ADDR: {}", host.read_bd_addr().await?);
     {
-        let _conn_manager = ConnectionManagerBuilder::default()
-            .with_link_key_store("link-keys.dat")
+        let conn_manager = ConnectionManagerBuilder::default()
+            .with_link_key_store(FileLinkKeyStore::open("link-keys.dat")?)
             .spawn(host.clone())
             .await?;
         let volume = Arc::new(AtomicF32::new(1.0));
+        let sdp = SdpBuilder::default()
+            .with_record(A2dpSinkServiceRecord::new(0x00010001))
+            .with_record(AvrcpControllerServiceRecord::new(0x00010002))
+            .with_record(AvrcpTargetServiceRecord::new(0x00010003));
+        let eir = ExtendedInquiryResponse::default()
+            .with_name("bluefang")
+            .with_service_classes(sdp.service_classes())
+            .with_tx_power_level(host.read_inquiry_response_transmit_power_level().await?);
         let _l2cap_server = L2capServerBuilder::default()
-            .with_protocol(
-                SdpBuilder::default()
-                    .with_record(A2dpSinkServiceRecord::new(0x00010001))
-                    .with_record(AvrcpControllerServiceRecord::new(0x00010002))
-                    .with_record(AvrcpTargetServiceRecord::new(0x00010003))
-                    .build()
-            )
+            .with_protocol(sdp.build())
             .with_protocol(Avrcp::new(
                 cloned!([volume] move |session| avrcp_session_handler(volume.clone(), session))
             ))
@@ -104,17 +108,20 @@ async fn main() -> anyhow::Result<()> {
                         capabilities: vec![
                             Capability::MediaTransport,
                             Capability::MediaCodec(SbcMediaCodecInformation::default().into()),
+                            Capability::DELAY_REPORTING,
                         ],
                         //stream_handler_factory: Box::new(|cap| Box::new(FileDumpHandler::new())),
                         factory: StreamHandlerFactory::new(cloned!([volume] move |cap| SbcStreamHandler::new(volume.clone(), cap)))
                     })
                     .build()
             )
+            .with_connection_manager(conn_manager)
             .run(&host)
             .map(spawn)?;
 
         host.write_local_name("bluefang").await?;
         host.write_class_of_device(cod).await?;
+        host.write_extended_inquiry_response(false, &eir).await?;
         host.set_scan_enabled(true, true).await?;
 
         println!("Waiting for connections...");
@@ -212,9 +219,14 @@ struct SbcStreamHandler {
     volume: Arc<AtomicF32>,
     input_buffers: [Vec<f32>; 2],
     output_buffers: [Vec<f32>; 2],
-    interleave_buffer: Vec<i16>
+    interleave_buffer: Vec<i16>,
+    reported_delay: Option<Duration>,
+    delay_waker: Option<Waker>
 }
 
+/// Only report changes of the playback delay that are noticeable.
+const DELAY_REPORT_THRESHOLD: Duration = Duration::from_millis(20);
+
 impl SbcStreamHandler {
     pub fn new(volume: Arc<AtomicF32>, capabilities: &[Capability]) -> Self {
         let (source_frequency, input_size) = Self::parse_capabilities(capabilities)
@@ -238,6 +250,8 @@ impl SbcStreamHandler {
             input_buffers: from_fn(|_| vec![0f32; resampler.input_frames_max()]),
             output_buffers: from_fn(|_| vec![0f32; resampler.output_frames_max()]),
             interleave_buffer: Vec::with_capacity(2 * resampler.output_frames_max()),
+            reported_delay: None,
+            delay_waker: None,
             audio_session,
             resampler
         }
@@ -292,9 +306,26 @@ impl StreamHandler for SbcStreamHandler {
         self.audio_session.stop();
     }
 
-    fn on_data(&mut self, data: Bytes) {
-        //TODO actually parse the header to make sure the packets are not fragmented
-        self.process_frames(&data.as_ref()[1..]);
+    fn on_data(&mut self, packet: MediaPacket) {
+        for frame in packet.frames {
+            self.process_frames(&frame);
+        }
+        if let Some(waker) = self.delay_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_delay_report(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Duration> {
+        let delay = self.audio_session.delay();
+        if self
+            .reported_delay
+            .is_none_or(|reported| reported.abs_diff(delay) >= DELAY_REPORT_THRESHOLD)
+        {
+            self.reported_delay = Some(delay);
+            return Poll::Ready(delay);
+        }
+        self.delay_waker = Some(cx.waker().clone());
+        Poll::Pending
     }
 }
 
@@ -365,6 +396,12 @@ impl AudioSession {
     pub fn max_buffer_size(&self) -> usize {
         self.max_buffer_size
     }
+
+    /// How long it takes until the buffered samples are played.
+    pub fn delay(&self) -> Duration {
+        let samples_per_second = self.config.sample_rate.0 as u64 * self.config.channels as u64;
+        Duration::from_micros(self.buffer.occupied_len() as u64 * 1_000_000 / samples_per_second)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Sequence)]
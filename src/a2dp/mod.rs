@@ -1,5 +1,6 @@
 pub mod sbc;
 pub mod sdp;
+pub mod source;
 
 use instructor::utils::u24;
 use instructor::{Exstruct, Instruct};
@@ -1,6 +1,10 @@
+mod encoder;
+
 use bitflags::bitflags;
 use instructor::{ByteSize, Exstruct, Instruct};
 
+pub use encoder::SbcEncoder;
+
 // ([A2DP] Section 4.3.2).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Instruct, Exstruct)]
 #[instructor(endian = "big")]
@@ -35,6 +39,80 @@ impl Default for SbcMediaCodecInformation {
     }
 }
 
+impl SbcMediaCodecInformation {
+    /// Picks the preferred configuration that both endpoints support, or `None` if they have nothing in common.
+    pub fn select_configuration(&self, remote: &Self) -> Option<Self> {
+        fn pick<T: Copy>(preferences: &[T], supported: impl Fn(T) -> bool) -> Option<T> {
+            preferences.iter().copied().find(|&value| supported(value))
+        }
+        let minimum_bitpool = self.minimum_bitpool.max(remote.minimum_bitpool);
+        let maximum_bitpool = self.maximum_bitpool.min(remote.maximum_bitpool);
+        if minimum_bitpool > maximum_bitpool {
+            return None;
+        }
+        Some(Self {
+            sampling_frequencies: pick(
+                &[SamplingFrequencies::FREQ_44100, SamplingFrequencies::FREQ_48000, SamplingFrequencies::FREQ_32000, SamplingFrequencies::FREQ_16000],
+                |value| (self.sampling_frequencies & remote.sampling_frequencies).contains(value)
+            )?,
+            channel_modes: pick(
+                &[ChannelModes::JOINT_STEREO, ChannelModes::STEREO, ChannelModes::DUAL_CHANNEL, ChannelModes::MONO],
+                |value| (self.channel_modes & remote.channel_modes).contains(value)
+            )?,
+            block_lengths: pick(
+                &[BlockLengths::SIXTEEN, BlockLengths::TWELVE, BlockLengths::EIGHT, BlockLengths::FOUR],
+                |value| (self.block_lengths & remote.block_lengths).contains(value)
+            )?,
+            subbands: pick(&[Subbands::EIGHT, Subbands::FOUR], |value| (self.subbands & remote.subbands).contains(value))?,
+            allocation_methods: pick(&[AllocationMethods::LOUDNESS, AllocationMethods::SNR], |value| {
+                (self.allocation_methods & remote.allocation_methods).contains(value)
+            })?,
+            minimum_bitpool,
+            maximum_bitpool
+        })
+    }
+}
+
+// ([A2DP] Section 4.3.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Instruct, Exstruct)]
+#[instructor(endian = "big")]
+pub struct SbcMediaPayloadHeader {
+    #[instructor(bitfield(u8))]
+    #[instructor(bits(7..8))]
+    pub fragmented: bool,
+    #[instructor(bits(6..7))]
+    pub starting_packet: bool,
+    #[instructor(bits(5..6))]
+    pub last_packet: bool,
+    #[instructor(bits(0..4))]
+    pub number_of_frames: u8
+}
+
+/// The length of the SBC frame that starts at the beginning of `data`, or `None` if `data` doesn't start with a frame header ([A2DP] Section 12.5).
+pub fn frame_length(data: &[u8]) -> Option<usize> {
+    let [syncword, config, bitpool, ..] = *data else {
+        return None;
+    };
+    if syncword != encoder::SYNCWORD {
+        return None;
+    }
+    let blocks = 4 * (((config >> 4) & 0b11) as usize + 1);
+    let channel_mode = ChannelModes::from_bits_truncate(1 << (((config >> 2) & 0b11) ^ 0b11));
+    let subbands = if config & 0b1 == 0 { 4 } else { 8 };
+    Some(calculate_frame_length(channel_mode, blocks, subbands, bitpool as usize))
+}
+
+// ([A2DP] Section 12.9).
+fn calculate_frame_length(channel_mode: ChannelModes, blocks: usize, subbands: usize, bitpool: usize) -> usize {
+    let channels = if channel_mode == ChannelModes::MONO { 1 } else { 2 };
+    4 + (4 * subbands * channels).div_ceil(8)
+        + match channel_mode {
+            ChannelModes::MONO | ChannelModes::DUAL_CHANNEL => (blocks * channels * bitpool).div_ceil(8),
+            ChannelModes::STEREO => (blocks * bitpool).div_ceil(8),
+            _ => (subbands + blocks * bitpool).div_ceil(8)
+        }
+}
+
 // ([A2DP] Section 4.3.2.1).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
@@ -71,6 +149,16 @@ bitflags! {
     }
 }
 
+impl ChannelModes {
+    pub fn as_channels(self) -> Option<u32> {
+        match self {
+            ChannelModes::MONO => Some(1),
+            ChannelModes::DUAL_CHANNEL | ChannelModes::STEREO | ChannelModes::JOINT_STEREO => Some(2),
+            _ => None
+        }
+    }
+}
+
 // ([A2DP] Section 4.3.2.3).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
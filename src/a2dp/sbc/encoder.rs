@@ -0,0 +1,387 @@
+use std::f32::consts::PI;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::a2dp::sbc::{calculate_frame_length, AllocationMethods, ChannelModes, SamplingFrequencies, SbcMediaCodecInformation};
+
+pub(super) const SYNCWORD: u8 = 0x9C;
+const CRC_POLYNOMIAL: u8 = 0x1D;
+const CRC_INIT: u8 = 0x0F;
+const MAX_BITS: i32 = 16;
+
+// Prototype filter of the analysis filterbank with four subbands ([A2DP] Appendix B).
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const PROTO_4_40: [f32; 40] = [
+    0.00000000E+00, 5.36548976E-04, 1.49188357E-03, 2.73370904E-03,
+    3.83720193E-03, 3.89205149E-03, 1.86581691E-03, -3.06012286E-03,
+    1.09137620E-02, 2.04385087E-02, 2.88757392E-02, 3.21939290E-02,
+    2.58767811E-02, 6.13245186E-03, -2.88217274E-02, -7.76463494E-02,
+    1.35593274E-01, 1.94987841E-01, 2.46636662E-01, 2.81828203E-01,
+    2.94315332E-01, 2.81828203E-01, 2.46636662E-01, 1.94987841E-01,
+    -1.35593274E-01, -7.76463494E-02, -2.88217274E-02, 6.13245186E-03,
+    2.58767811E-02, 3.21939290E-02, 2.88757392E-02, 2.04385087E-02,
+    -1.09137620E-02, -3.06012286E-03, 1.86581691E-03, 3.89205149E-03,
+    3.83720193E-03, 2.73370904E-03, 1.49188357E-03, 5.36548976E-04
+];
+
+// Prototype filter with eight subbands.
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const PROTO_8_80: [f32; 80] = [
+    0.00000000E+00, 1.56575398E-04, 3.43256425E-04, 5.54620202E-04,
+    8.23919506E-04, 1.13992507E-03, 1.47640169E-03, 1.78371725E-03,
+    2.01182542E-03, 2.10371989E-03, 1.99454554E-03, 1.61656283E-03,
+    9.02154502E-04, -1.78805361E-04, -1.64973098E-03, -3.49717454E-03,
+    5.65949473E-03, 8.02941163E-03, 1.04584443E-02, 1.27472335E-02,
+    1.46525263E-02, 1.59045603E-02, 1.62208471E-02, 1.53184106E-02,
+    1.29371806E-02, 8.85757540E-03, 2.92408442E-03, -4.91578024E-03,
+    -1.46404076E-02, -2.61098752E-02, -3.90751381E-02, -5.31873032E-02,
+    6.79989431E-02, 8.29847578E-02, 9.75753918E-02, 1.11196689E-01,
+    1.23264548E-01, 1.33264415E-01, 1.40753505E-01, 1.45389847E-01,
+    1.46955068E-01, 1.45389847E-01, 1.40753505E-01, 1.33264415E-01,
+    1.23264548E-01, 1.11196689E-01, 9.75753918E-02, 8.29847578E-02,
+    -6.79989431E-02, -5.31873032E-02, -3.90751381E-02, -2.61098752E-02,
+    -1.46404076E-02, -4.91578024E-03, 2.92408442E-03, 8.85757540E-03,
+    1.29371806E-02, 1.53184106E-02, 1.62208471E-02, 1.59045603E-02,
+    1.46525263E-02, 1.27472335E-02, 1.04584443E-02, 8.02941163E-03,
+    -5.65949473E-03, -3.49717454E-03, -1.64973098E-03, -1.78805361E-04,
+    9.02154502E-04, 1.61656283E-03, 1.99454554E-03, 2.10371989E-03,
+    2.01182542E-03, 1.78371725E-03, 1.47640169E-03, 1.13992507E-03,
+    8.23919506E-04, 5.54620202E-04, 3.43256425E-04, 1.56575398E-04
+];
+
+// Loudness offsets of the bit allocation, indexed by sampling frequency.
+const OFFSET_4: [[i32; 4]; 4] = [[-1, 0, 0, 0], [-2, 0, 0, 1], [-2, 0, 0, 1], [-2, 0, 0, 1]];
+const OFFSET_8: [[i32; 8]; 4] = [
+    [-2, 0, 0, 0, 0, 0, 0, 1],
+    [-3, 0, 0, 0, 0, 0, 1, 2],
+    [-4, 0, 0, 0, 0, 0, 1, 2],
+    [-4, 0, 0, 0, 0, 0, 1, 2]
+];
+
+/// Encodes interleaved 16 bit PCM into SBC frames ([A2DP] Appendix B).
+///
+/// The configuration is fixed for the lifetime of the encoder, so every frame has the same length.
+#[derive(Debug, Clone)]
+pub struct SbcEncoder {
+    sampling_frequency: SamplingFrequencies,
+    channel_mode: ChannelModes,
+    allocation_method: AllocationMethods,
+    blocks: usize,
+    subbands: usize,
+    bitpool: u8,
+    matrix: Vec<f32>,
+    history: [Vec<f32>; 2]
+}
+
+impl SbcEncoder {
+    /// Creates an encoder for a configuration that selects exactly one value of each parameter.
+    ///
+    /// Returns `None` if the configuration is ambiguous or the bitpool is outside of its range.
+    pub fn new(config: &SbcMediaCodecInformation, bitpool: u8) -> Option<Self> {
+        config.sampling_frequencies.as_value()?;
+        let blocks = config.block_lengths.as_value()? as usize;
+        let subbands = config.subbands.as_value()? as usize;
+        let channels = config.channel_modes.as_channels()? as usize;
+        let max_bitpool = match config.channel_modes {
+            ChannelModes::MONO | ChannelModes::DUAL_CHANNEL => 16 * subbands,
+            _ => 32 * subbands
+        };
+        if config.allocation_methods.bits().count_ones() != 1
+            || !(config.minimum_bitpool..=config.maximum_bitpool).contains(&bitpool)
+            || bitpool < 2
+            || bitpool as usize > max_bitpool
+        {
+            return None;
+        }
+        // Cosine modulation of the analysis filter.
+        let matrix = (0..subbands)
+            .flat_map(|k| {
+                (0..2 * subbands)
+                    .map(move |i| ((k as f32 + 0.5) * (i as f32 - subbands as f32 / 2.0) * PI / subbands as f32).cos())
+            })
+            .collect();
+        Some(Self {
+            sampling_frequency: config.sampling_frequencies,
+            channel_mode: config.channel_modes,
+            allocation_method: config.allocation_methods,
+            blocks,
+            subbands,
+            bitpool,
+            matrix,
+            history: [vec![0.0; 10 * subbands], vec![0.0; 10 * subbands * (channels - 1)]]
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        match self.channel_mode {
+            ChannelModes::MONO => 1,
+            _ => 2
+        }
+    }
+
+    pub fn sampling_frequency(&self) -> u32 {
+        self.sampling_frequency
+            .as_value()
+            .expect("validated in new")
+    }
+
+    pub fn bitpool(&self) -> u8 {
+        self.bitpool
+    }
+
+    /// The number of samples per channel encoded into each frame.
+    pub fn samples_per_frame(&self) -> usize {
+        self.blocks * self.subbands
+    }
+
+    pub fn frame_length(&self) -> usize {
+        calculate_frame_length(self.channel_mode, self.blocks, self.subbands, self.bitpool as usize)
+    }
+
+    /// Encodes one frame from exactly [`samples_per_frame`](Self::samples_per_frame) interleaved samples per channel.
+    #[allow(clippy::needless_range_loop)]
+    pub fn encode(&mut self, pcm: &[i16], output: &mut BytesMut) {
+        let (blocks, subbands, channels) = (self.blocks, self.subbands, self.channels());
+        assert_eq!(pcm.len(), self.samples_per_frame() * channels, "Invalid number of samples");
+
+        let mut samples = [[[0.0f32; 8]; 16]; 2];
+        for blk in 0..blocks {
+            for ch in 0..channels {
+                self.analyze(ch, pcm[blk * subbands * channels..].iter().skip(ch).step_by(channels), &mut samples[ch][blk]);
+            }
+        }
+
+        let mut scale_factors = [[0u8; 8]; 2];
+        for ch in 0..channels {
+            for sb in 0..subbands {
+                scale_factors[ch][sb] = scale_factor(samples[ch][..blocks].iter().map(|blk| blk[sb]));
+            }
+        }
+
+        // The last subband is never joined.
+        let mut join = [false; 8];
+        if self.channel_mode == ChannelModes::JOINT_STEREO {
+            for sb in 0..subbands - 1 {
+                let mid_sf = scale_factor(samples[0][..blocks].iter().zip(&samples[1][..blocks]).map(|(l, r)| (l[sb] + r[sb]) / 2.0));
+                let side_sf = scale_factor(samples[0][..blocks].iter().zip(&samples[1][..blocks]).map(|(l, r)| (l[sb] - r[sb]) / 2.0));
+                if mid_sf as u32 + (side_sf as u32) < scale_factors[0][sb] as u32 + scale_factors[1][sb] as u32 {
+                    join[sb] = true;
+                    scale_factors[0][sb] = mid_sf;
+                    scale_factors[1][sb] = side_sf;
+                    for blk in 0..blocks {
+                        let (l, r) = (samples[0][blk][sb], samples[1][blk][sb]);
+                        samples[0][blk][sb] = (l + r) / 2.0;
+                        samples[1][blk][sb] = (l - r) / 2.0;
+                    }
+                }
+            }
+        }
+
+        let bits = self.allocate_bits(&scale_factors);
+
+        let mut writer = BitWriter::default();
+        writer.write(SYNCWORD as u32, 8);
+        writer.write(self.sampling_frequency.bits().trailing_zeros() ^ 0b11, 2);
+        writer.write(self.blocks as u32 / 4 - 1, 2);
+        writer.write(self.channel_mode.bits().trailing_zeros() ^ 0b11, 2);
+        writer.write(u32::from(self.allocation_method == AllocationMethods::SNR), 1);
+        writer.write(u32::from(subbands == 8), 1);
+        writer.write(self.bitpool as u32, 8);
+        writer.write(0, 8);
+        if self.channel_mode == ChannelModes::JOINT_STEREO {
+            for &joined in &join[..subbands] {
+                writer.write(u32::from(joined), 1);
+            }
+        }
+        for ch in 0..channels {
+            for sb in 0..subbands {
+                writer.write(scale_factors[ch][sb] as u32, 4);
+            }
+        }
+        // The CRC covers the header without the syncword, the join flags and the scale factors.
+        let crc = bit_range(&writer.buffer, 8..24)
+            .chain(bit_range(&writer.buffer, 32..writer.position))
+            .fold(CRC_INIT, crc_step);
+        writer.buffer[3] = crc;
+
+        for blk in 0..blocks {
+            for ch in 0..channels {
+                for sb in 0..subbands {
+                    let bits = bits[ch][sb];
+                    if bits == 0 {
+                        continue;
+                    }
+                    let levels = (1u32 << bits) - 1;
+                    let scale = (1u32 << (scale_factors[ch][sb] + 1)) as f32;
+                    let quantized = ((samples[ch][blk][sb] / scale + 1.0) * levels as f32 / 2.0).floor();
+                    writer.write((quantized.max(0.0) as u32).min(levels), bits);
+                }
+            }
+        }
+        debug_assert!(writer.buffer.len() <= self.frame_length());
+        writer.buffer.resize(self.frame_length(), 0);
+        output.put_slice(&writer.buffer);
+    }
+
+    fn analyze<'a>(&mut self, ch: usize, input: impl Iterator<Item = &'a i16>, output: &mut [f32; 8]) {
+        let m = self.subbands;
+        let proto: &[f32] = if m == 4 { &PROTO_4_40 } else { &PROTO_8_80 };
+        let x = &mut self.history[ch];
+        x.copy_within(0..9 * m, m);
+        for (i, &sample) in input.take(m).enumerate() {
+            x[m - 1 - i] = sample as f32;
+        }
+        let mut y = [0.0f32; 16];
+        for (i, y) in y[..2 * m].iter_mut().enumerate() {
+            *y = (0..5)
+                .map(|j| proto[i + j * 2 * m] * x[i + j * 2 * m])
+                .sum();
+        }
+        for (k, output) in output[..m].iter_mut().enumerate() {
+            *output = self.matrix[k * 2 * m..(k + 1) * 2 * m]
+                .iter()
+                .zip(&y)
+                .map(|(m, y)| m * y)
+                .sum();
+        }
+    }
+
+    #[allow(clippy::needless_range_loop)]
+    fn allocate_bits(&self, scale_factors: &[[u8; 8]; 2]) -> [[u32; 8]; 2] {
+        let subbands = self.subbands;
+        let frequency = self.sampling_frequency.bits().trailing_zeros() as usize ^ 0b11;
+        let mut bitneed = [[0i32; 8]; 2];
+        for ch in 0..self.channels() {
+            for sb in 0..subbands {
+                let sf = scale_factors[ch][sb] as i32;
+                bitneed[ch][sb] = match self.allocation_method {
+                    AllocationMethods::SNR => sf,
+                    _ if sf == 0 => -5,
+                    _ => {
+                        let offset = match subbands {
+                            4 => OFFSET_4[frequency][sb],
+                            _ => OFFSET_8[frequency][sb]
+                        };
+                        let loudness = sf - offset;
+                        if loudness > 0 { loudness / 2 } else { loudness }
+                    }
+                };
+            }
+        }
+        let mut bits = [[0u32; 8]; 2];
+        match self.channel_mode {
+            ChannelModes::MONO => allocate(&bitneed, &mut bits, &[0], subbands, self.bitpool as i32),
+            ChannelModes::DUAL_CHANNEL => {
+                allocate(&bitneed, &mut bits, &[0], subbands, self.bitpool as i32);
+                allocate(&bitneed, &mut bits, &[1], subbands, self.bitpool as i32);
+            }
+            _ => allocate(&bitneed, &mut bits, &[0, 1], subbands, self.bitpool as i32)
+        }
+        bits
+    }
+}
+
+/// Distributes the bitpool among the subbands of the given channels.
+fn allocate(bitneed: &[[i32; 8]; 2], bits: &mut [[u32; 8]; 2], channels: &[usize], subbands: usize, bitpool: i32) {
+    let slots = || (0..subbands).flat_map(|sb| channels.iter().map(move |&ch| (ch, sb)));
+    let max_bitneed = slots()
+        .map(|(ch, sb)| bitneed[ch][sb])
+        .max()
+        .unwrap_or(0);
+    let mut bitcount = 0;
+    let mut slicecount = 0;
+    let mut bitslice = max_bitneed + 1;
+    loop {
+        bitslice -= 1;
+        bitcount += slicecount;
+        slicecount = 0;
+        for (ch, sb) in slots() {
+            let need = bitneed[ch][sb];
+            if need > bitslice + 1 && need < bitslice + MAX_BITS {
+                slicecount += 1;
+            } else if need == bitslice + 1 {
+                slicecount += 2;
+            }
+        }
+        if bitcount + slicecount >= bitpool {
+            break;
+        }
+    }
+    if bitcount + slicecount == bitpool {
+        bitcount += slicecount;
+        bitslice -= 1;
+    }
+
+    let mut allocated = [[0i32; 8]; 2];
+    for (ch, sb) in slots() {
+        let need = bitneed[ch][sb];
+        allocated[ch][sb] = if need < bitslice + 2 { 0 } else { (need - bitslice).min(MAX_BITS) };
+    }
+    for (ch, sb) in slots() {
+        if bitcount >= bitpool {
+            break;
+        }
+        let bits = &mut allocated[ch][sb];
+        if *bits >= 2 && *bits < MAX_BITS {
+            *bits += 1;
+            bitcount += 1;
+        } else if bitneed[ch][sb] == bitslice + 1 && bitpool > bitcount + 1 {
+            *bits = 2;
+            bitcount += 2;
+        }
+    }
+    for (ch, sb) in slots() {
+        if bitcount >= bitpool {
+            break;
+        }
+        let bits = &mut allocated[ch][sb];
+        if *bits < MAX_BITS {
+            *bits += 1;
+            bitcount += 1;
+        }
+    }
+    for (ch, sb) in slots() {
+        bits[ch][sb] = allocated[ch][sb] as u32;
+    }
+}
+
+/// The smallest scale factor whose range contains all samples.
+fn scale_factor(samples: impl Iterator<Item = f32>) -> u8 {
+    let max = samples.fold(0.0f32, |max, sample| max.max(sample.abs()));
+    (0..15)
+        .find(|&sf| max < (2u32 << sf) as f32)
+        .unwrap_or(15)
+}
+
+fn bit_range(buffer: &[u8], bits: std::ops::Range<usize>) -> impl Iterator<Item = bool> + '_ {
+    bits.map(|i| buffer[i / 8] & (0x80 >> (i % 8)) != 0)
+}
+
+fn crc_step(crc: u8, bit: bool) -> u8 {
+    match (crc & 0x80 != 0) ^ bit {
+        true => (crc << 1) ^ CRC_POLYNOMIAL,
+        false => crc << 1
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    buffer: Vec<u8>,
+    position: usize
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.position & 7 == 0 {
+                self.buffer.push(0);
+            }
+            if value & (1 << i) != 0 {
+                *self.buffer.last_mut().expect("pushed above") |= 0x80 >> (self.position % 8);
+            }
+            self.position += 1;
+        }
+    }
+}
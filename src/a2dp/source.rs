@@ -0,0 +1,480 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use instructor::BufferMut;
+use parking_lot::Mutex;
+use tokio::time::{sleep_until, Instant, Sleep};
+use tracing::{debug, trace, warn};
+
+use crate::a2dp::sbc::{SbcEncoder, SbcMediaCodecInformation, SbcMediaPayloadHeader};
+use crate::avdtp::capabilities::{Capability, MediaCodecCapability};
+use crate::avdtp::rtp::RtpHeader;
+use crate::avdtp::{LocalEndpoint, MediaPacket, MediaType, StreamEndpointType, StreamHandler, StreamHandlerFactory};
+
+/// How many samples per channel a [`SbcSource`] buffers by default.
+pub const DEFAULT_QUEUE_LENGTH: usize = 16384;
+
+/// The number of frames is a 4 bit field of the media payload header.
+const MAX_FRAMES_PER_PACKET: usize = 15;
+
+/// Packets that are this late get skipped instead of being sent in a burst.
+const MAX_LATENESS: Duration = Duration::from_millis(100);
+
+/// Packs SBC frames into RTP media packets ([A2DP] Section 4.3.4).
+pub struct SbcMediaWriter {
+    encoder: SbcEncoder,
+    sequence_number: u16,
+    timestamp: u32,
+    ssrc: u32
+}
+
+impl SbcMediaWriter {
+    pub fn new(encoder: SbcEncoder) -> Self {
+        let ssrc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |time| time.subsec_nanos());
+        Self {
+            encoder,
+            sequence_number: 0,
+            timestamp: 0,
+            ssrc
+        }
+    }
+
+    pub fn encoder(&self) -> &SbcEncoder {
+        &self.encoder
+    }
+
+    /// How many frames fit into a packet for the given MTU. At least one and at most 15.
+    ///
+    /// A single frame that doesn't fit gets fragmented by [`write_packets`](Self::write_packets).
+    pub fn frames_per_packet(&self, mtu: u16) -> usize {
+        (Self::max_payload(mtu) / self.encoder.frame_length()).clamp(1, MAX_FRAMES_PER_PACKET)
+    }
+
+    /// The number of samples per channel [`write_packets`](Self::write_packets) expects for the given MTU.
+    pub fn samples_per_packet(&self, mtu: u16) -> usize {
+        self.frames_per_packet(mtu) * self.encoder.samples_per_frame()
+    }
+
+    /// Encodes interleaved PCM holding a whole number of frames into media packets of at most `mtu` bytes.
+    ///
+    /// The frames end up in a single packet, unless it's a single frame larger than the MTU,
+    /// which gets split over several packets sharing its timestamp ([A2DP] Section 4.3.4).
+    pub fn write_packets(&mut self, pcm: &[i16], mtu: u16) -> Vec<Bytes> {
+        let samples_per_frame = self.encoder.samples_per_frame() * self.encoder.channels();
+        assert_eq!(pcm.len() % samples_per_frame, 0, "Partial frame");
+        let frames = pcm.len() / samples_per_frame;
+        assert!((1..=MAX_FRAMES_PER_PACKET).contains(&frames), "Invalid number of frames: {}", frames);
+
+        let mut encoded = BytesMut::with_capacity(frames * self.encoder.frame_length());
+        for frame in pcm.chunks_exact(samples_per_frame) {
+            self.encoder.encode(frame, &mut encoded);
+        }
+        let max_payload = Self::max_payload(mtu).max(1);
+        let packets = if encoded.len() <= max_payload {
+            let header = SbcMediaPayloadHeader {
+                fragmented: false,
+                starting_packet: false,
+                last_packet: false,
+                number_of_frames: frames as u8
+            };
+            vec![self.packet(header, &encoded)]
+        } else {
+            assert_eq!(frames, 1, "Only a single frame can be fragmented");
+            let fragments: Vec<&[u8]> = encoded.chunks(max_payload).collect();
+            let count = fragments.len();
+            fragments
+                .into_iter()
+                .enumerate()
+                .map(|(i, fragment)| {
+                    // For fragments the number of frames counts the remaining fragments
+                    let header = SbcMediaPayloadHeader {
+                        fragmented: true,
+                        starting_packet: i == 0,
+                        last_packet: i + 1 == count,
+                        number_of_frames: (count - i) as u8
+                    };
+                    self.packet(header, fragment)
+                })
+                .collect()
+        };
+        // The timestamp counts samples at the sampling frequency ([A2DP] Section 4.3.4).
+        self.timestamp = self
+            .timestamp
+            .wrapping_add((frames * self.encoder.samples_per_frame()) as u32);
+        packets
+    }
+
+    fn max_payload(mtu: u16) -> usize {
+        usize::from(mtu).saturating_sub(RtpHeader::SIZE + 1)
+    }
+
+    fn packet(&mut self, header: SbcMediaPayloadHeader, payload: &[u8]) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(RtpHeader::SIZE + 1 + payload.len());
+        buffer.write_be(RtpHeader::new(self.sequence_number, self.timestamp, self.ssrc));
+        buffer.write_be(header);
+        buffer.extend_from_slice(payload);
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        buffer.freeze()
+    }
+}
+
+struct SourceState {
+    samples: VecDeque<i16>,
+    queue_length: usize,
+    configuration: Option<SbcMediaCodecInformation>,
+    generation: u64,
+    playing: bool,
+    delay: Option<Duration>
+}
+
+/// Streams PCM audio from the application to a remote sink as SBC.
+///
+/// The PCM is queued here and pulled by the stream at the pace of the negotiated sampling frequency.
+/// When the queue runs dry, the stream is filled with silence.
+#[derive(Clone)]
+pub struct SbcSource {
+    capabilities: SbcMediaCodecInformation,
+    state: Arc<Mutex<SourceState>>
+}
+
+impl Default for SbcSource {
+    fn default() -> Self {
+        Self::new(SbcMediaCodecInformation::default())
+    }
+}
+
+impl SbcSource {
+    pub fn new(capabilities: SbcMediaCodecInformation) -> Self {
+        Self {
+            capabilities,
+            state: Arc::new(Mutex::new(SourceState {
+                samples: VecDeque::new(),
+                queue_length: DEFAULT_QUEUE_LENGTH,
+                configuration: None,
+                generation: 0,
+                playing: false,
+                delay: None
+            }))
+        }
+    }
+
+    /// Limits how many samples per channel are buffered. Defaults to [`DEFAULT_QUEUE_LENGTH`].
+    pub fn with_queue_length(self, samples: usize) -> Self {
+        self.state.lock().queue_length = samples;
+        self
+    }
+
+    /// A source endpoint that streams the audio written to this source.
+    pub fn endpoint(&self, seid: u8) -> LocalEndpoint {
+        let state = self.state.clone();
+        LocalEndpoint {
+            media_type: MediaType::Audio,
+            seid,
+            in_use: Arc::new(AtomicBool::new(false)),
+            tsep: StreamEndpointType::Source,
+            capabilities: vec![
+                Capability::MediaTransport,
+                Capability::MediaCodec(self.capabilities.into()),
+                Capability::DELAY_REPORTING
+            ],
+            factory: StreamHandlerFactory::new(move |capabilities| SbcSourceHandler::new(state.clone(), capabilities))
+        }
+    }
+
+    /// The configuration of the current stream, which decides the sampling frequency and channel count of the PCM.
+    pub fn configuration(&self) -> Option<SbcMediaCodecInformation> {
+        self.state.lock().configuration
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.state.lock().playing
+    }
+
+    /// The last delay reported by the sink between receiving audio and playing it.
+    ///
+    /// Video should be held back by this amount to stay in sync with the audio.
+    pub fn delay(&self) -> Option<Duration> {
+        self.state.lock().delay
+    }
+
+    /// The number of queued samples per channel.
+    pub fn queued(&self) -> usize {
+        let state = self.state.lock();
+        let channels = state.channels();
+        state.samples.len() / channels
+    }
+
+    /// Queues interleaved PCM and returns the number of samples that fit into the queue.
+    ///
+    /// Samples are discarded while no stream is configured.
+    pub fn write(&self, pcm: &[i16]) -> usize {
+        let mut state = self.state.lock();
+        if state.configuration.is_none() {
+            return 0;
+        }
+        let capacity = state.queue_length * state.channels();
+        let accepted = pcm.len().min(capacity.saturating_sub(state.samples.len()));
+        state.samples.extend(&pcm[..accepted]);
+        accepted
+    }
+}
+
+impl SourceState {
+    fn channels(&self) -> usize {
+        self.configuration
+            .and_then(|config| config.channel_modes.as_channels())
+            .unwrap_or(2) as usize
+    }
+}
+
+struct Clock {
+    start: Instant,
+    samples: u64
+}
+
+struct SbcSourceHandler {
+    state: Arc<Mutex<SourceState>>,
+    generation: u64,
+    writer: Option<SbcMediaWriter>,
+    clock: Option<Clock>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    buffer: Vec<i16>,
+    fragments: VecDeque<Bytes>
+}
+
+impl SbcSourceHandler {
+    fn new(state: Arc<Mutex<SourceState>>, capabilities: &[Capability]) -> Self {
+        let configuration = capabilities.iter().find_map(|cap| match cap {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => Some(*info),
+            _ => None
+        });
+        // Always use the highest bitpool the sink accepts.
+        let writer = configuration
+            .and_then(|config| SbcEncoder::new(&config, config.maximum_bitpool))
+            .map(SbcMediaWriter::new);
+        if writer.is_none() {
+            warn!("Unsupported stream configuration: {:?}", configuration);
+        }
+        let generation = {
+            let mut state = state.lock();
+            state.generation += 1;
+            state.configuration = writer.as_ref().and(configuration);
+            state.samples.clear();
+            state.delay = None;
+            state.generation
+        };
+        Self {
+            state,
+            generation,
+            writer,
+            clock: None,
+            sleep: None,
+            buffer: Vec::new(),
+            fragments: VecDeque::new()
+        }
+    }
+}
+
+impl StreamHandler for SbcSourceHandler {
+    fn on_play(&mut self) {
+        self.clock = Some(Clock {
+            start: Instant::now(),
+            samples: 0
+        });
+        self.state.lock().playing = true;
+    }
+
+    fn on_stop(&mut self) {
+        self.clock = None;
+        self.fragments.clear();
+        self.state.lock().playing = false;
+    }
+
+    fn on_data(&mut self, packet: MediaPacket) {
+        debug!("Ignoring {} frames of media received by a source", packet.frames.len());
+    }
+
+    fn on_delay_report(&mut self, delay: Duration) {
+        debug!("Sink reported a delay of {:?}", delay);
+        let mut state = self.state.lock();
+        if state.generation == self.generation {
+            state.delay = Some(delay);
+        }
+    }
+
+    fn poll_packet(&mut self, cx: &mut Context<'_>, mtu: u16) -> Poll<Bytes> {
+        // The remaining fragments of a frame go out right away
+        if let Some(fragment) = self.fragments.pop_front() {
+            return Poll::Ready(fragment);
+        }
+        let (Some(writer), Some(clock)) = (self.writer.as_mut(), self.clock.as_mut()) else {
+            return Poll::Pending;
+        };
+        let frequency = u64::from(writer.encoder().sampling_frequency());
+        let now = Instant::now();
+        let mut due = clock.start + Duration::from_micros(clock.samples * 1_000_000 / frequency);
+        if now > due + MAX_LATENESS {
+            trace!("Source fell behind by {:?}, resynchronizing", now - due);
+            clock.start += now - due;
+            due = now;
+        }
+        if now < due {
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(sleep_until(due)));
+            sleep.as_mut().reset(due);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        let samples = writer.samples_per_packet(mtu);
+        let length = samples * writer.encoder().channels();
+        self.buffer.clear();
+        {
+            let mut state = self.state.lock();
+            let available = length.min(state.samples.len());
+            self.buffer.extend(state.samples.drain(..available));
+        }
+        if self.buffer.len() < length {
+            trace!("Source queue ran dry, filling {} samples with silence", length - self.buffer.len());
+            self.buffer.resize(length, 0);
+        }
+        clock.samples += samples as u64;
+        self.fragments.extend(writer.write_packets(&self.buffer, mtu));
+        Poll::Ready(self.fragments.pop_front().expect("There is at least one packet"))
+    }
+}
+
+impl Drop for SbcSourceHandler {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if state.generation == self.generation {
+            state.configuration = None;
+            state.playing = false;
+            state.samples.clear();
+            state.delay = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use bytes::Bytes;
+    use instructor::Buffer;
+    use sbc_rs::BufferedDecoder;
+
+    use crate::a2dp::sbc::{
+        AllocationMethods, BlockLengths, ChannelModes, SamplingFrequencies, SbcEncoder, SbcMediaCodecInformation, SbcMediaPayloadHeader, Subbands
+    };
+    use crate::a2dp::source::SbcMediaWriter;
+    use crate::avdtp::capabilities::{Capability, MediaCodecCapability};
+    use crate::avdtp::rtp::{RtpHeader, RTP_VERSION};
+    use crate::avdtp::MediaReceiver;
+
+    #[test]
+    fn test_media_writer() {
+        let config = SbcMediaCodecInformation::default()
+            .select_configuration(&SbcMediaCodecInformation::default())
+            .unwrap();
+        assert_eq!(config.sampling_frequencies, SamplingFrequencies::FREQ_44100);
+        assert_eq!(config.channel_modes, ChannelModes::JOINT_STEREO);
+        assert_eq!(config.block_lengths, BlockLengths::SIXTEEN);
+        assert_eq!(config.subbands, Subbands::EIGHT);
+        assert_eq!(config.allocation_methods, AllocationMethods::LOUDNESS);
+        assert!(SbcEncoder::new(&SbcMediaCodecInformation::default(), 53).is_none());
+
+        let encoder = SbcEncoder::new(&config, config.maximum_bitpool).unwrap();
+        assert_eq!(encoder.frame_length(), 119);
+        let mut writer = SbcMediaWriter::new(encoder);
+        assert_eq!(writer.frames_per_packet(895), 7);
+        // A frame is larger than the minimum MTU, so it gets fragmented
+        assert_eq!(writer.frames_per_packet(48), 1);
+        assert_eq!(writer.frames_per_packet(u16::MAX), 15);
+
+        // A 1 kHz tone on both channels
+        let (frequency, amplitude) = (1000.0, 10000.0);
+        let tone = |n: usize| (2.0 * PI * frequency * n as f32 / 44100.0).sin() * amplitude;
+        let samples = writer.samples_per_packet(895);
+        let mut decoder = BufferedDecoder::default();
+        let mut decoded = [Vec::new(), Vec::new()];
+        for i in 0..4 {
+            let pcm: Vec<i16> = (i as usize * samples..(i as usize + 1) * samples)
+                .flat_map(|n| [tone(n) as i16; 2])
+                .collect();
+            let mut packets = writer.write_packets(&pcm, 895);
+            assert_eq!(packets.len(), 1);
+            let mut packet: Bytes = packets.remove(0);
+            assert_eq!(packet.len(), 13 + 7 * 119);
+            let header: RtpHeader = packet.read_be().unwrap();
+            assert_eq!(header.version, RTP_VERSION);
+            assert_eq!(header.sequence_number, i);
+            assert_eq!(header.timestamp, i as u32 * samples as u32);
+            let payload: SbcMediaPayloadHeader = packet.read_be().unwrap();
+            assert_eq!(payload.number_of_frames, 7);
+            // Joint stereo, 44.1 kHz, 16 blocks, loudness, 8 subbands, bitpool 53
+            assert_eq!(packet[..3], [0x9C, 0xBD, 0x35]);
+
+            decoder.refill_buffer(&packet);
+            while let Some(frame) = decoder.next_frame_lr() {
+                for (channel, pcm) in decoded.iter_mut().zip(frame) {
+                    channel.extend(pcm.iter().copied());
+                }
+            }
+        }
+
+        for channel in decoded {
+            assert_eq!(channel.len(), 4 * samples);
+            // Skip the first frame, which contains the delay of the filter banks
+            let signal: Vec<f32> = channel[128..].iter().map(|&s| f32::from(s)).collect();
+            let rms = (signal.iter().map(|s| s * s).sum::<f32>() / signal.len() as f32).sqrt();
+            // Correlating with the tone yields its amplitude regardless of the phase the decoder delay introduces
+            let (re, im) = signal
+                .iter()
+                .enumerate()
+                .map(|(n, s)| {
+                    let phase = 2.0 * PI * frequency * n as f32 / 44100.0;
+                    (s * phase.cos(), s * phase.sin())
+                })
+                .fold((0.0, 0.0), |(re, im), (c, s)| (re + c, im + s));
+            let tone_amplitude = 2.0 * (re * re + im * im).sqrt() / signal.len() as f32;
+            assert!((tone_amplitude - amplitude).abs() < amplitude * 0.1, "Amplitude of the tone: {}", tone_amplitude);
+            // Nearly all the energy is in the tone
+            assert!((rms - tone_amplitude / 2.0f32.sqrt()).abs() < amplitude * 0.05, "RMS: {}", rms);
+        }
+
+        let pcm: Vec<i16> = (0..writer.samples_per_packet(48))
+            .flat_map(|n| [tone(4 * samples + n) as i16; 2])
+            .collect();
+        let packets = writer.write_packets(&pcm, 48);
+        assert_eq!(packets.len(), 4);
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= 48);
+            let mut packet = packet.clone();
+            let header: RtpHeader = packet.read_be().unwrap();
+            assert_eq!(header.sequence_number, 4 + i as u16);
+            assert_eq!(header.timestamp, 4 * samples as u32);
+            let payload: SbcMediaPayloadHeader = packet.read_be().unwrap();
+            assert!(payload.fragmented);
+            assert_eq!((payload.starting_packet, payload.last_packet), (i == 0, i == 3));
+            assert_eq!(payload.number_of_frames, 4 - i as u8);
+        }
+        let mut receiver = MediaReceiver::new(&[Capability::MediaTransport, Capability::MediaCodec(MediaCodecCapability::Sbc(config))]);
+        let mut media = Vec::new();
+        for packet in packets {
+            media.extend(receiver.receive(packet));
+        }
+        assert_eq!(media.len(), 1);
+        assert_eq!(media[0].frames.len(), 1);
+        assert_eq!(media[0].frames[0].len(), 119);
+        assert_eq!(media[0].frames[0][..3], [0x9C, 0xBD, 0x35]);
+    }
+}
@@ -14,6 +14,9 @@ pub enum Capability {
 }
 
 impl Capability {
+    /// Lets the sink report its playback delay to the source ([AVDTP] Section 8.21.9).
+    pub const DELAY_REPORTING: Capability = Capability::Generic(ServiceCategory::DelayReporting, Vec::new());
+
     pub fn is_basic(&self) -> bool {
         // ([AVDTP] Section 8.21.1).
         !matches!(self, Capability::Generic(ServiceCategory::DelayReporting, _))
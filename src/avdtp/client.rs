@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use instructor::{Buffer, BufferMut};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot::Sender as OneshotSender;
+use tokio::time::timeout;
+
+use crate::avdtp::capabilities::Capability;
+use crate::avdtp::error::RequestError;
+use crate::avdtp::packets::{SignalIdentifier, StreamEndpoint};
+
+/// GAVDP allows the initiator to give up on a response after 0.5 to 3 seconds (TGAVDP100).
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub type CommandResponseSender = OneshotSender<Result<Bytes, RequestError>>;
+
+#[derive(Debug)]
+pub struct AvdtpCommand {
+    pub signal_identifier: SignalIdentifier,
+    pub data: Bytes,
+    pub sender: CommandResponseSender
+}
+
+/// Delays are reported in units of 0.1 ms ([AVDTP] Section 8.19.1).
+pub fn encode_delay_report(seid: u8, delay: Duration) -> Bytes {
+    let delay = u16::try_from(delay.as_micros() / 100).unwrap_or(u16::MAX);
+    let mut data = BytesMut::new();
+    data.write_be(seid << 2);
+    data.write_be(delay);
+    data.freeze()
+}
+
+pub fn decode_delay(delay: u16) -> Duration {
+    Duration::from_micros(u64::from(delay) * 100)
+}
+
+/// Sends commands to the remote device as initiator (INT) of an AVDTP session.
+///
+/// All SEIDs are the ones of the remote endpoints, except for the local endpoint passed to
+/// [`set_configuration`](Self::set_configuration), which becomes the local side of the stream.
+#[derive(Debug, Clone)]
+pub struct AvdtpClient {
+    pub(super) commands: Sender<AvdtpCommand>,
+    pub(super) timeout: Duration
+}
+
+impl AvdtpClient {
+    /// How long to wait for a response. Defaults to [`RESPONSE_TIMEOUT`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn send_command(&self, signal_identifier: SignalIdentifier, data: Bytes) -> Result<Bytes, RequestError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.commands
+            .send(AvdtpCommand {
+                signal_identifier,
+                data,
+                sender: tx
+            })
+            .await
+            .map_err(|_| RequestError::SessionClosed)?;
+        timeout(self.timeout, rx)
+            .await
+            .map_err(|_| RequestError::Timeout)?
+            .map_err(|_| RequestError::SessionClosed)?
+    }
+
+    async fn send_stream_command(&self, signal_identifier: SignalIdentifier, seid: u8) -> Result<(), RequestError> {
+        let mut result = self
+            .send_command(signal_identifier, Bytes::from(vec![seid << 2]))
+            .await?;
+        result.finish()?;
+        Ok(())
+    }
+
+    /// Lists the stream endpoints of the remote device ([AVDTP] Section 8.6).
+    pub async fn discover(&self) -> Result<Vec<StreamEndpoint>, RequestError> {
+        let mut result = self
+            .send_command(SignalIdentifier::Discover, Bytes::new())
+            .await?;
+        let mut endpoints = Vec::new();
+        while !result.is_empty() {
+            endpoints.push(result.read_be()?);
+        }
+        Ok(endpoints)
+    }
+
+    /// The basic and extended capabilities of a remote endpoint ([AVDTP] Section 8.8).
+    pub async fn get_all_capabilities(&self, seid: u8) -> Result<Vec<Capability>, RequestError> {
+        let mut result = self
+            .send_command(SignalIdentifier::GetAllCapabilities, Bytes::from(vec![seid << 2]))
+            .await?;
+        let capabilities: Vec<Capability> = result.read_be()?;
+        result.finish()?;
+        Ok(capabilities)
+    }
+
+    /// Configures a stream between the remote endpoint `seid` and the local endpoint `local_seid` ([AVDTP] Section 8.9).
+    pub async fn set_configuration(&self, seid: u8, local_seid: u8, capabilities: Vec<Capability>) -> Result<(), RequestError> {
+        let mut data = BytesMut::new();
+        data.write_be(seid << 2);
+        data.write_be(local_seid << 2);
+        data.write_be(capabilities);
+        let mut result = self
+            .send_command(SignalIdentifier::SetConfiguration, data.freeze())
+            .await?;
+        result.finish()?;
+        Ok(())
+    }
+
+    /// Opens a configured stream. Returns once the transport channel is established ([AVDTP] Section 8.12).
+    pub async fn open(&self, seid: u8) -> Result<(), RequestError> {
+        self.send_stream_command(SignalIdentifier::Open, seid)
+            .await
+    }
+
+    /// ([AVDTP] Section 8.13).
+    pub async fn start(&self, seid: u8) -> Result<(), RequestError> {
+        self.send_stream_command(SignalIdentifier::Start, seid)
+            .await
+    }
+
+    /// ([AVDTP] Section 8.15).
+    pub async fn suspend(&self, seid: u8) -> Result<(), RequestError> {
+        self.send_stream_command(SignalIdentifier::Suspend, seid)
+            .await
+    }
+
+    /// Closes the stream and releases its transport channel ([AVDTP] Section 8.14).
+    pub async fn close(&self, seid: u8) -> Result<(), RequestError> {
+        self.send_stream_command(SignalIdentifier::Close, seid)
+            .await
+    }
+
+    /// Reports the playback delay of a local sink to the remote source ([AVDTP] Section 8.19).
+    ///
+    /// Streams whose handler implements [`poll_delay_report`](crate::avdtp::StreamHandler::poll_delay_report) report on their own.
+    pub async fn delay_report(&self, seid: u8, delay: Duration) -> Result<(), RequestError> {
+        let mut result = self
+            .send_command(SignalIdentifier::DelayReport, encode_delay_report(seid, delay))
+            .await?;
+        result.finish()?;
+        Ok(())
+    }
+
+    /// Tears down the stream regardless of its state ([AVDTP] Section 8.16).
+    pub async fn abort(&self, seid: u8) -> Result<(), RequestError> {
+        self.send_stream_command(SignalIdentifier::Abort, seid)
+            .await
+    }
+}
@@ -2,13 +2,15 @@ use std::future::{poll_fn, Future};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::fmt::Debug;
 
 use bytes::Bytes;
-use tracing::warn;
+use tracing::{trace, warn};
 
 use crate::avdtp::capabilities::Capability;
 use crate::avdtp::error::Error;
+use crate::avdtp::media::{MediaPacket, MediaReceiver};
 use crate::avdtp::packets::{MediaType, StreamEndpoint, StreamEndpointType};
 use crate::ensure;
 use crate::l2cap::channel::Channel;
@@ -66,14 +68,22 @@ enum StreamState {
     Closing //Aborting,
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StreamEvent {
+    /// The local sink wants to report its playback delay to the source.
+    DelayReport(Duration),
+    Closed
+}
+
 pub struct Stream {
     state: StreamState,
     endpoint_usage_lock: Arc<AtomicBool>,
     pub local_endpoint: u8,
-    #[allow(dead_code)]
     pub remote_endpoint: u8,
     capabilities: Vec<Capability>,
+    delay_reporting: bool,
     channel: Option<Channel>,
+    media: MediaReceiver,
     handler: Box<dyn StreamHandler>
 }
 
@@ -85,6 +95,8 @@ impl Stream {
             local_endpoint: local_endpoint.seid,
             remote_endpoint,
             state: StreamState::Configured,
+            media: MediaReceiver::new(&capabilities),
+            delay_reporting: capabilities.contains(&Capability::DELAY_REPORTING),
             capabilities,
             channel: None,
             handler,
@@ -96,6 +108,8 @@ impl Stream {
         assert_eq!(self.local_endpoint, ep.seid);
         ensure!(matches!(self.state, StreamState::Open), Error::BadState);
         self.handler = ep.factory.make_stream_handler(&capabilities);
+        self.media = MediaReceiver::new(&capabilities);
+        self.delay_reporting = capabilities.contains(&Capability::DELAY_REPORTING);
         self.capabilities = capabilities;
         Ok(())
     }
@@ -109,6 +123,7 @@ impl Stream {
 
     pub fn start(&mut self) -> Result<(), Error> {
         ensure!(matches!(self.state, StreamState::Open), Error::BadState);
+        self.media.reset();
         self.handler.on_play();
         self.state = StreamState::Streaming;
         Ok(())
@@ -131,6 +146,13 @@ impl Stream {
         Ok(())
     }
 
+    /// Passes the playback delay reported by the remote sink to the handler ([AVDTP] Section 8.19).
+    pub fn set_delay(&mut self, delay: Duration) -> Result<(), Error> {
+        ensure!(self.state != StreamState::Closing, Error::BadState);
+        self.handler.on_delay_report(delay);
+        Ok(())
+    }
+
     pub fn is_opening(&self) -> bool {
         matches!(self.state, StreamState::Opening)
     }
@@ -147,19 +169,39 @@ impl Stream {
         Ok(&self.capabilities)
     }
 
-    pub fn process(&mut self) -> impl Future<Output = ()> + '_ {
+    pub fn process(&mut self) -> impl Future<Output = StreamEvent> + '_ {
         poll_fn(move |cx| self.poll(cx))
     }
 
-    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<StreamEvent> {
+        // Delays can be reported in any state after the configuration ([AVDTP] Section 8.19).
+        if self.delay_reporting && self.state != StreamState::Closing {
+            if let Poll::Ready(delay) = self.handler.poll_delay_report(cx) {
+                return Poll::Ready(StreamEvent::DelayReport(delay));
+            }
+        }
+        if let Some(channel) = self.channel.as_mut().filter(|_| self.state == StreamState::Streaming) {
+            while let Poll::Ready(packet) = self.handler.poll_packet(cx, channel.remote_mtu()) {
+                if packet.len() > usize::from(channel.remote_mtu()) {
+                    warn!("Dropping media packet of {} bytes that exceeds the MTU of {}", packet.len(), channel.remote_mtu());
+                    continue;
+                }
+                match channel.try_write(packet) {
+                    Ok(true) => {}
+                    Ok(false) => trace!("Transport channel is congested, dropping media packet"),
+                    Err(err) => warn!("Failed to send media packet: {:?}", err)
+                }
+            }
+        }
         loop {
             match self.channel.as_mut() {
                 Some(channel) => {
                     match channel.poll_data(cx) {
                         Poll::Ready(Some(data)) => {
                             if self.state == StreamState::Streaming {
-                                //TODO Parse the realtime media header and do something useful with it
-                                self.handler.on_data(data.slice(12..));
+                                for packet in self.media.receive(data) {
+                                    self.handler.on_data(packet);
+                                }
                             } else {
                                 warn!("Data received while not streaming");
                             }
@@ -167,14 +209,14 @@ impl Stream {
                         Poll::Ready(None) => {
                             self.state = StreamState::Closing;
                             self.channel = None;
-                            return Poll::Ready(());
+                            return Poll::Ready(StreamEvent::Closed);
                         }
                         Poll::Pending => return Poll::Pending
                     }
                 }
                 None => {
                     return match self.state {
-                        StreamState::Closing => Poll::Ready(()),
+                        StreamState::Closing => Poll::Ready(StreamEvent::Closed),
                         _ => Poll::Pending
                     };
                 }
@@ -193,5 +235,24 @@ pub trait StreamHandler: 'static {
     fn on_play(&mut self);
     fn on_stop(&mut self);
 
-    fn on_data(&mut self, data: Bytes);
+    /// Receives the frames of a sink endpoint in the order they were sent.
+    fn on_data(&mut self, packet: MediaPacket);
+
+    /// Produces the next media packet of a source endpoint while the stream is started.
+    ///
+    /// Packets must fit into `mtu` and get dropped when the transport channel can't keep up.
+    fn poll_packet(&mut self, _cx: &mut Context<'_>, _mtu: u16) -> Poll<Bytes> {
+        Poll::Pending
+    }
+
+    /// Produces the time from receiving media until it is heard on a sink endpoint.
+    ///
+    /// Only polled if the stream was configured with [`Capability::DELAY_REPORTING`]. A sink should
+    /// report right after the configuration and again whenever the delay changes.
+    fn poll_delay_report(&mut self, _cx: &mut Context<'_>) -> Poll<Duration> {
+        Poll::Pending
+    }
+
+    /// Receives the playback delay of the remote sink on a source endpoint.
+    fn on_delay_report(&mut self, _delay: Duration) {}
 }
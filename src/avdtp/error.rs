@@ -1,5 +1,6 @@
 use instructor::{Error as InstructorError, Exstruct, Instruct};
 use thiserror::Error;
+use tracing::error;
 
 // [AVDTP] Section 8.20.6.2.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct, Error)]
@@ -87,3 +88,32 @@ impl From<InstructorError> for Error {
         }
     }
 }
+
+/// Why a command sent as initiator (INT) failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Error)]
+pub enum RequestError {
+    #[error("The AVDTP session has been closed.")]
+    SessionClosed,
+    #[error("All 16 transaction labels are currently occupied.")]
+    NoTransactionLabelAvailable,
+    #[error("The remote device did not respond in time.")]
+    Timeout,
+    #[error("The remote device does not understand the command.")]
+    GeneralReject,
+    #[error("The remote device rejected the command (reason: {0}).")]
+    Rejected(Error),
+    #[error("The command is not possible in the state of the local endpoint (reason: {0}).")]
+    BadState(Error),
+    #[error("The transport channel could not be opened.")]
+    TransportFailed,
+    #[error("The returned data has an invalid format.")]
+    InvalidReturnData
+}
+
+impl From<InstructorError> for RequestError {
+    #[track_caller]
+    fn from(value: InstructorError) -> Self {
+        error!("Parsing error {} at {}", value, std::panic::Location::caller());
+        Self::InvalidReturnData
+    }
+}
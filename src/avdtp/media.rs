@@ -0,0 +1,348 @@
+use std::mem::take;
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use instructor::Buffer;
+use tracing::{debug, trace, warn};
+
+use crate::a2dp::sbc::{frame_length, SbcMediaPayloadHeader};
+use crate::avdtp::capabilities::{AudioCodec, Capability, MediaCodec, MediaCodecCapability};
+use crate::avdtp::rtp::RtpPacket;
+
+/// Sequence numbers that jump further than this in either direction are taken as a restart of the sender
+/// instead of loss or reordering ([RFC 3550] Appendix A.1).
+const MAX_DROPOUT: u16 = 3000;
+
+/// Complete codec frames received over a transport channel along with their timing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaPacket {
+    /// The sequence number of the first media packet that carried the frames.
+    pub sequence_number: u16,
+    /// The sampling instant of the first frame in units of the codec's clock rate.
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub csrc: Vec<u32>,
+    /// How many media packets went missing since the previous [`MediaPacket`].
+    pub lost: u16,
+    /// When the last media packet that carried the frames arrived.
+    pub received: Instant,
+    pub frames: Vec<Bytes>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// Any number of SBC frames behind a payload header, or a single frame split over several packets ([A2DP] Section 4.3.4).
+    Sbc,
+    /// One LATM AudioMuxElement split over packets, the last of which has the marker bit set ([A2DP] Section 4.5.4, [RFC 3016] Section 4.1).
+    Latm,
+    /// The whole payload is a single frame.
+    Whole
+}
+
+/// Parses the media packets of a stream, detects lost and reordered packets and splits or reassembles the codec frames.
+#[derive(Debug)]
+pub struct MediaReceiver {
+    framing: Framing,
+    ssrc: Option<u32>,
+    expected_sequence_number: Option<u16>,
+    lost: u16,
+    reordered: u64,
+    fragment: Option<(MediaPacket, BytesMut)>
+}
+
+impl MediaReceiver {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        let framing = capabilities
+            .iter()
+            .find_map(|capability| match capability {
+                Capability::MediaCodec(MediaCodecCapability::Sbc(_)) => Some(Framing::Sbc),
+                Capability::MediaCodec(MediaCodecCapability::Generic(MediaCodec::Audio(AudioCodec::Mpeg24Acc), _)) => Some(Framing::Latm),
+                Capability::MediaCodec(_) => Some(Framing::Whole),
+                _ => None
+            })
+            .unwrap_or(Framing::Whole);
+        Self {
+            framing,
+            ssrc: None,
+            expected_sequence_number: None,
+            lost: 0,
+            reordered: 0,
+            fragment: None
+        }
+    }
+
+    /// The number of packets that were dropped because they arrived late or twice.
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+
+    /// Forgets the sequence numbers and partial frames, e.g. when the stream gets restarted.
+    pub fn reset(&mut self) {
+        self.ssrc = None;
+        self.expected_sequence_number = None;
+        self.lost = 0;
+        self.fragment = None;
+    }
+
+    /// Processes the payload of the transport channel and returns the frames it completes.
+    pub fn receive(&mut self, data: Bytes) -> impl Iterator<Item = MediaPacket> {
+        let mut completed = [None, None];
+        match RtpPacket::parse(data) {
+            Ok(packet) => self.receive_packet(packet, &mut completed),
+            Err(err) => warn!("Received invalid media packet: {:?}", err)
+        }
+        completed.into_iter().flatten()
+    }
+
+    fn receive_packet(&mut self, packet: RtpPacket, completed: &mut [Option<MediaPacket>; 2]) {
+        let sequence_number = packet.header.sequence_number;
+        if self.ssrc.is_some_and(|ssrc| ssrc != packet.header.ssrc) {
+            debug!("Media source changed to {:08X}, resynchronizing", packet.header.ssrc);
+            self.reset();
+        }
+        if let Some(expected) = self.expected_sequence_number {
+            match sequence_number.wrapping_sub(expected) as i16 {
+                0 => {}
+                offset if offset.unsigned_abs() > MAX_DROPOUT => {
+                    debug!("Sequence number jumped from {} to {}, resynchronizing", expected, sequence_number);
+                    self.reset();
+                }
+                offset if offset < 0 => {
+                    debug!("Dropping late or duplicate media packet {} (expected {})", sequence_number, expected);
+                    self.reordered += 1;
+                    return;
+                }
+                offset => {
+                    debug!("Lost {} media packets before {}", offset, sequence_number);
+                    self.lost = self.lost.saturating_add(offset as u16);
+                    if self.fragment.take().is_some() {
+                        debug!("Discarding incomplete frame");
+                    }
+                }
+            }
+        }
+        self.ssrc = Some(packet.header.ssrc);
+        self.expected_sequence_number = Some(sequence_number.wrapping_add(1));
+
+        let marker = packet.header.marker;
+        let mut payload = packet.payload;
+        let media = MediaPacket {
+            sequence_number,
+            timestamp: packet.header.timestamp,
+            ssrc: packet.header.ssrc,
+            csrc: packet.csrc,
+            lost: 0,
+            received: Instant::now(),
+            frames: Vec::new()
+        };
+        match self.framing {
+            Framing::Sbc => {
+                let Ok(header) = payload.read_be::<SbcMediaPayloadHeader>() else {
+                    warn!("Media packet is missing the SBC payload header");
+                    return;
+                };
+                if !header.fragmented {
+                    if self.fragment.take().is_some() {
+                        debug!("Discarding incomplete frame");
+                    }
+                    let frames = split_sbc_frames(payload, header.number_of_frames);
+                    completed[0] = Some(self.complete(MediaPacket { frames, ..media }));
+                    return;
+                }
+                if !self.append_fragment(media, payload, header.starting_packet) {
+                    trace!("Dropping fragment of a frame whose start is missing");
+                    return;
+                }
+                if header.last_packet {
+                    completed[0] = self.complete_fragment();
+                }
+            }
+            Framing::Latm => {
+                // All fragments of an AudioMuxElement share a timestamp ([RFC 3016] Section 4.1),
+                // so a new one also completes the previous element for senders that never set the marker bit.
+                if self
+                    .fragment
+                    .as_ref()
+                    .is_some_and(|(pending, _)| pending.timestamp != media.timestamp)
+                {
+                    completed[0] = self.complete_fragment();
+                }
+                let start = self.fragment.is_none();
+                self.append_fragment(media, payload, start);
+                if marker {
+                    completed[1] = self.complete_fragment();
+                }
+            }
+            Framing::Whole => {
+                completed[0] = Some(self.complete(MediaPacket {
+                    frames: vec![payload],
+                    ..media
+                }));
+            }
+        }
+    }
+
+    fn append_fragment(&mut self, media: MediaPacket, payload: Bytes, start: bool) -> bool {
+        if start {
+            self.fragment = Some((media, BytesMut::from(payload.as_ref())));
+            return true;
+        }
+        match self.fragment.as_mut() {
+            Some((pending, buffer)) => {
+                pending.received = media.received;
+                buffer.extend_from_slice(&payload);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn complete_fragment(&mut self) -> Option<MediaPacket> {
+        let (mut media, buffer) = self.fragment.take()?;
+        media.frames.push(buffer.freeze());
+        Some(self.complete(media))
+    }
+
+    fn complete(&mut self, mut media: MediaPacket) -> MediaPacket {
+        media.lost = take(&mut self.lost);
+        media
+    }
+}
+
+fn split_sbc_frames(mut payload: Bytes, count: u8) -> Vec<Bytes> {
+    let mut frames = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        match frame_length(&payload).filter(|&length| length <= payload.len()) {
+            Some(length) => frames.push(payload.split_to(length)),
+            None => {
+                warn!("Media packet contains an invalid SBC frame");
+                return frames;
+            }
+        }
+    }
+    if !payload.is_empty() {
+        warn!("Ignoring {} bytes after the last SBC frame", payload.len());
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use instructor::BufferMut;
+
+    use crate::a2dp::sbc::{SbcEncoder, SbcMediaCodecInformation, SbcMediaPayloadHeader};
+    use crate::a2dp::source::SbcMediaWriter;
+    use crate::avdtp::capabilities::{AudioCodec, Capability, MediaCodec, MediaCodecCapability};
+    use crate::avdtp::media::MediaReceiver;
+    use crate::avdtp::rtp::RtpHeader;
+
+    fn packet(sequence_number: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Bytes {
+        let mut buffer = BytesMut::new();
+        buffer.write_be(RtpHeader {
+            marker,
+            ..RtpHeader::new(sequence_number, timestamp, 1)
+        });
+        buffer.extend_from_slice(payload);
+        buffer.freeze()
+    }
+
+    fn source_packet(ssrc: u32, sequence_number: u16) -> Bytes {
+        let mut buffer = BytesMut::new();
+        buffer.write_be(RtpHeader::new(sequence_number, u32::from(sequence_number) * 128, ssrc));
+        buffer.extend_from_slice(&[0xAA]);
+        buffer.freeze()
+    }
+
+    fn fragment(starting_packet: bool, last_packet: bool, number_of_frames: u8, data: &[u8]) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        buffer.write_be(SbcMediaPayloadHeader {
+            fragmented: true,
+            starting_packet,
+            last_packet,
+            number_of_frames
+        });
+        buffer.extend_from_slice(data);
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn test_sbc_receiver() {
+        let config = SbcMediaCodecInformation::default()
+            .select_configuration(&SbcMediaCodecInformation::default())
+            .unwrap();
+        let mut writer = SbcMediaWriter::new(SbcEncoder::new(&config, config.maximum_bitpool).unwrap());
+        let pcm = vec![0; writer.samples_per_packet(895) * 2];
+        let packets: Vec<Bytes> = (0..4).flat_map(|_| writer.write_packets(&pcm, 895)).collect();
+
+        let mut receiver = MediaReceiver::new(&[Capability::MediaTransport, Capability::MediaCodec(MediaCodecCapability::Sbc(config))]);
+        let media: Vec<_> = receiver.receive(packets[0].clone()).collect();
+        assert_eq!(media.len(), 1);
+        assert_eq!(media[0].sequence_number, 0);
+        assert_eq!(media[0].lost, 0);
+        assert_eq!(media[0].frames.len(), 7);
+        assert!(media[0].frames.iter().all(|frame| frame.len() == 119));
+
+        let media: Vec<_> = receiver.receive(packets[2].clone()).collect();
+        assert_eq!(media[0].lost, 1);
+        assert_eq!(media[0].timestamp, 2 * 7 * 128);
+        assert_eq!(receiver.receive(packets[1].clone()).count(), 0);
+        assert_eq!(receiver.reordered(), 1);
+        assert_eq!(receiver.receive(packets[3].clone()).next().unwrap().lost, 0);
+
+        // A fragmented frame is only delivered once complete, and not at all if a fragment is missing.
+        assert_eq!(receiver.receive(packet(4, 4096, false, &fragment(true, false, 3, &[1, 2]))).count(), 0);
+        assert_eq!(receiver.receive(packet(5, 4096, false, &fragment(false, false, 2, &[3, 4]))).count(), 0);
+        let media: Vec<_> = receiver
+            .receive(packet(6, 4096, false, &fragment(false, true, 1, &[5])))
+            .collect();
+        assert_eq!(media[0].sequence_number, 4);
+        assert_eq!(media[0].frames, vec![Bytes::from_static(&[1, 2, 3, 4, 5])]);
+        assert_eq!(receiver.receive(packet(7, 4224, false, &fragment(true, false, 2, &[1]))).count(), 0);
+        assert_eq!(receiver.receive(packet(9, 4352, false, &fragment(false, true, 1, &[2]))).count(), 0);
+        assert_eq!(receiver.receive(packets[0].clone()).count(), 0);
+    }
+
+    #[test]
+    fn test_aac_receiver() {
+        let codec = MediaCodec::Audio(AudioCodec::Mpeg24Acc);
+        let mut receiver = MediaReceiver::new(&[Capability::MediaCodec(MediaCodecCapability::Generic(codec, vec![]))]);
+        assert_eq!(receiver.receive(packet(0, 0, false, &[1, 2])).count(), 0);
+        let media: Vec<_> = receiver.receive(packet(1, 0, true, &[3])).collect();
+        assert_eq!(media.len(), 1);
+        assert_eq!(media[0].frames, vec![Bytes::from_static(&[1, 2, 3])]);
+
+        // Without the marker bit, an element ends with the next timestamp.
+        assert_eq!(receiver.receive(packet(2, 1024, false, &[4])).count(), 0);
+        let media: Vec<_> = receiver.receive(packet(3, 2048, true, &[5])).collect();
+        assert_eq!(media.len(), 2);
+        assert_eq!(media[0].timestamp, 1024);
+        assert_eq!(media[0].frames, vec![Bytes::from_static(&[4])]);
+        assert_eq!(media[1].frames, vec![Bytes::from_static(&[5])]);
+    }
+
+    #[test]
+    fn test_resynchronization() {
+        let mut receiver = MediaReceiver::new(&[]);
+        let lost = |receiver: &mut MediaReceiver, packet: Bytes| receiver.receive(packet).next().map(|media| media.lost);
+
+        // Sequence numbers wrap around
+        for sequence_number in [65534, 65535, 0] {
+            assert_eq!(lost(&mut receiver, source_packet(1, sequence_number)), Some(0));
+        }
+        assert_eq!(lost(&mut receiver, source_packet(1, 2)), Some(1));
+        assert_eq!(lost(&mut receiver, source_packet(1, 1)), None);
+        assert_eq!(lost(&mut receiver, source_packet(1, 65535)), None);
+        assert_eq!(receiver.reordered(), 2);
+
+        // A new source starts with its own sequence numbers
+        let media: Vec<_> = receiver.receive(source_packet(2, 100)).collect();
+        assert_eq!((media[0].ssrc, media[0].lost), (2, 0));
+        assert_eq!(lost(&mut receiver, source_packet(2, 101)), Some(0));
+
+        // So does a sender that restarts without changing its source
+        assert_eq!(lost(&mut receiver, source_packet(2, 50000)), Some(0));
+        assert_eq!(lost(&mut receiver, source_packet(2, 50001)), Some(0));
+        assert_eq!(receiver.reordered(), 2);
+    }
+}
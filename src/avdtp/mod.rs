@@ -1,35 +1,47 @@
 pub mod capabilities;
+mod client;
 mod endpoint;
 mod error;
+mod media;
 mod packets;
+pub mod rtp;
 pub mod utils;
 
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use instructor::{BigEndian, Buffer, BufferMut, Instruct};
 use parking_lot::Mutex;
 use tokio::runtime::Handle;
+use tokio::sync::mpsc::Receiver as MpscReceiver;
 use tokio::sync::oneshot::{Receiver, Sender};
+use tokio::time::timeout;
 use tokio::{select, spawn};
 use tracing::{debug, trace, warn, error};
 
 use crate::avdtp::capabilities::Capability;
-use crate::avdtp::endpoint::Stream;
+use crate::avdtp::client::{decode_delay, encode_delay_report, AvdtpCommand, CommandResponseSender};
+use crate::avdtp::endpoint::{Stream, StreamEvent};
 use crate::avdtp::packets::{MessageType, ServiceCategory, SignalChannelExt, SignalIdentifier, SignalMessage, SignalMessageAssembler};
 use crate::ensure;
+use crate::hci::security::SecurityLevel;
 use crate::l2cap::channel::{Channel, Error as L2capError};
-use crate::l2cap::{ProtocolHandler, AVDTP_PSM, L2capServer};
+use crate::l2cap::configuration::{ChannelConfig, Mtu};
+use crate::l2cap::{AclLink, L2capHandle, ProtocolHandler, AVDTP_PSM};
 use crate::utils::{select_all, MutexCell, OptionFuture, LoggableResult, IgnoreableResult};
 
+pub use client::{AvdtpClient, RESPONSE_TIMEOUT};
 pub use endpoint::{LocalEndpoint, StreamHandler, StreamHandlerFactory};
-pub use packets::{MediaType, StreamEndpointType};
-use crate::avdtp::error::Error;
+pub use error::{Error, RequestError};
+pub use media::{MediaPacket, MediaReceiver};
+pub use packets::{MediaType, StreamEndpoint, StreamEndpointType};
 
 #[derive(Default)]
 pub struct AvdtpBuilder {
-    endpoints: Vec<LocalEndpoint>
+    endpoints: Vec<LocalEndpoint>,
+    security_requirement: SecurityLevel
 }
 
 impl AvdtpBuilder {
@@ -38,10 +50,17 @@ impl AvdtpBuilder {
         self
     }
 
+    /// Only accepts AVDTP channels on links that reach `level`. No requirement by default.
+    pub fn with_security_requirement(mut self, level: SecurityLevel) -> Self {
+        self.security_requirement = level;
+        self
+    }
+
     pub fn build(self) -> Avdtp {
         Avdtp {
             pending_streams: Arc::new(Mutex::new(BTreeMap::new())),
-            local_endpoints: self.endpoints.into()
+            local_endpoints: self.endpoints.into(),
+            security_requirement: self.security_requirement
         }
     }
 }
@@ -51,19 +70,65 @@ type ChannelSender = MutexCell<Option<Sender<Channel>>>;
 #[derive(Clone)]
 pub struct Avdtp {
     pending_streams: Arc<Mutex<BTreeMap<u16, Arc<ChannelSender>>>>,
-    local_endpoints: Arc<[LocalEndpoint]>
+    local_endpoints: Arc<[LocalEndpoint]>,
+    security_requirement: SecurityLevel
 }
 
 impl Avdtp {
+    /// Opens the signaling channel to a remote device to act as initiator (INT) of the session.
+    ///
+    /// The session still answers the commands of the remote device. Sessions opened by the remote device
+    /// only act as acceptor (ACP).
+    pub async fn connect(&self, l2cap: &L2capHandle, link: impl Into<AclLink>) -> Result<AvdtpClient, L2capError> {
+        let channel = l2cap
+            .open_channel_with_config(link, self.psm(), signaling_config())
+            .await?;
+        let handle = channel.connection_handle();
+        let pending_stream = Arc::new(ChannelSender::default());
+        {
+            let mut pending_streams = self.pending_streams.lock();
+            // There is only one signaling channel per device ([AVDTP] Section 5.4.6).
+            ensure!(!pending_streams.contains_key(&handle), L2capError::BadState);
+            pending_streams.insert(handle, pending_stream.clone());
+        }
+        Ok(self.start_session(channel, pending_stream, Some(l2cap.clone())))
+    }
 
-    pub fn connect(self: Arc<Self>, l2cap: &mut L2capServer, handle: u16) {
-        let mut channel = l2cap.new_channel(handle).expect("Failed to create channel");
-        spawn(async move {
-            channel.connect(self.psm()).await.ignore();
-            self.handle(channel);
+    fn start_session(&self, channel: Channel, pending_stream: Arc<ChannelSender>, l2cap: Option<L2capHandle>) -> AvdtpClient {
+        let handle = channel.connection_handle();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let local_endpoints = self.local_endpoints.clone();
+        let pending_streams = self.pending_streams.clone();
+        // Use an OS thread instead a tokio task to avoid blocking the runtime with audio processing
+        let runtime = Handle::current();
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let mut session = AvdtpSession {
+                    handle,
+                    l2cap,
+                    channel_sender: pending_stream,
+                    channel_receiver: OptionFuture::never(),
+                    local_endpoints,
+                    streams: Vec::new(),
+                    commands: rx,
+                    outstanding_transactions: Default::default(),
+                    opening: None
+                };
+                session
+                    .handle_control_channel(channel)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("Error handling control channel: {:?}", err);
+                    });
+                trace!("AVDTP signaling session ended for 0x{:04x}", handle);
+                pending_streams.lock().remove(&handle);
+            })
         });
+        AvdtpClient {
+            commands: tx,
+            timeout: RESPONSE_TIMEOUT
+        }
     }
-
 }
 
 impl ProtocolHandler for Avdtp {
@@ -71,6 +136,10 @@ impl ProtocolHandler for Avdtp {
         AVDTP_PSM as u64
     }
 
+    fn security_requirement(&self) -> SecurityLevel {
+        self.security_requirement
+    }
+
     fn handle(&self, mut channel: Channel) {
         let handle = channel.connection_handle();
         let pending_stream = self.pending_streams.lock().get(&handle).cloned();
@@ -83,40 +152,28 @@ impl ProtocolHandler for Avdtp {
                     .lock()
                     .insert(handle, pending_stream.clone());
 
-                let local_endpoints = self.local_endpoints.clone();
-
+                // Signaling messages are small and get fragmented when they don't fit ([AVDTP] Section 8.4).
+                if channel.set_config(signaling_config()).log_err().is_err() {
+                    return;
+                }
                 if channel.is_response_pending() && channel.accept_connection().log_err().is_err() {
                     return;
                 }
-                // Use an OS thread instead a tokio task to avoid blocking the runtime with audio processing
-                let runtime = Handle::current();
-                std::thread::spawn(move || {
-                    runtime.block_on(async move {
-                        if let Err(err) = channel.configure().await {
-                            warn!("Error configuring channel: {:?}", err);
-                            return;
-                        }
-                        let mut session = AvdtpSession {
-                            channel_sender: pending_stream,
-                            channel_receiver: OptionFuture::never(),
-                            local_endpoints,
-                            streams: Vec::new()
-                        };
-                        session
-                            .handle_control_channel(channel)
-                            .await
-                            .unwrap_or_else(|err| {
-                                warn!("Error handling control channel: {:?}", err);
-                            });
-                        trace!("AVDTP signaling session ended for 0x{:04x}", handle);
+                let avdtp = self.clone();
+                spawn(async move {
+                    if let Err(err) = channel.configure().await {
+                        warn!("Error configuring channel: {:?}", err);
                         pending_streams.lock().remove(&handle);
-                    })
+                        return;
+                    }
+                    // The remote device is the initiator, so the client is not needed.
+                    avdtp.start_session(channel, pending_stream, None);
                 });
             }
             Some(pending) => match pending.take() {
                 Some(sender) => {
                     trace!("Existing AVDTP session (transport channel)");
-                    if channel.accept_connection().log_err().is_err() {
+                    if channel.set_config(media_config()).log_err().is_err() || channel.accept_connection().log_err().is_err() {
                         return;
                     }
                     spawn(async move {
@@ -140,11 +197,32 @@ impl ProtocolHandler for Avdtp {
     }
 }
 
+fn signaling_config() -> ChannelConfig {
+    ChannelConfig::default().with_mtu(Mtu::DEFAULT_ACL_U.0)
+}
+
+/// Media packets aren't fragmented by AVDTP, so the transport channel keeps the large default MTU to fit whole packets.
+fn media_config() -> ChannelConfig {
+    ChannelConfig::default()
+}
+
+struct PendingCommand {
+    signal_identifier: SignalIdentifier,
+    data: Bytes,
+    sender: CommandResponseSender
+}
+
 struct AvdtpSession {
+    handle: u16,
+    l2cap: Option<L2capHandle>,
     channel_sender: Arc<ChannelSender>,
     channel_receiver: OptionFuture<Receiver<Channel>>,
     local_endpoints: Arc<[LocalEndpoint]>,
-    streams: Vec<Stream>
+    streams: Vec<Stream>,
+    commands: MpscReceiver<AvdtpCommand>,
+    outstanding_transactions: [Option<PendingCommand>; 16],
+    /// The OPEN command we sent completes once the transport channel is connected.
+    opening: Option<CommandResponseSender>
 }
 
 impl AvdtpSession {
@@ -152,16 +230,23 @@ impl AvdtpSession {
         let mut assembler = SignalMessageAssembler::default();
         loop {
             select! {
-                (i, _) = select_all(self.streams.iter_mut().map(Stream::process)) => {
-                    debug!("Stream {} ended", i);
-                    self.streams.swap_remove(i);
+                (i, event) = select_all(self.streams.iter_mut().map(Stream::process)) => match event {
+                    StreamEvent::DelayReport(delay) => {
+                        let seid = self.streams[i].remote_endpoint;
+                        self.send_delay_report(&mut channel, seid, delay).await?;
+                    }
+                    StreamEvent::Closed => {
+                        debug!("Stream {} ended", i);
+                        self.streams.swap_remove(i);
+                    }
                 },
                 signal = channel.read() => match signal {
                     Some(packet) => match assembler.process_msg(packet) {
-                        Ok(Some(header)) => {
-                            let reply = self.handle_signal_message(header);
+                        Ok(Some(msg)) if msg.message_type == MessageType::Command => {
+                            let reply = self.handle_signal_message(msg);
                             channel.send_signal(reply).await?;
                         }
+                        Ok(Some(msg)) => self.handle_response(msg),
                         Ok(None) => continue,
                         Err(err) => {
                             warn!("Error processing signaling message: {:?}", err);
@@ -171,18 +256,184 @@ impl AvdtpSession {
                     None => break,
                 },
                 res = &mut self.channel_receiver => {
-                    let channel = res.expect("Channel receiver closed");
-                    self.streams
-                        .iter_mut()
-                        .find(|stream| stream.is_opening())
-                        .map(|stream| stream.set_channel(channel))
-                        .unwrap_or_else(|| warn!("No stream waiting for channel"));
+                    let opened = match res {
+                        Ok(channel) => self.streams
+                            .iter_mut()
+                            .find(|stream| stream.is_opening())
+                            .map(|stream| stream.set_channel(channel))
+                            .is_some(),
+                        Err(_) => false
+                    };
+                    if !opened {
+                        warn!("Failed to establish transport channel");
+                    }
+                    if let Some(sender) = self.opening.take() {
+                        let _ = sender.send(opened.then(Bytes::new).ok_or(RequestError::TransportFailed));
+                    }
+                },
+                Some(command) = self.commands.recv() => self.send_command(&mut channel, command).await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_command(&mut self, channel: &mut Channel, AvdtpCommand { signal_identifier, data, sender }: AvdtpCommand) -> Result<(), L2capError> {
+        if let Err(err) = self.check_command(signal_identifier, data.clone()) {
+            let _ = sender.send(Err(RequestError::BadState(err)));
+            return Ok(());
+        }
+        // Labels of commands whose initiator stopped waiting can be reused.
+        let Some(transaction_label) = self
+            .outstanding_transactions
+            .iter()
+            .position(|pending| !matches!(pending, Some(pending) if !pending.sender.is_closed()))
+        else {
+            let _ = sender.send(Err(RequestError::NoTransactionLabelAvailable));
+            return Ok(());
+        };
+        trace!("Sending {:?} command with label {}", signal_identifier, transaction_label);
+        channel
+            .send_signal(SignalMessage {
+                transaction_label: transaction_label as u8,
+                message_type: MessageType::Command,
+                signal_identifier,
+                data: data.clone()
+            })
+            .await?;
+        self.outstanding_transactions[transaction_label] = Some(PendingCommand {
+            signal_identifier,
+            data,
+            sender
+        });
+        Ok(())
+    }
+
+    async fn send_delay_report(&mut self, channel: &mut Channel, seid: u8, delay: Duration) -> Result<(), L2capError> {
+        trace!("Reporting a delay of {:?} for 0x{:02x}", delay, seid);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.send_command(channel, AvdtpCommand {
+            signal_identifier: SignalIdentifier::DelayReport,
+            data: encode_delay_report(seid, delay),
+            sender: tx
+        })
+        .await?;
+        // Nobody waits for the response, but the transaction label stays taken until it arrives.
+        spawn(async move {
+            match timeout(RESPONSE_TIMEOUT, rx).await {
+                Ok(Ok(Err(err))) => warn!("Delay report failed: {:?}", err),
+                Err(_) => warn!("Delay report timed out"),
+                _ => {}
+            }
+        });
+        Ok(())
+    }
+
+    /// Refuses commands that can't be applied to the local side of the stream before they are sent.
+    fn check_command(&mut self, signal_identifier: SignalIdentifier, mut data: Bytes) -> Result<(), Error> {
+        match signal_identifier {
+            SignalIdentifier::SetConfiguration => {
+                let _acp_seid = data.read_be::<u8>()?;
+                let local_seid = data.read_be::<u8>()? >> 2;
+                let ep = self.get_endpoint(local_seid)?;
+                ensure!(!ep.as_stream_endpoint().in_use, Error::SepInUse);
+            }
+            SignalIdentifier::Open => {
+                ensure!(self.l2cap.is_some() && self.opening.is_none(), Error::BadState);
+                self.get_remote_stream(data.read_be::<u8>()? >> 2)?;
+            }
+            SignalIdentifier::Start | SignalIdentifier::Suspend | SignalIdentifier::Close | SignalIdentifier::DelayReport => {
+                self.get_remote_stream(data.read_be::<u8>()? >> 2)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_response(&mut self, msg: SignalMessage) {
+        let transaction = &mut self.outstanding_transactions[msg.transaction_label as usize];
+        // General rejects may not carry the signal identifier of the command ([AVDTP] Section 8.18).
+        let Some(pending) = transaction
+            .take()
+            .filter(|pending| msg.message_type == MessageType::GeneralReject || pending.signal_identifier == msg.signal_identifier)
+        else {
+            warn!("Received response with no/wrong outstanding transaction: {:?}", msg);
+            return;
+        };
+        let result = match msg.message_type {
+            MessageType::ResponseAccept => {
+                trace!("{:?} command accepted", pending.signal_identifier);
+                match self.command_accepted(pending.signal_identifier, pending.data) {
+                    Ok(()) if pending.signal_identifier == SignalIdentifier::Open => {
+                        self.opening = Some(pending.sender);
+                        return;
+                    }
+                    Ok(()) => Ok(msg.data),
+                    Err(err) => {
+                        warn!("Failed to apply accepted {:?} command: {:?}", pending.signal_identifier, err);
+                        Err(RequestError::BadState(err))
+                    }
                 }
             }
+            // The error code is always the last byte of a reject ([AVDTP] Section 8.20.6).
+            MessageType::ResponseReject => {
+                let mut data = msg.data.slice(msg.data.len().saturating_sub(1)..);
+                match data.read_be::<Error>() {
+                    Ok(reason) => Err(RequestError::Rejected(reason)),
+                    Err(err) => Err(err.into())
+                }
+            }
+            MessageType::GeneralReject => Err(RequestError::GeneralReject),
+            MessageType::Command => unreachable!()
+        };
+        let _ = pending.sender.send(result);
+    }
+
+    /// Updates the local side of the stream after the remote device accepted a command.
+    fn command_accepted(&mut self, signal_identifier: SignalIdentifier, mut data: Bytes) -> Result<(), Error> {
+        match signal_identifier {
+            SignalIdentifier::SetConfiguration => {
+                let acp_seid = data.read_be::<u8>()? >> 2;
+                let int_seid = data.read_be::<u8>()? >> 2;
+                let capabilities: Vec<Capability> = data.read_be()?;
+                let ep = self.get_endpoint(int_seid)?;
+                self.streams.push(Stream::new(ep, acp_seid, capabilities)?);
+            }
+            SignalIdentifier::Open => {
+                self.get_remote_stream(data.read_be::<u8>()? >> 2)?
+                    .set_to_opening()?;
+                // The initiator connects the transport channel after the OPEN response ([AVDTP] Section 6.11).
+                let l2cap = self.l2cap.clone().ok_or(Error::BadState)?;
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.channel_receiver.set(rx);
+                let handle = self.handle;
+                spawn(async move {
+                    match l2cap.open_channel_with_config(handle, AVDTP_PSM as u64, media_config()).await {
+                        Ok(channel) => tx
+                            .send(channel)
+                            .unwrap_or_else(|_| error!("Failed to send channel to session")),
+                        Err(err) => warn!("Failed to open transport channel: {:?}", err)
+                    }
+                });
+            }
+            SignalIdentifier::Start => self.get_remote_stream(data.read_be::<u8>()? >> 2)?.start()?,
+            SignalIdentifier::Suspend => self.get_remote_stream(data.read_be::<u8>()? >> 2)?.stop()?,
+            SignalIdentifier::Close => self.get_remote_stream(data.read_be::<u8>()? >> 2)?.close()?,
+            SignalIdentifier::Abort => {
+                let seid = data.read_be::<u8>()? >> 2;
+                self.streams.retain(|stream| stream.remote_endpoint != seid);
+            }
+            _ => {}
         }
         Ok(())
     }
 
+    fn get_remote_stream(&mut self, seid: u8) -> Result<&mut Stream, Error> {
+        self.streams
+            .iter_mut()
+            .find(|stream| stream.remote_endpoint == seid)
+            .ok_or(Error::BadAcpSeid)
+    }
+
     fn get_endpoint(&self, seid: u8) -> Result<&LocalEndpoint, Error> {
         self.local_endpoints
             .iter()
@@ -350,7 +601,15 @@ impl AvdtpSession {
             // ([AVDTP] Section 8.18).
             SignalIdentifier::Unknown => resp.general_reject(),
             // ([AVDTP] Section 8.19).
-            SignalIdentifier::DelayReport => resp.unsupported()
+            SignalIdentifier::DelayReport => resp.try_accept((), |_, _| {
+                let seid = data.read_be::<u8>()? >> 2;
+                let delay = decode_delay(data.read_be()?);
+                data.finish()?;
+                trace!("Got DELAYREPORT of {:?} for 0x{:02x}", delay, seid);
+                let stream = self.get_stream(seid)?;
+                stream.set_delay(delay)?;
+                Ok(())
+            })
         }
     }
 }
@@ -411,3 +670,113 @@ impl SignalMessageResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::spawn;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use crate::a2dp::sbc::SbcMediaCodecInformation;
+    use crate::avdtp::capabilities::Capability;
+    use crate::avdtp::{AvdtpBuilder, Error, LocalEndpoint, MediaPacket, MediaType, RequestError, StreamEndpoint, StreamEndpointType, StreamHandler, StreamHandlerFactory};
+    use crate::host::virtual_controller::VirtualLink;
+    use crate::l2cap::L2capServerBuilder;
+
+    struct EventRecorder(UnboundedSender<&'static str>, Option<Duration>);
+
+    impl StreamHandler for EventRecorder {
+        fn on_play(&mut self) {
+            self.0.send("play").unwrap();
+        }
+
+        fn on_stop(&mut self) {
+            self.0.send("stop").unwrap();
+        }
+
+        fn on_data(&mut self, _: MediaPacket) {}
+
+        fn poll_delay_report(&mut self, _: &mut Context<'_>) -> Poll<Duration> {
+            self.1.take().map_or(Poll::Pending, Poll::Ready)
+        }
+
+        fn on_delay_report(&mut self, delay: Duration) {
+            assert_eq!(delay, Duration::from_millis(150));
+            self.0.send("delay").unwrap();
+        }
+    }
+
+    fn endpoint(tsep: StreamEndpointType, events: UnboundedSender<&'static str>) -> LocalEndpoint {
+        LocalEndpoint {
+            media_type: MediaType::Audio,
+            seid: 1,
+            in_use: Arc::new(AtomicBool::new(false)),
+            tsep,
+            capabilities: vec![
+                Capability::MediaTransport,
+                Capability::MediaCodec(SbcMediaCodecInformation::default().into()),
+                Capability::DELAY_REPORTING
+            ],
+            factory: StreamHandlerFactory::new(move |_| {
+                let delay = (tsep == StreamEndpointType::Sink).then_some(Duration::from_millis(150));
+                EventRecorder(events.clone(), delay)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initiator() {
+        let mut link = VirtualLink::new().await;
+        let (tx, mut sink_events) = unbounded_channel();
+        let server = L2capServerBuilder::default()
+            .with_protocol(AvdtpBuilder::default().with_endpoint(endpoint(StreamEndpointType::Sink, tx)).build())
+            .run(&link.b)
+            .unwrap();
+        spawn(server);
+
+        let (tx, mut source_events) = unbounded_channel();
+        let avdtp = AvdtpBuilder::default()
+            .with_endpoint(endpoint(StreamEndpointType::Source, tx))
+            .build();
+        let server = L2capServerBuilder::default()
+            .with_protocol(avdtp.clone())
+            .run(&link.a)
+            .unwrap();
+        let l2cap = server.handle();
+        spawn(server);
+        link.connect().await;
+
+        let client = avdtp.connect(&l2cap, link.addr_b).await.unwrap();
+        assert_eq!(
+            client.discover().await.unwrap(),
+            vec![StreamEndpoint {
+                seid: 1,
+                in_use: false,
+                media_type: MediaType::Audio,
+                tsep: StreamEndpointType::Sink
+            }]
+        );
+        assert_eq!(client.get_all_capabilities(2).await, Err(RequestError::Rejected(Error::BadAcpSeid)));
+        let capabilities = client.get_all_capabilities(1).await.unwrap();
+        assert_eq!(client.open(1).await, Err(RequestError::BadState(Error::BadAcpSeid)));
+
+        client.set_configuration(1, 1, capabilities.clone()).await.unwrap();
+        assert_eq!(client.set_configuration(1, 1, capabilities).await, Err(RequestError::BadState(Error::SepInUse)));
+        // The sink reports its delay as soon as the stream is configured.
+        assert_eq!(source_events.recv().await, Some("delay"));
+        assert_eq!(client.start(1).await, Err(RequestError::Rejected(Error::BadState)));
+        client.open(1).await.unwrap();
+        client.start(1).await.unwrap();
+        assert_eq!(sink_events.recv().await, Some("play"));
+        assert_eq!(source_events.recv().await, Some("play"));
+        client.suspend(1).await.unwrap();
+        assert_eq!(sink_events.recv().await, Some("stop"));
+        assert_eq!(source_events.recv().await, Some("stop"));
+        client.close(1).await.unwrap();
+        assert!(client.discover().await.is_ok());
+    }
+}
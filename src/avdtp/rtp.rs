@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use instructor::{Buffer, Exstruct, Instruct};
+
+use crate::ensure;
+
+pub const RTP_VERSION: u8 = 2;
+
+/// The first payload type of the dynamic range, which A2DP uses for all codecs ([RFC 3551] Section 6).
+pub const DYNAMIC_PAYLOAD_TYPE: u8 = 96;
+
+// Media packets start with an RTP header ([AVDTP] Section 7.2.1, [RFC 3550] Section 5.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Instruct, Exstruct)]
+#[instructor(endian = "big")]
+pub struct RtpHeader {
+    #[instructor(bitfield(u8))]
+    #[instructor(bits(6..8))]
+    pub version: u8,
+    #[instructor(bits(5..6))]
+    pub padding: bool,
+    #[instructor(bits(4..5))]
+    pub extension: bool,
+    #[instructor(bits(0..4))]
+    pub csrc_count: u8,
+    #[instructor(bitfield(u8))]
+    #[instructor(bits(7..8))]
+    pub marker: bool,
+    #[instructor(bits(0..7))]
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32
+}
+
+impl RtpHeader {
+    pub const SIZE: usize = 12;
+
+    pub fn new(sequence_number: u16, timestamp: u32, ssrc: u32) -> Self {
+        Self {
+            version: RTP_VERSION,
+            padding: false,
+            extension: false,
+            csrc_count: 0,
+            marker: false,
+            payload_type: DYNAMIC_PAYLOAD_TYPE,
+            sequence_number,
+            timestamp,
+            ssrc
+        }
+    }
+}
+
+/// A media packet with the optional parts of the header split off and the padding removed ([RFC 3550] Section 5.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpPacket {
+    pub header: RtpHeader,
+    pub csrc: Vec<u32>,
+    pub extension: Option<RtpExtension>,
+    pub payload: Bytes
+}
+
+// ([RFC 3550] Section 5.3.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpExtension {
+    pub profile: u16,
+    pub data: Bytes
+}
+
+impl RtpPacket {
+    pub fn parse(mut data: Bytes) -> Result<Self, instructor::Error> {
+        let header: RtpHeader = data.read_be()?;
+        ensure!(header.version == RTP_VERSION, instructor::Error::InvalidValue);
+        if header.padding {
+            let padding = data.last().copied().unwrap_or_default() as usize;
+            ensure!(padding > 0 && padding <= data.len(), instructor::Error::InvalidValue);
+            data.truncate(data.len() - padding);
+        }
+        let csrc = (0..header.csrc_count)
+            .map(|_| data.read_be::<u32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let extension = if header.extension {
+            let profile: u16 = data.read_be()?;
+            let length = 4 * data.read_be::<u16>()? as usize;
+            ensure!(length <= data.len(), instructor::Error::TooShort);
+            Some(RtpExtension {
+                profile,
+                data: data.split_to(length)
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            header,
+            csrc,
+            extension,
+            payload: data
+        })
+    }
+}
@@ -1,10 +1,9 @@
 use std::io::Write;
 use std::path::Path;
 
-use bytes::Bytes;
 use tracing::debug;
 
-use crate::avdtp::StreamHandler;
+use crate::avdtp::{MediaPacket, StreamHandler};
 
 pub struct DebugStreamHandler;
 
@@ -17,8 +16,8 @@ impl StreamHandler for DebugStreamHandler {
         debug!("Stop");
     }
 
-    fn on_data(&mut self, data: Bytes) {
-        debug!("Data: {} bytes", data.len());
+    fn on_data(&mut self, packet: MediaPacket) {
+        debug!("Data: {} frames (timestamp: {}, lost: {})", packet.frames.len(), packet.timestamp, packet.lost);
     }
 }
 
@@ -41,10 +40,11 @@ impl StreamHandler for FileDumpHandler {
 
     fn on_stop(&mut self) {}
 
-    fn on_data(&mut self, data: Bytes) {
-        let data = &data.as_ref()[1..];
-        self.file.write_all(data).unwrap();
-        self.total += data.len();
+    fn on_data(&mut self, packet: MediaPacket) {
+        for frame in packet.frames {
+            self.file.write_all(&frame).unwrap();
+            self.total += frame.len();
+        }
         debug!("total: {}", self.total);
     }
 }
@@ -1,7 +1,10 @@
+use std::collections::{BTreeMap, VecDeque};
+
 use bytes::{BufMut, Bytes, BytesMut};
 use instructor::utils::Length;
-use instructor::{Exstruct, Instruct};
-use tracing::warn;
+use instructor::{BufferMut, Exstruct, Instruct};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{debug, warn};
 
 // use crate::l2cap::Error;
 use crate::utils::SliceExt;
@@ -85,3 +88,195 @@ pub enum BroadcastFlag {
     PointToPoint = 0b00,
     BrEdrBroadcast = 0b01
 }
+
+/// An L2CAP PDU waiting to be fragmented and sent to the controller.
+pub struct AclPacket {
+    pub handle: u16,
+    pub pdu: Bytes,
+    /// Released once the last fragment was handed to the controller, which lets senders limit their queue.
+    pub permit: Option<OwnedSemaphorePermit>
+}
+
+impl AclPacket {
+    fn cid(&self) -> u16 {
+        self.pdu
+            .get_chunk(2)
+            .copied()
+            .map_or(0, u16::from_le_bytes)
+    }
+}
+
+/// Queues outgoing PDUs per connection and channel and hands out fragments
+/// as long as the controller has free buffers ([Vol 4] Part E, Section 4.1.1).
+///
+/// Links take turns fragment by fragment, channels of the same link take turns PDU by PDU
+/// as the fragments of a PDU can't be interleaved with others on the same link.
+#[derive(Default)]
+pub struct AclScheduler {
+    links: BTreeMap<u16, LinkQueue>,
+    last_handle: Option<u16>,
+    max_size: usize,
+    max_in_flight: u32
+}
+
+#[derive(Default)]
+struct LinkQueue {
+    in_flight: u32,
+    current: Option<(Bytes, bool, Option<OwnedSemaphorePermit>)>,
+    channels: VecDeque<(u16, VecDeque<AclPacket>)>
+}
+
+impl LinkQueue {
+    fn is_ready(&self) -> bool {
+        self.current.is_some() || !self.channels.is_empty()
+    }
+
+    fn next_fragment(&mut self, handle: u16, max_size: usize) -> Bytes {
+        if self.current.is_none() {
+            let (cid, mut queue) = self
+                .channels
+                .pop_front()
+                .expect("Link has no queued data");
+            let packet = queue.pop_front().expect("Empty channel queue");
+            if !queue.is_empty() {
+                self.channels.push_back((cid, queue));
+            }
+            self.current = Some((packet.pdu, true, packet.permit));
+        }
+        let (remaining, first, _) = self.current.as_mut().unwrap();
+        let chunk = remaining.split_to(max_size.min(remaining.len()));
+        let mut buffer = BytesMut::with_capacity(chunk.len() + 4);
+        buffer.write(AclHeader {
+            handle,
+            pb: match *first {
+                true => BoundaryFlag::FirstNonAutomaticallyFlushable,
+                false => BoundaryFlag::Continuing
+            },
+            bc: BroadcastFlag::PointToPoint,
+            length: Length::new(chunk.len()).expect("Fragment too large")
+        });
+        buffer.put(chunk);
+        *first = false;
+        if remaining.is_empty() {
+            self.current = None;
+        }
+        self.in_flight += 1;
+        buffer.freeze()
+    }
+}
+
+impl AclScheduler {
+    /// Applies the result of `HCI_Read_Buffer_Size` ([Vol 4] Part E, Section 7.4.5).
+    pub fn set_buffer_size(&mut self, max_size: usize, max_in_flight: u32) {
+        self.max_size = max_size.max(1);
+        self.max_in_flight = max_in_flight;
+    }
+
+    pub fn connected(&mut self, handle: u16) {
+        self.links.entry(handle).or_default();
+    }
+
+    /// The controller flushes all packets of a closed link, so their buffers become free again
+    /// without a `HCI_Number_Of_Completed_Packets` event ([Vol 4] Part E, Section 7.7.5).
+    pub fn disconnected(&mut self, handle: u16) {
+        if let Some(link) = self.links.remove(&handle) {
+            debug!("Reclaiming {} ACL buffers of 0x{:04X}", link.in_flight, handle);
+        }
+    }
+
+    pub fn push(&mut self, packet: AclPacket) {
+        let Some(link) = self.links.get_mut(&packet.handle) else {
+            warn!("Dropping ACL data for unknown handle 0x{:04X}", packet.handle);
+            return;
+        };
+        let cid = packet.cid();
+        match link.channels.iter_mut().find(|(id, _)| *id == cid) {
+            Some((_, queue)) => queue.push_back(packet),
+            None => link.channels.push_back((cid, VecDeque::from([packet])))
+        }
+    }
+
+    /// Releases the buffers reported by `HCI_Number_Of_Completed_Packets` ([Vol 4] Part E, Section 7.7.19).
+    pub fn completed(&mut self, handle: u16, count: u16) {
+        if let Some(link) = self.links.get_mut(&handle) {
+            link.in_flight = link.in_flight.saturating_sub(u32::from(count));
+        }
+    }
+
+    pub fn in_flight(&self) -> u32 {
+        self.links.values().map(|link| link.in_flight).sum()
+    }
+
+    /// Returns the next fragment if the controller can accept it.
+    pub fn next(&mut self) -> Option<Bytes> {
+        if self.in_flight() >= self.max_in_flight {
+            return None;
+        }
+        // Continue with the link after the one that sent last
+        let start = self.last_handle.map_or(0, |handle| handle.wrapping_add(1));
+        let handle = self
+            .links
+            .range(start..)
+            .chain(self.links.range(..start))
+            .find(|(_, link)| link.is_ready())
+            .map(|(&handle, _)| handle)?;
+        self.last_handle = Some(handle);
+        let max_size = self.max_size;
+        self.links
+            .get_mut(&handle)
+            .map(|link| link.next_fragment(handle, max_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use instructor::Buffer;
+
+    use crate::hci::acl::{AclHeader, AclPacket, AclScheduler};
+
+    fn pdu(cid: u16, len: usize) -> Bytes {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(len as u16).to_le_bytes());
+        data.extend_from_slice(&cid.to_le_bytes());
+        data.resize(len + 4, cid as u8);
+        Bytes::from(data)
+    }
+
+    fn next(scheduler: &mut AclScheduler) -> Option<(u16, bool, u8)> {
+        scheduler.next().map(|mut fragment| {
+            let header: AclHeader = fragment.read().unwrap();
+            (header.handle, header.pb.is_first(), fragment[fragment.len() - 1])
+        })
+    }
+
+    #[test]
+    fn test_scheduling() {
+        let mut scheduler = AclScheduler::default();
+        scheduler.set_buffer_size(10, 3);
+        scheduler.connected(1);
+        scheduler.connected(2);
+        for cid in [0x40, 0x40, 0x41] {
+            scheduler.push(AclPacket { handle: 1, pdu: pdu(cid, 12), permit: None });
+        }
+        scheduler.push(AclPacket { handle: 2, pdu: pdu(0x42, 2), permit: None });
+
+        // Links alternate while a PDU stays in one piece on its link
+        assert_eq!(next(&mut scheduler), Some((1, true, 0x40)));
+        assert_eq!(next(&mut scheduler), Some((2, true, 0x42)));
+        assert_eq!(next(&mut scheduler), Some((1, false, 0x40)));
+        assert_eq!(next(&mut scheduler), None);
+
+        // Channels of a link take turns
+        scheduler.completed(1, 2);
+        assert_eq!(next(&mut scheduler), Some((1, true, 0x41)));
+        assert_eq!(next(&mut scheduler), Some((1, false, 0x41)));
+        assert_eq!(next(&mut scheduler), None);
+
+        // Buffers of a closed link are free again
+        scheduler.disconnected(2);
+        assert_eq!(next(&mut scheduler), Some((1, true, 0x40)));
+        scheduler.push(AclPacket { handle: 2, pdu: pdu(0x42, 2), permit: None });
+        assert_eq!(scheduler.in_flight(), 3);
+    }
+}
@@ -1,8 +1,10 @@
 use bytes::BufMut;
-use instructor::BufferMut;
+use instructor::{Buffer, BufferMut, Instruct};
 
+use crate::ensure;
 use crate::hci::commands::{Opcode, OpcodeGroup};
-use crate::hci::consts::{ClassOfDevice, EventMask};
+use crate::hci::consts::{ClassOfDevice, ConnectionHandle, EventCode, EventMask, Status};
+use crate::hci::eir::ExtendedInquiryResponse;
 use crate::hci::{Error, Hci};
 
 /// Controller and baseband commands ([Vol 4] Part E, Section 7.3).
@@ -50,6 +52,57 @@ impl Hci {
         .await
     }
 
+    /// Selects which kind of inquiry result events the controller generates
+    /// ([Vol 4] Part E, Section 7.3.50).
+    pub async fn write_inquiry_mode(&self, mode: InquiryMode) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0045), |p| {
+            p.write_le(mode);
+        })
+        .await
+    }
+
+    /// Sets the data the controller sends during the inquiry response
+    /// ([Vol 4] Part E, Section 7.3.56).
+    ///
+    /// # Parameters
+    /// - `fec_required`: Use the slower but more robust FEC encoded packet types.
+    pub async fn write_extended_inquiry_response(&self, fec_required: bool, eir: &ExtendedInquiryResponse) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0052), |p| {
+            p.write_le(fec_required);
+            p.write_le_ref(eir);
+        })
+        .await
+    }
+
+    /// Refreshes the encryption key of an encrypted link, e.g. after a new link key got created by re-authenticating
+    /// ([Vol 4] Part E, Section 7.3.57).
+    pub async fn refresh_encryption_key(&self, handle: ConnectionHandle) -> Result<(), Error> {
+        self.call_and_wait(
+            Opcode::new(OpcodeGroup::HciControl, 0x0053),
+            [EventCode::EncryptionKeyRefreshComplete],
+            |p| p.write_le(handle),
+            |_, mut packet| {
+                // ([Vol 4] Part E, Section 7.7.39).
+                let status: Status = packet.read_le()?;
+                let target: ConnectionHandle = packet.read_le()?;
+                packet.finish()?;
+                if target != handle {
+                    return Ok(None);
+                }
+                ensure!(status.is_ok(), Error::Controller(status));
+                Ok(Some(()))
+            }
+        )
+        .await
+    }
+
+    /// Returns the power level in dBm used to transmit inquiry responses
+    /// ([Vol 4] Part E, Section 7.3.61).
+    pub async fn read_inquiry_response_transmit_power_level(&self) -> Result<i8, Error> {
+        self.call(Opcode::new(OpcodeGroup::HciControl, 0x0058))
+            .await
+    }
+
     /// ([Vol 4] Part E, Section 7.3.59).
     pub async fn set_simple_pairing_support(&self, enabled: bool) -> Result<(), Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0056), |p| {
@@ -66,3 +119,12 @@ impl Hci {
         .await
     }
 }
+
+/// ([Vol 4] Part E, Section 7.3.50).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct)]
+#[repr(u8)]
+pub enum InquiryMode {
+    Standard = 0x00,
+    WithRssi = 0x01,
+    WithRssiOrExtended = 0x02
+}
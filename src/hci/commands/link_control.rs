@@ -1,15 +1,19 @@
-use bytes::BufMut;
+use bytes::{BufMut, BytesMut};
 use instructor::{Buffer, BufferMut, Exstruct, Instruct};
-use tokio::sync::mpsc::unbounded_channel;
 use crate::ensure;
 
-use crate::hci::consts::{AuthenticationRequirements, EncryptionMode, EventCode, IoCapability, Lap, LinkKey, OobDataPresence, RemoteAddr, Role, Status};
+use crate::hci::consts::{
+    AuthenticationRequirements, ConnectionHandle, EncryptionMode, EventCode, IoCapability, Lap, LinkKey, LinkType, OobDataPresence, RemoteAddr, Role,
+    Status
+};
 use crate::hci::{Error, Hci, Opcode, OpcodeGroup};
 
 impl Hci {
     /// Start the inquiry process to discover other Bluetooth devices in the vicinity.
     /// ([Vol 4] Part E, Section 7.1.1).
     ///
+    /// The results are reported through inquiry result events, see [`DiscoveryBuilder`](crate::hci::discovery::DiscoveryBuilder).
+    ///
     /// # Parameters
     /// - `time`: The duration of the inquiry process in 1.28s units. Range: 1-30.
     /// - `max_responses`: The maximum number of responses to receive. 0 means no limit.
@@ -20,23 +24,90 @@ impl Hci {
             p.write_le(max_responses);
         })
         .await?;
-        // TODO return channel for inquiry results
         Ok(())
     }
 
+    /// Stops an ongoing inquiry
+    /// ([Vol 4] Part E, Section 7.1.2).
+    pub async fn cancel_inquiry(&self) -> Result<(), Error> {
+        self.call(Opcode::new(OpcodeGroup::LinkControl, 0x0002))
+            .await
+    }
+
+    /// Automatically starts a new inquiry every `min_period` to `max_period`
+    /// ([Vol 4] Part E, Section 7.1.3).
+    ///
+    /// # Parameters
+    /// - `max_period` / `min_period`: The time between consecutive inquiries in 1.28s units. `max_period > min_period > time`.
+    /// - `time`: The duration of the inquiry process in 1.28s units. Range: 1-30.
+    /// - `max_responses`: The maximum number of responses per inquiry. 0 means no limit.
+    pub async fn periodic_inquiry(&self, max_period: u16, min_period: u16, lap: Lap, time: u8, max_responses: u8) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0003), |p| {
+            p.write_le(max_period);
+            p.write_le(min_period);
+            p.write_le(lap);
+            p.write_le(time);
+            p.write_le(max_responses);
+        })
+        .await
+    }
+
+    /// ([Vol 4] Part E, Section 7.1.4).
+    pub async fn exit_periodic_inquiry(&self) -> Result<(), Error> {
+        self.call(Opcode::new(OpcodeGroup::LinkControl, 0x0004))
+            .await
+    }
+
     // ([Vol 4] Part E, Section 7.1.5).
     pub async fn create_connection(&self, addr: RemoteAddr, allow_role_switch: bool) -> Result<(), Error> {
-        self.call_with_args::<()>(Opcode::new(OpcodeGroup::LinkControl, 0x0005), |p| {
-            p.write_le(addr);
-            p.write_le(0xCC18u16);
-            p.write_le(PageScanRepititionMode::R2);
-            p.write_le(0x00u8);
-            p.write_le(0x00u16);
-            p.write_le(u8::from(allow_role_switch));
-        }).await?;
+        self.call_with_args::<()>(CREATE_CONNECTION, create_connection_params(addr, allow_role_switch))
+            .await?;
         Ok(())
     }
 
+    /// Creates an ACL connection to a remote device and waits until it is established
+    /// ([Vol 4] Part E, Section 7.1.5).
+    pub async fn connect(&self, addr: RemoteAddr) -> Result<ConnectionHandle, Error> {
+        self.call_and_wait(
+            CREATE_CONNECTION,
+            [EventCode::ConnectionComplete],
+            create_connection_params(addr, true),
+            |_, mut packet| {
+                // ([Vol 4] Part E, Section 7.7.3).
+                let status: Status = packet.read_le()?;
+                let handle: ConnectionHandle = packet.read_le()?;
+                let target: RemoteAddr = packet.read_le()?;
+                let link_type: LinkType = packet.read_le()?;
+                if target != addr || link_type != LinkType::Acl {
+                    return Ok(None);
+                }
+                ensure!(status.is_ok(), Error::Controller(status));
+                Ok(Some(handle))
+            }
+        )
+        .await
+    }
+
+    /// Terminates a connection. Completion is reported through a `DisconnectionComplete` event
+    /// ([Vol 4] Part E, Section 7.1.6).
+    pub async fn disconnect(&self, handle: ConnectionHandle, reason: Status) -> Result<(), Error> {
+        assert!(matches!(
+            reason,
+            Status::AuthenticationFailure
+                | Status::RemoteUserTerminatedConnection
+                | Status::RemoteDeviceTerminatedConnectionDueToLowResources
+                | Status::RemoteDeviceTerminatedConnectionDueToPowerOff
+                | Status::UnsupportedRemoteFeature
+                | Status::PairingWithUnitKeyNotSupported
+                | Status::UnacceptableConnectionParameters
+        ));
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0006), |p| {
+            p.write_le(handle);
+            p.write_le(reason);
+        })
+        .await
+    }
+
     /// Accept a connection request from a remote device.
     /// ([Vol 4] Part E, Section 7.1.8).
     pub async fn accept_connection_request(&self, bd_addr: RemoteAddr, role: Role) -> Result<(), Error> {
@@ -87,61 +158,71 @@ impl Hci {
         assert!(pin.len() <= 16);
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x000D), |p| {
             p.write_le(bd_addr);
-            p.write_le(pin.len());
+            p.write_le(pin.len() as u8);
             p.put_slice(pin.as_bytes());
             p.put_bytes(0, 16 - pin.len());
         })
         .await
     }
 
+    /// ([Vol 4] Part E, Section 7.1.13).
+    pub async fn pin_code_request_negative_reply(&self, bd_addr: RemoteAddr) -> Result<RemoteAddr, Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x000E), |p| {
+            p.write_le(bd_addr);
+        })
+        .await
+    }
+
+    /// Authenticates the remote device of a connection and waits for the result
     /// ([Vol 4] Part E, Section 7.1.15).
-    pub async fn request_authentication(&self, handle: u16) -> Result<(), Error> {
-        let (tx, mut rx) = unbounded_channel();
-        self.register_event_handler([EventCode::AuthenticationComplete], tx)?;
-        self.call_with_args::<()>(Opcode::new(OpcodeGroup::LinkControl, 0x0011), |p| {
-            p.write_le(handle);
-        }).await?;
-        while let Some((code, mut packet)) = rx.recv().await {
-            assert_eq!(code, EventCode::AuthenticationComplete);
-            let status: Status = packet.read_le()?;
-            let handle: u16 = packet.read_le()?;
-            packet.finish()?;
-            if handle == handle {
+    pub async fn authenticate(&self, handle: ConnectionHandle) -> Result<(), Error> {
+        self.call_and_wait(
+            Opcode::new(OpcodeGroup::LinkControl, 0x0011),
+            [EventCode::AuthenticationComplete],
+            |p| p.write_le(handle),
+            |_, mut packet| {
+                // ([Vol 4] Part E, Section 7.7.6).
+                let status: Status = packet.read_le()?;
+                let target: ConnectionHandle = packet.read_le()?;
+                packet.finish()?;
+                if target != handle {
+                    return Ok(None);
+                }
                 ensure!(status.is_ok(), Error::Controller(status));
-                return Ok(());
+                Ok(Some(()))
             }
-        }
-        Err(Error::EventLoopClosed)
+        )
+        .await
     }
 
     /// ([Vol 4] Part E, Section 7.1.16).
-    pub async fn set_encryption(&self, handle: u16, enabled: bool) -> Result<(EncryptionMode, Option<u8>), Error> {
-        let (tx, mut rx) = unbounded_channel();
-        self.register_event_handler([EventCode::EncryptionChange, EventCode::EncryptionChangeV2], tx)?;
-        self.call_with_args::<()>(Opcode::new(OpcodeGroup::LinkControl, 0x0013), |p| {
-            p.write_le(handle);
-            p.write_le(u8::from(enabled));
-        }).await?;
-        while let Some((code, mut packet)) = rx.recv().await {
-            assert!(matches!(code, EventCode::EncryptionChange | EventCode::EncryptionChangeV2));
-            let status: Status = packet.read_le()?;
-            let handle: u16 = packet.read_le()?;
-            let mode: EncryptionMode = packet.read_le()?;
-            let key_size: u8 = if code == EventCode::EncryptionChangeV2 {
-                packet.read_le()?
-            } else {
-                0
-            };
-            let key_size = (key_size > 0 && mode != EncryptionMode::Off).then_some(key_size);
-            packet.finish()?;
-            if handle == handle {
+    pub async fn set_encryption(&self, handle: ConnectionHandle, enabled: bool) -> Result<(EncryptionMode, Option<u8>), Error> {
+        self.call_and_wait(
+            Opcode::new(OpcodeGroup::LinkControl, 0x0013),
+            [EventCode::EncryptionChange, EventCode::EncryptionChangeV2],
+            |p| {
+                p.write_le(handle);
+                p.write_le(u8::from(enabled));
+            },
+            |code, mut packet| {
+                let status: Status = packet.read_le()?;
+                let target: ConnectionHandle = packet.read_le()?;
+                let mode: EncryptionMode = packet.read_le()?;
+                let key_size: u8 = if code == EventCode::EncryptionChangeV2 {
+                    packet.read_le()?
+                } else {
+                    0
+                };
+                let key_size = (key_size > 0 && mode != EncryptionMode::Off).then_some(key_size);
+                packet.finish()?;
+                if target != handle {
+                    return Ok(None);
+                }
                 ensure!(status.is_ok(), Error::Controller(status));
-                return Ok((mode, key_size));
+                Ok(Some((mode, key_size)))
             }
-        }
-        Err(Error::EventLoopClosed)
-
-
+        )
+        .await
     }
 
     /// ([Vol 4] Part E, Section 7.1.19).
@@ -155,6 +236,35 @@ impl Hci {
         }).await
     }
 
+    /// Requests the user-friendly name of a remote device and waits for the answer
+    /// ([Vol 4] Part E, Section 7.1.19).
+    pub async fn remote_name(&self, addr: RemoteAddr) -> Result<String, Error> {
+        self.call_and_wait(
+            Opcode::new(OpcodeGroup::LinkControl, 0x0019),
+            [EventCode::RemoteNameRequestComplete],
+            |p| {
+                p.write_le(addr);
+                p.write_le(PageScanRepititionMode::R2);
+                p.write_le(0x00u8);
+                p.write_le(0x00u16);
+            },
+            |_, mut packet| {
+                // ([Vol 4] Part E, Section 7.7.7).
+                let status: Status = packet.read_le()?;
+                let target: RemoteAddr = packet.read_le()?;
+                if target != addr {
+                    return Ok(None);
+                }
+                ensure!(status.is_ok(), Error::Controller(status));
+                let name = String::from_utf8_lossy(&packet.split_to(packet.len().min(248)))
+                    .trim_end_matches('\0')
+                    .to_string();
+                Ok(Some(name))
+            }
+        )
+        .await
+    }
+
     /// ([Vol 4] Part E, Section 7.1.29).
     pub async fn io_capability_reply(
         &self, bd_addr: RemoteAddr, io: IoCapability, oob: OobDataPresence, auth: AuthenticationRequirements
@@ -175,6 +285,62 @@ impl Hci {
         })
         .await
     }
+
+    /// ([Vol 4] Part E, Section 7.1.31).
+    pub async fn user_confirmation_request_reject(&self, bd_addr: RemoteAddr) -> Result<RemoteAddr, Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x002D), |p| {
+            p.write_le(bd_addr);
+        })
+        .await
+    }
+
+    /// ([Vol 4] Part E, Section 7.1.32).
+    pub async fn user_passkey_request_reply(&self, bd_addr: RemoteAddr, passkey: u32) -> Result<RemoteAddr, Error> {
+        assert!(passkey <= 999999);
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x002E), |p| {
+            p.write_le(bd_addr);
+            p.write_le(passkey);
+        })
+        .await
+    }
+
+    /// ([Vol 4] Part E, Section 7.1.33).
+    pub async fn user_passkey_request_negative_reply(&self, bd_addr: RemoteAddr) -> Result<RemoteAddr, Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x002F), |p| {
+            p.write_le(bd_addr);
+        })
+        .await
+    }
+
+    /// ([Vol 4] Part E, Section 7.1.35).
+    pub async fn remote_oob_data_request_negative_reply(&self, bd_addr: RemoteAddr) -> Result<RemoteAddr, Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0033), |p| {
+            p.write_le(bd_addr);
+        })
+        .await
+    }
+
+    /// ([Vol 4] Part E, Section 7.1.36).
+    pub async fn io_capability_request_negative_reply(&self, bd_addr: RemoteAddr, reason: Status) -> Result<RemoteAddr, Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0034), |p| {
+            p.write_le(bd_addr);
+            p.write_le(reason);
+        })
+        .await
+    }
+}
+
+const CREATE_CONNECTION: Opcode = Opcode::new(OpcodeGroup::LinkControl, 0x0005);
+
+fn create_connection_params(addr: RemoteAddr, allow_role_switch: bool) -> impl FnOnce(&mut BytesMut) {
+    move |p| {
+        p.write_le(addr);
+        p.write_le(0xCC18u16);
+        p.write_le(PageScanRepititionMode::R2);
+        p.write_le(0x00u8);
+        p.write_le(0x00u16);
+        p.write_le(u8::from(allow_role_switch));
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
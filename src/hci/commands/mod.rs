@@ -2,17 +2,17 @@ mod hci_control;
 mod info_params;
 mod link_control;
 mod link_policy;
+mod status_params;
 
 use std::fmt::{Debug, Formatter};
 use instructor::Exstruct;
 use num_enum::TryFromPrimitive;
 
+pub use hci_control::*;
 pub use info_params::*;
 pub use link_control::*;
 pub use link_policy::*;
 
-//pub use hci_control::*;
-
 // Opcode group field definitions.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
@@ -31,11 +31,10 @@ pub enum OpcodeGroup {
 #[derive(Default, Copy, Clone, Eq, PartialEq, Exstruct)]
 pub struct Opcode(u16);
 
-#[allow(dead_code)]
 impl Opcode {
     /// Opcode 0x0000 is used to update `Num_HCI_Command_Packets`
     /// ([Vol 4] Part E, Section 7.7.14).
-    const NONE: Opcode = Opcode(0x0000);
+    pub const NONE: Opcode = Opcode(0x0000);
 }
 
 impl Opcode {
@@ -0,0 +1,30 @@
+use instructor::BufferMut;
+
+use crate::hci::commands::{Opcode, OpcodeGroup};
+use crate::hci::consts::ConnectionHandle;
+use crate::hci::{Error, Hci};
+
+/// Status parameters commands ([Vol 4] Part E, Section 7.5).
+impl Hci {
+    /// Returns the difference between the measured RSSI of the link and the golden receive power range in dB
+    /// ([Vol 4] Part E, Section 7.5.4).
+    pub async fn read_rssi(&self, handle: ConnectionHandle) -> Result<i8, Error> {
+        let (_, rssi): (ConnectionHandle, i8) = self
+            .call_with_args(Opcode::new(OpcodeGroup::StatusParams, 0x0005), |p| {
+                p.write_le(handle);
+            })
+            .await?;
+        Ok(rssi)
+    }
+
+    /// Returns the size of the encryption key of the link in bytes
+    /// ([Vol 4] Part E, Section 7.5.7).
+    pub async fn read_encryption_key_size(&self, handle: ConnectionHandle) -> Result<u8, Error> {
+        let (_, size): (ConnectionHandle, u8) = self
+            .call_with_args(Opcode::new(OpcodeGroup::StatusParams, 0x0008), |p| {
+                p.write_le(handle);
+            })
+            .await?;
+        Ok(size)
+    }
+}
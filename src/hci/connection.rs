@@ -1,42 +1,120 @@
-use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Debug, Formatter};
 use std::future::Future;
-use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use futures_lite::{Stream, StreamExt};
-use instructor::{Buffer, BufferMut};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
-use tokio::task::{spawn_blocking, JoinHandle};
-use tokio::{fs, spawn};
+use instructor::Buffer;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use parking_lot::Mutex;
+use tokio::spawn;
+use tokio::time::{sleep, timeout};
 use tracing::{debug, trace, warn};
 
 use crate::ensure;
 use crate::hci::consts::*;
+use crate::hci::link_keys::{AuthenticationLevel, FileLinkKeyStore, LinkKeyStore, StoredLinkKey};
+use crate::hci::pairing::{ConnectionDecision, HeadlessAgent, PairingAgent};
+use crate::hci::security::{SecurityLevel, SecurityPolicy};
 use crate::hci::{Error, Hci};
-use crate::utils::catch_error;
+use crate::utils::{catch_error, DispatchExt};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ConnectionManagerBuilder {
-    link_key_store: PathBuf,
-    simple_secure_pairing: bool
+    link_key_store: Option<Arc<dyn LinkKeyStore>>,
+    simple_secure_pairing: bool,
+    agent: Arc<dyn PairingAgent>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    security_policy: SecurityPolicy
 }
 
 impl Default for ConnectionManagerBuilder {
     fn default() -> Self {
         Self {
-            link_key_store: PathBuf::from("link-keys.dat"),
-            simple_secure_pairing: true
+            link_key_store: None,
+            simple_secure_pairing: true,
+            agent: Arc::new(HeadlessAgent::default()),
+            reconnect_policy: None,
+            security_policy: SecurityPolicy::default()
+        }
+    }
+}
+
+impl Debug for ConnectionManagerBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionManagerBuilder")
+            .field("simple_secure_pairing", &self.simple_secure_pairing)
+            .field("reconnect_policy", &self.reconnect_policy)
+            .field("security_policy", &self.security_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decides when the [`ConnectionManager`] reconnects to bonded devices on its own.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    on_startup: usize,
+    on_link_loss: bool
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+            on_startup: 1,
+            on_link_loss: true
         }
     }
 }
 
+impl ReconnectPolicy {
+    /// The delay after a failed attempt starts at `initial` and doubles up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        assert!(initial <= max);
+        self.initial_delay = initial;
+        self.max_delay = max;
+        self
+    }
+
+    /// Gives up after `attempts` failed attempts. `None` retries forever.
+    pub fn with_max_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Connects to the `devices` most recently used bonded devices when the manager starts.
+    pub fn with_reconnect_on_startup(mut self, devices: usize) -> Self {
+        self.on_startup = devices;
+        self
+    }
+
+    /// Reconnects to bonded devices whose link timed out.
+    pub fn with_reconnect_on_link_loss(mut self, enabled: bool) -> Self {
+        self.on_link_loss = enabled;
+        self
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay)
+    }
+}
+
 impl ConnectionManagerBuilder {
-    pub fn with_link_key_store<P: AsRef<Path>>(mut self, path: P) -> Self {
-        self.link_key_store = PathBuf::from(path.as_ref());
+    /// Defaults to a [`FileLinkKeyStore`] at `link-keys.dat`.
+    pub fn with_link_key_store<S: LinkKeyStore + 'static>(mut self, store: S) -> Self {
+        self.link_key_store = Some(Arc::new(store));
         self
     }
 
@@ -45,35 +123,58 @@ impl ConnectionManagerBuilder {
         self
     }
 
-    pub async fn spawn(self, hci: Arc<Hci>) -> Result<JoinHandle<()>, Error> {
-        let link_keys = match fs::read(&self.link_key_store).await {
-            Ok(data) => {
-                let mut data = data.as_slice();
-                let mut result = BTreeMap::new();
-                while !data.is_empty() {
-                    let addr: RemoteAddr = data.read_le()?;
-                    let key: LinkKey = data.read_le()?;
-                    result.insert(addr, key);
-                }
-                result
-            }
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
-            Err(err) => return Err(err.into())
+    /// Defaults to a [`HeadlessAgent`].
+    pub fn with_pairing_agent<A: PairingAgent + 'static>(mut self, agent: A) -> Self {
+        self.agent = Arc::new(agent);
+        self
+    }
+
+    /// Bonded devices are not reconnected automatically by default.
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Defaults to a policy that accepts any link key and the minimum key size allowed by the specification.
+    pub fn with_security_policy(mut self, policy: SecurityPolicy) -> Self {
+        self.security_policy = policy;
+        self
+    }
+
+    pub async fn spawn(self, hci: Arc<Hci>) -> Result<ConnectionManager, Error> {
+        let link_keys = match self.link_key_store {
+            Some(store) => store,
+            None => Arc::new(FileLinkKeyStore::open("link-keys.dat")?)
         };
 
         let mut events = ConnectionEventReceiver::new(&hci)?;
 
-        if self.simple_secure_pairing {
+        if self.simple_secure_pairing || self.security_policy.simple_pairing_required() {
             hci.set_simple_pairing_support(true).await?;
         }
+        if self.security_policy.secure_connections_only() {
+            hci.set_secure_connections_support(true).await?;
+        }
+
+        let manager = ConnectionManager {
+            hci: hci.clone(),
+            links: Default::default(),
+            listeners: Default::default(),
+            reconnecting: Default::default(),
+            mitm_required: Default::default(),
+            reconnect_policy: self.reconnect_policy.map(Arc::new),
+            security_policy: Arc::new(self.security_policy)
+        };
 
         let mut state = ConnectionManagerState {
             hci,
-            link_key_store: self.link_key_store,
-            link_keys
+            manager: manager.clone(),
+            agent: self.agent,
+            link_keys: link_keys.clone(),
+            remote_io_capabilities: BTreeMap::new()
         };
 
-        Ok(spawn(async move {
+        spawn(async move {
             while let Some(event) = events.recv().await {
                 // trace!("Connection event: {:?}", event);
                 state.handle_event(event).await.unwrap_or_else(|err| {
@@ -81,35 +182,562 @@ impl ConnectionManagerBuilder {
                 });
             }
             trace!("Connection event handler finished");
-        }))
+        });
+
+        if let Some(policy) = &manager.reconnect_policy {
+            let mut bonds: Vec<_> = link_keys
+                .bonded_devices()
+                .into_iter()
+                .filter_map(|addr| link_keys.get(addr).map(|key| (addr, key.last_used)))
+                .collect();
+            bonds.sort_by_key(|(_, last_used)| Reverse(*last_used));
+            for (addr, _) in bonds.into_iter().take(policy.on_startup) {
+                manager.reconnect(addr);
+            }
+        }
+
+        Ok(manager)
+    }
+}
+
+/// Keeps track of the ACL links and establishes new ones.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    hci: Arc<Hci>,
+    links: Arc<Mutex<BTreeMap<ConnectionHandle, ConnectionInfo>>>,
+    listeners: Arc<Mutex<Vec<UnboundedSender<LinkEvent>>>>,
+    reconnecting: Arc<Mutex<BTreeSet<RemoteAddr>>>,
+    /// Devices that are being re-authenticated because their current link key lacks MITM protection.
+    mitm_required: Arc<Mutex<BTreeSet<RemoteAddr>>>,
+    reconnect_policy: Option<Arc<ReconnectPolicy>>,
+    security_policy: Arc<SecurityPolicy>
+}
+
+impl ConnectionManager {
+    /// Creates an ACL link to `addr` and secures it.
+    ///
+    /// Bonded devices authenticate with their stored link key, all others pair through the [`PairingAgent`].
+    /// The link is dropped again if authentication or encryption fails.
+    pub async fn connect(&self, addr: RemoteAddr) -> Result<Connection, Error> {
+        let handle = self.hci.connect(addr).await?;
+        debug!("Connected to {} (0x{:04X})", addr, handle);
+        // The event handler might not have seen the new link yet
+        self.links
+            .lock()
+            .entry(handle)
+            .or_insert_with(|| ConnectionInfo::new(addr, Role::Master));
+        let connection = Connection {
+            manager: self.clone(),
+            handle,
+            addr
+        };
+        if let Err(err) = connection.encrypt().await {
+            warn!("Failed to secure the link to {}: {:?}", addr, err);
+            connection
+                .disconnect(Status::AuthenticationFailure)
+                .await
+                .unwrap_or_else(|err| warn!("Failed to disconnect: {:?}", err));
+            return Err(err);
+        }
+        Ok(connection)
+    }
+
+    pub fn is_connected(&self, addr: RemoteAddr) -> bool {
+        self.links.lock().values().any(|info| info.addr == addr)
+    }
+
+    /// Returns the link to `addr` if there is one.
+    pub fn connection(&self, addr: RemoteAddr) -> Option<Connection> {
+        self.links
+            .lock()
+            .iter()
+            .find(|(_, info)| info.addr == addr)
+            .map(|(handle, info)| Connection {
+                manager: self.clone(),
+                handle: *handle,
+                addr: info.addr
+            })
+    }
+
+    /// Returns all currently established links.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.links
+            .lock()
+            .iter()
+            .map(|(handle, info)| Connection {
+                manager: self.clone(),
+                handle: *handle,
+                addr: info.addr
+            })
+            .collect()
+    }
+
+    /// Subscribes to the changes of all links. Only events that happen after this call are reported.
+    pub fn events(&self) -> LinkEventReceiver {
+        let (tx, rx) = unbounded_channel();
+        self.listeners.lock().push(tx);
+        LinkEventReceiver(rx)
+    }
+
+    pub fn connection_by_handle(&self, handle: ConnectionHandle) -> Option<Connection> {
+        self.links.lock().get(&handle).map(|info| Connection {
+            manager: self.clone(),
+            handle,
+            addr: info.addr
+        })
+    }
+
+    /// The security level the [`SecurityPolicy`] requires for channels to `psm`.
+    pub fn security_requirement(&self, psm: u64) -> SecurityLevel {
+        self.security_policy.service_requirement(psm)
+    }
+
+    /// Checks whether the link is secure enough for a channel to `psm` according to the [`SecurityPolicy`].
+    pub fn channel_allowed(&self, handle: ConnectionHandle, psm: u64) -> bool {
+        let required = self.security_requirement(psm);
+        required == SecurityLevel::None
+            || self
+                .connection_by_handle(handle)
+                .is_some_and(|connection| connection.security_level() >= required)
+    }
+
+    fn publish(&self, event: LinkEvent) {
+        trace!("Link event: {:?}", event);
+        self.listeners.lock().dispatch(event);
+    }
+
+    fn update_link<R>(&self, handle: ConnectionHandle, f: impl FnOnce(&mut ConnectionInfo) -> R) -> Option<R> {
+        self.links.lock().get_mut(&handle).map(f)
+    }
+
+    fn handles(&self, addr: RemoteAddr) -> Vec<ConnectionHandle> {
+        self.links
+            .lock()
+            .iter()
+            .filter(|(_, info)| info.addr == addr)
+            .map(|(handle, _)| *handle)
+            .collect()
+    }
+
+    /// Keeps trying to connect to `addr` according to the [`ReconnectPolicy`] until it succeeds
+    /// or the device connects on its own.
+    fn reconnect(&self, addr: RemoteAddr) {
+        let Some(policy) = self.reconnect_policy.clone() else {
+            return;
+        };
+        if !self.reconnecting.lock().insert(addr) {
+            return;
+        }
+        let manager = self.clone();
+        spawn(async move {
+            let mut attempt = 0;
+            while !manager.is_connected(addr) && !manager.hci.is_closed() {
+                if policy.max_attempts.is_some_and(|max| attempt >= max) {
+                    warn!("Giving up reconnecting to {}", addr);
+                    break;
+                }
+                debug!("Reconnecting to {} (attempt {})", addr, attempt + 1);
+                match manager.connect(addr).await {
+                    Ok(_) => break,
+                    Err(err) => debug!("Failed to reconnect to {}: {:?}", addr, err)
+                }
+                sleep(policy.delay(attempt)).await;
+                attempt += 1;
+            }
+            manager.reconnecting.lock().remove(&addr);
+        });
+    }
+}
+
+/// The state of an ACL link.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    pub addr: RemoteAddr,
+    pub role: Role,
+    pub encryption: EncryptionMode,
+    /// Reported by the encryption change v2 event or read from the controller afterward.
+    pub key_size: Option<u8>,
+    /// The strength of the link key, known once the link was authenticated.
+    pub authentication: Option<AuthenticationLevel>,
+    pub mode: ConnectionMode,
+    /// Only known once the name was requested.
+    pub name: Option<String>
+}
+
+impl ConnectionInfo {
+    fn new(addr: RemoteAddr, role: Role) -> Self {
+        Self {
+            addr,
+            role,
+            encryption: EncryptionMode::Off,
+            key_size: None,
+            authentication: None,
+            mode: ConnectionMode::Active,
+            name: None
+        }
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        SecurityLevel::new(self.encryption, self.key_size, self.authentication)
+    }
+}
+
+/// A handle to an ACL link of a [`ConnectionManager`].
+///
+/// The handle can outlive the link, all operations fail with [`Status::UnknownConnectionIdentifier`] afterward.
+#[derive(Clone)]
+pub struct Connection {
+    manager: ConnectionManager,
+    handle: ConnectionHandle,
+    addr: RemoteAddr
+}
+
+impl Debug for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("handle", &self.handle)
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl Connection {
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle
+    }
+
+    pub fn addr(&self) -> RemoteAddr {
+        self.addr
+    }
+
+    /// Returns the current state of the link or `None` if it is gone.
+    pub fn info(&self) -> Option<ConnectionInfo> {
+        self.manager
+            .links
+            .lock()
+            .get(&self.handle)
+            .filter(|info| info.addr == self.addr)
+            .cloned()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.info().is_some()
+    }
+
+    /// Terminates the link. Must be one of the reasons allowed by [`Hci::disconnect`].
+    pub async fn disconnect(&self, reason: Status) -> Result<(), Error> {
+        self.ensure_connected()?;
+        self.manager.hci.disconnect(self.handle, reason).await
+    }
+
+    /// Authenticates the remote device and enables encryption. Does nothing if the link is already encrypted.
+    ///
+    /// Devices without a bond get paired through the [`PairingAgent`] first.
+    pub async fn encrypt(&self) -> Result<(), Error> {
+        self.secure(SecurityLevel::Encrypted).await
+    }
+
+    /// Raises the security of the link to at least `level`. Does nothing if the link already meets it.
+    ///
+    /// Levels that require an authenticated link key make the [`PairingAgent`] pair again with MITM protection
+    /// if the current key lacks it, followed by a refresh of the encryption key.
+    pub async fn secure(&self, level: SecurityLevel) -> Result<(), Error> {
+        self.ensure_connected()?;
+        if self.security_level() >= level {
+            return Ok(());
+        }
+        let mitm_required = level >= SecurityLevel::Authenticated;
+        if mitm_required {
+            self.manager.mitm_required.lock().insert(self.addr);
+        }
+        let result = self.authenticate_and_encrypt().await;
+        if mitm_required {
+            self.manager.mitm_required.lock().remove(&self.addr);
+        }
+        result?;
+        let info = self.info().ok_or(Error::Controller(Status::UnknownConnectionIdentifier))?;
+        if let Some(key_size) = info.key_size {
+            let accepted = self.manager.security_policy.accepts_encryption(info.encryption, key_size);
+            ensure!(accepted, Error::Controller(Status::InsufficientSecurity));
+        }
+        ensure!(self.security_level() >= level, Error::Controller(Status::InsufficientSecurity));
+        Ok(())
+    }
+
+    async fn authenticate_and_encrypt(&self) -> Result<(), Error> {
+        let encrypted = self
+            .info()
+            .is_some_and(|info| info.encryption != EncryptionMode::Off);
+        let mut events = self.manager.events();
+        self.manager.hci.authenticate(self.handle).await?;
+        if encrypted {
+            // The new link key only takes effect once the encryption key is derived from it again
+            return self.manager.hci.refresh_encryption_key(self.handle).await;
+        }
+        let (mode, key_size) = self.manager.hci.set_encryption(self.handle, true).await?;
+        // Wait for the event handler so that the link key and key size are known afterward
+        let processed = async {
+            while let Some(event) = events.recv().await {
+                if matches!(event, LinkEvent::EncryptionChanged { handle, .. } if handle == self.handle) {
+                    break;
+                }
+            }
+        };
+        if timeout(Duration::from_secs(1), processed).await.is_err() {
+            warn!("Encryption change of 0x{:04X} was not processed in time", self.handle);
+            self.manager.update_link(self.handle, |info| {
+                info.encryption = mode;
+                info.key_size = key_size.or(info.key_size);
+            });
+        }
+        Ok(())
+    }
+
+    /// The security level of the link. Keys shorter than the [`SecurityPolicy`] allows count as no encryption at all.
+    pub fn security_level(&self) -> SecurityLevel {
+        let min_key_size = self.manager.security_policy.min_key_size();
+        self.info()
+            .filter(|info| info.key_size.is_some_and(|size| size >= min_key_size))
+            .map_or(SecurityLevel::None, |info| info.security_level())
+    }
+
+    /// Reads the signal strength of the link relative to the golden receive power range in dB.
+    pub async fn read_rssi(&self) -> Result<i8, Error> {
+        self.ensure_connected()?;
+        self.manager.hci.read_rssi(self.handle).await
+    }
+
+    /// Returns the user-friendly name of the remote device. The name is only requested once per link.
+    pub async fn remote_name(&self) -> Result<String, Error> {
+        if let Some(name) = self.info().and_then(|info| info.name) {
+            return Ok(name);
+        }
+        self.ensure_connected()?;
+        let name = self.manager.hci.remote_name(self.addr).await?;
+        self.manager
+            .update_link(self.handle, |info| info.name = Some(name.clone()));
+        Ok(name)
+    }
+
+    fn ensure_connected(&self) -> Result<(), Error> {
+        ensure!(self.is_connected(), Error::Controller(Status::UnknownConnectionIdentifier));
+        Ok(())
+    }
+}
+
+/// Changes of the ACL links, see [`ConnectionManager::events`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LinkEvent {
+    Connected {
+        handle: ConnectionHandle,
+        addr: RemoteAddr
+    },
+    Disconnected {
+        handle: ConnectionHandle,
+        addr: RemoteAddr,
+        reason: Status
+    },
+    EncryptionChanged {
+        handle: ConnectionHandle,
+        addr: RemoteAddr,
+        encryption: EncryptionMode,
+        key_size: Option<u8>
+    },
+    RoleChanged {
+        handle: ConnectionHandle,
+        addr: RemoteAddr,
+        role: Role
+    },
+    ModeChanged {
+        handle: ConnectionHandle,
+        addr: RemoteAddr,
+        mode: ConnectionMode,
+        /// The hold time or sniff interval. `None` in active mode.
+        interval: Option<Duration>
+    }
+}
+
+pub struct LinkEventReceiver(UnboundedReceiver<LinkEvent>);
+
+impl LinkEventReceiver {
+    pub async fn recv(&mut self) -> Option<LinkEvent> {
+        self.0.recv().await
+    }
+}
+
+impl Stream for LinkEventReceiver {
+    type Item = LinkEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
     }
 }
 
 struct ConnectionManagerState {
     hci: Arc<Hci>,
-    link_key_store: PathBuf,
-    link_keys: BTreeMap<RemoteAddr, LinkKey>
+    manager: ConnectionManager,
+    agent: Arc<dyn PairingAgent>,
+    link_keys: Arc<dyn LinkKeyStore>,
+    remote_io_capabilities: BTreeMap<RemoteAddr, IoCapability>
 }
 
 impl ConnectionManagerState {
     async fn handle_event(&mut self, event: ConnectionEvent) -> Result<(), Error> {
         match event {
-            ConnectionEvent::ConnectionRequest { addr, link_type, .. } => {
+            ConnectionEvent::ConnectionComplete { status, handle, addr, link_type, encryption_enabled } => {
+                if status.is_ok() && link_type == LinkType::Acl {
+                    let encryption = match encryption_enabled {
+                        true => EncryptionMode::E0OrAesCcm,
+                        false => EncryptionMode::Off
+                    };
+                    // Outgoing links are already registered by `ConnectionManager::connect`
+                    self.manager
+                        .links
+                        .lock()
+                        .entry(handle)
+                        .or_insert_with(|| ConnectionInfo {
+                            encryption,
+                            ..ConnectionInfo::new(addr, Role::Slave)
+                        });
+                    // The connection complete event does not tell which side ended up as master
+                    match self.hci.discover_role(handle).await {
+                        Ok(role) => {
+                            self.manager.update_link(handle, |info| info.role = role);
+                        }
+                        Err(err) => warn!("Failed to discover the role of {}: {:?}", addr, err)
+                    }
+                    self.manager.publish(LinkEvent::Connected { handle, addr });
+                }
+            }
+            ConnectionEvent::DisconnectionComplete { status, handle, reason } => {
+                let info = match status.is_ok() {
+                    true => self.manager.links.lock().remove(&handle),
+                    false => None
+                };
+                // Only unexpected disconnects warrant a reconnect, not the ones requested by either side
+                let link_lost = matches!(reason, Status::ConnectionTimeout | Status::LmpLlResponseTimeout);
+                if let Some(ConnectionInfo { addr, .. }) = info {
+                    self.manager
+                        .publish(LinkEvent::Disconnected { handle, addr, reason });
+                    let reconnect = self
+                        .manager
+                        .reconnect_policy
+                        .as_ref()
+                        .is_some_and(|policy| policy.on_link_loss);
+                    if link_lost && reconnect && self.link_keys.get(addr).is_some() {
+                        debug!("Lost connection to {}", addr);
+                        self.manager.reconnect(addr);
+                    }
+                }
+            }
+            ConnectionEvent::EncryptionChanged { status, handle, mode, key_size } if status.is_ok() => {
+                // Older controllers do not report the key size with the event
+                let key_size = match (mode, key_size) {
+                    (EncryptionMode::Off, _) => None,
+                    (_, Some(key_size)) => Some(key_size),
+                    (_, None) => match self.hci.read_encryption_key_size(handle).await {
+                        Ok(key_size) => Some(key_size),
+                        Err(err) => {
+                            warn!("Failed to read the key size of 0x{:04X}: {:?}", handle, err);
+                            None
+                        }
+                    }
+                };
+                let addr = self.manager.update_link(handle, |info| {
+                    info.encryption = mode;
+                    info.key_size = key_size;
+                    info.addr
+                });
+                if let Some(key_size) = key_size {
+                    if !self.manager.security_policy.accepts_encryption(mode, key_size) {
+                        warn!("Encryption of 0x{:04X} ({:?}, {} bytes) does not meet the security policy", handle, mode, key_size);
+                        self.hci.disconnect(handle, Status::AuthenticationFailure).await?;
+                    }
+                }
+                if let Some(addr) = addr {
+                    self.manager.publish(LinkEvent::EncryptionChanged {
+                        handle,
+                        addr,
+                        encryption: mode,
+                        key_size
+                    });
+                }
+            }
+            ConnectionEvent::RoleChanged { status, addr, role } if status.is_ok() => {
+                let handle = self
+                    .manager
+                    .links
+                    .lock()
+                    .iter_mut()
+                    .find(|(_, info)| info.addr == addr)
+                    .map(|(handle, info)| {
+                        info.role = role;
+                        *handle
+                    });
+                if let Some(handle) = handle {
+                    self.manager
+                        .publish(LinkEvent::RoleChanged { handle, addr, role });
+                }
+            }
+            ConnectionEvent::ModeChanged { status, handle, mode, interval } if status.is_ok() => {
+                if let Some(addr) = self.manager.update_link(handle, |info| {
+                    info.mode = mode;
+                    info.addr
+                }) {
+                    self.manager.publish(LinkEvent::ModeChanged {
+                        handle,
+                        addr,
+                        mode,
+                        interval
+                    });
+                }
+            }
+            ConnectionEvent::RemoteNameRequestComplete { status, addr, name } if status.is_ok() => {
+                self.manager
+                    .links
+                    .lock()
+                    .values_mut()
+                    .filter(|info| info.addr == addr)
+                    .for_each(|info| info.name = Some(name.clone()));
+            }
+            ConnectionEvent::ConnectionRequest { addr, class, link_type } => {
                 ensure!(link_type == LinkType::Acl, "Invalid link type");
                 debug!("Connection request: {}", addr);
-                self.hci
-                    .accept_connection_request(addr, Role::Slave)
-                    .await?;
+                self.respond(|hci, agent| async move {
+                    match agent.connection_request(addr, class).await {
+                        ConnectionDecision::Accept(role) => hci.accept_connection_request(addr, role).await,
+                        ConnectionDecision::Reject(reason) => hci.reject_connection_request(addr, reason).await
+                    }
+                });
+            }
+            ConnectionEvent::PinCodeRequest { addr } if self.manager.security_policy.simple_pairing_required() => {
+                debug!("Rejecting legacy pairing with {}", addr);
+                self.hci.pin_code_request_negative_reply(addr).await?;
             }
             ConnectionEvent::PinCodeRequest { addr } => {
                 debug!("Pin code request: {}", addr);
-                self.hci.pin_code_request_reply(addr, "0000").await?;
+                self.respond(|hci, agent| async move {
+                    match agent.pin_code(addr).await {
+                        Some(pin) => hci.pin_code_request_reply(addr, &pin).await?,
+                        None => hci.pin_code_request_negative_reply(addr).await?
+                    };
+                    Ok(())
+                });
             }
             ConnectionEvent::LinkKeyRequest { addr } => {
                 debug!("Link key request: {}", addr);
-                if let Some(key) = self.link_keys.get(&addr) {
+                let mitm_required = self.manager.mitm_required.lock().contains(&addr);
+                let key = self
+                    .link_keys
+                    .get(addr)
+                    .filter(|key| self.manager.security_policy.accepts_key(key.authentication))
+                    .filter(|key| !mitm_required || key.authentication >= AuthenticationLevel::Authenticated);
+                if let Some(mut key) = key {
                     debug!("   Link key present");
-                    self.hci.link_key_present(addr, key).await?;
+                    self.hci.link_key_present(addr, &key.key).await?;
+                    self.set_authentication(addr, key.authentication);
+                    key.last_used = SystemTime::now();
+                    self.link_keys.insert(addr, key);
                 } else {
                     debug!("   Link key not present");
                     self.hci.link_key_not_present(addr).await?;
@@ -117,59 +745,109 @@ impl ConnectionManagerState {
             }
             ConnectionEvent::LinkKeyNotification { addr, key, key_type } => {
                 debug!("Link key notification: {} {:?} {:?}", addr, key, key_type);
-                self.link_keys.insert(addr, key);
-                self.save_link_keys();
+                let key = StoredLinkKey::new(key, key_type);
+                if self.manager.security_policy.accepts_key(key.authentication) {
+                    self.set_authentication(addr, key.authentication);
+                    self.link_keys.insert(addr, key);
+                } else {
+                    warn!("Link key of {} ({:?}) does not meet the security policy", addr, key_type);
+                    for handle in self.manager.handles(addr) {
+                        self.hci.disconnect(handle, Status::AuthenticationFailure).await?;
+                    }
+                }
             }
-            ConnectionEvent::IoCapabilityRequest { addr} => {
+            ConnectionEvent::IoCapabilityRequest { addr } => {
                 debug!("Io capability request: {}", addr);
+                let mut requirements = self
+                    .manager
+                    .security_policy
+                    .authentication_requirements(self.agent.authentication_requirements());
+                if self.manager.mitm_required.lock().contains(&addr) {
+                    requirements = requirements.with_mitm_protection();
+                }
                 self.hci
                     .io_capability_reply(
                         addr,
-                        IoCapability::NoInputNoOutput,
+                        self.agent.io_capability(),
                         OobDataPresence::NotPresent,
-                        AuthenticationRequirements::DedicatedBondingProtected
+                        requirements
                     )
                     .await?;
             }
             ConnectionEvent::IoCapabilityResponse { addr, io, oob, auth } => {
                 debug!("Io capability response: {} {:?} {} {:?}", addr, io, oob, auth);
+                self.remote_io_capabilities.insert(addr, io);
             }
             ConnectionEvent::UserConfirmationRequest { addr, passkey } => {
                 debug!("User confirmation request: {} {}", addr, passkey);
-                self.hci.user_confirmation_request_accept(addr).await?;
+                // Numeric comparison is only possible if both sides can display the passkey and answer yes or no,
+                // everything else falls back to just works ([Vol 3] Part C, Section 5.2.2.6).
+                let remote = self.remote_io_capabilities.get(&addr).copied();
+                let comparison = self.agent.io_capability() == IoCapability::DisplayYesNo && remote == Some(IoCapability::DisplayYesNo);
+                self.respond(move |hci, agent| async move {
+                    let accepted = match comparison {
+                        true => agent.confirm_passkey(addr, passkey).await,
+                        false => agent.authorize(addr).await
+                    };
+                    match accepted {
+                        true => hci.user_confirmation_request_accept(addr).await?,
+                        false => hci.user_confirmation_request_reject(addr).await?
+                    };
+                    Ok(())
+                });
             }
             ConnectionEvent::SimplePairingComplete { status, addr } => {
                 debug!("Simple pairing complete: {} {}", addr, status);
+                self.remote_io_capabilities.remove(&addr);
             }
             ConnectionEvent::UserPasskeyNotification { addr, passkey } => {
                 debug!("User passkey notification: {} {}", addr, passkey);
-                panic!("Passkeys not supported");
+                self.agent.display_passkey(addr, passkey);
             }
             ConnectionEvent::UserPasskeyRequest { addr } => {
                 debug!("User passkey request: {}", addr);
-                panic!("Passkeys not supported");
+                self.respond(|hci, agent| async move {
+                    match agent.enter_passkey(addr).await {
+                        Some(passkey) => hci.user_passkey_request_reply(addr, passkey).await?,
+                        None => hci.user_passkey_request_negative_reply(addr).await?
+                    };
+                    Ok(())
+                });
             }
             ConnectionEvent::KeypressNotification { addr, ty } => {
                 debug!("Keypress notification: {} {:?}", addr, ty);
             }
             ConnectionEvent::RemoteOobDataRequest { addr } => {
                 debug!("Remote OOB data request: {}", addr);
-                panic!("OOB data not supported");
-            },
+                // We never claim to have OOB data, so this should not happen
+                self.hci.remote_oob_data_request_negative_reply(addr).await?;
+            }
             _ => {}
         }
         Ok(())
     }
 
-    fn save_link_keys(&self) {
-        let mut data = BytesMut::new();
-        for (addr, key) in &self.link_keys {
-            data.write_le_ref(addr);
-            data.write_le_ref(key);
-        }
-        let data = data.freeze();
-        let path = self.link_key_store.clone();
-        spawn_blocking(move || std::fs::write(path, &data).unwrap_or_else(|err| warn!("Failed to save link keys: {:?}", err)));
+    fn set_authentication(&self, addr: RemoteAddr, level: AuthenticationLevel) {
+        self.manager
+            .links
+            .lock()
+            .values_mut()
+            .filter(|info| info.addr == addr)
+            .for_each(|info| info.authentication = Some(level));
+    }
+
+    /// Runs `handler` in its own task so that a slow agent does not block other events.
+    fn respond<F, Fut>(&self, handler: F)
+    where
+        F: FnOnce(Arc<Hci>, Arc<dyn PairingAgent>) -> Fut,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static
+    {
+        let response = handler(self.hci.clone(), self.agent.clone());
+        spawn(async move {
+            response
+                .await
+                .unwrap_or_else(|err| warn!("Failed to answer the controller: {:?}", err));
+        });
     }
 }
 
@@ -208,6 +886,19 @@ pub enum ConnectionEvent {
         mode: EncryptionMode,
         key_size: Option<u8>
     },
+    // ([Vol 4] Part E, Section 7.7.18).
+    RoleChanged {
+        status: Status,
+        addr: RemoteAddr,
+        role: Role
+    },
+    // ([Vol 4] Part E, Section 7.7.20).
+    ModeChanged {
+        status: Status,
+        handle: u16,
+        mode: ConnectionMode,
+        interval: Option<Duration>
+    },
     // ([Vol 4] Part E, Section 7.7.22)
     PinCodeRequest {
         addr: RemoteAddr
@@ -281,6 +972,8 @@ impl ConnectionEventReceiver {
                     EventCode::DisconnectionComplete,
                     EventCode::RemoteNameRequestComplete,
                     EventCode::EncryptionChange,
+                    EventCode::RoleChange,
+                    EventCode::ModeChange,
                     EventCode::PinCodeRequest,
                     EventCode::LinkKeyNotification,
                     EventCode::LinkKeyRequest,
@@ -356,6 +1049,23 @@ impl Stream for ConnectionEventReceiver {
                     data.finish()?;
                     Ok(ConnectionEvent::EncryptionChanged { status, handle, mode, key_size})
                 }
+                EventCode::RoleChange => {
+                    let status: Status = data.read_le()?;
+                    let addr: RemoteAddr = data.read_le()?;
+                    let role: Role = data.read_le()?;
+                    data.finish()?;
+                    Ok(ConnectionEvent::RoleChanged { status, addr, role })
+                }
+                EventCode::ModeChange => {
+                    let status: Status = data.read_le()?;
+                    let handle: u16 = data.read_le()?;
+                    let mode: ConnectionMode = data.read_le()?;
+                    let interval: u16 = data.read_le()?;
+                    let interval = (mode != ConnectionMode::Active)
+                        .then_some(BASE_BAND_SLOT * interval as u32);
+                    data.finish()?;
+                    Ok(ConnectionEvent::ModeChanged { status, handle, mode, interval })
+                }
                 EventCode::ConnectionRequest => {
                     let addr: RemoteAddr = data.read_le()?;
                     let class: ClassOfDevice = data.read_le()?;
@@ -446,4 +1156,312 @@ impl Stream for ConnectionEventReceiver {
         }
         Poll::Pending
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::time::{sleep, timeout};
+
+    use crate::hci::connection::{ConnectionManagerBuilder, LinkEvent, ReconnectPolicy};
+    use crate::hci::consts::{AuthenticationRequirements, EncryptionMode, EventCode, IoCapability, LinkKey, LinkKeyType, RemoteAddr, Role, Status};
+    use crate::hci::link_keys::{AuthenticationLevel, LinkKeyStore, MemoryLinkKeyStore, StoredLinkKey};
+    use crate::hci::pairing::PairingAgent;
+    use crate::hci::security::{SecurityLevel, SecurityPolicy};
+    use crate::hci::{Hci, Opcode, OpcodeGroup};
+    use crate::host::virtual_controller::{VirtualController, VirtualLink};
+    use crate::l2cap::{AVDTP_PSM, SDP_PSM};
+
+    struct DisplayAgent;
+
+    impl PairingAgent for DisplayAgent {
+        fn io_capability(&self) -> IoCapability {
+            IoCapability::DisplayYesNo
+        }
+    }
+
+    struct UnprotectedAgent;
+
+    impl PairingAgent for UnprotectedAgent {
+        fn authentication_requirements(&self) -> AuthenticationRequirements {
+            AuthenticationRequirements::GeneralBondingUnprotected
+        }
+    }
+
+    #[tokio::test]
+    async fn test_agent_rejects_pairing() {
+        let addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let (tx, mut replies) = unbounded_channel();
+        let mut controller = VirtualController::new(RemoteAddr::from([0x01; 6]));
+        for ocf in [0x000E, 0x002D] {
+            let tx = tx.clone();
+            controller = controller.with_command_handler(Opcode::new(OpcodeGroup::LinkControl, ocf), move |params| {
+                tx.send((ocf, params.clone())).unwrap();
+                Some(Bytes::from([&[0x00][..], &params[..]].concat()))
+            });
+        }
+        let handle = controller.handle();
+        let hci = Arc::new(Hci::new(controller).await.unwrap());
+        let _manager = ConnectionManagerBuilder::default()
+            .with_link_key_store(MemoryLinkKeyStore::default())
+            .with_pairing_agent(DisplayAgent)
+            .spawn(hci)
+            .await
+            .unwrap();
+
+        handle.inject_event(EventCode::PinCodeRequest, &addr).unwrap();
+        assert_eq!(replies.recv().await.unwrap(), (0x000E, Bytes::copy_from_slice(&addr)));
+
+        // Both sides can compare the passkey, so the agent gets asked and rejects by default
+        handle
+            .inject_event(EventCode::IoCapabilityResponse, &[&addr[..], &[0x01, 0x00, 0x03][..]].concat())
+            .unwrap();
+        handle
+            .inject_event(EventCode::UserConfirmationRequest, &[&addr[..], &123456u32.to_le_bytes()[..]].concat())
+            .unwrap();
+        assert_eq!(replies.recv().await.unwrap(), (0x002D, Bytes::copy_from_slice(&addr)));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_on_startup() {
+        let VirtualLink { addr_b, a, b, manager: _manager_b, .. } = VirtualLink::new().await;
+        let link_keys = MemoryLinkKeyStore::default();
+        link_keys.insert(addr_b, StoredLinkKey::new(LinkKey::default(), LinkKeyType::AuthenticatedCombinationP256));
+        let policy = ReconnectPolicy::default().with_backoff(Duration::from_millis(10), Duration::from_millis(50));
+        let manager_a = ConnectionManagerBuilder::default()
+            .with_link_key_store(link_keys)
+            .with_reconnect_policy(policy)
+            .spawn(a.clone())
+            .await
+            .unwrap();
+
+        // B only becomes connectable after the first attempts failed
+        sleep(Duration::from_millis(50)).await;
+        assert!(!manager_a.is_connected(addr_b));
+        b.set_scan_enabled(true, false).await.unwrap();
+
+        timeout(Duration::from_secs(1), async {
+            while !manager_a.is_connected(addr_b) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_link_events() {
+        let VirtualLink {
+            addr_a,
+            addr_b,
+            a,
+            b,
+            manager: manager_b,
+            events: mut events_b
+        } = VirtualLink::new().await;
+        b.write_local_name("Device B").await.unwrap();
+        b.set_scan_enabled(true, false).await.unwrap();
+
+        let manager_a = ConnectionManagerBuilder::default()
+            .with_link_key_store(MemoryLinkKeyStore::default())
+            .spawn(a)
+            .await
+            .unwrap();
+        let mut events_a = manager_a.events();
+
+        let connection = manager_a.connect(addr_b).await.unwrap();
+        let handle = connection.handle();
+        assert_eq!(events_a.recv().await, Some(LinkEvent::Connected { handle, addr: addr_b }));
+        assert!(matches!(
+            events_a.recv().await,
+            Some(LinkEvent::EncryptionChanged { encryption: EncryptionMode::E0OrAesCcm, .. })
+        ));
+        let info = connection.info().unwrap();
+        assert_eq!(info.role, Role::Master);
+        assert_eq!(info.encryption, EncryptionMode::E0OrAesCcm);
+        assert_eq!(connection.read_rssi().await.unwrap(), -40);
+        assert_eq!(connection.remote_name().await.unwrap(), "Device B");
+        assert_eq!(manager_a.connection(addr_b).unwrap().info().unwrap().name.as_deref(), Some("Device B"));
+
+        assert!(matches!(events_b.recv().await, Some(LinkEvent::Connected { addr, .. }) if addr == addr_a));
+        assert_eq!(manager_b.connection(addr_a).unwrap().info().unwrap().role, Role::Slave);
+
+        connection
+            .disconnect(Status::RemoteUserTerminatedConnection)
+            .await
+            .unwrap();
+        assert_eq!(
+            events_a.recv().await,
+            Some(LinkEvent::Disconnected {
+                handle,
+                addr: addr_b,
+                reason: Status::ConnectionTerminatedByLocalHost
+            })
+        );
+        assert!(matches!(
+            events_b.recv().await,
+            Some(LinkEvent::EncryptionChanged { .. })
+        ));
+        assert!(matches!(
+            events_b.recv().await,
+            Some(LinkEvent::Disconnected { reason: Status::RemoteUserTerminatedConnection, .. })
+        ));
+        assert!(!connection.is_connected());
+        assert!(manager_a.connections().is_empty());
+        assert!(connection.read_rssi().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_security_policy() {
+        let addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let key_size = Arc::new(AtomicU8::new(7));
+        let controller_key_size = key_size.clone();
+        let (tx, mut disconnects) = unbounded_channel();
+        let controller = VirtualController::new(RemoteAddr::from([0x01; 6]))
+            .with_command_handler(Opcode::new(OpcodeGroup::LinkControl, 0x0006), move |params| {
+                tx.send(params).unwrap();
+                Some(Bytes::from_static(&[0x00]))
+            })
+            .with_command_handler(Opcode::new(OpcodeGroup::StatusParams, 0x0008), move |params| {
+                Some(Bytes::from([&[0x00][..], &params[..], &[controller_key_size.load(Ordering::SeqCst)][..]].concat()))
+            });
+        let handle = controller.handle();
+        let hci = Arc::new(Hci::new(controller).await.unwrap());
+        let policy = SecurityPolicy::default()
+            .with_min_key_size(16)
+            .with_service_requirement(AVDTP_PSM, SecurityLevel::Authenticated);
+        let manager = ConnectionManagerBuilder::default()
+            .with_link_key_store(MemoryLinkKeyStore::default())
+            .with_security_policy(policy)
+            .spawn(hci)
+            .await
+            .unwrap();
+        let mut events = manager.events();
+
+        handle
+            .inject_event(EventCode::ConnectionComplete, &[&[0x00, 0x01, 0x00][..], &addr[..], &[0x01, 0x00][..]].concat())
+            .unwrap();
+        assert!(matches!(events.recv().await, Some(LinkEvent::Connected { handle: 0x0001, .. })));
+        assert!(manager.channel_allowed(0x0001, SDP_PSM.into()));
+        assert!(!manager.channel_allowed(0x0001, AVDTP_PSM.into()));
+
+        handle
+            .inject_event(EventCode::LinkKeyNotification, &[&addr[..], &[0x11; 16][..], &[0x03][..]].concat())
+            .unwrap();
+        handle
+            .inject_event(EventCode::EncryptionChange, &[0x00, 0x01, 0x00, 0x01])
+            .unwrap();
+        assert!(matches!(
+            events.recv().await,
+            Some(LinkEvent::EncryptionChanged { key_size: Some(7), .. })
+        ));
+        // The key is authenticated, but too short for the policy
+        assert!(!manager.channel_allowed(0x0001, AVDTP_PSM.into()));
+        assert!(manager.channel_allowed(0x0001, SDP_PSM.into()));
+        assert_eq!(disconnects.recv().await.unwrap(), Bytes::from_static(&[0x01, 0x00, 0x05]));
+
+        key_size.store(16, Ordering::SeqCst);
+        handle
+            .inject_event(EventCode::EncryptionChange, &[0x00, 0x01, 0x00, 0x01])
+            .unwrap();
+        assert!(matches!(
+            events.recv().await,
+            Some(LinkEvent::EncryptionChanged { key_size: Some(16), .. })
+        ));
+        assert!(manager.channel_allowed(0x0001, AVDTP_PSM.into()));
+    }
+
+    #[tokio::test]
+    async fn test_reauthenticate_with_mitm() {
+        let addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let (tx, mut commands) = unbounded_channel();
+        let mut controller = VirtualController::new(RemoteAddr::from([0x01; 6]))
+            .with_command_handler(Opcode::new(OpcodeGroup::StatusParams, 0x0008), |params| {
+                Some(Bytes::from([&[0x00][..], &params[..], &[16][..]].concat()))
+            });
+        for (group, ocf) in [
+            (OpcodeGroup::LinkControl, 0x0011),
+            (OpcodeGroup::LinkControl, 0x000B),
+            (OpcodeGroup::LinkControl, 0x000C),
+            (OpcodeGroup::LinkControl, 0x002B),
+            (OpcodeGroup::HciControl, 0x0053)
+        ] {
+            let tx = tx.clone();
+            controller = controller.with_command_handler(Opcode::new(group, ocf), move |params| {
+                tx.send((ocf, params.clone())).unwrap();
+                // Authentication and key refresh only report their status, the test injects it
+                match ocf {
+                    0x0011 | 0x0053 => None,
+                    _ => Some(Bytes::from([&[0x00][..], &params[..6]].concat()))
+                }
+            });
+        }
+        let handle = controller.handle();
+        let hci = Arc::new(Hci::new(controller).await.unwrap());
+        let link_keys = MemoryLinkKeyStore::default();
+        link_keys.insert(addr.into(), StoredLinkKey::new(LinkKey::default(), LinkKeyType::UnauthenticatedCombinationP192));
+        let manager = ConnectionManagerBuilder::default()
+            .with_link_key_store(link_keys)
+            .with_pairing_agent(UnprotectedAgent)
+            .spawn(hci)
+            .await
+            .unwrap();
+        let mut events = manager.events();
+
+        handle
+            .inject_event(EventCode::ConnectionComplete, &[&[0x00, 0x01, 0x00][..], &addr[..], &[0x01, 0x00][..]].concat())
+            .unwrap();
+        assert!(matches!(events.recv().await, Some(LinkEvent::Connected { handle: 0x0001, .. })));
+        handle.inject_event(EventCode::LinkKeyRequest, &addr).unwrap();
+        assert_eq!(commands.recv().await.unwrap().0, 0x000B);
+        handle
+            .inject_event(EventCode::EncryptionChange, &[0x00, 0x01, 0x00, 0x01])
+            .unwrap();
+        assert!(matches!(events.recv().await, Some(LinkEvent::EncryptionChanged { .. })));
+        let connection = manager.connection(addr.into()).unwrap();
+        assert_eq!(connection.security_level(), SecurityLevel::Encrypted);
+
+        // The link is encrypted, but the stored key lacks MITM protection
+        let secured = tokio::spawn({
+            let connection = connection.clone();
+            async move { connection.secure(SecurityLevel::Authenticated).await }
+        });
+        assert_eq!(commands.recv().await.unwrap().0, 0x0011);
+        handle
+            .inject_event(EventCode::CommandStatus, &[0x00, 0x01, 0x11, 0x04])
+            .unwrap();
+        handle.inject_event(EventCode::LinkKeyRequest, &addr).unwrap();
+        assert_eq!(commands.recv().await.unwrap(), (0x000C, Bytes::copy_from_slice(&addr)));
+        handle.inject_event(EventCode::IoCapabilityRequest, &addr).unwrap();
+        let (ocf, params) = commands.recv().await.unwrap();
+        assert_eq!(ocf, 0x002B);
+        assert_eq!(params[8], AuthenticationRequirements::GeneralBondingProtected as u8);
+        handle
+            .inject_event(EventCode::LinkKeyNotification, &[&addr[..], &[0x11; 16][..], &[0x03][..]].concat())
+            .unwrap();
+        timeout(Duration::from_secs(1), async {
+            while connection.info().unwrap().authentication != Some(AuthenticationLevel::Authenticated) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        handle
+            .inject_event(EventCode::AuthenticationComplete, &[0x00, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(commands.recv().await.unwrap().0, 0x0053);
+        handle
+            .inject_event(EventCode::CommandStatus, &[0x00, 0x01, 0x53, 0x0C])
+            .unwrap();
+        handle
+            .inject_event(EventCode::EncryptionKeyRefreshComplete, &[0x00, 0x01, 0x00])
+            .unwrap();
+        secured.await.unwrap().unwrap();
+        assert_eq!(connection.security_level(), SecurityLevel::Authenticated);
+    }
+}
@@ -1,11 +1,17 @@
 use std::fmt::Debug;
-use instructor::Exstruct;
+use instructor::{Exstruct, Instruct};
 
 /// Company identifier ([Assigned Numbers] Section 7.1).
-#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd, Exstruct)]
+#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd, Exstruct, Instruct)]
 #[repr(transparent)]
 pub struct CompanyId(u16);
 
+impl From<u16> for CompanyId {
+    fn from(value: u16) -> Self {
+        Self(value)
+    }
+}
+
 impl Debug for CompanyId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(name) = self.name() {
@@ -90,6 +90,9 @@ pub enum Role {
     Slave = 0x01
 }
 
+/// Identifies a logical link to a remote device ([Vol 4] Part E, Section 5.4.2).
+pub type ConnectionHandle = u16;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Exstruct, Instruct)]
 pub struct RemoteAddr([u8; 6]);
 
@@ -253,6 +256,18 @@ pub enum AuthenticationRequirements {
     GeneralBondingProtected = 0x05
 }
 
+impl AuthenticationRequirements {
+    /// The same bonding behavior, but requiring MITM protection.
+    pub fn with_mitm_protection(self) -> Self {
+        match self {
+            Self::NoBondingUnprotected => Self::NoBondingProtected,
+            Self::DedicatedBondingUnprotected => Self::DedicatedBondingProtected,
+            Self::GeneralBondingUnprotected => Self::GeneralBondingProtected,
+            other => other
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Exstruct, Instruct)]
 #[repr(u8)]
 pub enum OobDataPresence {
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_lite::Stream;
+use instructor::Buffer;
+use tokio::spawn;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tracing::{debug, trace, warn};
+
+use crate::hci::consts::{ClassOfDevice, EventCode, Lap, RemoteAddr, Status};
+use crate::hci::eir::{ExtendedInquiryResponse, LocalName};
+use crate::hci::{Error, Hci, InquiryMode};
+use crate::sdp::Uuid;
+use crate::utils::catch_error;
+
+/// A device that responded to an inquiry.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiscoveredDevice {
+    pub addr: RemoteAddr,
+    pub class: ClassOfDevice,
+    pub page_scan_repetition_mode: u8,
+    pub clock_offset: u16,
+    /// Only reported by controllers that support inquiry results with RSSI.
+    pub rssi: Option<i8>,
+    /// Only reported by devices that send an extended inquiry response.
+    pub name: Option<LocalName>,
+    /// Only reported by devices that send an extended inquiry response.
+    pub uuids: Vec<Uuid>
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DiscoveryBuilder {
+    lap: Lap,
+    duration: u8,
+    max_responses: u8,
+    period: Option<(u16, u16)>
+}
+
+impl Default for DiscoveryBuilder {
+    fn default() -> Self {
+        Self {
+            lap: Lap::General,
+            duration: 8,
+            max_responses: 0,
+            period: None
+        }
+    }
+}
+
+impl DiscoveryBuilder {
+    pub fn with_lap(mut self, lap: Lap) -> Self {
+        self.lap = lap;
+        self
+    }
+
+    /// The duration of a single inquiry in 1.28s units. Range: 1-30.
+    pub fn with_duration(mut self, duration: u8) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// The maximum number of responses per inquiry. 0 means no limit.
+    pub fn with_max_responses(mut self, max_responses: u8) -> Self {
+        self.max_responses = max_responses;
+        self
+    }
+
+    /// Repeats the inquiry with a random delay between `min_period` and `max_period` (both in 1.28s units)
+    /// until the discovery is cancelled. Requires `max_period > min_period > duration`.
+    pub fn with_periodic_inquiry(mut self, min_period: u16, max_period: u16) -> Self {
+        self.period = Some((min_period, max_period));
+        self
+    }
+
+    pub async fn start(self, hci: &Arc<Hci>) -> Result<Discovery, Error> {
+        // Ask for the richest result format, older controllers only know standard results
+        match hci.write_inquiry_mode(InquiryMode::WithRssiOrExtended).await {
+            Err(Error::Controller(status)) => warn!("Failed to enable extended inquiry results: {}", status),
+            other => other?
+        }
+
+        let (tx, events) = unbounded_channel();
+        hci.register_event_handler(
+            [
+                EventCode::InquiryComplete,
+                EventCode::InquiryResult,
+                EventCode::InquiryResultWithRssi,
+                EventCode::ExtendedInquiryResult
+            ],
+            tx
+        )?;
+        match self.period {
+            Some((min_period, max_period)) => {
+                hci.periodic_inquiry(max_period, min_period, self.lap, self.duration, self.max_responses)
+                    .await?
+            }
+            None => hci.inquiry(self.lap, self.duration, self.max_responses).await?
+        }
+        debug!("Started {} inquiry", if self.period.is_some() { "periodic" } else { "single" });
+
+        Ok(Discovery {
+            hci: hci.clone(),
+            events,
+            pending: VecDeque::new(),
+            periodic: self.period.is_some(),
+            running: true
+        })
+    }
+}
+
+/// A running inquiry ([Vol 2] Part B, Section 8.4).
+///
+/// The stream ends once a single inquiry completes. Periodic inquiries run until they are cancelled.
+/// Devices can be reported more than once.
+/// Dropping a running discovery cancels it in the background.
+pub struct Discovery {
+    hci: Arc<Hci>,
+    events: UnboundedReceiver<(EventCode, Bytes)>,
+    pending: VecDeque<DiscoveredDevice>,
+    periodic: bool,
+    running: bool
+}
+
+impl Discovery {
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Stops the inquiry and waits for the controller to confirm.
+    pub async fn cancel(mut self) -> Result<(), Error> {
+        match self.running {
+            true => {
+                self.running = false;
+                cancel_inquiry(&self.hci, self.periodic).await
+            }
+            false => Ok(())
+        }
+    }
+
+    fn process_event(&mut self, code: EventCode, mut data: Bytes) -> Result<(), instructor::Error> {
+        match code {
+            EventCode::InquiryComplete => {
+                // ([Vol 4] Part E, Section 7.7.1).
+                let status: Status = data.read_le()?;
+                data.finish()?;
+                debug!("Inquiry complete: {}", status);
+                if !self.periodic {
+                    self.running = false;
+                }
+            }
+            EventCode::InquiryResult => {
+                // ([Vol 4] Part E, Section 7.7.2).
+                let count: u8 = data.read_le()?;
+                for _ in 0..count {
+                    let addr: RemoteAddr = data.read_le()?;
+                    let page_scan_repetition_mode: u8 = data.read_le()?;
+                    data.skip(2)?;
+                    let class: ClassOfDevice = data.read_le()?;
+                    let clock_offset: u16 = data.read_le()?;
+                    self.pending.push_back(DiscoveredDevice {
+                        addr,
+                        class,
+                        page_scan_repetition_mode,
+                        clock_offset,
+                        rssi: None,
+                        name: None,
+                        uuids: Vec::new()
+                    });
+                }
+                data.finish()?;
+            }
+            EventCode::InquiryResultWithRssi => {
+                // ([Vol 4] Part E, Section 7.7.33).
+                let count: u8 = data.read_le()?;
+                for _ in 0..count {
+                    let addr: RemoteAddr = data.read_le()?;
+                    let page_scan_repetition_mode: u8 = data.read_le()?;
+                    data.skip(1)?;
+                    let class: ClassOfDevice = data.read_le()?;
+                    let clock_offset: u16 = data.read_le()?;
+                    let rssi: i8 = data.read_le()?;
+                    self.pending.push_back(DiscoveredDevice {
+                        addr,
+                        class,
+                        page_scan_repetition_mode,
+                        clock_offset,
+                        rssi: Some(rssi),
+                        name: None,
+                        uuids: Vec::new()
+                    });
+                }
+                data.finish()?;
+            }
+            EventCode::ExtendedInquiryResult => {
+                // ([Vol 4] Part E, Section 7.7.38).
+                let count: u8 = data.read_le()?;
+                debug_assert_eq!(count, 1);
+                let addr: RemoteAddr = data.read_le()?;
+                let page_scan_repetition_mode: u8 = data.read_le()?;
+                data.skip(1)?;
+                let class: ClassOfDevice = data.read_le()?;
+                let clock_offset: u16 = data.read_le()?;
+                let rssi: i8 = data.read_le()?;
+                let eir: ExtendedInquiryResponse = data.read_le()?;
+                self.pending.push_back(DiscoveredDevice {
+                    addr,
+                    class,
+                    page_scan_repetition_mode,
+                    clock_offset,
+                    rssi: Some(rssi),
+                    name: eir.local_name,
+                    uuids: eir.service_classes
+                });
+            }
+            _ => trace!("Ignoring unexpected event: {:?}", code)
+        }
+        Ok(())
+    }
+}
+
+impl Stream for Discovery {
+    type Item = DiscoveredDevice;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(device) = self.pending.pop_front() {
+                return Poll::Ready(Some(device));
+            }
+            if !self.running {
+                return Poll::Ready(None);
+            }
+            match self.events.poll_recv(cx) {
+                Poll::Ready(Some((code, data))) => {
+                    catch_error(|| self.process_event(code, data))
+                        .unwrap_or_else(|err| warn!("Failed to parse inquiry event: {:?}", err));
+                }
+                Poll::Ready(None) => {
+                    self.running = false;
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        if self.running && !self.hci.is_closed() {
+            let hci = self.hci.clone();
+            let periodic = self.periodic;
+            spawn(async move {
+                cancel_inquiry(&hci, periodic)
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to cancel inquiry: {:?}", err));
+            });
+        }
+    }
+}
+
+async fn cancel_inquiry(hci: &Hci, periodic: bool) -> Result<(), Error> {
+    match periodic {
+        true => hci.exit_periodic_inquiry().await,
+        false => hci.cancel_inquiry().await
+    }
+}
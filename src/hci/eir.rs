@@ -0,0 +1,289 @@
+use std::mem::size_of;
+
+use bytes::{Bytes, BytesMut};
+use instructor::{Buffer, BufferMut, Error, Exstruct, Instruct, LittleEndian};
+use tracing::{trace, warn};
+
+use crate::hci::consts::CompanyId;
+use crate::sdp::{PackedUuid, Uuid};
+
+/// The size of the extended inquiry response including the non-significant part ([Vol 3] Part C, Section 8).
+pub const MAX_EIR_LENGTH: usize = 240;
+
+/// Extended inquiry response data ([Vol 3] Part C, Section 8).
+///
+/// Doubles as a builder for the local response, see [`Hci::write_extended_inquiry_response`](crate::hci::Hci::write_extended_inquiry_response).
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ExtendedInquiryResponse {
+    pub local_name: Option<LocalName>,
+    pub service_classes: Vec<Uuid>,
+    pub tx_power_level: Option<i8>,
+    pub manufacturer_data: Vec<ManufacturerData>
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LocalName {
+    Complete(String),
+    Shortened(String)
+}
+
+impl LocalName {
+    pub fn as_str(&self) -> &str {
+        match self {
+            LocalName::Complete(name) => name,
+            LocalName::Shortened(name) => name
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ManufacturerData {
+    pub company: CompanyId,
+    pub data: Bytes
+}
+
+/// EIR data types ([Assigned Numbers] Section 2.3).
+mod data_type {
+    pub const INCOMPLETE_UUID16_LIST: u8 = 0x02;
+    pub const COMPLETE_UUID16_LIST: u8 = 0x03;
+    pub const INCOMPLETE_UUID32_LIST: u8 = 0x04;
+    pub const COMPLETE_UUID32_LIST: u8 = 0x05;
+    pub const INCOMPLETE_UUID128_LIST: u8 = 0x06;
+    pub const COMPLETE_UUID128_LIST: u8 = 0x07;
+    pub const SHORTENED_LOCAL_NAME: u8 = 0x08;
+    pub const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    pub const TX_POWER_LEVEL: u8 = 0x0A;
+    pub const MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
+}
+
+impl ExtendedInquiryResponse {
+    /// The name gets shortened automatically if the response runs out of space.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.local_name = Some(LocalName::Complete(name.into()));
+        self
+    }
+
+    pub fn with_service_class(mut self, uuid: Uuid) -> Self {
+        if !self.service_classes.contains(&uuid) {
+            self.service_classes.push(uuid);
+        }
+        self
+    }
+
+    /// Use [`SdpBuilder::service_classes`](crate::sdp::SdpBuilder::service_classes) to advertise all registered services.
+    pub fn with_service_classes(self, uuids: impl IntoIterator<Item = Uuid>) -> Self {
+        uuids
+            .into_iter()
+            .fold(self, |eir, uuid| eir.with_service_class(uuid))
+    }
+
+    /// The transmit power in dBm, see [`Hci::read_inquiry_response_transmit_power_level`](crate::hci::Hci::read_inquiry_response_transmit_power_level).
+    pub fn with_tx_power_level(mut self, level: i8) -> Self {
+        self.tx_power_level = Some(level);
+        self
+    }
+
+    pub fn with_manufacturer_data(mut self, company: CompanyId, data: impl Into<Bytes>) -> Self {
+        self.manufacturer_data.push(ManufacturerData {
+            company,
+            data: data.into()
+        });
+        self
+    }
+
+    /// Encodes the significant part of the response.
+    ///
+    /// Everything that does not fit into [`MAX_EIR_LENGTH`] is dropped.
+    /// Service class lists are marked as incomplete and the name as shortened in that case.
+    pub fn encode(&self) -> BytesMut {
+        let mut buffer = BytesMut::with_capacity(MAX_EIR_LENGTH);
+        if let Some(level) = self.tx_power_level {
+            write_structure(&mut buffer, data_type::TX_POWER_LEVEL, |b| b.write_le(level));
+        }
+        let lists = [
+            (2, data_type::COMPLETE_UUID16_LIST, data_type::INCOMPLETE_UUID16_LIST),
+            (4, data_type::COMPLETE_UUID32_LIST, data_type::INCOMPLETE_UUID32_LIST),
+            (16, data_type::COMPLETE_UUID128_LIST, data_type::INCOMPLETE_UUID128_LIST)
+        ];
+        for (size, complete, incomplete) in lists {
+            let uuids: Vec<PackedUuid> = self
+                .service_classes
+                .iter()
+                .map(|uuid| uuid.as_packed())
+                .filter(|uuid| uuid.byte_size() == size)
+                .collect();
+            if uuids.is_empty() {
+                continue;
+            }
+            let count = uuids.len().min(remaining(&buffer) / size);
+            if count == 0 {
+                warn!("No space left for {}-bit service class UUIDs", size * 8);
+                continue;
+            }
+            let data_type = if count == uuids.len() { complete } else { incomplete };
+            write_structure(&mut buffer, data_type, |b| {
+                for uuid in &uuids[..count] {
+                    match *uuid {
+                        PackedUuid::Uuid16(uuid) => b.write_le(uuid),
+                        PackedUuid::Uuid32(uuid) => b.write_le(uuid),
+                        PackedUuid::Uuid128(uuid) => b.write_le(uuid)
+                    }
+                }
+            });
+        }
+        if let Some(name) = &self.local_name {
+            let mut length = name.as_str().len().min(remaining(&buffer));
+            while !name.as_str().is_char_boundary(length) {
+                length -= 1;
+            }
+            let data_type = match name {
+                LocalName::Complete(_) if length == name.as_str().len() => data_type::COMPLETE_LOCAL_NAME,
+                _ => data_type::SHORTENED_LOCAL_NAME
+            };
+            match length {
+                0 => warn!("No space left for the local name"),
+                _ => write_structure(&mut buffer, data_type, |b| b.extend_from_slice(&name.as_str().as_bytes()[..length]))
+            }
+        }
+        for ManufacturerData { company, data } in &self.manufacturer_data {
+            if remaining(&buffer) < size_of::<u16>() + data.len() {
+                warn!("No space left for the manufacturer data of {:?}", company);
+                continue;
+            }
+            write_structure(&mut buffer, data_type::MANUFACTURER_SPECIFIC_DATA, |b| {
+                b.write_le(*company);
+                b.extend_from_slice(data);
+            });
+        }
+        buffer
+    }
+}
+
+/// The number of data bytes the next structure can hold.
+fn remaining(buffer: &BytesMut) -> usize {
+    (MAX_EIR_LENGTH - buffer.len()).saturating_sub(2)
+}
+
+// ([Vol 3] Part C, Section 8, Figure 8.1).
+fn write_structure(buffer: &mut BytesMut, data_type: u8, writer: impl FnOnce(&mut BytesMut)) {
+    let start = buffer.len();
+    buffer.write_le(0u8);
+    buffer.write_le(data_type);
+    writer(buffer);
+    buffer[start] = u8::try_from(buffer.len() - start - 1).expect("EIR structure too long");
+    debug_assert!(buffer.len() <= MAX_EIR_LENGTH);
+}
+
+impl Instruct<LittleEndian> for ExtendedInquiryResponse {
+    fn write_to_buffer<B: BufferMut>(&self, buffer: &mut B) {
+        let data = self.encode();
+        buffer.extend_from_slice(&data);
+        // The non-significant part has to be zero
+        buffer.extend_from_slice(&[0u8; MAX_EIR_LENGTH][data.len()..]);
+    }
+}
+
+impl Exstruct<LittleEndian> for ExtendedInquiryResponse {
+    fn read_from_buffer<B: Buffer>(buffer: &mut B) -> Result<Self, Error> {
+        // ([Vol 3] Part C, Section 8, Figure 8.1).
+        let mut eir = ExtendedInquiryResponse::default();
+        while buffer.remaining() > 0 {
+            let length: u8 = buffer.read_le()?;
+            // A zero length marks the start of the non-significant part
+            if length == 0 {
+                buffer.skip(buffer.remaining())?;
+                break;
+            }
+            let data_type: u8 = buffer.read_le()?;
+            let mut data = vec![0u8; length as usize - 1];
+            buffer.try_copy_to_slice(&mut data)?;
+            let mut data = data.as_slice();
+            match data_type {
+                data_type::INCOMPLETE_UUID16_LIST | data_type::COMPLETE_UUID16_LIST => {
+                    while !data.is_empty() {
+                        eir.service_classes.push(Uuid::from_u16(data.read_le()?));
+                    }
+                }
+                data_type::INCOMPLETE_UUID32_LIST | data_type::COMPLETE_UUID32_LIST => {
+                    while !data.is_empty() {
+                        eir.service_classes.push(Uuid::from_u32(data.read_le()?));
+                    }
+                }
+                data_type::INCOMPLETE_UUID128_LIST | data_type::COMPLETE_UUID128_LIST => {
+                    while !data.is_empty() {
+                        eir.service_classes.push(Uuid::from_u128(data.read_le()?));
+                    }
+                }
+                data_type::SHORTENED_LOCAL_NAME => {
+                    eir.local_name = Some(LocalName::Shortened(String::from_utf8_lossy(data).into_owned()));
+                }
+                data_type::COMPLETE_LOCAL_NAME => {
+                    eir.local_name = Some(LocalName::Complete(String::from_utf8_lossy(data).into_owned()));
+                }
+                data_type::TX_POWER_LEVEL => {
+                    eir.tx_power_level = Some(data.read_le()?);
+                    data.finish()?;
+                }
+                data_type::MANUFACTURER_SPECIFIC_DATA => {
+                    let company: CompanyId = data.read_le()?;
+                    eir.manufacturer_data.push(ManufacturerData {
+                        company,
+                        data: Bytes::copy_from_slice(data)
+                    });
+                }
+                _ => trace!("Ignoring EIR data type 0x{:02X}", data_type)
+            }
+        }
+        Ok(eir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use instructor::{Buffer, BufferMut};
+
+    use crate::hci::consts::CompanyId;
+    use crate::hci::eir::{ExtendedInquiryResponse, LocalName, MAX_EIR_LENGTH};
+    use crate::sdp::Uuid;
+
+    #[test]
+    fn test_round_trip() {
+        let eir = ExtendedInquiryResponse::default()
+            .with_name("bluefang")
+            .with_service_class(Uuid::from_u16(0x110B))
+            .with_service_class(Uuid::from_u32(0x0001_110E))
+            .with_service_class(Uuid::from_u128(0x12345678_9ABC_DEF0_1234_56789ABCDEF0))
+            .with_tx_power_level(-4)
+            .with_manufacturer_data(CompanyId::from(0xFFFF), &[0x01, 0x02][..]);
+
+        let mut buffer = BytesMut::new();
+        buffer.write_le_ref(&eir);
+        assert_eq!(buffer.len(), MAX_EIR_LENGTH);
+        assert_eq!(&buffer[..5], &[0x02, 0x0A, 0xFC, 0x03, 0x03]);
+
+        let decoded: ExtendedInquiryResponse = buffer.read_le().unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(decoded, eir);
+    }
+
+    #[test]
+    fn test_truncation() {
+        let eir = ExtendedInquiryResponse::default()
+            .with_name("bluefang")
+            .with_service_classes((0..150).map(|i| Uuid::from_u16(0x1100 + i)));
+        let data = eir.encode();
+        assert_eq!(data.len(), MAX_EIR_LENGTH);
+        assert_eq!(&data[..2], &[239, 0x02]);
+        let decoded: ExtendedInquiryResponse = data.freeze().read_le().unwrap();
+        assert_eq!(decoded.service_classes.len(), 119);
+        assert_eq!(decoded.local_name, None);
+
+        let eir = ExtendedInquiryResponse::default()
+            .with_name("bluefang")
+            .with_service_classes((0..116).map(|i| Uuid::from_u16(0x1100 + i)));
+        let decoded: ExtendedInquiryResponse = eir.encode().freeze().read_le().unwrap();
+        assert_eq!(decoded.service_classes.len(), 116);
+        assert_eq!(decoded.local_name, Some(LocalName::Shortened(String::from("blue"))));
+    }
+}
@@ -1,24 +1,25 @@
 use std::collections::{BTreeMap, BTreeSet};
-use std::future::pending;
+use std::future::{pending, poll_fn};
 use std::mem::size_of;
+use std::task::Poll;
+use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use instructor::utils::Length;
 use instructor::{Buffer, Exstruct};
-use nusb::transfer::{ControlOut, ControlType, Recipient, RequestBuffer, TransferError};
+use tokio::select;
 use tokio::sync::mpsc::{UnboundedReceiver as MpscReceiver, UnboundedSender as MpscSender};
 use tokio::sync::oneshot::Sender as OneshotSender;
+use tokio::time::{sleep_until, Instant};
 use tracing::{debug, error, warn};
 
+use crate::hci::acl::{AclPacket, AclScheduler};
 use crate::hci::btsnoop::{LogWriter, PacketType};
 use crate::hci::consts::{EventCode, Status};
 use crate::hci::{Error, Opcode};
-use crate::host::usb::UsbHost;
+use crate::host::{IncomingPacket, Transport};
 use crate::utils::DispatchExt;
 
-const TRANSFER_BUFFER_SIZE: usize = 4096;
-const TRANSFER_BUFFER_COUNT: usize = 4;
-
 pub enum EventLoopCommand {
     Shutdown,
     RegisterHciEventHandler {
@@ -28,43 +29,70 @@ pub enum EventLoopCommand {
     RegisterAclDataHandler {
         handler: MpscSender<Bytes>
     },
-    SetMaxInFlightAclPackets(u32)
+    SetAclBufferSize {
+        packet_length: usize,
+        packet_count: u32
+    }
 }
 
-pub type CmdResultSender = OneshotSender<Result<Bytes, TransferError>>;
+pub type CmdResultSender = OneshotSender<Result<Bytes, Error>>;
 
-pub async fn event_loop(
-    transport: UsbHost, mut cmd_receiver: MpscReceiver<(Opcode, Bytes, CmdResultSender)>, mut acl_receiver: MpscReceiver<Bytes>,
+pub struct CommandRequest {
+    pub opcode: Opcode,
+    pub packet: Bytes,
+    pub timeout: Duration,
+    pub result: CmdResultSender
+}
+
+/// Runs until the [`Hci`](crate::hci::Hci) shuts down or the controller becomes unusable.
+/// In both cases all handler channels get closed and all pending commands fail.
+pub async fn event_loop<T: Transport>(
+    mut transport: T, mut cmd_receiver: MpscReceiver<CommandRequest>, mut acl_receiver: MpscReceiver<AclPacket>,
     mut ctl_receiver: MpscReceiver<EventLoopCommand>
 ) {
-    let mut events = transport
-        .interface
-        .interrupt_in_queue(transport.endpoints.event);
-    for _ in 0..TRANSFER_BUFFER_COUNT {
-        events.submit(RequestBuffer::new(TRANSFER_BUFFER_SIZE));
-    }
-
-    let mut acl_in = transport
-        .interface
-        .bulk_in_queue(transport.endpoints.acl_in);
-    for _ in 0..TRANSFER_BUFFER_COUNT {
-        acl_in.submit(RequestBuffer::new(TRANSFER_BUFFER_SIZE));
-    }
-    let mut acl_out = transport
-        .interface
-        .bulk_out_queue(transport.endpoints.acl_out);
-
-    let mut state = State::default();
+    let mut state = State {
+        // The controller is always able to accept one command after power-on ([Vol 4] Part E, Section 4.4).
+        command_credits: 1,
+        ..Default::default()
+    };
     let log = LogWriter::new();
-    let mut buffer = BytesMut::with_capacity(4096);
 
-    loop {
+    'outer: loop {
+        while let Some(data) = state.acl.next() {
+            log.write(PacketType::AclTx, data.clone());
+            if let Err(err) = transport.send_acl_data(data).await {
+                error!("Error writing ACL data: {:?}", err);
+                if err.is_fatal() {
+                    state.fail_outstanding_command(err);
+                    break 'outer;
+                }
+            }
+        }
         tokio::select! {
-            event = events.next_complete() => {
-                match event.status {
-                    Ok(_) => {
-                        buffer.put_slice(&event.data);
-                        let data = buffer.split().freeze();
+            // Control messages go first so that a handler registered before sending a command is always in place
+            // before the command's events are dispatched.
+            biased;
+            cmd = ctl_receiver.recv() => {
+                match cmd {
+                    Some(EventLoopCommand::RegisterHciEventHandler { events, handler }) => {
+                        for event in events {
+                            state.hci_event_handlers.entry(event).or_default().push(handler.clone());
+                        }
+                    }
+                    Some(EventLoopCommand::RegisterAclDataHandler { handler }) => {
+                        state.acl_data_handlers.push(handler);
+                    }
+                    Some(EventLoopCommand::SetAclBufferSize { packet_length, packet_count }) => {
+                        state.acl.set_buffer_size(packet_length, packet_count);
+                    }
+                    Some(EventLoopCommand::Shutdown) | None => {
+                        break;
+                    }
+                }
+            },
+            packet = transport.receive() => {
+                match packet {
+                    Ok(IncomingPacket::Event(data)) => {
                         log.write(PacketType::Event, data.clone());
                         match state.process_hci_event(data) {
                             Ok(true) => (),
@@ -72,100 +100,113 @@ pub async fn event_loop(
                             Err(err) => error!("Error processing HCI event: {:?}", err),
                         }
                     },
-                    Err(err) => error!("Error reading HCI event: {:?}", err),
-                }
-                events.submit(RequestBuffer::reuse(event.data, TRANSFER_BUFFER_SIZE));
-            },
-            data = acl_in.next_complete() => {
-                match data.status {
-                    Ok(_) => {
-                        buffer.put_slice(&data.data);
-                        let data = buffer.split().freeze();
+                    Ok(IncomingPacket::AclData(data)) => {
                         log.write(PacketType::AclRx, data.clone());
                         state.process_acl_data(data)
                             .unwrap_or_else(|err| error!("Error processing ACL data: {:?}", err));
                     },
-                    Err(err) => error!("Error reading HCI event: {:?}", err),
+                    Err(err) if err.is_fatal() => {
+                        error!("Lost connection to the controller: {:?}", err);
+                        state.fail_outstanding_command(err);
+                        break;
+                    },
+                    Err(err) => error!("Error reading from transport: {:?}", err),
                 }
-                acl_in.submit(RequestBuffer::reuse(data.data, TRANSFER_BUFFER_SIZE));
-            },
-            completion = acl_out.next_complete(), if acl_out.pending() > 0 => {
-                completion
-                    .status
-                    .unwrap_or_else(|err| error!("Error writing ACL data: {:?}", err));
             },
-            data = acl_receiver.recv(), if state.in_flight < state.max_in_flight => {
-                if let Some(data) = data {
-                    state.in_flight += 1;
-                    log.write(PacketType::AclTx, data.clone());
-                    let data = data.to_vec();
-                    acl_out.submit(data);
-                } else  {
-                    break;
+            packet = acl_receiver.recv() => {
+                match packet {
+                    Some(packet) => state.acl.push(packet),
+                    None => break
                 }
             },
-            cmd = cmd_receiver.recv(), if state.outstanding_command.is_none() => {
-                if let Some((opcode, req, tx)) = cmd {
-                    log.write(PacketType::Command, req.clone());
-                    let cmd = transport.interface.control_out(ControlOut {
-                        control_type: ControlType::Class,
-                        recipient: Recipient::Device,
-                        request: 0x00,
-                        value: 0x00,
-                        index: transport.endpoints.main_iface.into(),
-                        data: &req,
-                    }).await;
-                    match cmd.status {
-                        Ok(_) => state.outstanding_command = Some((opcode, tx)),
+            cmd = cmd_receiver.recv(), if state.command_credits > 0 => {
+                if let Some(CommandRequest { opcode, packet, timeout, result }) = cmd {
+                    log.write(PacketType::Command, packet.clone());
+                    match transport.send_command(packet).await {
+                        Ok(()) => {
+                            state.command_credits -= 1;
+                            state.outstanding_commands.push((opcode, result, Instant::now() + timeout));
+                        },
                         Err(err) => {
-                            let _ = tx.send(Err(err));
+                            let fatal = err.is_fatal();
+                            let _ = result.send(Err(err));
+                            if fatal {
+                                error!("Lost connection to the controller");
+                                break;
+                            }
                         }
                     }
                 } else {
                     break;
                 }
             },
-            _ = state.outstanding_command_dropped() => {
-                state.outstanding_command = None;
-            },
-            cmd = ctl_receiver.recv() => {
-                match cmd {
-                    Some(EventLoopCommand::RegisterHciEventHandler { events, handler }) => {
-                        for event in events {
-                            state.hci_event_handlers.entry(event).or_default().push(handler.clone());
-                        }
-                    }
-                    Some(EventLoopCommand::RegisterAclDataHandler { handler }) => {
-                        state.acl_data_handlers.push(handler);
-                    }
-                    Some(EventLoopCommand::SetMaxInFlightAclPackets(n)) => {
-                        state.max_in_flight = n;
-                    }
-                    Some(EventLoopCommand::Shutdown) | None => {
+            expired = state.outstanding_command_expired() => {
+                match expired {
+                    Expiry::Abandoned(index) => {
+                        state.outstanding_commands.remove(index);
+                    },
+                    Expiry::TimedOut(index) => {
+                        // The controller has to answer every command, so it is most likely hung ([Vol 4] Part E, Section 4.4).
+                        let (opcode, tx, _) = state.outstanding_commands.remove(index);
+                        error!("Controller failed to respond to {:?}", opcode);
+                        let _ = tx.send(Err(Error::CommandTimeout(opcode)));
                         break;
                     }
                 }
-            }
+            },
         }
     }
 
     debug!("Event loop closed");
 }
 
+enum Expiry {
+    Abandoned(usize),
+    TimedOut(usize)
+}
+
 #[derive(Default)]
 struct State {
-    outstanding_command: Option<(Opcode, OneshotSender<Result<Bytes, TransferError>>)>,
+    command_credits: u8,
+    outstanding_commands: Vec<(Opcode, CmdResultSender, Instant)>,
     hci_event_handlers: BTreeMap<EventCode, Vec<MpscSender<(EventCode, Bytes)>>>,
     acl_data_handlers: Vec<MpscSender<Bytes>>,
-    max_in_flight: u32,
-    in_flight: u32
+    acl: AclScheduler
 }
 
 impl State {
-    async fn outstanding_command_dropped(&mut self) {
-        match self.outstanding_command.as_mut() {
-            None => pending().await,
-            Some((_, tx)) => tx.closed().await
+    /// Resolves once the caller of an outstanding command lost interest in it or the command timed out.
+    async fn outstanding_command_expired(&mut self) -> Expiry {
+        let Some((index, deadline)) = self
+            .outstanding_commands
+            .iter()
+            .enumerate()
+            .map(|(i, (_, _, deadline))| (i, *deadline))
+            .min_by_key(|(_, deadline)| *deadline)
+        else {
+            return pending().await;
+        };
+        let abandoned = poll_fn(|cx| {
+            match self
+                .outstanding_commands
+                .iter_mut()
+                .position(|(_, tx, _)| tx.poll_closed(cx).is_ready())
+            {
+                Some(i) => Poll::Ready(i),
+                None => Poll::Pending
+            }
+        });
+        select! {
+            i = abandoned => Expiry::Abandoned(i),
+            _ = sleep_until(deadline) => Expiry::TimedOut(index)
+        }
+    }
+
+    /// Fails the oldest outstanding command. All others fail with [`Error::EventLoopClosed`] once the event loop exits.
+    fn fail_outstanding_command(&mut self, err: Error) {
+        if !self.outstanding_commands.is_empty() {
+            let (_, tx, _) = self.outstanding_commands.remove(0);
+            let _ = tx.send(Err(err));
         }
     }
 
@@ -182,19 +223,21 @@ impl State {
                     tmp.rotate_left(size_of::<Status>());
                     data = tmp.freeze();
                 }
-                let _cmd_quota: u8 = data.read_le()?;
+                // Num_HCI_Command_Packets is the absolute number of commands the controller can accept right now
+                self.command_credits = data.read_le()?;
                 let opcode: Opcode = data.read_le()?;
                 // trace!("Received CommandComplete for {:?}", opcode);
-                match self.outstanding_command.take() {
-                    Some((op, tx)) if op == opcode => tx
-                        .send(Ok(data))
-                        .unwrap_or_else(|_| debug!("CommandComplete receiver dropped")),
-                    Some((op, tx)) => {
-                        self.outstanding_command = Some((op, tx));
-                        return Err(Error::UnexpectedCommandResponse(opcode));
-                    }
-                    None => return Err(Error::UnexpectedCommandResponse(opcode))
+                if opcode == Opcode::NONE {
+                    return Ok(true);
                 }
+                let index = self
+                    .outstanding_commands
+                    .iter()
+                    .position(|(op, _, _)| *op == opcode)
+                    .ok_or(Error::UnexpectedCommandResponse(opcode))?;
+                let (_, tx, _) = self.outstanding_commands.remove(index);
+                tx.send(Ok(data))
+                    .unwrap_or_else(|_| debug!("CommandComplete receiver dropped"));
                 Ok(true)
             }
             EventCode::NumberOfCompletedPackets => {
@@ -203,16 +246,17 @@ impl State {
                 let mut handles = data.split_to(count * 2);
                 let mut counts = data.split_to(count * 2);
                 for _ in 0..count {
-                    let _handle: u16 = handles.read_le()?;
+                    let handle: u16 = handles.read_le()?;
                     let count: u16 = counts.read_le()?;
                     //trace!("Flushed {} packets for handle {}", count, handle);
-                    self.in_flight = self.in_flight.saturating_sub(count as u32);
+                    self.acl.completed(handle, count);
                 }
                 data.finish()?;
                 Ok(true)
             }
             _ => {
                 let code = header.code;
+                self.track_link(code, data.clone())?;
                 let handled = self
                     .hci_event_handlers
                     .get_mut(&code)
@@ -225,6 +269,30 @@ impl State {
         }
     }
 
+    /// Keeps the ACL buffers of every link in sync with the link's lifetime.
+    fn track_link(&mut self, code: EventCode, mut data: Bytes) -> Result<(), Error> {
+        match code {
+            // ([Vol 4] Part E, Section 7.7.3).
+            EventCode::ConnectionComplete => {
+                let status: Status = data.read_le()?;
+                let handle: u16 = data.read_le()?;
+                if status == Status::Success {
+                    self.acl.connected(handle);
+                }
+            }
+            // ([Vol 4] Part E, Section 7.7.5).
+            EventCode::DisconnectionComplete => {
+                let status: Status = data.read_le()?;
+                let handle: u16 = data.read_le()?;
+                if status == Status::Success {
+                    self.acl.disconnected(handle);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     fn process_acl_data(&mut self, data: Bytes) -> Result<(), Error> {
         // let data = AclDataPacket::from_bytes(data).ok_or(Error::BadEventPacketSize)?;
         self.acl_data_handlers.dispatch(data);
@@ -257,31 +325,3 @@ pub struct EventHeader {
 //            })
 //    }
 //}
-
-/*
-InquiryEvent::Complete => {
-    // ([Vol 4] Part E, Section 7.7.1).
-    let status = Status::from(payload.u8()?);
-    payload.finish()?;
-    debug!("Inquiry complete: {}", status);
-},
-InquiryEvent::Result => {
-    // ([Vol 4] Part E, Section 7.7.2).
-    let count = payload.u8()? as usize;
-    let addr: SmallVec<[RemoteAddr; 2]> = (0..count)
-        .map(|_| payload.bytes().map(RemoteAddr::from))
-        .collect::<Result<_, _>>()?;
-    payload.skip(count * 3); // repetition mode
-    let classes: SmallVec<[ClassOfDevice; 2]> = (0..count)
-        .map(|_| payload
-            .u24()
-            .map(ClassOfDevice::from))
-        .collect::<Result<_, _>>()?;
-    payload.skip(count * 2); // clock offset
-    payload.finish()?;
-
-    for i in 0..count {
-        debug!("Inquiry result: {} {:?}", addr[i], classes[i]);
-    }
-}
- */
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use instructor::{Buffer, BufferMut, Exstruct, Instruct};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::ensure;
+use crate::hci::consts::{LinkKey, LinkKeyType, RemoteAddr};
+use crate::hci::Error;
+
+/// How well the remote device was authenticated when the link key was created ([Vol 3] Part C, Section 5.2.2.8).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Exstruct, Instruct)]
+#[repr(u8)]
+pub enum AuthenticationLevel {
+    /// Legacy pairing with a PIN code.
+    Legacy = 0x00,
+    /// Simple pairing using just works.
+    Unauthenticated = 0x01,
+    /// Simple pairing with MITM protection.
+    Authenticated = 0x02,
+    /// Secure connections pairing with MITM protection.
+    AuthenticatedSecureConnections = 0x03
+}
+
+impl From<LinkKeyType> for AuthenticationLevel {
+    fn from(key_type: LinkKeyType) -> Self {
+        match key_type {
+            LinkKeyType::Combination | LinkKeyType::DebugCombination | LinkKeyType::ChangedCombination => Self::Legacy,
+            LinkKeyType::UnauthenticatedCombinationP192 | LinkKeyType::UnauthenticatedCombinationP256 => Self::Unauthenticated,
+            LinkKeyType::AuthenticatedCombinationP192 => Self::Authenticated,
+            LinkKeyType::AuthenticatedCombinationP256 => Self::AuthenticatedSecureConnections
+        }
+    }
+}
+
+/// A bond with a remote device.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StoredLinkKey {
+    pub key: LinkKey,
+    pub key_type: LinkKeyType,
+    pub authentication: AuthenticationLevel,
+    pub created: SystemTime,
+    pub last_used: SystemTime
+}
+
+impl StoredLinkKey {
+    pub fn new(key: LinkKey, key_type: LinkKeyType) -> Self {
+        let now = SystemTime::now();
+        Self {
+            key,
+            key_type,
+            authentication: key_type.into(),
+            created: now,
+            last_used: now
+        }
+    }
+}
+
+/// Persists the link keys of bonded devices.
+///
+/// The methods are called from the connection manager and should not block for long.
+/// Pass an `Arc` to [`ConnectionManagerBuilder::with_link_key_store`](crate::hci::connection::ConnectionManagerBuilder::with_link_key_store)
+/// to keep access to the store, e.g. to remove bonds.
+pub trait LinkKeyStore: Send + Sync {
+    fn get(&self, addr: RemoteAddr) -> Option<StoredLinkKey>;
+
+    /// Adds a new bond or replaces an existing one.
+    fn insert(&self, addr: RemoteAddr, key: StoredLinkKey);
+
+    /// Deletes the bond with `addr`. Future connections have to pair again.
+    fn remove(&self, addr: RemoteAddr) -> Option<StoredLinkKey>;
+
+    fn bonded_devices(&self) -> Vec<RemoteAddr>;
+}
+
+impl<T: LinkKeyStore + ?Sized> LinkKeyStore for Arc<T> {
+    fn get(&self, addr: RemoteAddr) -> Option<StoredLinkKey> {
+        (**self).get(addr)
+    }
+
+    fn insert(&self, addr: RemoteAddr, key: StoredLinkKey) {
+        (**self).insert(addr, key)
+    }
+
+    fn remove(&self, addr: RemoteAddr) -> Option<StoredLinkKey> {
+        (**self).remove(addr)
+    }
+
+    fn bonded_devices(&self) -> Vec<RemoteAddr> {
+        (**self).bonded_devices()
+    }
+}
+
+/// Keeps the bonds only for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct MemoryLinkKeyStore {
+    keys: Mutex<BTreeMap<RemoteAddr, StoredLinkKey>>
+}
+
+impl LinkKeyStore for MemoryLinkKeyStore {
+    fn get(&self, addr: RemoteAddr) -> Option<StoredLinkKey> {
+        self.keys.lock().get(&addr).cloned()
+    }
+
+    fn insert(&self, addr: RemoteAddr, key: StoredLinkKey) {
+        self.keys.lock().insert(addr, key);
+    }
+
+    fn remove(&self, addr: RemoteAddr) -> Option<StoredLinkKey> {
+        self.keys.lock().remove(&addr)
+    }
+
+    fn bonded_devices(&self) -> Vec<RemoteAddr> {
+        self.keys.lock().keys().copied().collect()
+    }
+}
+
+/// Keeps the bonds in a file that is replaced on every change.
+///
+/// The file is written by a background thread, so the connection manager never waits for the disk.
+/// Dropping the store waits for the pending writes.
+#[derive(Debug)]
+pub struct FileLinkKeyStore {
+    keys: Mutex<BTreeMap<RemoteAddr, StoredLinkKey>>,
+    writer: Option<Sender<Bytes>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl FileLinkKeyStore {
+    const MAGIC: &'static [u8] = b"BFLK";
+    const VERSION: u8 = 1;
+    /// Updates that only refresh [`StoredLinkKey::last_used`] are stored with this resolution to not rewrite the file on every connection.
+    const LAST_USED_RESOLUTION: Duration = Duration::from_secs(60 * 60);
+
+    /// Loads the bonds from `path`. A missing file is treated as empty.
+    ///
+    /// Files written by older versions, which only contain addresses and keys, are converted on the next change.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = PathBuf::from(path.as_ref());
+        let keys = match std::fs::read(&path) {
+            Ok(data) => Self::parse(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into())
+        };
+        debug!("Loaded {} link keys from {}", keys.len(), path.display());
+        let (writer, snapshots) = channel::<Bytes>();
+        let thread = std::thread::Builder::new()
+            .name(String::from("link-key-writer"))
+            .spawn(move || {
+                while let Ok(mut data) = snapshots.recv() {
+                    // Only the latest state has to end up in the file
+                    while let Ok(newer) = snapshots.try_recv() {
+                        data = newer;
+                    }
+                    write_atomically(&path, &data).unwrap_or_else(|err| warn!("Failed to save link keys: {:?}", err));
+                }
+            })?;
+        Ok(Self {
+            keys: Mutex::new(keys),
+            writer: Some(writer),
+            thread: Some(thread)
+        })
+    }
+
+    fn parse(mut data: &[u8]) -> Result<BTreeMap<RemoteAddr, StoredLinkKey>, instructor::Error> {
+        let mut keys = BTreeMap::new();
+        if let Some(mut data) = data.strip_prefix(Self::MAGIC) {
+            let version: u8 = data.read_le()?;
+            ensure!(version == Self::VERSION, instructor::Error::InvalidValue);
+            while !data.is_empty() {
+                let record: Record = data.read_le()?;
+                keys.insert(record.addr, StoredLinkKey {
+                    key: record.key,
+                    key_type: record.key_type,
+                    authentication: record.authentication,
+                    created: UNIX_EPOCH + Duration::from_secs(record.created),
+                    last_used: UNIX_EPOCH + Duration::from_secs(record.last_used)
+                });
+            }
+        } else {
+            // The unversioned format did not store the key type
+            while !data.is_empty() {
+                let addr: RemoteAddr = data.read_le()?;
+                let key: LinkKey = data.read_le()?;
+                keys.insert(addr, StoredLinkKey::new(key, LinkKeyType::Combination));
+            }
+        }
+        Ok(keys)
+    }
+
+    fn save(&self, keys: &BTreeMap<RemoteAddr, StoredLinkKey>) {
+        let mut data = BytesMut::new();
+        data.extend_from_slice(Self::MAGIC);
+        data.write_le(Self::VERSION);
+        for (addr, key) in keys {
+            data.write_le(Record {
+                addr: *addr,
+                key: key.key,
+                key_type: key.key_type,
+                authentication: key.authentication,
+                created: unix_seconds(key.created),
+                last_used: unix_seconds(key.last_used)
+            });
+        }
+        // Sending while holding the lock of the keys keeps the snapshots in order
+        if let Some(writer) = &self.writer {
+            writer
+                .send(data.freeze())
+                .unwrap_or_else(|_| warn!("Link key writer has stopped"));
+        }
+    }
+}
+
+impl Drop for FileLinkKeyStore {
+    fn drop(&mut self) {
+        self.writer = None;
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .unwrap_or_else(|_| warn!("Link key writer panicked"));
+        }
+    }
+}
+
+impl LinkKeyStore for FileLinkKeyStore {
+    fn get(&self, addr: RemoteAddr) -> Option<StoredLinkKey> {
+        self.keys.lock().get(&addr).cloned()
+    }
+
+    fn insert(&self, addr: RemoteAddr, key: StoredLinkKey) {
+        let mut keys = self.keys.lock();
+        let recently_used = keys.get(&addr).is_some_and(|old| {
+            let unchanged = *old == StoredLinkKey { last_used: old.last_used, ..key.clone() };
+            unchanged && key.last_used < old.last_used + Self::LAST_USED_RESOLUTION
+        });
+        if recently_used {
+            return;
+        }
+        keys.insert(addr, key);
+        self.save(&keys);
+    }
+
+    fn remove(&self, addr: RemoteAddr) -> Option<StoredLinkKey> {
+        let mut keys = self.keys.lock();
+        let key = keys.remove(&addr);
+        if key.is_some() {
+            self.save(&keys);
+        }
+        key
+    }
+
+    fn bonded_devices(&self) -> Vec<RemoteAddr> {
+        self.keys.lock().keys().copied().collect()
+    }
+}
+
+#[derive(Exstruct, Instruct)]
+struct Record {
+    addr: RemoteAddr,
+    key: LinkKey,
+    key_type: LinkKeyType,
+    authentication: AuthenticationLevel,
+    created: u64,
+    last_used: u64
+}
+
+/// Writes to a temporary file next to `path` first, so a crash can't leave a truncated file behind.
+fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::hci::consts::{LinkKey, LinkKeyType, RemoteAddr};
+    use crate::hci::link_keys::{AuthenticationLevel, FileLinkKeyStore, LinkKeyStore, StoredLinkKey};
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("bluefang-link-keys-{}.dat", std::process::id()));
+        let addr = RemoteAddr::from([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let key: LinkKey = "00112233445566778899AABBCCDDEEFF".parse().unwrap();
+
+        // Unversioned files only contain the address and the key
+        std::fs::write(&path, [addr.as_ref(), &[0x11; 16][..]].concat()).unwrap();
+        let store = FileLinkKeyStore::open(&path).unwrap();
+        let legacy = store.get(addr).unwrap();
+        assert_eq!(legacy.key_type, LinkKeyType::Combination);
+        assert_eq!(legacy.authentication, AuthenticationLevel::Legacy);
+
+        let mut stored = StoredLinkKey::new(key, LinkKeyType::AuthenticatedCombinationP256);
+        stored.created = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        stored.last_used = UNIX_EPOCH + Duration::from_secs(1_700_000_100);
+        store.insert(addr, stored.clone());
+        drop(store);
+
+        let store = FileLinkKeyStore::open(&path).unwrap();
+        assert_eq!(store.bonded_devices(), vec![addr]);
+        assert_eq!(store.get(addr), Some(stored.clone()));
+        assert_eq!(stored.authentication, AuthenticationLevel::AuthenticatedSecureConnections);
+
+        // Using the key again only gets stored once the last use is notably newer
+        let mut used = stored.clone();
+        used.last_used += Duration::from_secs(60);
+        store.insert(addr, used.clone());
+        assert_eq!(store.get(addr), Some(stored.clone()));
+        used.last_used += Duration::from_secs(2 * 60 * 60);
+        store.insert(addr, used.clone());
+        drop(store);
+        let store = FileLinkKeyStore::open(&path).unwrap();
+        assert_eq!(store.get(addr), Some(used));
+
+        assert!(store.remove(addr).is_some());
+        drop(store);
+        assert!(FileLinkKeyStore::open(&path).unwrap().get(addr).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
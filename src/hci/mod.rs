@@ -5,47 +5,53 @@ mod error;
 pub mod acl;
 pub mod btsnoop;
 pub mod connection;
+pub mod discovery;
+pub mod eir;
 mod event_loop;
+pub mod link_keys;
+pub mod pairing;
+pub mod security;
 
 use std::collections::BTreeSet;
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
+use std::io::ErrorKind;
 use std::pin::Pin;
 use std::sync::OnceLock;
 use std::time::Duration;
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 pub use commands::*;
-use instructor::utils::Length;
 use instructor::{Buffer, BufferMut, Exstruct, LittleEndian};
 use nusb::transfer::TransferError;
 use parking_lot::Mutex;
-use tokio::spawn;
+use tokio::{spawn, try_join};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender as MpscSender};
+use tokio::sync::OwnedSemaphorePermit;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tracing::{debug, error};
 
-use crate::hci::acl::{AclHeader, BoundaryFlag, BroadcastFlag};
+use crate::hci::acl::AclPacket;
 use crate::hci::consts::{EventCode, EventMask, Status};
-use crate::hci::event_loop::{CmdResultSender, EventLoopCommand};
-use crate::host::usb::UsbHost;
+use crate::hci::event_loop::{CommandRequest, EventLoopCommand};
+use crate::host::Transport;
 use crate::utils::Loggable;
 
-//TODO make generic over transport
+/// The default time the controller gets to respond to a command.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub struct Hci {
-    //transport: UsbHost,
     //router: Arc<EventRouter>,
-    cmd_out: MpscSender<(Opcode, Bytes, CmdResultSender)>,
-    acl_out: MpscSender<Bytes>,
+    cmd_out: MpscSender<CommandRequest>,
+    acl_out: MpscSender<AclPacket>,
     ctl_out: MpscSender<EventLoopCommand>,
-    acl_size: usize,
     event_loop: Mutex<Option<JoinHandle<()>>>,
     version: LocalVersion
 }
 
 impl Hci {
-    pub async fn new(transport: UsbHost) -> Result<Self, Error> {
+    pub async fn new<T: Transport>(transport: T) -> Result<Self, Error> {
         let (acl_out, acl_in) = unbounded_channel();
         let (cmd_out, cmd_in) = unbounded_channel();
         let (ctl_out, ctl_in) = unbounded_channel();
@@ -54,7 +60,6 @@ impl Hci {
             cmd_out,
             acl_out,
             ctl_out,
-            acl_size: 0,
             event_loop: Mutex::new(Some(event_loop)),
             version: Default::default(),
         };
@@ -66,22 +71,26 @@ impl Hci {
 
         Self::try_load_firmware(&hci).await;
 
-        hci.version = hci.read_local_version().await?;
+        // These don't depend on each other, so they can be pipelined if the controller allows it
+        let (version, buffer_size, _) = try_join!(hci.read_local_version(), hci.read_buffer_size(), hci.set_event_mask(EventMask::all()))?;
+        hci.version = version;
         debug!("HCI version: {:?}", hci.version);
 
         //debug!("{:?}", hci.read_local_supported_commands().await?);
 
-        hci.set_event_mask(EventMask::all()).await?;
-
-        let buffer_size = hci.read_buffer_size().await?;
-        hci.acl_size = buffer_size.acl_data_packet_length as usize;
         hci.ctl_out
-            .send(EventLoopCommand::SetMaxInFlightAclPackets(buffer_size.total_num_acl_data_packets as u32))
+            .send(EventLoopCommand::SetAclBufferSize {
+                packet_length: buffer_size.acl_data_packet_length as usize,
+                packet_count: buffer_size.total_num_acl_data_packets as u32
+            })
             .map_err(|_| Error::EventLoopClosed)?;
 
         Ok(hci)
     }
 
+    /// Forwards all events of the given types to `handler`.
+    ///
+    /// The channel gets closed once the HCI is gone, e.g. because the controller stopped responding.
     pub fn register_event_handler(&self, events: impl Into<BTreeSet<EventCode>>, handler: MpscSender<(EventCode, Bytes)>) -> Result<(), Error> {
         let events = events.into();
         debug_assert!(!events.is_empty());
@@ -100,8 +109,7 @@ impl Hci {
 
     pub fn get_acl_sender(&self) -> AclSender {
         AclSender {
-            sender: self.acl_out.clone(),
-            max_size: self.acl_size
+            sender: self.acl_out.clone()
         }
     }
 
@@ -110,6 +118,15 @@ impl Hci {
     }
 
     pub async fn call_with_args<T: Exstruct<LittleEndian>>(&self, cmd: Opcode, packer: impl FnOnce(&mut BytesMut)) -> Result<T, Error> {
+        self.call_with_timeout(cmd, COMMAND_TIMEOUT, packer).await
+    }
+
+    /// Sends a command and waits for its `HCI_Command_Complete` or `HCI_Command_Status` event.
+    ///
+    /// A controller that fails to respond within `timeout` is considered dead and the HCI gets closed.
+    pub async fn call_with_timeout<T: Exstruct<LittleEndian>>(
+        &self, cmd: Opcode, timeout: Duration, packer: impl FnOnce(&mut BytesMut)
+    ) -> Result<T, Error> {
         // TODO: check if the command is supported
         let mut buf = BytesMut::with_capacity(255);
         buf.write::<u16, LittleEndian>(cmd.into());
@@ -120,9 +137,13 @@ impl Hci {
 
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.cmd_out
-            .send((cmd, buf.freeze(), tx))
+            .send(CommandRequest {
+                opcode: cmd,
+                packet: buf.freeze(),
+                timeout,
+                result: tx
+            })
             .map_err(|_| Error::EventLoopClosed)?;
-        //TODO: 1s timeout
         let mut resp = rx.await.map_err(|_| Error::EventLoopClosed)??;
         let status: Status = resp.read_le()?;
         match status {
@@ -135,6 +156,36 @@ impl Hci {
         }
     }
 
+    /// Sends a command that finishes asynchronously and waits for its completion event.
+    ///
+    /// Every received event of the given types is passed to `matcher` until it returns a result.
+    /// `matcher` should return `Ok(None)` for events that belong to a different command.
+    pub async fn call_and_wait<R>(
+        &self, cmd: Opcode, events: impl Into<BTreeSet<EventCode>>, packer: impl FnOnce(&mut BytesMut),
+        mut matcher: impl FnMut(EventCode, Bytes) -> Result<Option<R>, Error>
+    ) -> Result<R, Error> {
+        // The handler has to be registered before sending the command to not miss the event
+        let (tx, mut rx) = unbounded_channel();
+        self.register_event_handler(events, tx)?;
+        self.call_with_args::<()>(cmd, packer).await?;
+        while let Some((code, packet)) = rx.recv().await {
+            if let Some(result) = matcher(code, packet)? {
+                return Ok(result);
+            }
+        }
+        Err(Error::EventLoopClosed)
+    }
+
+    /// Returns `true` if the event loop has terminated, either because of [`Hci::shutdown`] or because the controller is gone.
+    pub fn is_closed(&self) -> bool {
+        self.cmd_out.is_closed()
+    }
+
+    /// Waits until the event loop has terminated.
+    pub async fn closed(&self) {
+        self.cmd_out.closed().await
+    }
+
     pub async fn shutdown(&self) -> Result<(), Error> {
         let handle = self.event_loop.lock().take();
         if let Some(event_loop) = handle {
@@ -174,31 +225,27 @@ impl Loggable for AclSendError {
     }
 }
 
+/// Queues L2CAP PDUs for the event loop, which fragments them and shares the controller's buffers fairly between links.
 #[derive(Clone)]
 pub struct AclSender {
-    sender: MpscSender<Bytes>,
-    max_size: usize
+    sender: MpscSender<AclPacket>
 }
 
 impl AclSender {
     pub fn send(&self, handle: u16, pdu: Bytes) -> Result<(), AclSendError> {
-        //trace!("Sending ACL data to handle 0x{:04X}", handle);
-        let mut buffer = BytesMut::with_capacity(512);
-        let mut pb = BoundaryFlag::FirstNonAutomaticallyFlushable;
-        for chunk in pdu.chunks(self.max_size) {
-            buffer.write(AclHeader {
-                handle,
-                pb,
-                bc: BroadcastFlag::PointToPoint,
-                length: Length::new(chunk.len())?
-            });
-            buffer.put(chunk);
-            self.sender
-                .send(buffer.split().freeze())
-                .map_err(|_| AclSendError::EventLoopClosed)?;
-            pb = BoundaryFlag::Continuing;
-        }
-        Ok(())
+        self.send_packet(AclPacket { handle, pdu, permit: None })
+    }
+
+    /// Like [`send`](Self::send), but holds `permit` until the PDU was handed to the controller.
+    pub fn send_with_permit(&self, handle: u16, pdu: Bytes, permit: OwnedSemaphorePermit) -> Result<(), AclSendError> {
+        self.send_packet(AclPacket { handle, pdu, permit: Some(permit) })
+    }
+
+    fn send_packet(&self, packet: AclPacket) -> Result<(), AclSendError> {
+        //trace!("Sending ACL data to handle 0x{:04X}", packet.handle);
+        self.sender
+            .send(packet)
+            .map_err(|_| AclSendError::EventLoopClosed)
     }
 }
 
@@ -225,6 +272,8 @@ pub enum Error {
     EventLoopClosed,
     #[error("Unknown HCI Event code: 0x{0:02X}")]
     UnknownEventCode(u8),
+    #[error("The controller did not respond to {0:?} in time")]
+    CommandTimeout(Opcode),
     #[error("Unexpected HCI Command Response for {0:?}")]
     UnexpectedCommandResponse(Opcode),
     #[error("Unknown connection handle: 0x{0:02X}")]
@@ -238,7 +287,18 @@ pub enum Error {
 impl Error {
     pub fn is_timeout(&self) -> bool {
         match self {
-            Error::TransportError(err) => err.kind() == std::io::ErrorKind::TimedOut,
+            Error::TransportError(err) => err.kind() == ErrorKind::TimedOut,
+            Error::CommandTimeout(_) => true,
+            _ => false
+        }
+    }
+
+    /// Returns whether the error indicates that the controller can no longer be used.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::TransportError(err) => !matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut),
+            Error::TransferError(err) => matches!(err, TransferError::Disconnected),
+            Error::CommandTimeout(_) | Error::EventLoopClosed => true,
             _ => false
         }
     }
@@ -0,0 +1,89 @@
+use std::future::{ready, Future};
+use std::pin::Pin;
+
+use crate::hci::consts::{AuthenticationRequirements, ClassOfDevice, IoCapability, RemoteAddr, Role, Status};
+
+pub type AgentFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConnectionDecision {
+    Accept(Role),
+    /// Must be one of the reasons allowed by [`Hci::reject_connection_request`](crate::hci::Hci::reject_connection_request).
+    Reject(Status)
+}
+
+/// Makes the decisions during connection setup and pairing that need the user or the application.
+///
+/// The callbacks run in their own task, so they are free to wait for user input.
+/// The controller only waits a limited amount of time for the answer though, after which the pairing fails.
+///
+/// The defaults reject everything that requires user input and accept the rest.
+pub trait PairingAgent: Send + Sync {
+    /// The input and output capabilities of this device. Together with the capabilities of the remote device
+    /// these decide which association model simple pairing uses ([Vol 3] Part C, Section 5.2.2.6).
+    fn io_capability(&self) -> IoCapability;
+
+    fn authentication_requirements(&self) -> AuthenticationRequirements {
+        AuthenticationRequirements::DedicatedBondingProtected
+    }
+
+    /// Decides whether an incoming connection is accepted and which role this device takes.
+    fn connection_request(&self, _addr: RemoteAddr, _class: ClassOfDevice) -> AgentFuture<'_, ConnectionDecision> {
+        Box::pin(ready(ConnectionDecision::Accept(Role::Slave)))
+    }
+
+    /// Legacy pairing. Returning `None` rejects the pairing.
+    fn pin_code(&self, _addr: RemoteAddr) -> AgentFuture<'_, Option<String>> {
+        Box::pin(ready(None))
+    }
+
+    /// Numeric comparison: both devices display `passkey` and the user confirms that they match.
+    fn confirm_passkey(&self, _addr: RemoteAddr, _passkey: u32) -> AgentFuture<'_, bool> {
+        Box::pin(ready(false))
+    }
+
+    /// Passkey entry: the user types the passkey shown on the remote device. Returning `None` rejects the pairing.
+    fn enter_passkey(&self, _addr: RemoteAddr) -> AgentFuture<'_, Option<u32>> {
+        Box::pin(ready(None))
+    }
+
+    /// Passkey entry: the passkey has to be shown to the user so they can type it on the remote device.
+    fn display_passkey(&self, _addr: RemoteAddr, _passkey: u32) {}
+
+    /// Just works: there is no way to authenticate the remote device, the pairing can only be allowed or denied.
+    fn authorize(&self, _addr: RemoteAddr) -> AgentFuture<'_, bool> {
+        Box::pin(ready(true))
+    }
+}
+
+/// An agent for devices without any user interface, like a speaker.
+///
+/// Accepts all connections and pairings. Legacy pairing uses a fixed PIN.
+#[derive(Debug, Clone)]
+pub struct HeadlessAgent {
+    pin: String
+}
+
+impl Default for HeadlessAgent {
+    fn default() -> Self {
+        Self { pin: String::from("0000") }
+    }
+}
+
+impl HeadlessAgent {
+    pub fn with_pin_code(mut self, pin: &str) -> Self {
+        assert!((1..=16).contains(&pin.len()), "PIN codes are 1 to 16 bytes long");
+        self.pin = pin.to_string();
+        self
+    }
+}
+
+impl PairingAgent for HeadlessAgent {
+    fn io_capability(&self) -> IoCapability {
+        IoCapability::NoInputNoOutput
+    }
+
+    fn pin_code(&self, _addr: RemoteAddr) -> AgentFuture<'_, Option<String>> {
+        Box::pin(ready(Some(self.pin.clone())))
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use crate::hci::consts::{AuthenticationRequirements, EncryptionMode};
+use crate::hci::link_keys::AuthenticationLevel;
+
+/// The smallest encryption key size allowed by the specification ([Vol 2] Part H, Section 4.1).
+pub const MIN_ENCRYPTION_KEY_SIZE: u8 = 7;
+pub const MAX_ENCRYPTION_KEY_SIZE: u8 = 16;
+
+/// The protection a link offers, ordered from weakest to strongest ([Vol 3] Part C, Section 5.2.2.8).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SecurityLevel {
+    #[default]
+    None,
+    /// Encrypted with any link key, including unauthenticated and legacy ones.
+    Encrypted,
+    /// Encrypted with a key that was created with MITM protection.
+    Authenticated,
+    /// Encrypted with AES-CCM and a key created by secure connections pairing with MITM protection.
+    SecureConnections
+}
+
+impl SecurityLevel {
+    /// The level of a link encrypted with a key of `key_size` bytes. Links with an unknown key size count as unencrypted.
+    pub fn new(encryption: EncryptionMode, key_size: Option<u8>, authentication: Option<AuthenticationLevel>) -> Self {
+        let Some(key_size) = key_size.filter(|&size| size >= MIN_ENCRYPTION_KEY_SIZE) else {
+            return Self::None;
+        };
+        match (encryption, authentication) {
+            (EncryptionMode::Off, _) => Self::None,
+            (EncryptionMode::AesCcm, Some(AuthenticationLevel::AuthenticatedSecureConnections)) if key_size == MAX_ENCRYPTION_KEY_SIZE => {
+                Self::SecureConnections
+            }
+            (_, Some(AuthenticationLevel::Authenticated | AuthenticationLevel::AuthenticatedSecureConnections)) => Self::Authenticated,
+            _ => Self::Encrypted
+        }
+    }
+}
+
+/// The minimum security the [`ConnectionManager`](crate::hci::connection::ConnectionManager) enforces on all links.
+///
+/// Links that fall short of the policy are disconnected, link keys that do not meet it are neither stored nor used.
+#[derive(Debug, Clone)]
+pub struct SecurityPolicy {
+    simple_pairing_required: bool,
+    secure_connections_only: bool,
+    min_key_size: u8,
+    services: BTreeMap<u64, SecurityLevel>
+}
+
+impl Default for SecurityPolicy {
+    fn default() -> Self {
+        Self {
+            simple_pairing_required: false,
+            secure_connections_only: false,
+            min_key_size: MIN_ENCRYPTION_KEY_SIZE,
+            services: BTreeMap::new()
+        }
+    }
+}
+
+impl SecurityPolicy {
+    /// Rejects legacy pairing with PIN codes and ignores bonds created by it.
+    pub fn with_simple_pairing_required(mut self, required: bool) -> Self {
+        self.simple_pairing_required = required;
+        self
+    }
+
+    /// Only accepts links secured by secure connections pairing with MITM protection,
+    /// AES-CCM encryption and 128 bit keys ([Vol 3] Part C, Section 5.2.2.8).
+    ///
+    /// Pairing requires a [`PairingAgent`](crate::hci::pairing::PairingAgent) that can authenticate the remote device,
+    /// so this does not work with the [`HeadlessAgent`](crate::hci::pairing::HeadlessAgent).
+    pub fn with_secure_connections_only(mut self, enabled: bool) -> Self {
+        self.secure_connections_only = enabled;
+        self
+    }
+
+    /// Disconnects links that are encrypted with a shorter key and refuses secured channels on them. Range: 7-16 bytes.
+    pub fn with_min_key_size(mut self, size: u8) -> Self {
+        assert!((MIN_ENCRYPTION_KEY_SIZE..=MAX_ENCRYPTION_KEY_SIZE).contains(&size), "Invalid key size");
+        self.min_key_size = size;
+        self
+    }
+
+    /// Refuses L2CAP channels to `psm` on links that do not reach `level`.
+    pub fn with_service_requirement(mut self, psm: impl Into<u64>, level: SecurityLevel) -> Self {
+        self.services.insert(psm.into(), level);
+        self
+    }
+
+    pub fn simple_pairing_required(&self) -> bool {
+        self.simple_pairing_required || self.secure_connections_only
+    }
+
+    pub fn secure_connections_only(&self) -> bool {
+        self.secure_connections_only
+    }
+
+    pub fn min_key_size(&self) -> u8 {
+        match self.secure_connections_only {
+            true => MAX_ENCRYPTION_KEY_SIZE,
+            false => self.min_key_size
+        }
+    }
+
+    /// The level a link needs before a channel to `psm` is accepted.
+    pub fn service_requirement(&self, psm: u64) -> SecurityLevel {
+        self.services
+            .get(&psm)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Whether a link key of the given strength may be stored and used.
+    pub fn accepts_key(&self, authentication: AuthenticationLevel) -> bool {
+        match authentication {
+            AuthenticationLevel::Legacy => !self.simple_pairing_required(),
+            AuthenticationLevel::AuthenticatedSecureConnections => true,
+            _ => !self.secure_connections_only
+        }
+    }
+
+    /// Whether an encrypted link satisfies the policy.
+    pub fn accepts_encryption(&self, mode: EncryptionMode, key_size: u8) -> bool {
+        let mode_ok = !self.secure_connections_only || mode == EncryptionMode::AesCcm;
+        mode_ok && key_size >= self.min_key_size()
+    }
+
+    /// Adjusts the requirements sent during simple pairing so the resulting key satisfies the policy.
+    pub fn authentication_requirements(&self, requested: AuthenticationRequirements) -> AuthenticationRequirements {
+        match self.secure_connections_only {
+            true => requested.with_mitm_protection(),
+            false => requested
+        }
+    }
+}
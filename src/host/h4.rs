@@ -0,0 +1,165 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::hci::Error;
+use crate::host::{IncomingPacket, Transport};
+use crate::utils::SliceExt;
+
+/// HCI packet indicators ([Vol 4] Part A, Section 2).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum PacketIndicator {
+    Command = 0x01,
+    AclData = 0x02,
+    SynchronousData = 0x03,
+    Event = 0x04,
+    IsoData = 0x05
+}
+
+impl PacketIndicator {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::Command),
+            0x02 => Some(Self::AclData),
+            0x03 => Some(Self::SynchronousData),
+            0x04 => Some(Self::Event),
+            0x05 => Some(Self::IsoData),
+            _ => None
+        }
+    }
+
+    /// Returns the size of the packet header and the size of the packet payload if enough data is available.
+    fn packet_size(self, header: &[u8]) -> Option<(usize, usize)> {
+        // ([Vol 4] Part E, Section 5.4).
+        match self {
+            Self::Command => header.get(2).map(|&len| (3, len as usize)),
+            Self::AclData => header.get_chunk(2).map(|&len| (4, u16::from_le_bytes(len) as usize)),
+            Self::SynchronousData => header.get(2).map(|&len| (3, len as usize)),
+            Self::Event => header.get(1).map(|&len| (2, len as usize)),
+            Self::IsoData => header.get_chunk(2).map(|&len| (4, (u16::from_le_bytes(len) & 0x3FFF) as usize))
+        }
+    }
+}
+
+/// UART transport layer ([Vol 4] Part A).
+///
+/// Works on top of any byte stream. The serial port itself (baud rate, flow control) has to be configured beforehand.
+pub struct H4Transport<S> {
+    stream: S,
+    buffer: BytesMut
+}
+
+impl H4Transport<File> {
+    /// Opens a serial device (like `/dev/ttyUSB0`) for reading and writing.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path).await?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> H4Transport<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::with_capacity(4096)
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    async fn send_packet(&mut self, indicator: PacketIndicator, data: Bytes) -> Result<(), Error> {
+        let mut packet = BytesMut::with_capacity(data.len() + 1);
+        packet.put_u8(indicator as u8);
+        packet.put(data);
+        self.stream.write_all(&packet).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Tries to split the next complete packet from the receive buffer.
+    fn next_packet(&mut self) -> Option<(PacketIndicator, Bytes)> {
+        loop {
+            let &indicator = self.buffer.first()?;
+            let Some(indicator) = PacketIndicator::from_u8(indicator) else {
+                warn!("Invalid H4 packet indicator: 0x{:02X}", indicator);
+                self.buffer.advance(1);
+                continue;
+            };
+            let (header, payload) = indicator.packet_size(&self.buffer[1..])?;
+            if self.buffer.len() < 1 + header + payload {
+                return None;
+            }
+            self.buffer.advance(1);
+            return Some((indicator, self.buffer.split_to(header + payload).freeze()));
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for H4Transport<S> {
+    async fn send_command(&mut self, data: Bytes) -> Result<(), Error> {
+        self.send_packet(PacketIndicator::Command, data).await
+    }
+
+    async fn send_acl_data(&mut self, data: Bytes) -> Result<(), Error> {
+        self.send_packet(PacketIndicator::AclData, data).await
+    }
+
+    async fn receive(&mut self) -> Result<IncomingPacket, Error> {
+        loop {
+            while let Some((indicator, packet)) = self.next_packet() {
+                match indicator {
+                    PacketIndicator::Event => return Ok(IncomingPacket::Event(packet)),
+                    PacketIndicator::AclData => return Ok(IncomingPacket::AclData(packet)),
+                    other => warn!("Ignoring unsupported H4 packet: {:?}", other)
+                }
+            }
+            // `read_buf` is cancel safe, so no data gets lost when this future is dropped
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::host::h4::H4Transport;
+    use crate::host::{IncomingPacket, Transport};
+
+    #[tokio::test]
+    async fn test_reassembly() {
+        let (local, mut remote) = duplex(64);
+        let mut transport = H4Transport::new(local);
+
+        // Command Complete for HCI_Reset split across two writes, followed by an ACL packet
+        remote.write_all(&[0x04, 0x0E, 0x04, 0x01]).await.unwrap();
+        remote.write_all(&[0x03, 0x0C, 0x00, 0x02, 0x01, 0x20, 0x02, 0x00, 0xAA, 0xBB]).await.unwrap();
+
+        assert_eq!(
+            transport.receive().await.unwrap(),
+            IncomingPacket::Event(Bytes::from_static(&[0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00]))
+        );
+        assert_eq!(
+            transport.receive().await.unwrap(),
+            IncomingPacket::AclData(Bytes::from_static(&[0x01, 0x20, 0x02, 0x00, 0xAA, 0xBB]))
+        );
+
+        transport.send_command(Bytes::from_static(&[0x03, 0x0C, 0x00])).await.unwrap();
+        let mut buffer = [0u8; 4];
+        remote.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [0x01, 0x03, 0x0C, 0x00]);
+
+        drop(remote);
+        assert!(transport.receive().await.is_err());
+    }
+}
@@ -1 +1,34 @@
+pub mod h4;
 pub mod usb;
+#[cfg(target_os = "linux")]
+pub mod user_channel;
+pub mod virtual_controller;
+
+use std::future::Future;
+
+use bytes::Bytes;
+
+use crate::hci::Error;
+
+/// A packet received from the controller.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum IncomingPacket {
+    Event(Bytes),
+    AclData(Bytes)
+}
+
+/// The physical link between the host stack and the controller ([Vol 4] Part A).
+///
+/// All packets are passed without any transport specific framing, i.e. starting with the HCI packet header.
+pub trait Transport: Send + 'static {
+    /// Sends an HCI command packet to the controller.
+    fn send_command(&mut self, data: Bytes) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Sends an HCI ACL data packet to the controller.
+    fn send_acl_data(&mut self, data: Bytes) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Waits for the next event or ACL data packet from the controller.
+    ///
+    /// The returned future must be cancel safe as it is used inside a `select!` loop.
+    fn receive(&mut self) -> impl Future<Output = Result<IncomingPacket, Error>> + Send;
+}
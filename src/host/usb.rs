@@ -1,13 +1,22 @@
 use std::fmt::Debug;
+
+use bytes::Bytes;
 use nusb::descriptors::InterfaceAltSetting;
 use nusb::transfer::Direction::{In, Out};
 use nusb::transfer::EndpointType::{Bulk, Interrupt};
+use nusb::transfer::{ControlOut, ControlType, Queue, Recipient, RequestBuffer};
 use nusb::{Device, DeviceInfo, Error, Interface};
-use tracing::{debug, warn};
+use tokio::select;
+use tracing::{debug, error, warn};
 
 use crate::ensure;
+use crate::hci::Error as HciError;
+use crate::host::{IncomingPacket, Transport};
 use crate::utils::IteratorExt;
 
+const TRANSFER_BUFFER_SIZE: usize = 4096;
+const TRANSFER_BUFFER_COUNT: usize = 4;
+
 pub struct UsbController {
     device: Device,
     endpoints: Endpoints
@@ -31,10 +40,20 @@ impl UsbController {
         let interface = self
             .device
             .detach_and_claim_interface(self.endpoints.main_iface)?;
+        let mut events = interface.interrupt_in_queue(self.endpoints.event);
+        let mut acl_in = interface.bulk_in_queue(self.endpoints.acl_in);
+        for _ in 0..TRANSFER_BUFFER_COUNT {
+            events.submit(RequestBuffer::new(TRANSFER_BUFFER_SIZE));
+            acl_in.submit(RequestBuffer::new(TRANSFER_BUFFER_SIZE));
+        }
+        let acl_out = interface.bulk_out_queue(self.endpoints.acl_out);
         Ok(UsbHost {
             device: self.device,
             endpoints: self.endpoints,
-            interface
+            interface,
+            events,
+            acl_in,
+            acl_out
         })
     }
 }
@@ -42,7 +61,55 @@ impl UsbController {
 pub struct UsbHost {
     pub device: Device,
     pub endpoints: Endpoints,
-    pub interface: Interface
+    pub interface: Interface,
+    events: Queue<RequestBuffer>,
+    acl_in: Queue<RequestBuffer>,
+    acl_out: Queue<Vec<u8>>
+}
+
+// ([Vol 4] Part B, Section 2.1).
+impl Transport for UsbHost {
+    async fn send_command(&mut self, data: Bytes) -> Result<(), HciError> {
+        self.interface
+            .control_out(ControlOut {
+                control_type: ControlType::Class,
+                recipient: Recipient::Device,
+                request: 0x00,
+                value: 0x00,
+                index: self.endpoints.main_iface.into(),
+                data: &data
+            })
+            .await
+            .status?;
+        Ok(())
+    }
+
+    async fn send_acl_data(&mut self, data: Bytes) -> Result<(), HciError> {
+        self.acl_out.submit(data.to_vec());
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<IncomingPacket, HciError> {
+        loop {
+            select! {
+                event = self.events.next_complete() => {
+                    let result = event.status.map(|_| Bytes::copy_from_slice(&event.data));
+                    self.events.submit(RequestBuffer::reuse(event.data, TRANSFER_BUFFER_SIZE));
+                    return Ok(IncomingPacket::Event(result?));
+                },
+                data = self.acl_in.next_complete() => {
+                    let result = data.status.map(|_| Bytes::copy_from_slice(&data.data));
+                    self.acl_in.submit(RequestBuffer::reuse(data.data, TRANSFER_BUFFER_SIZE));
+                    return Ok(IncomingPacket::AclData(result?));
+                },
+                completion = self.acl_out.next_complete(), if self.acl_out.pending() > 0 => {
+                    completion
+                        .status
+                        .unwrap_or_else(|err| error!("Error writing ACL data: {:?}", err));
+                }
+            }
+        }
+    }
 }
 
 /// USB addresses for Bluetooth interfaces and endpoints ([Vol 4] Part B, Section 2.1.1).
@@ -0,0 +1,166 @@
+use std::io::{Error as IoError, ErrorKind};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, warn};
+
+use crate::hci::Error;
+use crate::host::h4::PacketIndicator;
+use crate::host::{IncomingPacket, Transport};
+
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_CHANNEL_USER: u16 = 1;
+
+// Indicator + ACL header + maximum ACL payload
+const MAX_PACKET_SIZE: usize = 1 + 4 + u16::MAX as usize;
+
+#[repr(C)]
+struct SockAddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16
+}
+
+/// Controller access through a Linux `HCI_CHANNEL_USER` socket.
+///
+/// The kernel keeps its driver attached but hands exclusive control of the controller to this socket.
+/// The device must be down (`hciconfig hciX down`) and the process needs `CAP_NET_ADMIN`.
+/// All packets are framed with the UART packet indicator ([Vol 4] Part A, Section 2).
+pub struct UserChannel {
+    fd: AsyncFd<OwnedFd>,
+    buffer: Box<[u8]>
+}
+
+impl UserChannel {
+    /// Opens `hci<dev_id>` as a user channel.
+    pub fn open(dev_id: u16) -> Result<Self, Error> {
+        let fd = cvt(unsafe { libc::socket(AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK, BTPROTO_HCI) })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let addr = SockAddrHci {
+            hci_family: AF_BLUETOOTH as libc::sa_family_t,
+            hci_dev: dev_id,
+            hci_channel: HCI_CHANNEL_USER
+        };
+        cvt(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const SockAddrHci as *const libc::sockaddr,
+                size_of::<SockAddrHci>() as libc::socklen_t
+            )
+        })?;
+        debug!("Opened user channel for hci{}", dev_id);
+        Self::from_fd(fd)
+    }
+
+    /// Creates two connected sockets that behave like a user channel.
+    ///
+    /// Useful for running the stack against a fake controller: one end is passed to [`Hci::new`](crate::hci::Hci::new),
+    /// the other one is driven manually using [`send_packet`](Self::send_packet) and [`receive_packet`](Self::receive_packet).
+    pub fn pair() -> Result<(Self, Self), Error> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK, 0, fds.as_mut_ptr()) })?;
+        let (a, b) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok((Self::from_fd(a)?, Self::from_fd(b)?))
+    }
+
+    fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            buffer: vec![0u8; MAX_PACKET_SIZE].into_boxed_slice()
+        })
+    }
+
+    pub async fn send_packet(&self, indicator: PacketIndicator, data: &[u8]) -> Result<(), Error> {
+        let mut packet = BytesMut::with_capacity(data.len() + 1);
+        packet.put_u8(indicator as u8);
+        packet.put_slice(data);
+        loop {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| cvt(unsafe { libc::send(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len(), libc::MSG_NOSIGNAL) })) {
+                Ok(result) => return Ok(result.map(|_| ())?),
+                Err(_would_block) => continue
+            }
+        }
+    }
+
+    /// Waits for the next packet. This function is cancel safe.
+    pub async fn receive_packet(&mut self) -> Result<(PacketIndicator, Bytes), Error> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let buffer = &mut self.buffer;
+            let len = match guard.try_io(|fd| cvt(unsafe { libc::recv(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) })) {
+                Ok(result) => result?,
+                Err(_would_block) => continue
+            };
+            if len == 0 {
+                return Err(IoError::from(ErrorKind::UnexpectedEof).into());
+            }
+            let packet = &self.buffer[..len as usize];
+            match PacketIndicator::from_u8(packet[0]) {
+                Some(indicator) => return Ok((indicator, Bytes::copy_from_slice(&packet[1..]))),
+                None => warn!("Invalid packet indicator: 0x{:02X}", packet[0])
+            }
+        }
+    }
+}
+
+impl Transport for UserChannel {
+    async fn send_command(&mut self, data: Bytes) -> Result<(), Error> {
+        self.send_packet(PacketIndicator::Command, &data).await
+    }
+
+    async fn send_acl_data(&mut self, data: Bytes) -> Result<(), Error> {
+        self.send_packet(PacketIndicator::AclData, &data).await
+    }
+
+    async fn receive(&mut self) -> Result<IncomingPacket, Error> {
+        loop {
+            match self.receive_packet().await? {
+                (PacketIndicator::Event, packet) => return Ok(IncomingPacket::Event(packet)),
+                (PacketIndicator::AclData, packet) => return Ok(IncomingPacket::AclData(packet)),
+                (other, _) => warn!("Ignoring unsupported packet: {:?}", other)
+            }
+        }
+    }
+}
+
+fn cvt<T: Default + PartialOrd>(result: T) -> Result<T, IoError> {
+    match result < T::default() {
+        true => Err(IoError::last_os_error()),
+        false => Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::host::h4::PacketIndicator;
+    use crate::host::user_channel::UserChannel;
+    use crate::host::{IncomingPacket, Transport};
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let (mut host, mut controller) = UserChannel::pair().unwrap();
+
+        host.send_command(Bytes::from_static(&[0x03, 0x0C, 0x00])).await.unwrap();
+        let (indicator, packet) = controller.receive_packet().await.unwrap();
+        assert_eq!(indicator, PacketIndicator::Command);
+        assert_eq!(packet.as_ref(), &[0x03, 0x0C, 0x00]);
+
+        controller
+            .send_packet(PacketIndicator::Event, &[0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00])
+            .await
+            .unwrap();
+        assert_eq!(
+            host.receive().await.unwrap(),
+            IncomingPacket::Event(Bytes::from_static(&[0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00]))
+        );
+
+        drop(controller);
+        assert!(host.receive().await.is_err());
+    }
+}
@@ -0,0 +1,951 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use instructor::utils::Length;
+use instructor::{Buffer, BufferMut};
+use parking_lot::Mutex;
+use tokio::select;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{trace, warn};
+
+use crate::hci::acl::{AclHeader, BoundaryFlag, BroadcastFlag};
+use crate::hci::consts::{EventCode, LinkType, RemoteAddr, Role, Status};
+use crate::hci::eir::MAX_EIR_LENGTH;
+use crate::hci::security::MAX_ENCRYPTION_KEY_SIZE;
+use crate::hci::{Error, Opcode, OpcodeGroup};
+use crate::host::{IncomingPacket, Transport};
+
+// ([Vol 4] Part E, Section 7.3.1).
+const DEFAULT_EVENT_MASK: u64 = 0x00001FFFFFFFFFFF;
+const MAX_NAME_LENGTH: usize = 248;
+const SIMULATED_RSSI: i8 = -40;
+const SIMULATED_TX_POWER: i8 = 4;
+
+type CommandHandler = Box<dyn FnMut(Bytes) -> Option<Bytes> + Send>;
+
+/// A simulated radio environment in which [`VirtualController`]s can discover and connect to each other.
+#[derive(Default, Clone)]
+pub struct VirtualAir {
+    devices: Arc<Mutex<BTreeMap<RemoteAddr, Device>>>
+}
+
+struct Device {
+    sender: UnboundedSender<AirPacket>,
+    info: Arc<Mutex<DeviceInfo>>
+}
+
+#[derive(Debug, Clone, Default)]
+struct DeviceInfo {
+    name: String,
+    class: [u8; 3],
+    page_scan: bool,
+    inquiry_scan: bool,
+    eir: Bytes
+}
+
+/// The baseband / link manager level messages exchanged between virtual controllers.
+#[derive(Debug)]
+enum AirPacket {
+    Page { from: RemoteAddr, class: [u8; 3] },
+    PageResponse { from: RemoteAddr, status: Status, role: Role },
+    Detach { from: RemoteAddr, reason: Status },
+    EncryptionChange { from: RemoteAddr, enabled: bool },
+    AclData { from: RemoteAddr, pb: BoundaryFlag, data: Bytes }
+}
+
+impl VirtualAir {
+    /// Creates a new controller with the given address that is visible to all other controllers of this environment.
+    pub fn controller(&self, addr: RemoteAddr) -> VirtualController {
+        let (sender, air_in) = unbounded_channel();
+        let (injected_out, injected_in) = unbounded_channel();
+        let info = Arc::new(Mutex::new(DeviceInfo::default()));
+        let previous = self.devices.lock().insert(
+            addr,
+            Device {
+                sender,
+                info: info.clone()
+            }
+        );
+        assert!(previous.is_none(), "Address {} is already in use", addr);
+        VirtualController {
+            addr,
+            air: self.clone(),
+            info,
+            air_in,
+            injected_in,
+            injected_out,
+            pending: VecDeque::new(),
+            event_mask: DEFAULT_EVENT_MASK,
+            inquiry_mode: 0x00,
+            acl_buffer_size: (1021, 8),
+            command_handlers: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            incoming: BTreeSet::new(),
+            outgoing: BTreeSet::new(),
+            next_handle: 1
+        }
+    }
+
+    fn send(&self, to: RemoteAddr, packet: AirPacket) -> bool {
+        self.devices
+            .lock()
+            .get(&to)
+            .map_or(false, |device| device.sender.send(packet).is_ok())
+    }
+
+    fn info(&self, addr: RemoteAddr) -> Option<DeviceInfo> {
+        let info = self.devices.lock().get(&addr).map(|device| device.info.clone());
+        info.map(|info| info.lock().clone())
+    }
+
+    fn others(&self, addr: RemoteAddr) -> Vec<(RemoteAddr, DeviceInfo)> {
+        let devices: Vec<_> = self
+            .devices
+            .lock()
+            .iter()
+            .filter(|(other, _)| **other != addr)
+            .map(|(other, device)| (*other, device.info.clone()))
+            .collect();
+        devices
+            .into_iter()
+            .map(|(addr, info)| (addr, info.lock().clone()))
+            .collect()
+    }
+}
+
+/// A software controller that implements the HCI command and event contract without any hardware.
+///
+/// Controllers created from the same [`VirtualAir`] can page, name-request and inquire each other and
+/// exchange ACL data over a simulated link. Authentication and encryption always succeed immediately.
+pub struct VirtualController {
+    addr: RemoteAddr,
+    air: VirtualAir,
+    info: Arc<Mutex<DeviceInfo>>,
+    air_in: UnboundedReceiver<AirPacket>,
+    injected_in: UnboundedReceiver<IncomingPacket>,
+    injected_out: UnboundedSender<IncomingPacket>,
+    pending: VecDeque<IncomingPacket>,
+    event_mask: u64,
+    inquiry_mode: u8,
+    acl_buffer_size: (u16, u16),
+    command_handlers: BTreeMap<u16, CommandHandler>,
+    connections: BTreeMap<u16, Connection>,
+    incoming: BTreeSet<RemoteAddr>,
+    outgoing: BTreeSet<RemoteAddr>,
+    next_handle: u16
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Connection {
+    addr: RemoteAddr,
+    role: Role
+}
+
+/// Allows injecting packets into a [`VirtualController`] after it has been handed to [`Hci::new`](crate::hci::Hci::new).
+#[derive(Clone)]
+pub struct VirtualControllerHandle {
+    sender: UnboundedSender<IncomingPacket>
+}
+
+impl VirtualControllerHandle {
+    /// Sends an arbitrary event to the host.
+    pub fn inject_event(&self, code: EventCode, parameters: &[u8]) -> Result<(), Error> {
+        let mut packet = BytesMut::with_capacity(parameters.len() + 2);
+        packet.put_u8(code as u8);
+        packet.put_u8(u8::try_from(parameters.len()).map_err(|_| Error::PayloadTooLarge)?);
+        packet.put_slice(parameters);
+        self.sender
+            .send(IncomingPacket::Event(packet.freeze()))
+            .map_err(|_| Error::EventLoopClosed)
+    }
+
+    /// Sends an ACL data packet (including the HCI ACL header) to the host.
+    pub fn inject_acl_data(&self, data: Bytes) -> Result<(), Error> {
+        self.sender
+            .send(IncomingPacket::AclData(data))
+            .map_err(|_| Error::EventLoopClosed)
+    }
+}
+
+impl VirtualController {
+    /// Creates a standalone controller.
+    pub fn new(addr: RemoteAddr) -> Self {
+        VirtualAir::default().controller(addr)
+    }
+
+    /// Creates two controllers that share the same [`VirtualAir`].
+    pub fn pair(first: RemoteAddr, second: RemoteAddr) -> (Self, Self) {
+        let air = VirtualAir::default();
+        (air.controller(first), air.controller(second))
+    }
+
+    pub fn with_name(self, name: &str) -> Self {
+        assert!(name.len() < MAX_NAME_LENGTH);
+        self.info.lock().name = name.to_string();
+        self
+    }
+
+    /// Sets the values reported by `HCI_Read_Buffer_Size`.
+    pub fn with_acl_buffer_size(mut self, packet_length: u16, packet_count: u16) -> Self {
+        self.acl_buffer_size = (packet_length, packet_count);
+        self
+    }
+
+    /// Overrides the handling of a command.
+    ///
+    /// The handler receives the command parameters and returns the return parameters (starting with the status)
+    /// of the resulting `HCI_Command_Complete` event. Returning `None` drops the command without any response.
+    pub fn with_command_handler<F>(mut self, opcode: Opcode, handler: F) -> Self
+    where
+        F: FnMut(Bytes) -> Option<Bytes> + Send + 'static
+    {
+        self.command_handlers.insert(opcode.into(), Box::new(handler));
+        self
+    }
+
+    pub fn handle(&self) -> VirtualControllerHandle {
+        VirtualControllerHandle {
+            sender: self.injected_out.clone()
+        }
+    }
+
+    pub fn address(&self) -> RemoteAddr {
+        self.addr
+    }
+
+    fn event(&mut self, code: EventCode, writer: impl FnOnce(&mut BytesMut)) {
+        let mask = code.to_mask_bits();
+        if mask != 0 && self.event_mask & mask == 0 {
+            trace!("Suppressing masked event: {:?}", code);
+            return;
+        }
+        let mut packet = BytesMut::with_capacity(64);
+        packet.put_u8(code as u8);
+        packet.put_u8(0);
+        writer(&mut packet);
+        packet[1] = u8::try_from(packet.len() - 2).expect("Event parameters too large");
+        self.pending.push_back(IncomingPacket::Event(packet.freeze()));
+    }
+
+    // ([Vol 4] Part E, Section 7.7.14).
+    fn command_complete(&mut self, opcode: Opcode, status: Status, writer: impl FnOnce(&mut BytesMut)) {
+        self.event(EventCode::CommandComplete, |p| {
+            p.write_le(1u8);
+            p.write_le(u16::from(opcode));
+            p.write_le(status);
+            writer(p);
+        });
+    }
+
+    // ([Vol 4] Part E, Section 7.7.15).
+    fn command_status(&mut self, opcode: Opcode, status: Status) {
+        self.event(EventCode::CommandStatus, |p| {
+            p.write_le(status);
+            p.write_le(1u8);
+            p.write_le(u16::from(opcode));
+        });
+    }
+
+    // ([Vol 4] Part E, Section 7.7.3).
+    fn connection_complete(&mut self, status: Status, handle: u16, addr: RemoteAddr) {
+        self.event(EventCode::ConnectionComplete, |p| {
+            p.write_le(status);
+            p.write_le(handle);
+            p.write_le(addr);
+            p.write_le(LinkType::Acl as u8);
+            p.write_le(0x00u8);
+        });
+    }
+
+    /// Reports every inquiry scannable device using the configured result format.
+    fn inquiry(&mut self) {
+        for (addr, info) in self.air.others(self.addr) {
+            if !info.inquiry_scan {
+                continue;
+            }
+            match self.inquiry_mode {
+                // ([Vol 4] Part E, Section 7.7.2).
+                0x00 => self.event(EventCode::InquiryResult, |p| {
+                    p.write_le(1u8);
+                    p.write_le(addr);
+                    p.write_le(0x01u8);
+                    p.write_le(0x0000u16);
+                    p.put_slice(&info.class);
+                    p.write_le(0x0000u16);
+                }),
+                // ([Vol 4] Part E, Section 7.7.33).
+                0x01 => self.event(EventCode::InquiryResultWithRssi, |p| {
+                    p.write_le(1u8);
+                    p.write_le(addr);
+                    p.write_le(0x01u8);
+                    p.write_le(0x00u8);
+                    p.put_slice(&info.class);
+                    p.write_le(0x0000u16);
+                    p.write_le(SIMULATED_RSSI);
+                }),
+                // ([Vol 4] Part E, Section 7.7.38).
+                _ => self.event(EventCode::ExtendedInquiryResult, |p| {
+                    p.write_le(1u8);
+                    p.write_le(addr);
+                    p.write_le(0x01u8);
+                    p.write_le(0x00u8);
+                    p.put_slice(&info.class);
+                    p.write_le(0x0000u16);
+                    p.write_le(SIMULATED_RSSI);
+                    p.put_slice(&info.eir);
+                    p.put_bytes(0, MAX_EIR_LENGTH - info.eir.len());
+                })
+            }
+        }
+        self.event(EventCode::InquiryComplete, |p| p.write_le(Status::Success));
+    }
+
+    fn new_connection(&mut self, addr: RemoteAddr, role: Role) -> u16 {
+        let handle = self.next_handle;
+        self.next_handle = (self.next_handle % 0x0EFF) + 1;
+        self.connections.insert(handle, Connection { addr, role });
+        handle
+    }
+
+    fn find_connection(&self, addr: RemoteAddr) -> Option<u16> {
+        self.connections
+            .iter()
+            .find(|(_, conn)| conn.addr == addr)
+            .map(|(handle, _)| *handle)
+    }
+
+    /// Drops all links without notifying the host, as if the controller was power cycled.
+    fn detach_all(&mut self) {
+        let peers: BTreeSet<RemoteAddr> = self
+            .connections
+            .values()
+            .map(|conn| conn.addr)
+            .chain(self.incoming.iter().copied())
+            .chain(self.outgoing.iter().copied())
+            .collect();
+        for addr in peers {
+            self.air.send(addr, AirPacket::Detach {
+                from: self.addr,
+                reason: Status::ConnectionTimeout
+            });
+        }
+        self.connections.clear();
+        self.incoming.clear();
+        self.outgoing.clear();
+    }
+
+    fn process_command(&mut self, opcode: Opcode, params: Bytes) {
+        if let Some(handler) = self.command_handlers.get_mut(&u16::from(opcode)) {
+            if let Some(result) = handler(params) {
+                self.event(EventCode::CommandComplete, |p| {
+                    p.write_le(1u8);
+                    p.write_le(u16::from(opcode));
+                    p.put(result);
+                });
+            }
+            return;
+        }
+        if let Err(err) = self.execute_command(opcode, params) {
+            warn!("Invalid parameters for {:?}: {:?}", opcode, err);
+            self.command_complete(opcode, Status::InvalidCommandParameters, |_| {});
+        }
+    }
+
+    fn execute_command(&mut self, opcode: Opcode, mut params: Bytes) -> Result<(), instructor::Error> {
+        match opcode.split() {
+            // ([Vol 4] Part E, Section 7.1.1).
+            Some((OpcodeGroup::LinkControl, 0x0001)) => {
+                self.command_status(opcode, Status::Success);
+                self.inquiry();
+            }
+            // ([Vol 4] Part E, Section 7.1.2).
+            // ([Vol 4] Part E, Section 7.1.4).
+            Some((OpcodeGroup::LinkControl, 0x0002 | 0x0004)) => {
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.1.3).
+            Some((OpcodeGroup::LinkControl, 0x0003)) => {
+                // Only the first period is simulated
+                self.command_complete(opcode, Status::Success, |_| {});
+                self.inquiry();
+            }
+            // ([Vol 4] Part E, Section 7.1.5).
+            Some((OpcodeGroup::LinkControl, 0x0005)) => {
+                let addr: RemoteAddr = params.read_le()?;
+                if self.find_connection(addr).is_some() || self.outgoing.contains(&addr) {
+                    self.command_status(opcode, Status::ConnectionAlreadyExists);
+                    return Ok(());
+                }
+                self.command_status(opcode, Status::Success);
+                let class = self.info.lock().class;
+                let reachable = self.air.info(addr).map_or(false, |info| info.page_scan);
+                if reachable && self.air.send(addr, AirPacket::Page { from: self.addr, class }) {
+                    self.outgoing.insert(addr);
+                } else {
+                    self.connection_complete(Status::PageTimeout, 0x0000, addr);
+                }
+            }
+            // ([Vol 4] Part E, Section 7.1.6).
+            Some((OpcodeGroup::LinkControl, 0x0006)) => {
+                let handle: u16 = params.read_le()?;
+                let reason: Status = params.read_le()?;
+                params.finish()?;
+                match self.connections.remove(&handle) {
+                    Some(conn) => {
+                        self.command_status(opcode, Status::Success);
+                        self.air.send(conn.addr, AirPacket::Detach { from: self.addr, reason });
+                        self.event(EventCode::DisconnectionComplete, |p| {
+                            p.write_le(Status::Success);
+                            p.write_le(handle);
+                            p.write_le(Status::ConnectionTerminatedByLocalHost);
+                        });
+                    }
+                    None => self.command_status(opcode, Status::UnknownConnectionIdentifier)
+                }
+            }
+            // ([Vol 4] Part E, Section 7.1.8).
+            Some((OpcodeGroup::LinkControl, 0x0009)) => {
+                let addr: RemoteAddr = params.read_le()?;
+                let role: Role = params.read_le()?;
+                params.finish()?;
+                if !self.incoming.remove(&addr) {
+                    self.command_status(opcode, Status::UnknownConnectionIdentifier);
+                    return Ok(());
+                }
+                self.command_status(opcode, Status::Success);
+                let handle = self.new_connection(addr, role);
+                self.air.send(addr, AirPacket::PageResponse {
+                    from: self.addr,
+                    status: Status::Success,
+                    role
+                });
+                self.connection_complete(Status::Success, handle, addr);
+            }
+            // ([Vol 4] Part E, Section 7.1.9).
+            Some((OpcodeGroup::LinkControl, 0x000A)) => {
+                let addr: RemoteAddr = params.read_le()?;
+                let reason: Status = params.read_le()?;
+                params.finish()?;
+                if !self.incoming.remove(&addr) {
+                    self.command_status(opcode, Status::UnknownConnectionIdentifier);
+                    return Ok(());
+                }
+                self.command_status(opcode, Status::Success);
+                self.air.send(addr, AirPacket::PageResponse {
+                    from: self.addr,
+                    status: reason,
+                    role: Role::Slave
+                });
+                self.connection_complete(reason, 0x0000, addr);
+            }
+            // ([Vol 4] Part E, Section 7.1.15).
+            Some((OpcodeGroup::LinkControl, 0x0011)) => {
+                let handle: u16 = params.read_le()?;
+                params.finish()?;
+                if !self.connections.contains_key(&handle) {
+                    self.command_status(opcode, Status::UnknownConnectionIdentifier);
+                    return Ok(());
+                }
+                self.command_status(opcode, Status::Success);
+                self.event(EventCode::AuthenticationComplete, |p| {
+                    p.write_le(Status::Success);
+                    p.write_le(handle);
+                });
+            }
+            // ([Vol 4] Part E, Section 7.1.16).
+            Some((OpcodeGroup::LinkControl, 0x0013)) => {
+                let handle: u16 = params.read_le()?;
+                let enabled: bool = params.read_le()?;
+                params.finish()?;
+                let Some(conn) = self.connections.get(&handle).copied() else {
+                    self.command_status(opcode, Status::UnknownConnectionIdentifier);
+                    return Ok(());
+                };
+                self.command_status(opcode, Status::Success);
+                self.air.send(conn.addr, AirPacket::EncryptionChange { from: self.addr, enabled });
+                self.encryption_change(handle, enabled);
+            }
+            // ([Vol 4] Part E, Section 7.1.19).
+            Some((OpcodeGroup::LinkControl, 0x0019)) => {
+                let addr: RemoteAddr = params.read_le()?;
+                self.command_status(opcode, Status::Success);
+                let connected = self.find_connection(addr).is_some();
+                let info = self
+                    .air
+                    .info(addr)
+                    .filter(|info| connected || info.page_scan);
+                // ([Vol 4] Part E, Section 7.7.7).
+                self.event(EventCode::RemoteNameRequestComplete, |p| {
+                    p.write_le(info.as_ref().map_or(Status::PageTimeout, |_| Status::Success));
+                    p.write_le(addr);
+                    let name = info.as_ref().map_or(&[][..], |info| info.name.as_bytes());
+                    p.put_slice(name);
+                    p.put_bytes(0, MAX_NAME_LENGTH - name.len());
+                });
+            }
+            // ([Vol 4] Part E, Section 7.2.7).
+            Some((OpcodeGroup::LinkPolicy, 0x0009)) => {
+                let handle: u16 = params.read_le()?;
+                params.finish()?;
+                match self.connections.get(&handle).copied() {
+                    Some(conn) => self.command_complete(opcode, Status::Success, |p| {
+                        p.write_le(handle);
+                        p.write_le(conn.role);
+                    }),
+                    None => self.command_complete(opcode, Status::UnknownConnectionIdentifier, |p| {
+                        p.write_le(handle);
+                        p.write_le(Role::Master);
+                    })
+                }
+            }
+            // ([Vol 4] Part E, Section 7.2.12).
+            Some((OpcodeGroup::LinkPolicy, 0x000F)) => {
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.1).
+            Some((OpcodeGroup::HciControl, 0x0001)) => {
+                self.event_mask = params.read_le()?;
+                params.finish()?;
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.2).
+            Some((OpcodeGroup::HciControl, 0x0003)) => {
+                self.detach_all();
+                self.pending.clear();
+                self.event_mask = DEFAULT_EVENT_MASK;
+                self.inquiry_mode = 0x00;
+                {
+                    let mut info = self.info.lock();
+                    info.page_scan = false;
+                    info.inquiry_scan = false;
+                    info.eir = Bytes::new();
+                }
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.11).
+            Some((OpcodeGroup::HciControl, 0x0013)) => {
+                let name = params.split_to(params.len().min(MAX_NAME_LENGTH));
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                self.info.lock().name = String::from_utf8_lossy(name).into_owned();
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.12).
+            Some((OpcodeGroup::HciControl, 0x0014)) => {
+                let name = self.info.lock().name.clone();
+                self.command_complete(opcode, Status::Success, |p| {
+                    p.put_slice(name.as_bytes());
+                    p.put_bytes(0, MAX_NAME_LENGTH - name.len());
+                });
+            }
+            // ([Vol 4] Part E, Section 7.3.18).
+            Some((OpcodeGroup::HciControl, 0x001A)) => {
+                let scan: u8 = params.read_le()?;
+                params.finish()?;
+                {
+                    let mut info = self.info.lock();
+                    info.inquiry_scan = scan & 0x01 != 0;
+                    info.page_scan = scan & 0x02 != 0;
+                }
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.26).
+            Some((OpcodeGroup::HciControl, 0x0024)) => {
+                let mut class = [0u8; 3];
+                params.try_copy_to_slice(&mut class)?;
+                params.finish()?;
+                self.info.lock().class = class;
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.50).
+            Some((OpcodeGroup::HciControl, 0x0045)) => {
+                let mode: u8 = params.read_le()?;
+                params.finish()?;
+                match mode {
+                    0x00..=0x02 => {
+                        self.inquiry_mode = mode;
+                        self.command_complete(opcode, Status::Success, |_| {});
+                    }
+                    _ => self.command_complete(opcode, Status::InvalidCommandParameters, |_| {})
+                }
+            }
+            // ([Vol 4] Part E, Section 7.3.56).
+            Some((OpcodeGroup::HciControl, 0x0052)) => {
+                let _fec_required: bool = params.read_le()?;
+                let eir = params.split_to(MAX_EIR_LENGTH.min(params.len()));
+                params.finish()?;
+                // Only the significant part is stored
+                let mut length = 0;
+                while length < eir.len() && eir[length] != 0 {
+                    length += 1 + eir[length] as usize;
+                }
+                self.info.lock().eir = eir.slice(..length.min(eir.len()));
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.3.57).
+            Some((OpcodeGroup::HciControl, 0x0053)) => {
+                let handle: u16 = params.read_le()?;
+                params.finish()?;
+                if !self.connections.contains_key(&handle) {
+                    self.command_status(opcode, Status::UnknownConnectionIdentifier);
+                    return Ok(());
+                }
+                self.command_status(opcode, Status::Success);
+                self.event(EventCode::EncryptionKeyRefreshComplete, |p| {
+                    p.write_le(Status::Success);
+                    p.write_le(handle);
+                });
+            }
+            // ([Vol 4] Part E, Section 7.3.61).
+            Some((OpcodeGroup::HciControl, 0x0058)) => {
+                self.command_complete(opcode, Status::Success, |p| p.write_le(SIMULATED_TX_POWER));
+            }
+            // ([Vol 4] Part E, Section 7.3.59).
+            // ([Vol 4] Part E, Section 7.3.92).
+            Some((OpcodeGroup::HciControl, 0x0056 | 0x007A)) => {
+                self.command_complete(opcode, Status::Success, |_| {});
+            }
+            // ([Vol 4] Part E, Section 7.4.1).
+            Some((OpcodeGroup::InfoParams, 0x0001)) => {
+                self.command_complete(opcode, Status::Success, |p| {
+                    p.write_le(0x0Cu8);
+                    p.write_le(0x0000u16);
+                    p.write_le(0x0Cu8);
+                    // Company id reserved for internal use
+                    p.write_le(0xFFFFu16);
+                    p.write_le(0x0000u16);
+                });
+            }
+            // ([Vol 4] Part E, Section 7.4.2).
+            Some((OpcodeGroup::InfoParams, 0x0002)) => {
+                self.command_complete(opcode, Status::Success, |p| p.put_bytes(0xFF, 64));
+            }
+            // ([Vol 4] Part E, Section 7.4.5).
+            Some((OpcodeGroup::InfoParams, 0x0005)) => {
+                let (packet_length, packet_count) = self.acl_buffer_size;
+                self.command_complete(opcode, Status::Success, |p| {
+                    p.write_le(packet_length);
+                    p.write_le(0u8);
+                    p.write_le(packet_count);
+                    p.write_le(0u16);
+                });
+            }
+            // ([Vol 4] Part E, Section 7.4.6).
+            Some((OpcodeGroup::InfoParams, 0x0009)) => {
+                let addr = self.addr;
+                self.command_complete(opcode, Status::Success, |p| p.write_le(addr));
+            }
+            // ([Vol 4] Part E, Section 7.5.4).
+            Some((OpcodeGroup::StatusParams, 0x0005)) => {
+                let handle: u16 = params.read_le()?;
+                params.finish()?;
+                let status = match self.connections.contains_key(&handle) {
+                    true => Status::Success,
+                    false => Status::UnknownConnectionIdentifier
+                };
+                self.command_complete(opcode, status, |p| {
+                    p.write_le(handle);
+                    p.write_le(SIMULATED_RSSI);
+                });
+            }
+            // ([Vol 4] Part E, Section 7.5.7).
+            Some((OpcodeGroup::StatusParams, 0x0008)) => {
+                let handle: u16 = params.read_le()?;
+                params.finish()?;
+                let status = match self.connections.contains_key(&handle) {
+                    true => Status::Success,
+                    false => Status::UnknownConnectionIdentifier
+                };
+                self.command_complete(opcode, status, |p| {
+                    p.write_le(handle);
+                    p.write_le(MAX_ENCRYPTION_KEY_SIZE);
+                });
+            }
+            _ => {
+                warn!("Unsupported command: {:?}", opcode);
+                self.command_complete(opcode, Status::UnknownCommand, |_| {});
+            }
+        }
+        Ok(())
+    }
+
+    // ([Vol 4] Part E, Section 7.7.8).
+    fn encryption_change(&mut self, handle: u16, enabled: bool) {
+        self.event(EventCode::EncryptionChange, |p| {
+            p.write_le(Status::Success);
+            p.write_le(handle);
+            p.write_le(u8::from(enabled));
+        });
+    }
+
+    fn process_acl_data(&mut self, mut data: Bytes) -> Result<(), Error> {
+        let header: AclHeader = data.read()?;
+        let Some(conn) = self.connections.get(&header.handle).copied() else {
+            warn!("Dropping ACL data for unknown handle: 0x{:04X}", header.handle);
+            return Ok(());
+        };
+        self.air.send(conn.addr, AirPacket::AclData {
+            from: self.addr,
+            pb: header.pb,
+            data
+        });
+        // ([Vol 4] Part E, Section 7.7.19).
+        self.event(EventCode::NumberOfCompletedPackets, |p| {
+            p.write_le(1u8);
+            p.write_le(header.handle);
+            p.write_le(1u16);
+        });
+        Ok(())
+    }
+
+    fn process_air_packet(&mut self, packet: AirPacket) -> Result<(), Error> {
+        trace!("Received air packet: {:?}", packet);
+        match packet {
+            AirPacket::Page { from, class } => {
+                self.incoming.insert(from);
+                // ([Vol 4] Part E, Section 7.7.4).
+                self.event(EventCode::ConnectionRequest, |p| {
+                    p.write_le(from);
+                    p.put_slice(&class);
+                    p.write_le(LinkType::Acl as u8);
+                });
+            }
+            AirPacket::PageResponse { from, status, role } => {
+                if self.outgoing.remove(&from) {
+                    let handle = match status {
+                        Status::Success => self.new_connection(from, opposite(role)),
+                        _ => 0x0000
+                    };
+                    self.connection_complete(status, handle, from);
+                }
+            }
+            AirPacket::Detach { from, reason } => {
+                self.incoming.remove(&from);
+                if self.outgoing.remove(&from) {
+                    self.connection_complete(Status::PageTimeout, 0x0000, from);
+                }
+                if let Some(handle) = self.find_connection(from) {
+                    self.connections.remove(&handle);
+                    self.event(EventCode::DisconnectionComplete, |p| {
+                        p.write_le(Status::Success);
+                        p.write_le(handle);
+                        p.write_le(reason);
+                    });
+                }
+            }
+            AirPacket::EncryptionChange { from, enabled } => {
+                if let Some(handle) = self.find_connection(from) {
+                    self.encryption_change(handle, enabled);
+                }
+            }
+            AirPacket::AclData { from, pb, data } => {
+                if let Some(handle) = self.find_connection(from) {
+                    let mut packet = BytesMut::with_capacity(data.len() + 4);
+                    packet.write(AclHeader {
+                        handle,
+                        pb,
+                        bc: BroadcastFlag::PointToPoint,
+                        length: Length::new(data.len())?
+                    });
+                    packet.put(data);
+                    self.pending.push_back(IncomingPacket::AclData(packet.freeze()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn opposite(role: Role) -> Role {
+    match role {
+        Role::Master => Role::Slave,
+        Role::Slave => Role::Master
+    }
+}
+
+impl Drop for VirtualController {
+    fn drop(&mut self) {
+        self.air.devices.lock().remove(&self.addr);
+        self.detach_all();
+    }
+}
+
+impl Transport for VirtualController {
+    async fn send_command(&mut self, mut data: Bytes) -> Result<(), Error> {
+        let opcode: Opcode = data.read_le()?;
+        let _length: u8 = data.read_le()?;
+        self.process_command(opcode, data);
+        Ok(())
+    }
+
+    async fn send_acl_data(&mut self, data: Bytes) -> Result<(), Error> {
+        self.process_acl_data(data)
+    }
+
+    async fn receive(&mut self) -> Result<IncomingPacket, Error> {
+        loop {
+            if let Some(packet) = self.pending.pop_front() {
+                return Ok(packet);
+            }
+            select! {
+                Some(packet) = self.air_in.recv() => self
+                    .process_air_packet(packet)
+                    .unwrap_or_else(|err| warn!("Error processing air packet: {:?}", err)),
+                Some(packet) = self.injected_in.recv() => return Ok(packet)
+            }
+        }
+    }
+}
+
+/// Two hosts on a [`VirtualController::pair`] for tests, with a connection manager on B.
+///
+/// Handlers that need to see the link have to be registered before [`connect`](Self::connect).
+#[cfg(test)]
+pub(crate) struct VirtualLink {
+    pub addr_a: RemoteAddr,
+    pub addr_b: RemoteAddr,
+    pub a: Arc<crate::hci::Hci>,
+    pub b: Arc<crate::hci::Hci>,
+    pub manager: crate::hci::connection::ConnectionManager,
+    pub events: crate::hci::connection::LinkEventReceiver
+}
+
+#[cfg(test)]
+impl VirtualLink {
+    pub async fn new() -> Self {
+        use crate::hci::connection::ConnectionManagerBuilder;
+        use crate::hci::link_keys::MemoryLinkKeyStore;
+        use crate::hci::Hci;
+
+        let addr_a = RemoteAddr::from([0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let addr_b = RemoteAddr::from([0x02, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let (controller_a, controller_b) = VirtualController::pair(addr_a, addr_b);
+        let a = Arc::new(Hci::new(controller_a).await.unwrap());
+        let b = Arc::new(Hci::new(controller_b).await.unwrap());
+        let manager = ConnectionManagerBuilder::default()
+            .with_link_key_store(MemoryLinkKeyStore::default())
+            .spawn(b.clone())
+            .await
+            .unwrap();
+        let events = manager.events();
+        Self {
+            addr_a,
+            addr_b,
+            a,
+            b,
+            manager,
+            events
+        }
+    }
+
+    /// Makes B connectable and connects A to it.
+    pub async fn connect(&mut self) -> crate::hci::consts::ConnectionHandle {
+        use crate::hci::connection::LinkEvent;
+
+        self.b.set_scan_enabled(true, false).await.unwrap();
+        let handle = self.a.connect(self.addr_b).await.unwrap();
+        assert!(matches!(self.events.recv().await, Some(LinkEvent::Connected { .. })));
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures_lite::StreamExt;
+    use instructor::Buffer;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::hci::acl::AclHeader;
+    use crate::hci::consts::{EventCode, RemoteAddr, Role, Status};
+    use crate::hci::discovery::DiscoveryBuilder;
+    use crate::hci::eir::{ExtendedInquiryResponse, LocalName};
+    use crate::hci::{Error, Hci, Opcode, OpcodeGroup};
+    use crate::host::virtual_controller::VirtualController;
+    use crate::sdp::ids::service_classes::AUDIO_SINK;
+
+    #[tokio::test]
+    async fn test_unresponsive_controller() {
+        let opcode = Opcode::new(OpcodeGroup::InfoParams, 0x0009);
+        let controller = VirtualController::new(RemoteAddr::from([0x01; 6])).with_command_handler(opcode, |_| None);
+        let hci = Hci::new(controller).await.unwrap();
+
+        let (tx, mut events) = unbounded_channel();
+        hci.register_event_handler([EventCode::ConnectionRequest], tx).unwrap();
+
+        let result = hci.call_with_timeout::<RemoteAddr>(opcode, Duration::from_millis(100), |_| {}).await;
+        assert!(matches!(result, Err(Error::CommandTimeout(op)) if op == opcode));
+        assert!(events.recv().await.is_none());
+        assert!(hci.is_closed());
+        assert!(matches!(hci.read_local_version().await, Err(Error::EventLoopClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_virtual_link() {
+        let addr_a = RemoteAddr::from([0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let addr_b = RemoteAddr::from([0x02, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let (controller_a, controller_b) = VirtualController::pair(addr_a, addr_b);
+        let a = Hci::new(controller_a).await.unwrap();
+        let b = Hci::new(controller_b.with_name("Device B")).await.unwrap();
+
+        let (tx, mut events_a) = unbounded_channel();
+        a.register_event_handler([EventCode::ConnectionComplete], tx).unwrap();
+        let (tx, mut events_b) = unbounded_channel();
+        b.register_event_handler([EventCode::ConnectionRequest, EventCode::ConnectionComplete], tx).unwrap();
+        let (tx, mut data_b) = unbounded_channel();
+        b.register_data_handler(tx).unwrap();
+
+        assert_eq!(a.read_bd_addr().await.unwrap(), addr_a);
+        b.set_scan_enabled(true, false).await.unwrap();
+
+        a.create_connection(addr_b, false).await.unwrap();
+        let (code, _) = events_b.recv().await.unwrap();
+        assert_eq!(code, EventCode::ConnectionRequest);
+        b.accept_connection_request(addr_a, Role::Slave).await.unwrap();
+
+        let mut handles = Vec::new();
+        for events in [&mut events_a, &mut events_b] {
+            let (code, mut data) = events.recv().await.unwrap();
+            assert_eq!(code, EventCode::ConnectionComplete);
+            assert_eq!(data.read_le::<Status>().unwrap(), Status::Success);
+            handles.push(data.read_le::<u16>().unwrap());
+        }
+        assert_eq!(b.discover_role(handles[1]).await.unwrap(), Role::Slave);
+        assert_eq!(a.remote_name(addr_b).await.unwrap(), "Device B");
+        a.authenticate(handles[0]).await.unwrap();
+
+        let pdu = Bytes::from_static(&[0x02, 0x00, 0x40, 0x00, 0xAA, 0xBB]);
+        a.get_acl_sender().send(handles[0], pdu.clone()).unwrap();
+        let mut packet = data_b.recv().await.unwrap();
+        let header: AclHeader = packet.read().unwrap();
+        assert_eq!(header.handle, handles[1]);
+        assert_eq!(packet, pdu);
+    }
+
+    #[tokio::test]
+    async fn test_discovery() {
+        let addr_a = RemoteAddr::from([0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let addr_b = RemoteAddr::from([0x02, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let (controller_a, controller_b) = VirtualController::pair(addr_a, addr_b);
+        let a = Arc::new(Hci::new(controller_a).await.unwrap());
+        let b = Hci::new(controller_b).await.unwrap();
+        b.set_scan_enabled(true, true).await.unwrap();
+
+        let eir = ExtendedInquiryResponse::default()
+            .with_name("Device B")
+            .with_service_class(AUDIO_SINK);
+        b.write_extended_inquiry_response(false, &eir).await.unwrap();
+
+        let mut discovery = DiscoveryBuilder::default().start(&a).await.unwrap();
+        let device = discovery.next().await.unwrap();
+        assert_eq!(device.addr, addr_b);
+        assert_eq!(device.rssi, Some(-40));
+        assert_eq!(device.name, Some(LocalName::Complete(String::from("Device B"))));
+        assert_eq!(device.uuids, vec![AUDIO_SINK]);
+        assert!(discovery.next().await.is_none());
+        assert!(!discovery.is_running());
+    }
+}
@@ -1,5 +1,7 @@
 use std::future::{poll_fn, Future};
-use std::task::{Context, Poll};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
@@ -7,13 +9,18 @@ use futures_lite::FutureExt;
 use instructor::utils::Length;
 use instructor::{BufferMut, Instruct, LittleEndian};
 use tokio::sync::mpsc::UnboundedReceiver as MpscReceiver;
-use tokio::time::sleep;
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
 use tracing::{debug, info_span, instrument, trace, warn, Span, error};
 use tracing::field::Empty;
 use crate::ensure;
 
 use crate::hci::{AclSendError, AclSender};
-use crate::l2cap::configuration::{ConfigurationParameter, FlushTimeout, Mtu};
+use crate::l2cap::configuration::{
+    ChannelConfig, ConfigurationParameter, ExtendedFlowSpecification, FlushTimeout, Mode, Mtu, QualityOfService, RetransmissionAndFlowControl, ServiceType,
+    SupportedMode
+};
+use crate::l2cap::ertm::{self, Ertm, Parameters};
 use crate::l2cap::signaling::{Psm, RejectReason, SignalingCode, SignalingContext};
 use crate::l2cap::{ChannelEvent, CID_ID_NONE, ConfigureResult, ConnectionResult, ConnectionStatus, L2capHeader, SignalingIds};
 use crate::utils::{now_or_never, Loggable, IgnoreableResult};
@@ -26,7 +33,14 @@ macro_rules! event {
     };
 }
 
-const DEFAULT_MTU: Mtu = Mtu(1691);
+const CONFIGURATION_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many PDUs may wait for the controller before [`Channel::write`] blocks.
+pub(crate) const MAX_QUEUED_PDUS: usize = 8;
+/// How often a request is adjusted to the counter-proposals of the remote device before giving up.
+const MAX_CONFIGURATION_ATTEMPTS: u8 = 3;
+/// The option bytes that fit into a configuration request on the minimum signaling MTU of 48 bytes
+/// after the command header, the channel id and the flags ([Vol 3] Part A, Section 4).
+const MAX_OPTIONS_SIZE: usize = 48 - 4 - 4;
 
 enum Event {
     DataReceived(Bytes),
@@ -45,6 +59,22 @@ pub enum Error {
     Timeout,
     #[error("The channel has been disconnected")]
     Disconnected,
+    #[error("The remote device refused the connection: {0:?}")]
+    Refused(ConnectionResult),
+    #[error("There is no ACL link to the remote device")]
+    NotConnected,
+    #[error("All channel ids are in use")]
+    NoResources,
+    #[error("The remote device does not support this feature")]
+    NotSupported,
+    #[error("Not a fixed channel: 0x{0:04X}")]
+    InvalidCid(u16),
+    #[error("Enhanced retransmission failed: {0}")]
+    Retransmission(#[from] ertm::Error),
+    #[error("The remote device refused the configuration: {0:?}")]
+    ConfigurationRefused(ConfigureResult),
+    #[error("The remote device rejected the command: {0:?}")]
+    CommandRejected(RejectReason),
     #[error("The underlying transport has been closed. Is the event loop still running?")]
     ChannelClosed
 }
@@ -103,6 +133,15 @@ pub struct Channel {
     local_mtu: Mtu,
     remote_mtu: Mtu,
     flush_timeout: FlushTimeout,
+    config: ChannelConfig,
+    configuration_attempts: u8,
+    request_options: Vec<ConfigurationParameter>,
+    response_options: Vec<ConfigurationParameter>,
+    tx_parameters: RetransmissionAndFlowControl,
+    ertm: Option<Ertm>,
+    ertm_timer: Option<Pin<Box<Sleep>>>,
+    tx_permits: Arc<Semaphore>,
+    tx_permit_request: Option<Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>>,
     span: Span
 }
 
@@ -117,9 +156,18 @@ impl Channel {
             receiver,
             sender,
             next_signaling_id,
-            local_mtu: Mtu::MINIMUM_ACL_U,
-            remote_mtu: Mtu::MINIMUM_ACL_U,
+            local_mtu: Mtu::DEFAULT_ACL_U,
+            remote_mtu: Mtu::DEFAULT_ACL_U,
             flush_timeout: FlushTimeout::default(),
+            config: ChannelConfig::default(),
+            configuration_attempts: 0,
+            request_options: Vec::new(),
+            response_options: Vec::new(),
+            tx_parameters: RetransmissionAndFlowControl::default(),
+            ertm: None,
+            ertm_timer: None,
+            tx_permits: Arc::new(Semaphore::new(MAX_QUEUED_PDUS)),
+            tx_permit_request: None,
             span: info_span!(parent: None, "l2cap_channel", remote_cid = Empty, local_cid = format_args!("{:#X}", local_cid))
         }
     }
@@ -143,6 +191,15 @@ impl Channel {
         Ok(())
     }
 
+    /// Connects to `psm` on the remote device and waits until the channel is configured in both directions.
+    pub async fn open(&mut self, psm: u64) -> Result<(), Error> {
+        self.connect(psm).await?;
+        self.configure().await?;
+        self.wait_for_configuration_complete()
+            .or(timeout(CONFIGURATION_TIMEOUT))
+            .await
+    }
+
     #[instrument(parent = &self.span, skip(self))]
     pub fn accept_connection(&mut self) -> Result<(), Error> {
         if let State::Closed(ClosedState::WaitingForResponse(transaction_id)) = self.state {
@@ -158,13 +215,18 @@ impl Channel {
         }
     }
 
-    #[instrument(parent = &self.span, skip(self))]
     pub fn reject_connection(&mut self) -> Result<(), Error> {
+        self.refuse_connection(ConnectionResult::RefusedNoResources)
+    }
+
+    #[instrument(parent = &self.span, skip(self))]
+    pub fn refuse_connection(&mut self, result: ConnectionResult) -> Result<(), Error> {
+        debug_assert!(!matches!(result, ConnectionResult::Success | ConnectionResult::Pending));
         if let State::Closed(ClosedState::WaitingForResponse(transaction_id)) = self.state {
             self.send_signaling(Some(transaction_id), SignalingCode::ConnectionResponse, (
                 self.local_cid,
                 self.remote_cid,
-                ConnectionResult::RefusedNoResources,
+                result,
                 ConnectionStatus::NoFurtherInformation))?;
             self.set_state(State::Closed(ClosedState::Disconnected));
             Ok(())
@@ -173,6 +235,23 @@ impl Channel {
         }
     }
 
+    /// Tells the remote device that the connection request is still being processed.
+    /// The final answer has to follow with [`accept_connection`](Self::accept_connection) or [`refuse_connection`](Self::refuse_connection)
+    /// ([Vol 3] Part A, Section 4.3).
+    #[instrument(parent = &self.span, skip(self))]
+    pub fn defer_connection(&mut self, status: ConnectionStatus) -> Result<(), Error> {
+        if let State::Closed(ClosedState::WaitingForResponse(transaction_id)) = self.state {
+            self.send_signaling(Some(transaction_id), SignalingCode::ConnectionResponse, (
+                self.local_cid,
+                self.remote_cid,
+                ConnectionResult::Pending,
+                status))?;
+            Ok(())
+        } else {
+            Err(Error::BadState)
+        }
+    }
+
     fn set_remote_cid(&mut self, remote_cid: u16) {
         self.remote_cid = remote_cid;
         self.span.record("remote_cid", format_args!("{:#X}", remote_cid));
@@ -186,13 +265,41 @@ impl Channel {
         self.remote_mtu.0
     }
 
+    pub fn local_mtu(&self) -> u16 {
+        self.local_mtu.0
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.config.mode()
+    }
+
+    /// The requested parameters, including the adjustments made while negotiating with the remote device.
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
+    /// Sets the parameters [`configure`](Self::configure) requests, so it has to be called before.
+    ///
+    /// In Enhanced Retransmission Mode frames are only acknowledged and retransmitted while the channel is read.
+    pub fn set_config(&mut self, config: ChannelConfig) -> Result<(), Error> {
+        ensure!(
+            matches!(self.state, State::Closed(ClosedState::Idle | ClosedState::WaitingForResponse(_)) | State::Config(ConfigState::Config)),
+            Error::BadState
+        );
+        self.config = config;
+        Ok(())
+    }
+
     fn set_state(&mut self, state: State) -> Option<Event> {
         debug_assert_ne!(self.state, state, "State transition to same state");
         trace!("State transition: {:?} -> {:?}", self.state, state);
         self.state = state;
         match self.state {
             State::Closed(ClosedState::Disconnected) => Some(Event::DisconnectComplete),
-            State::Open => Some(Event::ConfigurationCompete),
+            State::Open => {
+                self.start_ertm();
+                Some(Event::ConfigurationCompete)
+            }
             State::Config(ConfigState::Config) => Some(Event::ConnectionComplete),
             _ => None
         }
@@ -202,24 +309,72 @@ impl Channel {
         poll_fn(move |cx| self.poll_data(cx))
     }
 
+    /// Sends an SDU, waiting while too many earlier ones are still queued for the controller.
+    ///
+    /// In Enhanced Retransmission Mode it also waits while the transmit window is full,
+    /// processing the acknowledgments of the remote device in the meantime.
     #[instrument(parent = &self.span, skip(self, data))]
     pub async fn write(&mut self, data: Bytes) -> Result<(), Error> {
         if self.state != State::Open {
             trace!("Channel not yet open, waiting for configuration");
             self.wait_for_configuration_complete()
-                .or(timeout(Duration::from_secs(2)))
+                .or(timeout(CONFIGURATION_TIMEOUT))
                 .await?;
         }
+        if self.ertm.is_some() {
+            self.wait_for_transmit_window().await?;
+            if let Some(engine) = self.ertm.as_mut() {
+                engine.send(Instant::now(), data);
+            }
+            self.flush_frames()?;
+            return poll_fn(|cx| self.poll_flush(cx)).await;
+        }
         let mut buffer = BytesMut::new();
         buffer.write_le(L2capHeader {
             len: Length::new(data.len())?,
             cid: self.remote_cid
         });
         buffer.put(data);
-        self.sender.send(self.connection_handle, buffer.freeze())?;
+        let permit = self
+            .tx_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        self.sender
+            .send_with_permit(self.connection_handle, buffer.freeze(), permit)?;
         Ok(())
     }
 
+    /// Like [`write`](Self::write), but drops the SDU instead of waiting. Returns whether it was queued.
+    ///
+    /// Meant for real-time data like audio, where a late packet is worthless.
+    pub fn try_write(&mut self, data: Bytes) -> Result<bool, Error> {
+        ensure!(self.state == State::Open, Error::BadState);
+        if self.ertm.is_some() {
+            self.flush_frames()?;
+            let Some(engine) = self.ertm.as_mut() else { return Ok(false); };
+            if engine.is_full() || engine.has_frames() || self.tx_permits.available_permits() == 0 {
+                return Ok(false);
+            }
+            engine.send(Instant::now(), data);
+            self.flush_frames()?;
+            return Ok(true);
+        }
+        let Ok(permit) = self.tx_permits.clone().try_acquire_owned() else {
+            return Ok(false);
+        };
+        let mut buffer = BytesMut::new();
+        buffer.write_le(L2capHeader {
+            len: Length::new(data.len())?,
+            cid: self.remote_cid
+        });
+        buffer.put(data);
+        self.sender
+            .send_with_permit(self.connection_handle, buffer.freeze(), permit)?;
+        Ok(true)
+    }
+
     #[instrument(parent = &self.span, skip(self))]
     pub async fn disconnect(&mut self) -> Result<(), Error> {
         self.send_signaling(None, SignalingCode::DisconnectionRequest, (self.remote_cid, self.local_cid))?;
@@ -236,8 +391,8 @@ impl Channel {
             _ => return Err(Error::BadState)
         };
         // Send ConfigReq
-        self.send_configuration_request(vec![DEFAULT_MTU.into()])?;
-        self.local_mtu = DEFAULT_MTU;
+        self.configuration_attempts = 0;
+        self.request_configuration()?;
 
         //self.wait_for_configuration_complete().await?;
         Ok(())
@@ -274,8 +429,9 @@ impl Channel {
                             event!(self.set_state(State::Config(ConfigState::Config)));
                         }
                         ConnectionResult::Pending => { /* Stall */ }
-                        _ => {
-                            event!(self.set_state(State::Closed(ClosedState::Disconnected)));
+                        refused => {
+                            self.set_state(State::Closed(ClosedState::Disconnected));
+                            return Poll::Ready(Err(Error::Refused(refused)));
                         }
                     }
                     ConfigurationRequest { id, .. } => {
@@ -286,24 +442,24 @@ impl Channel {
                 }
                 // ([Vol 3] Part A, Section 6.1.4)
                 State::Config(cs) => match data {
-                    ConfigurationRequest { id, options} => match cs {
+                    ConfigurationRequest { id, continuation, options } => match cs {
                         ConfigState::Config => {
-                            event!(self.handle_config_req(id, options, State::Config(ConfigState::SendConfig))?);
+                            event!(self.handle_config_req(id, continuation, options, State::Config(ConfigState::SendConfig))?);
                         }
                         ConfigState::ConfigReqRsp => {
-                            event!(self.handle_config_req(id, options, State::Config(ConfigState::ConfigRsp))?);
+                            event!(self.handle_config_req(id, continuation, options, State::Config(ConfigState::ConfigRsp))?);
                         }
                         ConfigState::ConfigReq => {
-                            event!(self.handle_config_req(id, options, State::Open)?);
+                            event!(self.handle_config_req(id, continuation, options, State::Open)?);
                         }
                         _ => debug!("Unexpected ConfigurationRequest in state {:?}", self.state)
                     },
-                    ConfigurationResponse {result, options, .. } => match cs {
+                    ConfigurationResponse { continuation, result, options, .. } => match cs {
                         ConfigState::ConfigReqRsp => {
-                            event!(self.handle_config_resp(result, options, State::Config(ConfigState::ConfigReq))?);
+                            event!(self.handle_config_resp(continuation, result, options, State::Config(ConfigState::ConfigReq))?);
                         }
                         ConfigState::ConfigRsp => {
-                            event!(self.handle_config_resp(result, options, State::Open)?);
+                            event!(self.handle_config_resp(continuation, result, options, State::Open)?);
                         }
                         ConfigState::Config | ConfigState::SendConfig | ConfigState::ConfigReq => { /* Ignore */ }
                     },
@@ -313,19 +469,23 @@ impl Channel {
                         event!(self.set_state(State::Closed(ClosedState::Disconnected)));
                     }
                     DisconnectResponse { .. } | ConnectionResponse { .. } => { /* Ignore */ }
-                    DataReceived(data) => return Poll::Ready(Ok(Event::DataReceived(data)))
+                    DataReceived(data) => {
+                        event!(self.receive_frame(data)?);
+                    }
                 },
                 // ([Vol 3] Part A, Section 6.1.5)
                 State::Open => match data {
-                    ConfigurationRequest { id, options} => {
-                        event!(self.handle_config_req(id, options, State::Config(ConfigState::SendConfig))?);
+                    ConfigurationRequest { id, continuation, options } => {
+                        event!(self.handle_config_req(id, continuation, options, State::Config(ConfigState::SendConfig))?);
                     }
                     DisconnectRequest { id } => {
                         // Send DisconnectRsp
                         self.send_disconnect_response(id)?;
                         event!(self.set_state(State::Closed(ClosedState::Disconnected)));
                     }
-                    DataReceived(data) => return Poll::Ready(Ok(Event::DataReceived(data))),
+                    DataReceived(data) => {
+                        event!(self.receive_frame(data)?);
+                    }
                     DisconnectResponse { .. } | ConfigurationResponse { .. } | ConnectionResponse { .. } => { /* Ignore */ }
                 },
                 // ([Vol 3] Part A, Section 6.1.6)
@@ -346,60 +506,326 @@ impl Channel {
                 }
             }
         }
+        if self.poll_timer(cx)? {
+            cx.waker().wake_by_ref();
+        }
+        if let Poll::Ready(Err(err)) = self.poll_flush(cx) {
+            return Poll::Ready(Err(err));
+        }
         Poll::Pending
     }
 
     pub fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
-        while let Poll::Ready(event) = self.poll_events(cx) {
+        loop {
+            // The ERTM engine keeps the reassembled SDUs, so writers waiting for acknowledgments don't drop them
+            if let Some(sdu) = self.ertm.as_mut().and_then(Ertm::next_sdu) {
+                return Poll::Ready(Some(sdu));
+            }
+            let Poll::Ready(event) = self.poll_events(cx) else {
+                return match self.ertm.as_mut().and_then(Ertm::next_sdu) {
+                    Some(sdu) => Poll::Ready(Some(sdu)),
+                    None => Poll::Pending
+                };
+            };
             match event {
                 Ok(Event::DataReceived(data)) => return Poll::Ready(Some(data)),
-                Ok(Event::DisconnectComplete) | Err(Error::Disconnected | Error::ChannelClosed | Error::Timeout) => return Poll::Ready(None),
+                Ok(Event::DisconnectComplete) | Err(Error::Disconnected | Error::ChannelClosed | Error::Timeout | Error::Refused(_) | Error::Retransmission(_) | Error::ConfigurationRefused(_)) => return Poll::Ready(None),
                 Ok(Event::ConnectionComplete | Event::ConfigurationCompete) => {}
                 Err(e) => panic!("{}", e)
             }
         }
-        Poll::Pending
     }
 
-    fn handle_config_req(&mut self, id: u8, mut options: Vec<ConfigurationParameter>, success: State) -> Result<Option<Event>, Error> {
-        let updated = false;
+    // ([Vol 3] Part A, Section 7.1.3).
+    fn handle_config_req(&mut self, id: u8, continuation: bool, options: Vec<ConfigurationParameter>, success: State) -> Result<Option<Event>, Error> {
+        self.request_options.extend(options);
+        if continuation {
+            // Every fragment of a request gets an empty response, the options are handled once the last one arrived
+            self.send_configuration_response(id, true, ConfigureResult::Success, Vec::new())?;
+            return Ok(None);
+        }
+        let mut options = std::mem::take(&mut self.request_options);
+        let mut unacceptable = Vec::new();
+        let mut mode = Mode::Basic;
+        options.retain(|option| !option.is_hint());
         for option in options.iter_mut() {
             match option {
-                ConfigurationParameter::Mtu(mtu) => self.remote_mtu = *mtu,
+                ConfigurationParameter::Mtu(mtu) => match mtu.0 < self.config.min_remote_mtu().0 {
+                    true => unacceptable.push(self.config.min_remote_mtu().into()),
+                    false => self.remote_mtu = *mtu
+                },
                 //TODO How to actually handle a flush timeout?
                 ConfigurationParameter::FlushTimeout(timeout) => self.flush_timeout = *timeout,
-                _ => {
+                // Only best effort traffic is supported ([Vol 3] Part A, Section 5.3).
+                ConfigurationParameter::QualityOfService(qos) => {
+                    if qos.service_type == ServiceType::Guaranteed {
+                        unacceptable.push(QualityOfService { service_type: ServiceType::BestEffort, ..*qos }.into());
+                    }
+                }
+                ConfigurationParameter::ExtendedFlowSpecification(spec) => {
+                    if spec.service_type == ServiceType::Guaranteed {
+                        unacceptable.push(ExtendedFlowSpecification { service_type: ServiceType::BestEffort, ..*spec }.into());
+                    }
+                }
+                ConfigurationParameter::RetransmissionAndFlowControl(rfc) => {
+                    mode = rfc.mode;
+                    if rfc.mode == Mode::EnhancedRetransmission {
+                        // The responder chooses the timeouts of the requesting side ([Vol 3] Part A, Section 5.4).
+                        rfc.tx_window_size = rfc.tx_window_size.clamp(1, 63);
+                        rfc.retransmission_timeout = ertm::RETRANSMISSION_TIMEOUT;
+                        rfc.monitor_timeout = ertm::MONITOR_TIMEOUT;
+                    }
+                    self.tx_parameters.tx_window_size = rfc.tx_window_size;
+                    self.tx_parameters.max_transmit = rfc.max_transmit;
+                    self.tx_parameters.mps = rfc.mps;
+                }
+                // Frames always carry an FCS as this side never asks to omit it ([Vol 3] Part A, Section 5.5).
+                ConfigurationParameter::Fcs(_) => {}
+                // The standard control field doesn't support the extended window size
+                ConfigurationParameter::ExtendedWindowSize(_) => {
                     warn!("Unsupported configuration parameter: {:?}", option);
-                    self.send_configuration_response(id, ConfigureResult::Rejected, Vec::new())?;
+                    self.send_configuration_response(id, false, ConfigureResult::Rejected, Vec::new())?;
                     return Ok(None);
                 }
+                ConfigurationParameter::Unknown(_) => {}
             }
         }
-        if updated {
-            self.send_configuration_response(id, ConfigureResult::UnacceptableParameters, options)?;
+        let unknown: Vec<ConfigurationParameter> = options
+            .iter()
+            .filter(|option| matches!(option, ConfigurationParameter::Unknown(_)))
+            .copied()
+            .collect();
+        if !unknown.is_empty() {
+            warn!("Unknown configuration parameters: {:?}", unknown);
+            self.send_configuration_response(id, false, ConfigureResult::UnknownOptions, unknown)?;
+            return Ok(None);
+        }
+        if !self.adopt_mode(mode) {
+            debug!("Remote device requested {:?} instead of {:?}", mode, self.mode());
+            unacceptable.push(self.local_rfc().into());
+        }
+        if !unacceptable.is_empty() {
+            debug!("Counter-proposing {:?}", unacceptable);
+            self.send_configuration_response(id, false, ConfigureResult::UnacceptableParameters, unacceptable)?;
             Ok(None)
         } else {
-            self.send_configuration_response(id, ConfigureResult::Success, options)?;
+            self.send_configuration_response(id, false, ConfigureResult::Success, options)?;
             Ok(self.set_state(success))
         }
     }
 
-    fn handle_config_resp(&mut self, result: ConfigureResult, options: Vec<ConfigurationParameter>, success: State) -> Result<Option<Event>, Error> {
+    // ([Vol 3] Part A, Section 7.1.4).
+    fn handle_config_resp(&mut self, continuation: bool, result: ConfigureResult, options: Vec<ConfigurationParameter>, success: State) -> Result<Option<Event>, Error> {
+        self.response_options.extend(options);
+        if continuation {
+            return Ok(None);
+        }
+        let options = std::mem::take(&mut self.response_options);
         match result {
             ConfigureResult::Success => {
                 for option in options {
                     match option {
                         ConfigurationParameter::Mtu(mtu) => self.local_mtu = mtu,
+                        ConfigurationParameter::RetransmissionAndFlowControl(rfc) => {
+                            self.tx_parameters.retransmission_timeout = rfc.retransmission_timeout;
+                            self.tx_parameters.monitor_timeout = rfc.monitor_timeout;
+                        }
+                        ConfigurationParameter::Fcs(_) => {}
                         _ => warn!("Unexpected configuration parameter: {:?}", option)
                     }
                 }
                 Ok(self.set_state(success))
             }
-            other => {
-                // Send ConfigReq (new options)
-                unimplemented!("Configuration failed: {:?}", other)
+            ConfigureResult::UnacceptableParameters => {
+                if self.configuration_attempts >= MAX_CONFIGURATION_ATTEMPTS || !self.accept_counter_proposal(options) {
+                    return Err(self.abort(Error::ConfigurationRefused(result)));
+                }
+                self.configuration_attempts += 1;
+                self.request_configuration()?;
+                Ok(None)
+            }
+            // The final response follows later
+            ConfigureResult::Pending => Ok(None),
+            other => Err(self.abort(Error::ConfigurationRefused(other)))
+        }
+    }
+
+    /// Adjusts the request to the values the remote device suggested.
+    fn accept_counter_proposal(&mut self, options: Vec<ConfigurationParameter>) -> bool {
+        debug!("Remote device suggested {:?}", options);
+        options.into_iter().all(|option| match option {
+            ConfigurationParameter::Mtu(mtu) if mtu.0 >= Mtu::MINIMUM_ACL_U.0 => {
+                self.config = self.config.with_mtu(mtu.0);
+                true
+            }
+            ConfigurationParameter::FlushTimeout(timeout) => {
+                self.config = self.config.with_flush_timeout(timeout);
+                true
+            }
+            ConfigurationParameter::QualityOfService(qos) => {
+                self.config = self.config.with_qos(qos);
+                true
+            }
+            ConfigurationParameter::RetransmissionAndFlowControl(rfc) => self.adopt_mode(rfc.mode),
+            ConfigurationParameter::Fcs(_) => true,
+            _ => false
+        })
+    }
+
+    fn request_configuration(&mut self) -> Result<(), Error> {
+        self.local_mtu = self.config.mtu();
+        self.send_configuration_request(self.configuration_options())?;
+        Ok(())
+    }
+
+    /// Both directions have to use the same mode. A channel in Basic mode switches to the mode the remote device
+    /// asks for as long as neither side has accepted a configuration yet.
+    fn adopt_mode(&mut self, mode: Mode) -> bool {
+        if mode == self.mode() {
+            return true;
+        }
+        let negotiable = self.mode() == Mode::Basic && matches!(self.state, State::Config(ConfigState::Config | ConfigState::ConfigReqRsp));
+        match SupportedMode::new(mode) {
+            Some(supported) if negotiable => {
+                debug!("Switching to {:?}", mode);
+                self.config = self.config.with_mode(supported);
+                true
+            }
+            _ => false
+        }
+    }
+
+    fn configuration_options(&self) -> Vec<ConfigurationParameter> {
+        let mut options = vec![self.config.mtu().into()];
+        if self.config.flush_timeout() != FlushTimeout::default() {
+            options.push(self.config.flush_timeout().into());
+        }
+        if let Some(qos) = self.config.qos() {
+            options.push(qos.into());
+        }
+        if self.mode() != Mode::Basic {
+            options.push(self.local_rfc().into());
+        }
+        options
+    }
+
+    /// The parameters for frames sent by the remote device.
+    fn local_rfc(&self) -> RetransmissionAndFlowControl {
+        match self.mode() {
+            Mode::EnhancedRetransmission => RetransmissionAndFlowControl {
+                mode: Mode::EnhancedRetransmission,
+                tx_window_size: ertm::RX_WINDOW,
+                max_transmit: ertm::MAX_TRANSMIT,
+                retransmission_timeout: 0,
+                monitor_timeout: 0,
+                mps: self.config.mtu().0
+            },
+            Mode::Streaming => RetransmissionAndFlowControl {
+                mode: Mode::Streaming,
+                mps: self.config.mtu().0,
+                ..Default::default()
+            },
+            mode => RetransmissionAndFlowControl {
+                mode,
+                ..Default::default()
+            }
+        }
+    }
+
+    fn start_ertm(&mut self) {
+        if self.mode() == Mode::Basic || self.ertm.is_some() {
+            return;
+        }
+        let millis = |value: u16, default: u16| Duration::from_millis(u64::from(match value {
+            0 => default,
+            value => value
+        }));
+        self.ertm = Some(Ertm::new(Parameters {
+            mode: self.mode(),
+            tx_window: self.tx_parameters.tx_window_size,
+            rx_window: ertm::RX_WINDOW,
+            max_transmit: ertm::MAX_TRANSMIT,
+            retransmission_timeout: millis(self.tx_parameters.retransmission_timeout, ertm::RETRANSMISSION_TIMEOUT),
+            monitor_timeout: millis(self.tx_parameters.monitor_timeout, ertm::MONITOR_TIMEOUT),
+            mps: self.tx_parameters.mps,
+            mtu: self.local_mtu.0
+        }));
+    }
+
+    fn receive_frame(&mut self, data: Bytes) -> Result<Option<Event>, Error> {
+        if self.mode() == Mode::Basic {
+            return Ok(Some(Event::DataReceived(data)));
+        }
+        let Some(engine) = self.ertm.as_mut() else {
+            warn!("Dropping frame received before the channel was configured");
+            return Ok(None);
+        };
+        match ertm::decode(self.local_cid, data) {
+            Ok((control, payload)) => {
+                let result = engine.receive(Instant::now(), control, payload);
+                self.check_ertm(result)?;
             }
+            // Frames with a bad FCS are dropped and recovered by retransmission ([Vol 3] Part A, Section 8.6.1.1).
+            Err(err) => debug!("Dropping invalid frame: {:?}", err)
         }
+        // Completed SDUs stay in the engine until they are read
+        self.flush_frames()?;
+        Ok(None)
+    }
+
+    /// Hands the frames of the ERTM engine to the controller as far as the queue has room for them.
+    /// The rest is sent once the channel gets polled again.
+    fn flush_frames(&mut self) -> Result<(), Error> {
+        let Some(engine) = self.ertm.as_ref() else { return Ok(()); };
+        self.ertm_timer = engine
+            .deadline()
+            .map(|deadline| Box::pin(sleep_until(deadline)));
+        now_or_never(poll_fn(|cx| self.poll_flush(cx))).unwrap_or(Ok(()))
+    }
+
+    /// Hands the frames of the ERTM engine to the controller, taking a permit for each one just like [`write`](Self::write) in Basic mode.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let Some(engine) = self.ertm.as_mut() else { return Poll::Ready(Ok(())); };
+        while engine.has_frames() {
+            let request = self
+                .tx_permit_request
+                .get_or_insert_with(|| Box::pin(self.tx_permits.clone().acquire_owned()));
+            let permit = ready!(request.as_mut().poll(cx)).expect("The semaphore is never closed");
+            self.tx_permit_request = None;
+            let Some((control, payload)) = engine.next_frame() else { break; };
+            self.sender
+                .send_with_permit(self.connection_handle, ertm::encode(self.remote_cid, control, payload)?, permit)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns whether a timer expired.
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> Result<bool, Error> {
+        let Some(timer) = self.ertm_timer.as_mut() else { return Ok(false); };
+        if Future::poll(timer.as_mut(), cx).is_pending() {
+            return Ok(false);
+        }
+        self.ertm_timer = None;
+        if let (State::Open, Some(engine)) = (self.state, self.ertm.as_mut()) {
+            let result = engine.handle_timeout(Instant::now());
+            self.check_ertm(result)?;
+            self.flush_frames()?;
+        }
+        Ok(true)
+    }
+
+    /// Closes the channel when the remote device breaks the protocol or stops responding ([Vol 3] Part A, Section 8.6.1).
+    fn check_ertm<T>(&mut self, result: Result<T, ertm::Error>) -> Result<T, Error> {
+        result.map_err(|err| self.abort(err.into()))
+    }
+
+    /// Starts disconnecting a channel that can't be used.
+    fn abort(&mut self, error: Error) -> Error {
+        warn!("Closing channel: {}", error);
+        self.send_signaling(None, SignalingCode::DisconnectionRequest, (self.remote_cid, self.local_cid))
+            .ignore();
+        self.set_state(State::WaitDisconnect);
+        error
     }
 
     fn wait_for_connection(&mut self) -> impl Future<Output = Result<(), Error>> + '_ {
@@ -435,6 +861,29 @@ impl Channel {
         })
     }
 
+    fn wait_for_transmit_window(&mut self) -> impl Future<Output = Result<(), Error>> + '_ {
+        poll_fn(|cx| {
+            loop {
+                if let State::Closed(ClosedState::Disconnected) = self.state {
+                    return Poll::Ready(Err(Error::Disconnected));
+                }
+                if !self.ertm.as_ref().is_some_and(Ertm::is_full) {
+                    return Poll::Ready(Ok(()));
+                }
+                match self.poll_events(cx) {
+                    Poll::Ready(event) => match event? {
+                        Event::DisconnectComplete => return Poll::Ready(Err(Error::Disconnected)),
+                        Event::DataReceived(_) => warn!("Unexpected data event"),
+                        Event::ConnectionComplete | Event::ConfigurationCompete => {}
+                    },
+                    // The acknowledgments processed so far may already have made room
+                    Poll::Pending if self.ertm.as_ref().is_some_and(Ertm::is_full) => return Poll::Pending,
+                    Poll::Pending => return Poll::Ready(Ok(()))
+                }
+            }
+        })
+    }
+
     fn wait_for_disconnect(&mut self) -> impl Future<Output = Result<(), Error>> + '_ {
         poll_fn(|cx| {
             if let State::Closed(ClosedState::Disconnected) = self.state {
@@ -469,18 +918,24 @@ impl Channel {
             Some(id),
             SignalingCode::CommandReject,
             RejectReason::InvalidCid {
-                scid: self.local_cid,
-                dcid: self.remote_cid
+                local_cid: self.local_cid,
+                remote_cid: self.remote_cid
             }
         )
     }
 
+    // Requests that don't fit into the signaling MTU are split and flagged as continued ([Vol 3] Part A, Section 4.4).
     fn send_configuration_request(&self, options: Vec<ConfigurationParameter>) -> Result<(), AclSendError> {
-        self.send_signaling(None, SignalingCode::ConfigureRequest, (self.remote_cid, u16::MIN, options))
+        let mut fragments = split_options(options).into_iter().peekable();
+        while let Some(fragment) = fragments.next() {
+            let flags = u16::from(fragments.peek().is_some());
+            self.send_signaling(None, SignalingCode::ConfigureRequest, (self.remote_cid, flags, fragment))?;
+        }
+        Ok(())
     }
 
-    fn send_configuration_response(&self, id: u8, result: ConfigureResult, options: Vec<ConfigurationParameter>) -> Result<(), AclSendError> {
-        self.send_signaling(Some(id), SignalingCode::ConfigureResponse, (self.remote_cid, u16::MIN, result, options))
+    fn send_configuration_response(&self, id: u8, continuation: bool, result: ConfigureResult, options: Vec<ConfigurationParameter>) -> Result<(), AclSendError> {
+        self.send_signaling(Some(id), SignalingCode::ConfigureResponse, (self.remote_cid, u16::from(continuation), result, options))
     }
 }
 
@@ -495,6 +950,25 @@ impl Drop for Channel {
     }
 }
 
+fn split_options(options: Vec<ConfigurationParameter>) -> Vec<Vec<ConfigurationParameter>> {
+    let mut fragments = vec![Vec::new()];
+    let mut size = 0;
+    for option in options {
+        let mut buffer = BytesMut::new();
+        buffer.write_le(option);
+        if size + buffer.len() > MAX_OPTIONS_SIZE && size > 0 {
+            fragments.push(Vec::new());
+            size = 0;
+        }
+        size += buffer.len();
+        fragments
+            .last_mut()
+            .expect("There is always a fragment")
+            .push(option);
+    }
+    fragments
+}
+
 async fn timeout(duration: Duration) -> Result<(), Error> {
     sleep(duration).await;
     Err(Error::Timeout)
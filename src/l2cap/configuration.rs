@@ -15,6 +15,8 @@ pub struct Mtu(pub u16);
 
 impl Mtu {
     pub const MINIMUM_ACL_U: Self = Self(48);
+    /// Assumed when a configuration request doesn't contain an MTU.
+    pub const DEFAULT_ACL_U: Self = Self(672);
 }
 
 impl Default for Mtu {
@@ -121,6 +123,37 @@ pub enum Mode {
     Streaming = 0x04
 }
 
+/// The modes this implementation can operate a channel in.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SupportedMode {
+    #[default]
+    Basic,
+    EnhancedRetransmission,
+    Streaming
+}
+
+impl SupportedMode {
+    /// Returns `None` for the retransmission and flow control modes, which are superseded by Enhanced Retransmission Mode.
+    pub fn new(mode: Mode) -> Option<Self> {
+        match mode {
+            Mode::Basic => Some(Self::Basic),
+            Mode::EnhancedRetransmission => Some(Self::EnhancedRetransmission),
+            Mode::Streaming => Some(Self::Streaming),
+            Mode::Retransmission | Mode::FlowControl => None
+        }
+    }
+}
+
+impl From<SupportedMode> for Mode {
+    fn from(mode: SupportedMode) -> Self {
+        match mode {
+            SupportedMode::Basic => Mode::Basic,
+            SupportedMode::EnhancedRetransmission => Mode::EnhancedRetransmission,
+            SupportedMode::Streaming => Mode::Streaming
+        }
+    }
+}
+
 // ([Vol 3] Part A, Section 5.4)
 // The Basic L2CAP mode is the default. If Basic L2CAP mode is requested then all other parameters shall be ignored.
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
@@ -232,6 +265,13 @@ pub enum ConfigurationParameter {
     Unknown(u8)
 }
 
+impl ConfigurationParameter {
+    /// Unknown hints can be ignored, all other unknown options have to be refused ([Vol 3] Part A, Section 5).
+    pub fn is_hint(&self) -> bool {
+        matches!(self, ConfigurationParameter::Unknown(ty) if ty & 0x80 != 0)
+    }
+}
+
 impl Instruct<LittleEndian> for ConfigurationParameter {
     fn write_to_buffer<B: BufferMut>(&self, buffer: &mut B) {
         match self {
@@ -270,7 +310,8 @@ impl Instruct<LittleEndian> for ConfigurationParameter {
                 buffer.write_le_ref(&ExtendedWindowSize::LENGTH);
                 buffer.write_le_ref(value);
             }
-            ConfigurationParameter::Unknown(_) => {}
+            // Only sent back in UnknownOptions responses ([Vol 3] Part A, Section 4.5).
+            ConfigurationParameter::Unknown(ty) => buffer.write_le_ref(ty)
         }
     }
 }
@@ -308,12 +349,11 @@ impl Exstruct<LittleEndian> for ConfigurationParameter {
                 ensure!(len == ExtendedWindowSize::LENGTH, Error::InvalidValue);
                 Ok(ConfigurationParameter::ExtendedWindowSize(buffer.read_le()?))
             }
-            0x80..=0xFF => {
+            _ => {
                 debug!("Unsupported option: {:02X}", ty);
                 buffer.skip(len as usize)?;
                 Ok(ConfigurationParameter::Unknown(ty))
             }
-            _ => Err(Error::InvalidValue)
         }
     }
 }
@@ -360,13 +400,91 @@ impl From<ExtendedWindowSize> for ConfigurationParameter {
     }
 }
 
+/// The parameters a channel requests during configuration ([Vol 3] Part A, Section 7.1).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ChannelConfig {
+    mtu: Mtu,
+    min_remote_mtu: Mtu,
+    flush_timeout: FlushTimeout,
+    qos: Option<QualityOfService>,
+    mode: SupportedMode
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            mtu: Mtu(1691),
+            min_remote_mtu: Mtu::MINIMUM_ACL_U,
+            flush_timeout: FlushTimeout::default(),
+            qos: None,
+            mode: SupportedMode::default()
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// The largest SDU this side accepts. Range: 48-65535 bytes.
+    pub fn with_mtu(mut self, mtu: u16) -> Self {
+        assert!(mtu >= Mtu::MINIMUM_ACL_U.0, "MTU below the minimum");
+        self.mtu = Mtu(mtu);
+        self
+    }
+
+    /// Asks the remote device to raise its MTU when it is smaller, e.g. to fit a whole media packet into one SDU.
+    pub fn with_min_remote_mtu(mut self, mtu: u16) -> Self {
+        assert!(mtu >= Mtu::MINIMUM_ACL_U.0, "MTU below the minimum");
+        self.min_remote_mtu = Mtu(mtu);
+        self
+    }
+
+    /// How long this side tries to deliver a packet before it gets flushed.
+    pub fn with_flush_timeout(mut self, timeout: FlushTimeout) -> Self {
+        self.flush_timeout = timeout;
+        self
+    }
+
+    /// Describes the traffic this side is going to send.
+    pub fn with_qos(mut self, qos: QualityOfService) -> Self {
+        self.qos = Some(qos);
+        self
+    }
+
+    /// Requests Enhanced Retransmission or Streaming Mode.
+    /// Channels in Basic mode switch to the mode the remote device asks for.
+    pub fn with_mode(mut self, mode: SupportedMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mtu(&self) -> Mtu {
+        self.mtu
+    }
+
+    pub fn min_remote_mtu(&self) -> Mtu {
+        self.min_remote_mtu
+    }
+
+    pub fn flush_timeout(&self) -> FlushTimeout {
+        self.flush_timeout
+    }
+
+    pub fn qos(&self) -> Option<QualityOfService> {
+        self.qos
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode.into()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
     use instructor::BufferMut;
 
     use crate::l2cap::configuration::{
-        ConfigurationOption, ExtendedFlowSpecification, ExtendedWindowSize, Fcs, FlushTimeout, Mtu, QualityOfService, RetransmissionAndFlowControl
+        ConfigurationOption, ExtendedFlowSpecification, ExtendedWindowSize, Fcs, FlushTimeout, Mode, Mtu, QualityOfService,
+        RetransmissionAndFlowControl, SupportedMode
     };
 
     #[test]
@@ -385,4 +503,13 @@ mod tests {
         check_size::<ExtendedFlowSpecification>();
         check_size::<ExtendedWindowSize>();
     }
+
+    #[test]
+    fn test_supported_modes() {
+        for mode in [SupportedMode::Basic, SupportedMode::EnhancedRetransmission, SupportedMode::Streaming] {
+            assert_eq!(SupportedMode::new(mode.into()), Some(mode));
+        }
+        assert_eq!(SupportedMode::new(Mode::Retransmission), None);
+        assert_eq!(SupportedMode::new(Mode::FlowControl), None);
+    }
 }
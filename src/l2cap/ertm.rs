@@ -0,0 +1,679 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use instructor::utils::Length;
+use instructor::{Buffer, BufferMut, Exstruct, Instruct, LittleEndian};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::ensure;
+use crate::l2cap::configuration::Mode;
+use crate::l2cap::L2capHeader;
+
+/// The receive window this side advertises. Small enough to tell retransmitted frames apart from new ones.
+pub const RX_WINDOW: u8 = 32;
+pub const MAX_TRANSMIT: u8 = 3;
+/// The default timeouts in milliseconds the responder assigns to the requester ([Vol 3] Part A, Section 8.6.2).
+pub const RETRANSMISSION_TIMEOUT: u16 = 2000;
+pub const MONITOR_TIMEOUT: u16 = 12000;
+
+const SEQ_MODULO: u8 = 64;
+const CONTROL_SIZE: usize = 2;
+const SDU_LENGTH_SIZE: usize = 2;
+const FCS_SIZE: usize = 2;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum Error {
+    #[error("The remote device acknowledged a frame that was never sent")]
+    InvalidReqSeq,
+    #[error("The remote device sent a frame outside of the receive window")]
+    InvalidTxSeq,
+    #[error("The remote device sent an invalid sequence of segments")]
+    InvalidSegmentation,
+    #[error("The remote device sent an SDU larger than the MTU")]
+    SduTooLarge,
+    #[error("A frame was not acknowledged within the maximum number of transmissions")]
+    MaxTransmitExceeded
+}
+
+// ([Vol 3] Part A, Section 3.3.2).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Sar {
+    Unsegmented = 0b00,
+    Start = 0b01,
+    End = 0b10,
+    Continuation = 0b11
+}
+
+impl Sar {
+    fn from_bits(bits: u16) -> Self {
+        match bits & 0b11 {
+            0b00 => Sar::Unsegmented,
+            0b01 => Sar::Start,
+            0b10 => Sar::End,
+            _ => Sar::Continuation
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SupervisoryFunction {
+    ReceiverReady = 0b00,
+    Reject = 0b01,
+    ReceiverNotReady = 0b10,
+    SelectiveReject = 0b11
+}
+
+impl SupervisoryFunction {
+    fn from_bits(bits: u16) -> Self {
+        match bits & 0b11 {
+            0b00 => SupervisoryFunction::ReceiverReady,
+            0b01 => SupervisoryFunction::Reject,
+            0b10 => SupervisoryFunction::ReceiverNotReady,
+            _ => SupervisoryFunction::SelectiveReject
+        }
+    }
+}
+
+/// The standard control field of I-frames and S-frames ([Vol 3] Part A, Section 3.3.2).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Control {
+    Information {
+        tx_seq: u8,
+        req_seq: u8,
+        fin: bool,
+        sar: Sar
+    },
+    Supervisory {
+        function: SupervisoryFunction,
+        req_seq: u8,
+        poll: bool,
+        fin: bool
+    }
+}
+
+impl Instruct<LittleEndian> for Control {
+    fn write_to_buffer<B: BufferMut>(&self, buffer: &mut B) {
+        let bits = match *self {
+            Control::Information { tx_seq, req_seq, fin, sar } => {
+                debug_assert!(tx_seq < SEQ_MODULO && req_seq < SEQ_MODULO);
+                (u16::from(tx_seq) << 1) | (u16::from(fin) << 7) | (u16::from(req_seq) << 8) | ((sar as u16) << 14)
+            }
+            Control::Supervisory { function, req_seq, poll, fin } => {
+                debug_assert!(req_seq < SEQ_MODULO);
+                0x0001 | ((function as u16) << 2) | (u16::from(poll) << 4) | (u16::from(fin) << 7) | (u16::from(req_seq) << 8)
+            }
+        };
+        buffer.write_le(bits);
+    }
+}
+
+impl Exstruct<LittleEndian> for Control {
+    fn read_from_buffer<B: Buffer>(buffer: &mut B) -> Result<Self, instructor::Error> {
+        let bits: u16 = buffer.read_le()?;
+        let req_seq = (bits >> 8) as u8 & 0x3F;
+        let fin = bits & 0x0080 != 0;
+        match bits & 0x0001 {
+            0 => Ok(Control::Information {
+                tx_seq: (bits >> 1) as u8 & 0x3F,
+                req_seq,
+                fin,
+                sar: Sar::from_bits(bits >> 14)
+            }),
+            _ => Ok(Control::Supervisory {
+                function: SupervisoryFunction::from_bits(bits >> 2),
+                req_seq,
+                poll: bits & 0x0010 != 0,
+                fin
+            })
+        }
+    }
+}
+
+/// CRC-16 with the generator polynomial x^16 + x^15 + x^2 + 1 ([Vol 3] Part A, Section 3.3.5).
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = match crc & 0x0001 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xA001
+            };
+        }
+    }
+    crc
+}
+
+/// Builds a complete PDU including the basic L2CAP header and the FCS ([Vol 3] Part A, Section 3.3).
+pub fn encode(cid: u16, control: Control, payload: Bytes) -> Result<Bytes, instructor::Error> {
+    let mut buffer = BytesMut::new();
+    buffer.write_le(L2capHeader {
+        len: Length::new(CONTROL_SIZE + payload.len() + FCS_SIZE)?,
+        cid
+    });
+    buffer.write_le(control);
+    buffer.put(payload);
+    let fcs = crc16(0, &buffer);
+    buffer.write_le(fcs);
+    Ok(buffer.freeze())
+}
+
+/// Checks the FCS of a received PDU (without the basic L2CAP header) and splits it into control field and payload.
+pub fn decode(cid: u16, mut data: Bytes) -> Result<(Control, Bytes), instructor::Error> {
+    ensure!(data.len() >= CONTROL_SIZE + FCS_SIZE, instructor::Error::TooShort);
+    let mut header = BytesMut::new();
+    header.write_le(L2capHeader {
+        len: Length::new(data.len())?,
+        cid
+    });
+    let mut fcs = data.split_off(data.len() - FCS_SIZE);
+    let expected = crc16(crc16(0, &header), &data);
+    ensure!(fcs.read_le::<u16>()? == expected, instructor::Error::InvalidValue);
+    let control: Control = data.read_le()?;
+    Ok((control, data))
+}
+
+/// `a - b` in sequence number space.
+fn seq_offset(a: u8, b: u8) -> u8 {
+    a.wrapping_sub(b) % SEQ_MODULO
+}
+
+fn next_seq(seq: u8) -> u8 {
+    (seq + 1) % SEQ_MODULO
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Parameters {
+    /// Either [`Mode::EnhancedRetransmission`] or [`Mode::Streaming`].
+    pub mode: Mode,
+    /// The number of unacknowledged frames the remote device can buffer.
+    pub tx_window: u8,
+    /// The number of unacknowledged frames this side can buffer.
+    pub rx_window: u8,
+    /// How often a frame is sent before the channel is given up. `0` retries forever.
+    pub max_transmit: u8,
+    pub retransmission_timeout: Duration,
+    pub monitor_timeout: Duration,
+    /// The largest payload the remote device accepts in a single frame.
+    pub mps: u16,
+    /// The largest SDU this side accepts.
+    pub mtu: u16
+}
+
+struct Frame {
+    tx_seq: u8,
+    sar: Sar,
+    payload: Bytes,
+    transmissions: u8
+}
+
+/// The data transfer state machine of Enhanced Retransmission Mode ([Vol 3] Part A, Section 8.6).
+///
+/// Also handles Streaming Mode, which shares the frame format but never acknowledges or retransmits frames.
+/// Lost frames are recovered with go-back-n (REJ) and polling, selective rejects from the remote device are honored.
+/// The state machine does no I/O: outgoing frames are collected with [`next_frame`](Self::next_frame),
+/// completed SDUs with [`next_sdu`](Self::next_sdu) and timers are driven by [`handle_timeout`](Self::handle_timeout).
+pub struct Ertm {
+    params: Parameters,
+
+    next_tx_seq: u8,
+    expected_ack_seq: u8,
+    unacked: VecDeque<Frame>,
+    pending: VecDeque<(Sar, Bytes)>,
+    remote_busy: bool,
+    wait_final: bool,
+    retry_count: u8,
+    retransmission_timer: Option<Instant>,
+    monitor_timer: Option<Instant>,
+
+    expected_tx_seq: u8,
+    reject_sent: bool,
+    ack_pending: bool,
+    sdu: Option<(usize, BytesMut)>,
+
+    received: VecDeque<Bytes>,
+    outgoing: VecDeque<(Control, Bytes)>
+}
+
+impl Ertm {
+    pub fn new(params: Parameters) -> Self {
+        debug_assert!(matches!(params.mode, Mode::EnhancedRetransmission | Mode::Streaming));
+        Self {
+            params,
+            next_tx_seq: 0,
+            expected_ack_seq: 0,
+            unacked: VecDeque::new(),
+            pending: VecDeque::new(),
+            remote_busy: false,
+            wait_final: false,
+            retry_count: 0,
+            retransmission_timer: None,
+            monitor_timer: None,
+            expected_tx_seq: 0,
+            reject_sent: false,
+            ack_pending: false,
+            sdu: None,
+            received: VecDeque::new(),
+            outgoing: VecDeque::new()
+        }
+    }
+
+    fn is_streaming(&self) -> bool {
+        self.params.mode == Mode::Streaming
+    }
+
+    /// The next frame to send to the remote device.
+    pub fn next_frame(&mut self) -> Option<(Control, Bytes)> {
+        self.outgoing.pop_front()
+    }
+
+    /// Whether frames are waiting to be sent.
+    pub fn has_frames(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Whether as many frames wait for the transmit window as fit into it.
+    /// No further SDUs should be sent until acknowledgments make room, otherwise the queue grows without bound.
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= usize::from(self.params.tx_window.max(1))
+    }
+
+    /// The next completely reassembled SDU.
+    pub fn next_sdu(&mut self) -> Option<Bytes> {
+        self.received.pop_front()
+    }
+
+    /// When [`handle_timeout`](Self::handle_timeout) has to be called next.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.monitor_timer.or(self.retransmission_timer)
+    }
+
+    /// Segments `sdu` into frames of at most MPS bytes and sends as many of them as the transmit window allows.
+    pub fn send(&mut self, now: Instant, mut sdu: Bytes) {
+        let mps = usize::from(self.params.mps).max(SDU_LENGTH_SIZE + 1);
+        if sdu.len() <= mps {
+            self.pending.push_back((Sar::Unsegmented, sdu));
+        } else {
+            let mut start = BytesMut::with_capacity(mps);
+            start.write_le(sdu.len() as u16);
+            start.put(sdu.split_to(mps - SDU_LENGTH_SIZE));
+            self.pending.push_back((Sar::Start, start.freeze()));
+            while sdu.len() > mps {
+                self.pending.push_back((Sar::Continuation, sdu.split_to(mps)));
+            }
+            self.pending.push_back((Sar::End, sdu));
+        }
+        self.transmit_pending(now);
+    }
+
+    pub fn receive(&mut self, now: Instant, control: Control, payload: Bytes) -> Result<(), Error> {
+        match control {
+            Control::Information { tx_seq, req_seq, fin, sar } => {
+                if !self.is_streaming() {
+                    self.process_req_seq(now, req_seq)?;
+                    if fin {
+                        self.process_final(now)?;
+                    }
+                }
+                self.receive_information(tx_seq, sar, payload)?;
+            }
+            Control::Supervisory { .. } if self.is_streaming() => {
+                debug!("Ignoring S-frame in streaming mode");
+            }
+            Control::Supervisory { function, req_seq, poll, fin } => {
+                // The ReqSeq of a SREJ names the missing frame and only acknowledges frames when polling
+                if function != SupervisoryFunction::SelectiveReject || poll {
+                    self.process_req_seq(now, req_seq)?;
+                }
+                self.remote_busy = function == SupervisoryFunction::ReceiverNotReady;
+                let retransmitted = fin && self.wait_final;
+                if fin {
+                    self.process_final(now)?;
+                }
+                match function {
+                    SupervisoryFunction::Reject if !retransmitted => self.retransmit_all(now)?,
+                    SupervisoryFunction::SelectiveReject => self.retransmit(now, req_seq)?,
+                    _ => {}
+                }
+                if poll {
+                    self.ack_pending = false;
+                    let response = self.supervisory(SupervisoryFunction::ReceiverReady, false, true);
+                    self.outgoing.push_back((response, Bytes::new()));
+                }
+            }
+        }
+        self.transmit_pending(now);
+        if self.ack_pending {
+            self.ack_pending = false;
+            let ack = self.supervisory(SupervisoryFunction::ReceiverReady, false, false);
+            self.outgoing.push_back((ack, Bytes::new()));
+        }
+        Ok(())
+    }
+
+    /// Polls the remote device when frames stay unacknowledged and gives up after [`Parameters::max_transmit`] polls.
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<(), Error> {
+        if self.monitor_timer.is_some_and(|deadline| deadline <= now) {
+            ensure!(self.params.max_transmit == 0 || self.retry_count < self.params.max_transmit, Error::MaxTransmitExceeded);
+            self.retry_count = self.retry_count.saturating_add(1);
+            self.send_poll(now);
+        } else if self.retransmission_timer.is_some_and(|deadline| deadline <= now) {
+            self.retransmission_timer = None;
+            self.wait_final = true;
+            self.retry_count = 1;
+            self.send_poll(now);
+        }
+        Ok(())
+    }
+
+    fn send_poll(&mut self, now: Instant) {
+        self.ack_pending = false;
+        let poll = self.supervisory(SupervisoryFunction::ReceiverReady, true, false);
+        self.outgoing.push_back((poll, Bytes::new()));
+        self.monitor_timer = Some(now + self.params.monitor_timeout);
+    }
+
+    fn supervisory(&self, function: SupervisoryFunction, poll: bool, fin: bool) -> Control {
+        Control::Supervisory {
+            function,
+            req_seq: self.expected_tx_seq,
+            poll,
+            fin
+        }
+    }
+
+    fn can_transmit(&self) -> bool {
+        self.is_streaming() || (!self.wait_final && !self.remote_busy && self.unacked.len() < usize::from(self.params.tx_window))
+    }
+
+    fn transmit_pending(&mut self, now: Instant) {
+        while self.can_transmit() {
+            let Some((sar, payload)) = self.pending.pop_front() else { break; };
+            let tx_seq = self.next_tx_seq;
+            self.next_tx_seq = next_seq(tx_seq);
+            if self.is_streaming() {
+                self.outgoing
+                    .push_back((Control::Information { tx_seq, req_seq: 0, fin: false, sar }, payload));
+            } else {
+                let control = Control::Information { tx_seq, req_seq: self.expected_tx_seq, fin: false, sar };
+                self.outgoing.push_back((control, payload.clone()));
+                self.unacked.push_back(Frame { tx_seq, sar, payload, transmissions: 1 });
+                self.ack_pending = false;
+                if self.retransmission_timer.is_none() {
+                    self.retransmission_timer = Some(now + self.params.retransmission_timeout);
+                }
+            }
+        }
+    }
+
+    fn process_req_seq(&mut self, now: Instant, req_seq: u8) -> Result<(), Error> {
+        let acked = usize::from(seq_offset(req_seq, self.expected_ack_seq));
+        ensure!(acked <= self.unacked.len(), Error::InvalidReqSeq);
+        self.unacked.drain(..acked);
+        self.expected_ack_seq = req_seq;
+        if acked > 0 && !self.wait_final {
+            self.retransmission_timer = match self.unacked.is_empty() {
+                true => None,
+                false => Some(now + self.params.retransmission_timeout)
+            };
+        }
+        Ok(())
+    }
+
+    fn process_final(&mut self, now: Instant) -> Result<(), Error> {
+        if self.wait_final {
+            // Everything that is still unacknowledged after the poll got lost
+            self.wait_final = false;
+            self.monitor_timer = None;
+            self.retry_count = 0;
+            self.retransmit_all(now)?;
+        }
+        Ok(())
+    }
+
+    fn retransmit_all(&mut self, now: Instant) -> Result<(), Error> {
+        for index in 0..self.unacked.len() {
+            self.retransmit_frame(index)?;
+        }
+        if !self.unacked.is_empty() && !self.wait_final {
+            self.retransmission_timer = Some(now + self.params.retransmission_timeout);
+        }
+        Ok(())
+    }
+
+    fn retransmit(&mut self, now: Instant, tx_seq: u8) -> Result<(), Error> {
+        let index = usize::from(seq_offset(tx_seq, self.expected_ack_seq));
+        ensure!(index < self.unacked.len(), Error::InvalidReqSeq);
+        self.retransmit_frame(index)?;
+        if !self.wait_final {
+            self.retransmission_timer = Some(now + self.params.retransmission_timeout);
+        }
+        Ok(())
+    }
+
+    fn retransmit_frame(&mut self, index: usize) -> Result<(), Error> {
+        let req_seq = self.expected_tx_seq;
+        let frame = &mut self.unacked[index];
+        ensure!(self.params.max_transmit == 0 || frame.transmissions < self.params.max_transmit, Error::MaxTransmitExceeded);
+        frame.transmissions += 1;
+        let control = Control::Information { tx_seq: frame.tx_seq, req_seq, fin: false, sar: frame.sar };
+        self.outgoing.push_back((control, frame.payload.clone()));
+        self.ack_pending = false;
+        Ok(())
+    }
+
+    fn receive_information(&mut self, tx_seq: u8, sar: Sar, payload: Bytes) -> Result<(), Error> {
+        if self.is_streaming() {
+            // Lost frames are never resent, so the SDU they belong to can't be completed
+            if tx_seq != self.expected_tx_seq && self.sdu.take().is_some() {
+                debug!("Dropping incomplete SDU");
+            }
+            self.expected_tx_seq = next_seq(tx_seq);
+            if let Err(err) = self.reassemble(sar, payload) {
+                debug!("Dropping SDU: {}", err);
+            }
+            return Ok(());
+        }
+        let ahead = seq_offset(tx_seq, self.expected_tx_seq);
+        let behind = seq_offset(self.expected_tx_seq, tx_seq);
+        if ahead == 0 {
+            self.expected_tx_seq = next_seq(tx_seq);
+            self.reject_sent = false;
+            self.ack_pending = true;
+            self.reassemble(sar, payload)
+        } else if ahead < self.params.rx_window {
+            // A frame got lost, ask for everything starting with the missing one
+            if !self.reject_sent {
+                self.reject_sent = true;
+                let reject = self.supervisory(SupervisoryFunction::Reject, false, false);
+                self.outgoing.push_back((reject, Bytes::new()));
+            }
+            Ok(())
+        } else if behind <= self.params.rx_window {
+            // A retransmission of a frame whose acknowledgement got lost
+            self.ack_pending = true;
+            Ok(())
+        } else {
+            Err(Error::InvalidTxSeq)
+        }
+    }
+
+    fn reassemble(&mut self, sar: Sar, mut payload: Bytes) -> Result<(), Error> {
+        let mtu = usize::from(self.params.mtu);
+        match (sar, self.sdu.take()) {
+            (Sar::Unsegmented, None) => {
+                ensure!(payload.len() <= mtu, Error::SduTooLarge);
+                self.received.push_back(payload);
+            }
+            (Sar::Start, None) => {
+                let length: u16 = payload
+                    .read_le()
+                    .map_err(|_| Error::InvalidSegmentation)?;
+                let length = usize::from(length);
+                ensure!(length <= mtu, Error::SduTooLarge);
+                ensure!(payload.len() < length, Error::InvalidSegmentation);
+                let mut buffer = BytesMut::with_capacity(length);
+                buffer.put(payload);
+                self.sdu = Some((length, buffer));
+            }
+            (Sar::Continuation, Some((length, mut buffer))) => {
+                buffer.put(payload);
+                ensure!(buffer.len() < length, Error::InvalidSegmentation);
+                self.sdu = Some((length, buffer));
+            }
+            (Sar::End, Some((length, mut buffer))) => {
+                buffer.put(payload);
+                ensure!(buffer.len() == length, Error::InvalidSegmentation);
+                self.received.push_back(buffer.freeze());
+            }
+            _ => return Err(Error::InvalidSegmentation)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::time::Instant;
+
+    use crate::l2cap::configuration::Mode;
+    use crate::l2cap::ertm::{crc16, decode, encode, Control, Ertm, Error, Parameters, Sar, SupervisoryFunction};
+
+    fn parameters(mode: Mode) -> Parameters {
+        Parameters {
+            mode,
+            tx_window: 4,
+            rx_window: 4,
+            max_transmit: 3,
+            retransmission_timeout: Duration::from_secs(2),
+            monitor_timeout: Duration::from_secs(12),
+            mps: 8,
+            mtu: 64
+        }
+    }
+
+    fn frames(ertm: &mut Ertm) -> Vec<(Control, Bytes)> {
+        std::iter::from_fn(|| ertm.next_frame()).collect()
+    }
+
+    fn deliver(now: Instant, frames: Vec<(Control, Bytes)>, to: &mut Ertm) {
+        for (control, payload) in frames {
+            to.receive(now, control, payload).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fcs() {
+        assert_eq!(crc16(0, b"123456789"), 0xBB3D);
+
+        let frame = encode(0x0040, Control::Information { tx_seq: 5, req_seq: 9, fin: true, sar: Sar::End }, Bytes::from_static(b"data")).unwrap();
+        assert_eq!(&frame[..6], &[0x08, 0x00, 0x40, 0x00, 0x8A, 0x89]);
+        let (control, payload) = decode(0x0040, frame.slice(4..)).unwrap();
+        assert_eq!(control, Control::Information { tx_seq: 5, req_seq: 9, fin: true, sar: Sar::End });
+        assert_eq!(payload, Bytes::from_static(b"data"));
+
+        let mut corrupted = frame.slice(4..).to_vec();
+        corrupted[3] ^= 0x01;
+        assert!(decode(0x0040, Bytes::from(corrupted)).is_err());
+        assert!(decode(0x0041, frame.slice(4..)).is_err());
+    }
+
+    #[test]
+    fn test_segmentation() {
+        let now = Instant::now();
+        let mut a = Ertm::new(parameters(Mode::EnhancedRetransmission));
+        let mut b = Ertm::new(parameters(Mode::EnhancedRetransmission));
+        let sdu = Bytes::from_static(b"a message that needs several frames");
+
+        // The window limits the frames in flight
+        a.send(now, sdu.clone());
+        assert!(!a.is_full());
+        let sent = frames(&mut a);
+        assert_eq!(sent.len(), 4);
+        assert!(!a.has_frames());
+        assert!(sent.iter().all(|(_, payload)| payload.len() <= 8));
+        deliver(now, sent, &mut b);
+        assert_eq!(b.next_sdu(), None);
+
+        loop {
+            let acks = frames(&mut b);
+            if acks.is_empty() {
+                break;
+            }
+            deliver(now, acks, &mut a);
+            deliver(now, frames(&mut a), &mut b);
+        }
+        assert_eq!(b.next_sdu(), Some(sdu));
+        assert_eq!(a.deadline(), None);
+
+        // Frames that don't fit into the window anymore fill up the queue
+        a.send(now, Bytes::from_static(&[0x55; 32]));
+        assert!(!a.is_full());
+        a.send(now, Bytes::from_static(&[0x55; 32]));
+        assert!(a.is_full());
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let now = Instant::now();
+        let mut a = Ertm::new(parameters(Mode::EnhancedRetransmission));
+        let mut b = Ertm::new(parameters(Mode::EnhancedRetransmission));
+        a.send(now, Bytes::from_static(b"first"));
+        a.send(now, Bytes::from_static(b"second"));
+        a.send(now, Bytes::from_static(b"third"));
+
+        // Losing the first frame makes the receiver reject the next one
+        let mut sent = frames(&mut a);
+        sent.remove(0);
+        deliver(now, sent, &mut b);
+        let reject = frames(&mut b);
+        assert!(matches!(reject[..], [(Control::Supervisory { function: SupervisoryFunction::Reject, req_seq: 0, .. }, _)]));
+        deliver(now, reject, &mut a);
+        deliver(now, frames(&mut a), &mut b);
+        assert_eq!(b.next_sdu(), Some(Bytes::from_static(b"first")));
+        assert_eq!(b.next_sdu(), Some(Bytes::from_static(b"second")));
+        assert_eq!(b.next_sdu(), Some(Bytes::from_static(b"third")));
+
+        // Lost acknowledgements are recovered by polling
+        let _ = frames(&mut b);
+        let deadline = a.deadline().unwrap();
+        a.handle_timeout(deadline).unwrap();
+        let poll = frames(&mut a);
+        assert!(matches!(poll[..], [(Control::Supervisory { poll: true, req_seq: 0, .. }, _)]));
+        deliver(now, poll, &mut b);
+        deliver(now, frames(&mut b), &mut a);
+        assert!(frames(&mut a).is_empty());
+        assert_eq!(a.deadline(), None);
+
+        // Without any answer the channel is given up
+        a.send(now, Bytes::from_static(b"lost"));
+        let _ = frames(&mut a);
+        for _ in 0..3 {
+            a.handle_timeout(a.deadline().unwrap()).unwrap();
+        }
+        assert_eq!(a.handle_timeout(a.deadline().unwrap()), Err(Error::MaxTransmitExceeded));
+    }
+
+    #[test]
+    fn test_streaming() {
+        let now = Instant::now();
+        let mut a = Ertm::new(parameters(Mode::Streaming));
+        let mut b = Ertm::new(parameters(Mode::Streaming));
+        a.send(now, Bytes::from_static(b"a segmented message"));
+        a.send(now, Bytes::from_static(b"short"));
+
+        // Frames are never held back and the incomplete SDU is dropped
+        let mut sent = frames(&mut a);
+        assert_eq!(sent.len(), 4);
+        sent.remove(1);
+        deliver(now, sent, &mut b);
+        assert_eq!(b.next_sdu(), Some(Bytes::from_static(b"short")));
+        assert_eq!(b.next_sdu(), None);
+        assert!(frames(&mut b).is_empty());
+        assert_eq!(a.deadline(), None);
+    }
+}
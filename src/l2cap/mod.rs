@@ -1,5 +1,6 @@
 pub mod channel;
 pub mod configuration;
+pub mod ertm;
 pub mod signaling;
 
 use std::collections::BTreeMap;
@@ -9,18 +10,25 @@ use std::pin::Pin;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use instructor::utils::Length;
-use instructor::{Buffer, Exstruct, Instruct};
+use instructor::{Buffer, BufferMut, Exstruct, Instruct, LittleEndian};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender as MpscSender};
-use tracing::{debug, warn};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Instant};
+use tracing::{debug, trace, warn};
 
 use crate::hci::acl::{AclDataAssembler, AclHeader};
+use crate::hci::connection::ConnectionManager;
+use crate::hci::security::SecurityLevel;
 use crate::hci::consts::{ConnectionMode, EventCode, LinkType, RemoteAddr, Status};
+use crate::ensure;
 use crate::hci::{AclSender, Error, Hci};
-use crate::l2cap::channel::Channel;
-use crate::l2cap::configuration::ConfigurationParameter;
+use crate::l2cap::channel::{Channel, Error as ChannelError};
+use crate::l2cap::configuration::{ChannelConfig, ConfigurationParameter, Mode, SupportedMode};
+use crate::l2cap::signaling::{ExtendedFeatures, FixedChannels, Psm, SignalingCode, SignalingContext, INFO_EXTENDED_FEATURES, INFO_FIXED_CHANNELS};
 
 pub const SDP_PSM: u16 = 0x0001;
 pub const AVCTP_PSM: u16 = 0x0017;
@@ -28,11 +36,22 @@ pub const AVDTP_PSM: u16 = 0x0019;
 
 const CID_ID_NONE: u16 = 0x0000;
 const CID_ID_SIGNALING: u16 = 0x0001;
+const CID_ID_CONNECTIONLESS: u16 = 0x0002;
+const CID_RANGE_FIXED: Range<u16> = 0x0003..0x0040;
 const CID_RANGE_DYNAMIC: Range<u16> = 0x0040..0xFFFF;
 
+/// The largest connectionless payload that is accepted, without the PSM ([Vol 3] Part A, Section 4.11).
+pub const CONNECTIONLESS_MTU: u16 = 1024;
+
+/// How long to wait for the response to an echo or information request (RTX, [Vol 3] Part A, Section 6.2.1).
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Default)]
 pub struct L2capServerBuilder {
-    handlers: BTreeMap<u64, Arc<dyn ProtocolHandler>>
+    handlers: BTreeMap<u64, Arc<dyn ProtocolHandler>>,
+    fixed_channels: BTreeMap<u16, Arc<dyn FixedChannelHandler>>,
+    connectionless: BTreeMap<u64, Arc<dyn ConnectionlessHandler>>,
+    connection_manager: Option<ConnectionManager>
 }
 
 impl L2capServerBuilder {
@@ -43,6 +62,27 @@ impl L2capServerBuilder {
         self
     }
 
+    /// Receives the data of a fixed channel and advertises it to remote devices.
+    pub fn with_fixed_channel<H: FixedChannelHandler + 'static>(mut self, handler: H) -> Self {
+        let cid = handler.cid();
+        assert!(CID_RANGE_FIXED.contains(&cid), "Not a fixed channel: {:04X}", cid);
+        assert!(self.fixed_channels.insert(cid, Arc::new(handler)).is_none(), "Duplicate fixed channels");
+        self
+    }
+
+    /// Receives connectionless data sent to the PSM of the handler and enables the connectionless channel.
+    pub fn with_connectionless<H: ConnectionlessHandler + 'static>(mut self, handler: H) -> Self {
+        assert!(self.connectionless.insert(handler.psm(), Arc::new(handler)).is_none(), "Duplicate PSMs");
+        self
+    }
+
+    /// Secures links for protocols with a [`security_requirement`](ProtocolHandler::security_requirement)
+    /// and enforces the [`SecurityPolicy`](crate::hci::security::SecurityPolicy) of `manager`.
+    pub fn with_connection_manager(mut self, manager: ConnectionManager) -> Self {
+        self.connection_manager = Some(manager);
+        self
+    }
+
     pub fn run(self, hci: &Hci) -> Result<L2capServer, Error> {
         let data = {
             let (tx, rx) = unbounded_channel();
@@ -58,13 +98,20 @@ impl L2capServerBuilder {
             rx
         };
         let sender = hci.get_acl_sender();
+        let (ctl_out, ctl_in) = unbounded_channel();
         Ok(L2capServer {
             data,
             events,
+            ctl_in,
+            ctl_out,
             sender,
             connections: Default::default(),
             handlers: self.handlers,
+            fixed_channels: self.fixed_channels,
+            connectionless: self.connectionless,
+            connection_manager: self.connection_manager,
             channels: Default::default(),
+            pending_requests: Default::default(),
             next_signaling_id: Default::default(),
         })
     }
@@ -78,18 +125,26 @@ struct PhysicalConnection {
     max_slots: u8,
     mode: ConnectionMode,
     addr: RemoteAddr,
-    assembler: AclDataAssembler
+    assembler: AclDataAssembler,
+    extended_features: Option<ExtendedFeatures>,
+    fixed_channels: Option<FixedChannels>
 }
 
 #[must_use = "Futures do nothing unless you `.await` or poll them"]
 pub struct L2capServer {
     data: UnboundedReceiver<Bytes>,
     events: UnboundedReceiver<(EventCode, Bytes)>,
+    ctl_in: UnboundedReceiver<ServerCommand>,
+    ctl_out: MpscSender<ServerCommand>,
 
     sender: AclSender,
     connections: BTreeMap<u16, PhysicalConnection>,
     handlers: BTreeMap<u64, Arc<dyn ProtocolHandler>>,
+    fixed_channels: BTreeMap<u16, Arc<dyn FixedChannelHandler>>,
+    connectionless: BTreeMap<u64, Arc<dyn ConnectionlessHandler>>,
+    connection_manager: Option<ConnectionManager>,
     channels: BTreeMap<u16, MpscSender<ChannelEvent>>,
+    pending_requests: BTreeMap<(u16, u8), PendingRequest>,
     next_signaling_id: SignalingIds
 }
 
@@ -107,11 +162,94 @@ impl Future for L2capServer {
            self.handle_event(event)
                .unwrap_or_else(|err| warn!("Error handling event: {:?}", err));
         }
+        // After the events so links that completed before the request are known
+        while let Poll::Ready(Some(command)) = self.ctl_in.poll_recv(cx) {
+            self.handle_command(command);
+        }
         Poll::Pending
     }
 }
 
 impl L2capServer {
+    /// Returns a handle that opens outgoing channels through this server.
+    pub fn handle(&self) -> L2capHandle {
+        L2capHandle {
+            ctl: self.ctl_out.clone()
+        }
+    }
+
+    fn handle_command(&mut self, command: ServerCommand) {
+        match command {
+            ServerCommand::NewChannel { link, reply } => {
+                let channel = self
+                    .resolve_link(link)
+                    .ok_or(ChannelError::NotConnected)
+                    .and_then(|handle| self.new_channel(handle).ok_or(ChannelError::NoResources));
+                let _ = reply.send(channel);
+            }
+            ServerCommand::Echo { link, data, reply } => match self.resolve_link(link) {
+                Some(handle) => self.send_request(handle, SignalingCode::EchoRequest, data, PendingRequest::Echo(reply)),
+                None => PendingRequest::Echo(reply).fail(ChannelError::NotConnected)
+            },
+            ServerCommand::ExtendedFeatures { link, reply } => match self.resolve_link(link) {
+                Some(handle) => match self.connections[&handle].extended_features {
+                    Some(features) => {
+                        let _ = reply.send(Ok(features));
+                    }
+                    None => self.send_request(handle, SignalingCode::InformationRequest, INFO_EXTENDED_FEATURES, PendingRequest::ExtendedFeatures(reply))
+                },
+                None => PendingRequest::ExtendedFeatures(reply).fail(ChannelError::NotConnected)
+            },
+            ServerCommand::FixedChannels { link, reply } => match self.resolve_link(link) {
+                Some(handle) => match self.connections[&handle].fixed_channels {
+                    Some(channels) => {
+                        let _ = reply.send(Ok(channels));
+                    }
+                    None => self.send_request(handle, SignalingCode::InformationRequest, INFO_FIXED_CHANNELS, PendingRequest::FixedChannels(reply))
+                },
+                None => PendingRequest::FixedChannels(reply).fail(ChannelError::NotConnected)
+            },
+            ServerCommand::SendFrame { link, cid, payload, reply } => {
+                let result = self
+                    .resolve_link(link)
+                    .ok_or(ChannelError::NotConnected)
+                    .and_then(|handle| {
+                        let mut data = BytesMut::new();
+                        data.write(L2capHeader {
+                            len: Length::new(payload.len())?,
+                            cid
+                        });
+                        data.put(payload);
+                        Ok(self.sender.send(handle, data.freeze())?)
+                    });
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    fn resolve_link(&self, link: AclLink) -> Option<u16> {
+        match link {
+            AclLink::Handle(handle) => Some(handle).filter(|handle| self.connections.contains_key(handle)),
+            AclLink::Addr(addr) => self
+                .connections
+                .values()
+                .find(|connection| connection.addr == addr)
+                .map(|connection| connection.handle)
+        }
+    }
+
+    fn send_request<P: Instruct<LittleEndian>>(&mut self, handle: u16, code: SignalingCode, parameters: P, request: PendingRequest) {
+        // Requests that timed out are never answered
+        self.pending_requests.retain(|_, request| !request.is_closed());
+        let ctx = SignalingContext { handle, id: self.next_signaling_id.next() };
+        match self.sender.send_signaling(ctx, code, parameters) {
+            Ok(()) => {
+                self.pending_requests.insert((handle, ctx.id), request);
+            }
+            Err(err) => request.fail(err.into())
+        }
+    }
+
     fn get_connection(&mut self, handle: u16) -> Result<&mut PhysicalConnection, Error> {
         self.connections
             .get_mut(&handle)
@@ -126,6 +264,7 @@ impl L2capServer {
         if channel.send(msg).is_err() {
             warn!("Channel closed: {:?}", cid);
             self.channels.remove(&cid);
+            return Err(Error::UnknownChannelId(cid));
         }
         Ok(())
     }
@@ -152,7 +291,9 @@ impl L2capServer {
                                     max_slots: 0x01,
                                     mode: ConnectionMode::default(),
                                     addr,
-                                    assembler: AclDataAssembler::default()
+                                    assembler: AclDataAssembler::default(),
+                                    extended_features: None,
+                                    fixed_channels: None
                                 }
                             )
                             .is_none()
@@ -170,6 +311,15 @@ impl L2capServer {
                 data.finish()?;
 
                 self.connections.remove(&handle);
+                let pending = std::mem::take(&mut self.pending_requests);
+                for ((link, id), request) in pending {
+                    match link == handle {
+                        true => request.fail(ChannelError::NotConnected),
+                        false => {
+                            self.pending_requests.insert((link, id), request);
+                        }
+                    }
+                }
                 if status == Status::Success {
                     debug!("Disconnection complete: {:?} {:?}", handle, reason);
                 } else {
@@ -221,7 +371,20 @@ impl L2capServer {
         match cid {
             CID_ID_NONE => Err(Error::BadPacket(instructor::Error::InvalidValue)),
             CID_ID_SIGNALING => self.handle_l2cap_signaling(handle, data),
-            cid if CID_RANGE_DYNAMIC.contains(&cid) => self.send_channel_msg(cid, ChannelEvent::DataReceived(data)),
+            CID_ID_CONNECTIONLESS => {
+                self.handle_connectionless(handle, data);
+                Ok(())
+            }
+            cid if self.fixed_channels.contains_key(&cid) => {
+                self.fixed_channels[&cid].receive(handle, data);
+                Ok(())
+            }
+            cid if CID_RANGE_DYNAMIC.contains(&cid) => {
+                // Data for unknown channels is silently discarded ([Vol 3] Part A, Section 2.1).
+                self.send_channel_msg(cid, ChannelEvent::DataReceived(data))
+                    .unwrap_or_else(|_| trace!("Discarding data for unknown CID: {:04X}", cid));
+                Ok(())
+            }
             _ => {
                 warn!("Unhandled L2CAP CID: {:04X}", cid);
                 Ok(())
@@ -229,6 +392,22 @@ impl L2capServer {
         }
     }
 
+    // ([Vol 3] Part A, Section 3.2).
+    fn handle_connectionless(&mut self, handle: u16, mut data: Bytes) {
+        let Ok(Psm(psm)) = data.read_le::<Psm>() else {
+            warn!("Malformed connectionless frame");
+            return;
+        };
+        if data.len() > CONNECTIONLESS_MTU as usize {
+            warn!("Connectionless frame of {} bytes exceeds the MTU", data.len());
+            return;
+        }
+        match self.connectionless.get(&psm) {
+            Some(handler) => handler.receive(handle, data),
+            None => debug!("Discarding connectionless data for PSM={:04X}", psm)
+        }
+    }
+
     pub fn new_channel(&mut self, handle: u16) -> Option<Channel> {
         assert!(self.connections.contains_key(&handle));
         self.channels.retain(|_, tx| !tx.is_closed());
@@ -248,6 +427,188 @@ impl L2capServer {
     }
 }
 
+/// The ACL link an outgoing channel is opened on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AclLink {
+    Handle(u16),
+    Addr(RemoteAddr)
+}
+
+impl From<u16> for AclLink {
+    fn from(handle: u16) -> Self {
+        Self::Handle(handle)
+    }
+}
+
+impl From<RemoteAddr> for AclLink {
+    fn from(addr: RemoteAddr) -> Self {
+        Self::Addr(addr)
+    }
+}
+
+enum ServerCommand {
+    NewChannel {
+        link: AclLink,
+        reply: oneshot::Sender<Result<Channel, ChannelError>>
+    },
+    Echo {
+        link: AclLink,
+        data: Bytes,
+        reply: oneshot::Sender<Result<Bytes, ChannelError>>
+    },
+    ExtendedFeatures {
+        link: AclLink,
+        reply: oneshot::Sender<Result<ExtendedFeatures, ChannelError>>
+    },
+    FixedChannels {
+        link: AclLink,
+        reply: oneshot::Sender<Result<FixedChannels, ChannelError>>
+    },
+    SendFrame {
+        link: AclLink,
+        cid: u16,
+        payload: Bytes,
+        reply: oneshot::Sender<Result<(), ChannelError>>
+    }
+}
+
+/// A signaling request of the local device that waits for its response.
+enum PendingRequest {
+    Echo(oneshot::Sender<Result<Bytes, ChannelError>>),
+    ExtendedFeatures(oneshot::Sender<Result<ExtendedFeatures, ChannelError>>),
+    FixedChannels(oneshot::Sender<Result<FixedChannels, ChannelError>>)
+}
+
+impl PendingRequest {
+    fn fail(self, error: ChannelError) {
+        match self {
+            PendingRequest::Echo(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            PendingRequest::ExtendedFeatures(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            PendingRequest::FixedChannels(reply) => {
+                let _ = reply.send(Err(error));
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            PendingRequest::Echo(reply) => reply.is_closed(),
+            PendingRequest::ExtendedFeatures(reply) => reply.is_closed(),
+            PendingRequest::FixedChannels(reply) => reply.is_closed()
+        }
+    }
+}
+
+/// Opens outgoing channels through a running [`L2capServer`]. Cheap to clone and usable from any task.
+#[derive(Clone)]
+pub struct L2capHandle {
+    ctl: MpscSender<ServerCommand>
+}
+
+impl L2capHandle {
+    /// Opens a channel to `psm` on an existing ACL link and waits until it is configured in both directions.
+    pub async fn open_channel(&self, link: impl Into<AclLink>, psm: u64) -> Result<Channel, ChannelError> {
+        self.open_channel_with_config(link, psm, ChannelConfig::default()).await
+    }
+
+    /// Like [`open_channel`](Self::open_channel), but negotiates the given MTU, flush timeout, QoS and mode.
+    ///
+    /// Falls back to Basic mode if the remote device doesn't support the requested one.
+    pub async fn open_channel_with_config(&self, link: impl Into<AclLink>, psm: u64, mut config: ChannelConfig) -> Result<Channel, ChannelError> {
+        let link = link.into();
+        let required = match config.mode() {
+            Mode::EnhancedRetransmission => ExtendedFeatures::ENHANCED_RETRANSMISSION,
+            Mode::Streaming => ExtendedFeatures::STREAMING,
+            _ => ExtendedFeatures::empty()
+        };
+        if !required.is_empty() && !self.extended_features(link).await?.contains(required) {
+            debug!("Remote device does not support {:?}", config.mode());
+            config = config.with_mode(SupportedMode::Basic);
+        }
+        let mut channel = self
+            .request(|reply| ServerCommand::NewChannel { link, reply })
+            .await?;
+        channel.set_config(config)?;
+        channel.open(psm).await?;
+        Ok(channel)
+    }
+
+    /// The optional features of the remote L2CAP implementation. Only the first call per link asks the remote device.
+    pub async fn extended_features(&self, link: impl Into<AclLink>) -> Result<ExtendedFeatures, ChannelError> {
+        let link = link.into();
+        self.request_with_timeout(RESPONSE_TIMEOUT, |reply| ServerCommand::ExtendedFeatures { link, reply })
+            .await
+    }
+
+    /// The fixed channels the remote device supports. Only the first call per link asks the remote device.
+    pub async fn fixed_channels(&self, link: impl Into<AclLink>) -> Result<FixedChannels, ChannelError> {
+        let link = link.into();
+        self.request_with_timeout(RESPONSE_TIMEOUT, |reply| ServerCommand::FixedChannels { link, reply })
+            .await
+    }
+
+    /// Sends `data` over a fixed channel the remote device advertises.
+    pub async fn send_fixed(&self, link: impl Into<AclLink>, cid: u16, data: Bytes) -> Result<(), ChannelError> {
+        let channel = FixedChannels::from_cid(cid)
+            .filter(|_| CID_RANGE_FIXED.contains(&cid))
+            .ok_or(ChannelError::InvalidCid(cid))?;
+        let link = link.into();
+        ensure!(self.fixed_channels(link).await?.contains(channel), ChannelError::NotSupported);
+        self.request(|reply| ServerCommand::SendFrame { link, cid, payload: data, reply })
+            .await
+    }
+
+    /// Sends `data` to `psm` without opening a channel ([Vol 3] Part A, Section 3.2).
+    ///
+    /// Connectionless data is unreliable and the remote device drops payloads larger than its connectionless MTU.
+    pub async fn send_connectionless(&self, link: impl Into<AclLink>, psm: u64, data: Bytes) -> Result<(), ChannelError> {
+        let link = link.into();
+        ensure!(self.fixed_channels(link).await?.contains(FixedChannels::CONNECTIONLESS), ChannelError::NotSupported);
+        let mut payload = BytesMut::new();
+        payload.write_le(Psm(psm));
+        payload.put(data);
+        let payload = payload.freeze();
+        self.request(|reply| ServerCommand::SendFrame { link, cid: CID_ID_CONNECTIONLESS, payload, reply })
+            .await
+    }
+
+    /// Sends an echo request and returns the data of the response ([Vol 3] Part A, Section 4.8).
+    ///
+    /// The remote device rejects data that doesn't fit into its signaling MTU, which is at least 44 bytes.
+    pub async fn echo(&self, link: impl Into<AclLink>, data: Bytes, timeout: Duration) -> Result<Bytes, ChannelError> {
+        let link = link.into();
+        self.request_with_timeout(timeout, |reply| ServerCommand::Echo { link, data, reply })
+            .await
+    }
+
+    /// Measures the round trip time of an empty echo request.
+    pub async fn ping(&self, link: impl Into<AclLink>, timeout: Duration) -> Result<Duration, ChannelError> {
+        let start = Instant::now();
+        self.echo(link, Bytes::new(), timeout).await?;
+        Ok(start.elapsed())
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<Result<T, ChannelError>>) -> ServerCommand) -> Result<T, ChannelError> {
+        let (tx, rx) = oneshot::channel();
+        self.ctl
+            .send(command(tx))
+            .map_err(|_| ChannelError::ChannelClosed)?;
+        rx.await.map_err(|_| ChannelError::ChannelClosed)?
+    }
+
+    async fn request_with_timeout<T>(
+        &self, duration: Duration, command: impl FnOnce(oneshot::Sender<Result<T, ChannelError>>) -> ServerCommand
+    ) -> Result<T, ChannelError> {
+        timeout(duration, self.request(command))
+            .await
+            .map_err(|_| ChannelError::Timeout)?
+    }
+}
+
 // ([Vol 3] Part A, Section 3.1).
 #[derive(Debug, Exstruct, Instruct)]
 #[instructor(endian = "little")]
@@ -325,10 +686,14 @@ pub enum ChannelEvent {
     },
     ConfigurationRequest {
         id: u8,
+        /// More options follow in another request.
+        continuation: bool,
         options: Vec<ConfigurationParameter>
     },
     ConfigurationResponse {
         id: u8,
+        /// More options follow in another response.
+        continuation: bool,
         result: ConfigureResult,
         options: Vec<ConfigurationParameter>
     },
@@ -347,6 +712,14 @@ pub trait ProtocolHandlerProvider {
 pub trait ProtocolHandler: Send + Sync {
     fn psm(&self) -> u64;
 
+    /// The security level the ACL link needs before a channel is handed to [`handle`](Self::handle).
+    ///
+    /// Links below the level are authenticated and encrypted first, which requires
+    /// [`L2capServerBuilder::with_connection_manager`]. The [`SecurityPolicy`](crate::hci::security::SecurityPolicy) of the manager can raise the level further.
+    fn security_requirement(&self) -> SecurityLevel {
+        SecurityLevel::None
+    }
+
     fn handle(&self, channel: Channel);
 }
 
@@ -368,6 +741,24 @@ impl<P> ProtocolHandlerProvider for Arc<P>
     }
 }
 
+/// Receives the data of a fixed channel on all links ([Vol 3] Part A, Section 2.1).
+///
+/// Called from the event loop of the [`L2capServer`], so implementations should hand the data off instead of blocking.
+pub trait FixedChannelHandler: Send + Sync {
+    fn cid(&self) -> u16;
+
+    fn receive(&self, handle: u16, data: Bytes);
+}
+
+/// Receives connectionless data (G-frames) sent to a PSM ([Vol 3] Part A, Section 3.2).
+///
+/// Called from the event loop of the [`L2capServer`], so implementations should hand the data off instead of blocking.
+pub trait ConnectionlessHandler: Send + Sync {
+    fn psm(&self) -> u64;
+
+    fn receive(&self, handle: u16, data: Bytes);
+}
+
 pub struct ProtocolDelegate<H, F> {
     psm: u64,
     handler: H,
@@ -401,3 +792,316 @@ impl<H, F> ProtocolHandler for ProtocolDelegate<H, F>
         (self.map_func)(&self.handler, channel)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use instructor::Buffer;
+    use tokio::spawn;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use crate::hci::acl::AclHeader;
+    use crate::hci::security::SecurityLevel;
+    use crate::host::virtual_controller::VirtualLink;
+    use crate::l2cap::channel::{Channel, MAX_QUEUED_PDUS};
+    use crate::l2cap::signaling::{ExtendedFeatures, FixedChannels, RejectReason, SignalingCode, SignalingHeader, SIGNALING_MTU};
+    use crate::l2cap::channel::Error as ChannelError;
+    use crate::l2cap::configuration::{ChannelConfig, FlushTimeout, Mode, QualityOfService, ServiceType, SupportedMode};
+    use crate::l2cap::{ConnectionResult, ConnectionStatus, ConnectionlessHandler, FixedChannelHandler, L2capHeader, L2capServerBuilder, ProtocolHandler};
+
+    #[derive(Clone)]
+    struct SecureProtocol {
+        psm: u64,
+        level: SecurityLevel,
+        channels: UnboundedSender<Channel>
+    }
+
+    impl ProtocolHandler for SecureProtocol {
+        fn psm(&self) -> u64 {
+            self.psm
+        }
+
+        fn security_requirement(&self) -> SecurityLevel {
+            self.level
+        }
+
+        fn handle(&self, channel: Channel) {
+            self.channels.send(channel).unwrap();
+        }
+    }
+
+    struct DataSink {
+        id: u16,
+        data: UnboundedSender<Bytes>
+    }
+
+    impl FixedChannelHandler for DataSink {
+        fn cid(&self) -> u16 {
+            self.id
+        }
+
+        fn receive(&self, _: u16, data: Bytes) {
+            self.data.send(data).unwrap();
+        }
+    }
+
+    impl ConnectionlessHandler for DataSink {
+        fn psm(&self) -> u64 {
+            u64::from(self.id)
+        }
+
+        fn receive(&self, _: u16, data: Bytes) {
+            self.data.send(data).unwrap();
+        }
+    }
+
+    async fn connection_response(data: &mut UnboundedReceiver<Bytes>) -> (u8, ConnectionResult, ConnectionStatus) {
+        let mut packet = data.recv().await.unwrap();
+        let _: AclHeader = packet.read().unwrap();
+        let _: L2capHeader = packet.read().unwrap();
+        let header: SignalingHeader = packet.read().unwrap();
+        assert_eq!(header.code, SignalingCode::ConnectionResponse);
+        let (_dcid, _scid, result, status): (u16, u16, ConnectionResult, ConnectionStatus) = packet.read_le().unwrap();
+        (header.id, result, status)
+    }
+
+    async fn signaling_response(data: &mut UnboundedReceiver<Bytes>) -> (SignalingHeader, Bytes) {
+        let mut packet = data.recv().await.unwrap();
+        let _: AclHeader = packet.read().unwrap();
+        let _: L2capHeader = packet.read().unwrap();
+        let header: SignalingHeader = packet.read().unwrap();
+        (header, packet)
+    }
+
+    #[tokio::test]
+    async fn test_command_reject() {
+        let mut link = VirtualLink::new().await;
+        let (tx, mut data_a) = unbounded_channel();
+        link.a.register_data_handler(tx).unwrap();
+        let server = L2capServerBuilder::default().run(&link.b).unwrap();
+        spawn(server);
+
+        let handle = link.connect().await;
+        let command = |code: u8, id: u8, payload: &[u8]| {
+            let length = payload.len() as u16;
+            let mut packet = Vec::new();
+            packet.extend_from_slice(&(length + 4).to_le_bytes());
+            packet.extend_from_slice(&[0x01, 0x00, code, id]);
+            packet.extend_from_slice(&length.to_le_bytes());
+            packet.extend_from_slice(payload);
+            Bytes::from(packet)
+        };
+        let sender = link.a.get_acl_sender();
+
+        // Disconnection request for a channel that doesn't exist
+        sender.send(handle, command(0x06, 1, &[0x50, 0x00, 0x41, 0x00])).unwrap();
+        let (header, mut reason) = signaling_response(&mut data_a).await;
+        assert_eq!((header.code, header.id), (SignalingCode::CommandReject, 1));
+        assert_eq!(reason.read_le::<RejectReason>().unwrap(), RejectReason::InvalidCid { local_cid: 0x0050, remote_cid: 0x0041 });
+
+        // Unknown command code
+        sender.send(handle, command(0x30, 2, &[])).unwrap();
+        let (header, mut reason) = signaling_response(&mut data_a).await;
+        assert_eq!((header.code, header.id), (SignalingCode::CommandReject, 2));
+        assert_eq!(reason.read_le::<RejectReason>().unwrap(), RejectReason::CommandNotUnderstood);
+
+        // Larger than the signaling MTU
+        sender.send(handle, command(0x08, 3, &[0xAA; 700])).unwrap();
+        let (header, mut reason) = signaling_response(&mut data_a).await;
+        assert_eq!((header.code, header.id), (SignalingCode::CommandReject, 3));
+        assert_eq!(reason.read_le::<RejectReason>().unwrap(), RejectReason::SignalingMtuExceeded { actual_mtu: SIGNALING_MTU });
+
+        sender.send(handle, command(0x08, 4, b"echo")).unwrap();
+        let (header, data) = signaling_response(&mut data_a).await;
+        assert_eq!((header.code, header.id), (SignalingCode::EchoResponse, 4));
+        assert_eq!(data, Bytes::from_static(b"echo"));
+    }
+
+    #[tokio::test]
+    async fn test_security_pending() {
+        let mut link = VirtualLink::new().await;
+        let (tx, mut data_a) = unbounded_channel();
+        link.a.register_data_handler(tx).unwrap();
+        let (tx, mut channels) = unbounded_channel();
+        let server = L2capServerBuilder::default()
+            .with_protocol(SecureProtocol { psm: 0x1001, level: SecurityLevel::Encrypted, channels: tx.clone() })
+            .with_protocol(SecureProtocol { psm: 0x1003, level: SecurityLevel::Authenticated, channels: tx })
+            .with_connection_manager(link.manager.clone())
+            .run(&link.b)
+            .unwrap();
+        spawn(server);
+
+        let handle = link.connect().await;
+        let request = |id: u8, psm: u8, scid: u8| Bytes::from(vec![0x08, 0x00, 0x01, 0x00, 0x02, id, 0x04, 0x00, psm, 0x10, scid, 0x00]);
+
+        // The link gets encrypted before the channel is handed to the protocol
+        link.a.get_acl_sender().send(handle, request(1, 0x01, 0x40)).unwrap();
+        assert_eq!(connection_response(&mut data_a).await, (1, ConnectionResult::Pending, ConnectionStatus::AuthenticationPending));
+        channels.recv().await.unwrap().accept_connection().unwrap();
+        assert_eq!(connection_response(&mut data_a).await, (1, ConnectionResult::Success, ConnectionStatus::NoFurtherInformation));
+
+        // Encryption alone does not authenticate the remote device
+        link.a.get_acl_sender().send(handle, request(2, 0x03, 0x41)).unwrap();
+        assert_eq!(connection_response(&mut data_a).await, (2, ConnectionResult::Pending, ConnectionStatus::AuthenticationPending));
+        assert_eq!(connection_response(&mut data_a).await, (2, ConnectionResult::RefusedSecurityBlock, ConnectionStatus::NoFurtherInformation));
+    }
+
+    #[tokio::test]
+    async fn test_open_channel() {
+        let mut link = VirtualLink::new().await;
+        let addr_b = link.addr_b;
+        let (tx, mut channels) = unbounded_channel();
+        let server = L2capServerBuilder::default()
+            .with_protocol(SecureProtocol { psm: 0x1001, level: SecurityLevel::None, channels: tx })
+            .run(&link.b)
+            .unwrap();
+        spawn(server);
+        spawn(async move {
+            while let Some(mut channel) = channels.recv().await {
+                channel.accept_connection().unwrap();
+                channel.configure().await.unwrap();
+                while let Some(data) = channel.read().await {
+                    channel.write(data).await.unwrap();
+                }
+            }
+        });
+
+        let server = L2capServerBuilder::default().run(&link.a).unwrap();
+        let l2cap = server.handle();
+        spawn(server);
+        assert!(matches!(l2cap.open_channel(addr_b, 0x1001).await, Err(ChannelError::NotConnected)));
+
+        link.connect().await;
+        let features = l2cap.extended_features(addr_b).await.unwrap();
+        assert!(features.contains(ExtendedFeatures::ENHANCED_RETRANSMISSION | ExtendedFeatures::FIXED_CHANNELS));
+        assert_eq!(l2cap.fixed_channels(addr_b).await.unwrap(), FixedChannels::SIGNALING);
+        assert_eq!(
+            l2cap.echo(addr_b, Bytes::from_static(b"echo"), Duration::from_secs(1)).await.unwrap(),
+            Bytes::from_static(b"echo")
+        );
+        l2cap.ping(addr_b, Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(
+            l2cap.open_channel(addr_b, 0x1003).await,
+            Err(ChannelError::Refused(ConnectionResult::RefusedPsmNotSupported))
+        ));
+
+        let mut channel = l2cap.open_channel(addr_b, 0x1001).await.unwrap();
+        channel.write(Bytes::from_static(b"ping")).await.unwrap();
+        assert_eq!(channel.read().await, Some(Bytes::from_static(b"ping")));
+        drop(channel);
+
+        // The acceptor switches to the requested mode
+        let mut channel = l2cap
+            .open_channel_with_config(addr_b, 0x1001, ChannelConfig::default().with_mode(SupportedMode::EnhancedRetransmission))
+            .await
+            .unwrap();
+        assert_eq!(channel.mode(), Mode::EnhancedRetransmission);
+        let data = Bytes::from(vec![0x55; 1000]);
+        channel.write(data.clone()).await.unwrap();
+        assert_eq!(channel.read().await, Some(data));
+        drop(channel);
+
+        // Too many options for a single request and a guaranteed QoS the acceptor counters with best effort
+        let config = ChannelConfig::default()
+            .with_mtu(1000)
+            .with_flush_timeout(FlushTimeout::Timeout(100))
+            .with_qos(QualityOfService { service_type: ServiceType::Guaranteed, ..Default::default() })
+            .with_mode(SupportedMode::EnhancedRetransmission);
+        let mut channel = l2cap
+            .open_channel_with_config(addr_b, 0x1001, config)
+            .await
+            .unwrap();
+        assert_eq!(channel.config().qos().map(|qos| qos.service_type), Some(ServiceType::BestEffort));
+        assert_eq!(channel.local_mtu(), 1000);
+        channel.write(Bytes::from_static(b"pong")).await.unwrap();
+        assert_eq!(channel.read().await, Some(Bytes::from_static(b"pong")));
+    }
+
+    #[tokio::test]
+    async fn test_streaming_backpressure() {
+        let mut link = VirtualLink::new().await;
+        let (tx, mut channels) = unbounded_channel();
+        let server = L2capServerBuilder::default()
+            .with_protocol(SecureProtocol { psm: 0x1001, level: SecurityLevel::None, channels: tx })
+            .run(&link.b)
+            .unwrap();
+        spawn(server);
+        let (tx, mut received) = unbounded_channel();
+        spawn(async move {
+            let mut channel = channels.recv().await.unwrap();
+            channel.accept_connection().unwrap();
+            channel.configure().await.unwrap();
+            while let Some(data) = channel.read().await {
+                tx.send(data).unwrap();
+            }
+        });
+
+        let server = L2capServerBuilder::default().run(&link.a).unwrap();
+        let l2cap = server.handle();
+        spawn(server);
+        link.connect().await;
+        let mut channel = l2cap
+            .open_channel_with_config(link.addr_b, 0x1001, ChannelConfig::default().with_mode(SupportedMode::Streaming))
+            .await
+            .unwrap();
+        assert_eq!(channel.mode(), Mode::Streaming);
+
+        // Without yielding the controller can't take any frames, so the queue fills up
+        let mut queued = 0;
+        while channel.try_write(Bytes::from_static(b"data")).unwrap() {
+            queued += 1;
+        }
+        assert_eq!(queued, MAX_QUEUED_PDUS);
+        channel.write(Bytes::from_static(b"last")).await.unwrap();
+        for _ in 0..queued {
+            assert_eq!(received.recv().await, Some(Bytes::from_static(b"data")));
+        }
+        assert_eq!(received.recv().await, Some(Bytes::from_static(b"last")));
+    }
+
+    #[tokio::test]
+    async fn test_connectionless() {
+        let mut link = VirtualLink::new().await;
+        let addr_b = link.addr_b;
+        let (tx, mut connectionless) = unbounded_channel();
+        let (tx_fixed, mut fixed) = unbounded_channel();
+        let server = L2capServerBuilder::default()
+            .with_connectionless(DataSink { id: 0x1005, data: tx })
+            .with_fixed_channel(DataSink { id: 0x003E, data: tx_fixed })
+            .run(&link.b)
+            .unwrap();
+        spawn(server);
+
+        let server = L2capServerBuilder::default().run(&link.a).unwrap();
+        let l2cap = server.handle();
+        spawn(server);
+        link.connect().await;
+
+        let channels = l2cap.fixed_channels(addr_b).await.unwrap();
+        assert_eq!(channels, FixedChannels::SIGNALING | FixedChannels::CONNECTIONLESS | FixedChannels::from_cid(0x003E).unwrap());
+        assert_eq!(FixedChannels::from_cid(0x0040), None);
+        assert!(l2cap
+            .extended_features(addr_b)
+            .await
+            .unwrap()
+            .contains(ExtendedFeatures::UNICAST_CONNECTIONLESS_DATA));
+
+        l2cap.send_connectionless(addr_b, 0x1005, Bytes::from_static(b"broadcast")).await.unwrap();
+        assert_eq!(connectionless.recv().await, Some(Bytes::from_static(b"broadcast")));
+        l2cap.send_fixed(addr_b, 0x003E, Bytes::from_static(b"fixed")).await.unwrap();
+        assert_eq!(fixed.recv().await, Some(Bytes::from_static(b"fixed")));
+        assert!(matches!(
+            l2cap.send_fixed(addr_b, 0x003D, Bytes::from_static(b"fixed")).await,
+            Err(ChannelError::NotSupported)
+        ));
+        // The signaling channel and dynamic channels can't be used directly
+        for cid in [0x0001, 0x0040] {
+            assert!(matches!(
+                l2cap.send_fixed(addr_b, cid, Bytes::from_static(b"fixed")).await,
+                Err(ChannelError::InvalidCid(invalid)) if invalid == cid
+            ));
+        }
+    }
+}
@@ -1,15 +1,38 @@
 use std::panic::Location;
+use std::sync::Arc;
 
+use bitflags::bitflags;
 use bytes::{Bytes, BytesMut};
 use instructor::utils::Length;
 use instructor::{Buffer, BufferMut, Exstruct, Instruct, LittleEndian};
+use tokio::spawn;
 use tracing::{debug, error, instrument, trace, warn, Span};
 
+use crate::hci::connection::Connection;
+use crate::hci::security::SecurityLevel;
 use crate::hci::{AclSendError, AclSender, Error};
+use crate::l2cap::channel::{Channel, Error as ChannelError};
 use crate::l2cap::configuration::ConfigurationParameter;
-use crate::l2cap::{ChannelEvent, ConfigureResult, ConnectionResult, ConnectionStatus, L2capHeader, L2capServer, CID_ID_SIGNALING, CID_RANGE_DYNAMIC};
+use crate::l2cap::{
+    ChannelEvent, ConfigureResult, ConnectionResult, ConnectionStatus, L2capHeader, L2capServer, PendingRequest, ProtocolHandler, CID_ID_SIGNALING, CID_RANGE_DYNAMIC, CONNECTIONLESS_MTU
+};
 use crate::utils::{catch_error, IgnoreableResult};
-use crate::{ensure, log_assert};
+use crate::ensure;
+
+/// The largest signaling packet that is accepted ([Vol 3] Part A, Section 4).
+pub const SIGNALING_MTU: u16 = 672;
+
+// ([Vol 3] Part A, Section 4.10).
+pub(crate) const INFO_CONNECTIONLESS_MTU: u16 = 0x0001;
+pub(crate) const INFO_EXTENDED_FEATURES: u16 = 0x0002;
+pub(crate) const INFO_FIXED_CHANNELS: u16 = 0x0003;
+const INFO_SUCCESS: u16 = 0x0000;
+const INFO_NOT_SUPPORTED: u16 = 0x0001;
+
+const SUPPORTED_FEATURES: ExtendedFeatures = ExtendedFeatures::ENHANCED_RETRANSMISSION
+    .union(ExtendedFeatures::STREAMING)
+    .union(ExtendedFeatures::FCS)
+    .union(ExtendedFeatures::FIXED_CHANNELS);
 
 #[derive(Debug, Copy, Clone)]
 pub struct SignalingContext {
@@ -57,22 +80,34 @@ impl L2capServer {
     // ([Vol 3] Part A, Section 4).
     #[instrument(skip(self, data))]
     pub fn handle_l2cap_signaling(&mut self, handle: u16, mut data: Bytes) -> Result<(), Error> {
-        // TODO: Send reject response when cid is unknown
+        if data.len() > SIGNALING_MTU as usize {
+            // Only the first command is rejected, the rest of the packet is dropped
+            let id = data.get(1).copied().unwrap_or_default();
+            warn!("Signaling packet of {} bytes exceeds the MTU", data.len());
+            self.sender
+                .send_signaling(SignalingContext { handle, id }, SignalingCode::CommandReject, RejectReason::SignalingMtuExceeded {
+                    actual_mtu: SIGNALING_MTU
+                })
+                .ignore();
+            return Ok(());
+        }
         while !data.is_empty() {
             let SignalingHeader { code, id, length } = data.read()?;
             Span::current()
                 .record("code", format_args!("{:?}", code))
                 .record("id", id);
+            let ctx = SignalingContext { handle, id };
+            if data.len() < length as usize {
+                warn!("Truncated signaling command");
+                self.sender
+                    .send_signaling(ctx, SignalingCode::CommandReject, RejectReason::CommandNotUnderstood)
+                    .ignore();
+                break;
+            }
             let mut data = data.split_to(length as usize);
 
-            let ctx = SignalingContext { handle, id };
             let result = catch_error(|| match code {
-                SignalingCode::CommandReject => {
-                    let reason: RejectReason = data.read()?;
-                    data.finish()?;
-                    error!("Command rejected: {:?}", reason);
-                    Ok(())
-                }
+                SignalingCode::CommandReject => self.handle_command_reject(ctx, data),
                 SignalingCode::ConnectionRequest => self.handle_connection_request(ctx, data),
                 SignalingCode::ConnectionResponse => self.handle_connection_response(ctx, data),
                 SignalingCode::ConfigureRequest => self.handle_configuration_request(ctx, data),
@@ -80,7 +115,9 @@ impl L2capServer {
                 SignalingCode::DisconnectionRequest => self.handle_disconnect_request(ctx, data),
                 SignalingCode::DisconnectionResponse => self.handle_disconnect_response(ctx, data),
                 SignalingCode::EchoRequest => self.handle_echo_request(ctx, data),
+                SignalingCode::EchoResponse => self.handle_echo_response(ctx, data),
                 SignalingCode::InformationRequest => self.handle_information_request(ctx, data),
+                SignalingCode::InformationResponse => self.handle_information_response(ctx, data),
                 _ => {
                     warn!("Command Unsupported");
                     Err(RejectReason::CommandNotUnderstood)
@@ -95,6 +132,22 @@ impl L2capServer {
         Ok(())
     }
 
+    // ([Vol 3] Part A, Section 4.1).
+    fn handle_command_reject(&mut self, ctx: SignalingContext, mut data: Bytes) -> Result<(), RejectReason> {
+        // A reject is never answered with another reject
+        let reason: Result<RejectReason, _> = data.read();
+        match reason {
+            Ok(reason) => {
+                warn!("Command rejected: {:?}", reason);
+                if let Some(request) = self.pending_requests.remove(&(ctx.handle, ctx.id)) {
+                    request.fail(ChannelError::CommandRejected(reason));
+                }
+            }
+            Err(err) => warn!("Malformed command reject: {:?}", err)
+        }
+        Ok(())
+    }
+
     // ([Vol 3] Part A, Section 4.2).
     fn handle_connection_request(&mut self, ctx: SignalingContext, mut data: Bytes) -> Result<(), RejectReason> {
         let psm: u64 = data.read_le::<Psm>()?.0;
@@ -109,10 +162,37 @@ impl L2capServer {
                 .ok_or(ConnectionResult::RefusedPsmNotSupported)?
                 .clone();
             ensure!(CID_RANGE_DYNAMIC.contains(&scid), ConnectionResult::RefusedInvalidSourceCid);
+            let required = self
+                .connection_manager
+                .as_ref()
+                .map_or(SecurityLevel::None, |manager| manager.security_requirement(psm))
+                .max(server.security_requirement());
+            let insecure = match required {
+                SecurityLevel::None => None,
+                _ => {
+                    let Some(connection) = self
+                        .connection_manager
+                        .as_ref()
+                        .and_then(|manager| manager.connection_by_handle(ctx.handle)) else {
+                        warn!("Can not secure link 0x{:04X} without a connection manager", ctx.handle);
+                        return Err(ConnectionResult::RefusedSecurityBlock);
+                    };
+                    Some(connection).filter(|connection| connection.security_level() < required)
+                }
+            };
             let mut channel = self.new_channel(ctx.handle)
                 .ok_or(ConnectionResult::RefusedNoResources)?;
             channel.connection_request_received(scid, ctx.id);
-            server.handle(channel);
+            match insecure {
+                None => server.handle(channel),
+                Some(connection) => {
+                    debug!("Securing link 0x{:04X} for PSM={:04X}", ctx.handle, psm);
+                    channel
+                        .defer_connection(ConnectionStatus::AuthenticationPending)
+                        .ignore();
+                    spawn(secure_channel(connection, required, server, channel));
+                }
+            }
             Ok(())
         });
         if let Err(result) = result {
@@ -132,26 +212,27 @@ impl L2capServer {
         data.finish()?;
         debug!("Connection response: DCID={:04X} SCID={:04X} result={:?} status={:?}", dcid, scid, result, status);
 
+        // Responses for unknown channels are dropped instead of rejected
         self.send_channel_msg(scid, ChannelEvent::ConnectionResponse {
             id: ctx.id,
             remote_cid: dcid,
             result,
             status,
-        }).map_err(|_| RejectReason::InvalidCid { scid, dcid })
+        }).unwrap_or_else(|_| debug!("Connection response for unknown SCID: {:04X}", scid));
+        Ok(())
     }
 
     // ([Vol 3] Part A, Section 4.4).
     fn handle_configuration_request(&mut self, ctx: SignalingContext, mut data: Bytes) -> Result<(), RejectReason> {
         let dcid: u16 = data.read_le()?;
         let flags: u16 = data.read_le()?;
-        //TODO handle continuation packets
-        log_assert!(flags & 0xFFFE == 0);
+        let continuation = flags & 0x0001 != 0;
         let options: Vec<ConfigurationParameter> = data.read()?;
         data.finish()?;
-        debug!("Configuration request: DCID={:04X}", dcid);
+        debug!("Configuration request: DCID={:04X} C={}", dcid, continuation);
 
-        self.send_channel_msg(dcid, ChannelEvent::ConfigurationRequest{ id: ctx.id, options })
-            .map_err(|_| RejectReason::InvalidCid { scid: 0, dcid })
+        self.send_channel_msg(dcid, ChannelEvent::ConfigurationRequest{ id: ctx.id, continuation, options })
+            .map_err(|_| RejectReason::InvalidCid { local_cid: dcid, remote_cid: 0 })
     }
 
     // ([Vol 3] Part A, Section 4.5).
@@ -159,14 +240,14 @@ impl L2capServer {
         let scid: u16 = data.read_le()?;
         let flags: u16 = data.read_le()?;
         let result: ConfigureResult = data.read_le()?;
-        //TODO handle continuation packets
-        log_assert!(flags & 0xFFFE == 0);
+        let continuation = flags & 0x0001 != 0;
         let options: Vec<ConfigurationParameter> = data.read()?;
         data.finish()?;
-        debug!("Configuration response: SCID={:04X}", scid);
+        debug!("Configuration response: SCID={:04X} C={}", scid, continuation);
 
-        self.send_channel_msg(scid, ChannelEvent::ConfigurationResponse{ id: ctx.id, result, options })
-            .map_err(|_| RejectReason::InvalidCid { scid, dcid: 0 })
+        self.send_channel_msg(scid, ChannelEvent::ConfigurationResponse{ id: ctx.id, continuation, result, options })
+            .unwrap_or_else(|_| debug!("Configuration response for unknown SCID: {:04X}", scid));
+        Ok(())
     }
 
     // ([Vol 3] Part A, Section 4.6).
@@ -180,7 +261,7 @@ impl L2capServer {
                 let _ = channel.send(ChannelEvent::DisconnectRequest { id: ctx.id });
                 Ok(())
             }
-            None => Err(RejectReason::InvalidCid { scid, dcid })
+            None => Err(RejectReason::InvalidCid { local_cid: dcid, remote_cid: scid })
         }
     }
 
@@ -210,54 +291,123 @@ impl L2capServer {
         Ok(())
     }
 
+    // ([Vol 3] Part A, Section 4.9).
+    fn handle_echo_response(&mut self, ctx: SignalingContext, data: Bytes) -> Result<(), RejectReason> {
+        match self.pending_requests.remove(&(ctx.handle, ctx.id)) {
+            Some(PendingRequest::Echo(reply)) => {
+                let _ = reply.send(Ok(data));
+            }
+            Some(request) => {
+                warn!("Echo response does not match the request");
+                self.pending_requests.insert((ctx.handle, ctx.id), request);
+            }
+            None => debug!("Unexpected echo response: id={}", ctx.id)
+        }
+        Ok(())
+    }
+
     // ([Vol 3] Part A, Section 4.10).
     fn handle_information_request(&mut self, ctx: SignalingContext, mut data: Bytes) -> Result<(), RejectReason> {
-        const SUCCESS: u16 = 0x0000;
-        const NOT_SUPPORTED: u16 = 0x0001;
         let info_type: u16 = data.read_le()?;
         data.finish()?;
         match info_type {
-            0x0001 => {
+            INFO_CONNECTIONLESS_MTU => {
                 debug!("Connectionless MTU");
-                let mtu = 1024u16; //TODO: fill in the real value
                 self.sender
-                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, SUCCESS, mtu))
+                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, INFO_SUCCESS, CONNECTIONLESS_MTU))
                     .ignore();
             }
-            0x0002 => {
+            INFO_EXTENDED_FEATURES => {
                 debug!("Local supported features");
-                // ([Vol 3] Part A, Section 4.12).
-                let mut features: u32 = 0;
-                //features |= 1 << 3; // Enhanced Retransmission Mode
-                //features |= 1 << 5; // FCS
-                features |= 1 << 7; // Fixed Channels supported over BR/EDR
-                //features |= 1 << 9; // Unicast Connectionless Data Reception
-
                 self.sender
-                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, SUCCESS, features))
+                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, INFO_SUCCESS, self.local_features()))
                     .ignore();
             }
-            0x0003 => {
-                debug!("        Fixed channels supported");
-                // ([Vol 3] Part A, Section 4.13).
-                let mut channels: u64 = 0;
-                channels |= 1 << 1; // L2CAP Signaling channel
-                //channels |= 1 << 2; // Connectionless reception
-                //channels |= 1 << 7; // BR/EDR Security Manager
-
+            INFO_FIXED_CHANNELS => {
+                debug!("Fixed channels supported");
                 self.sender
-                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, SUCCESS, channels))
+                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, INFO_SUCCESS, self.local_fixed_channels()))
                     .ignore();
             }
             _ => {
                 error!("Unknown information request: type={:04X}", info_type);
                 self.sender
-                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, NOT_SUPPORTED))
+                    .send_signaling(ctx, SignalingCode::InformationResponse, (info_type, INFO_NOT_SUPPORTED))
                     .ignore();
             }
         }
         Ok(())
     }
+
+    fn local_features(&self) -> ExtendedFeatures {
+        match self.connectionless.is_empty() {
+            true => SUPPORTED_FEATURES,
+            false => SUPPORTED_FEATURES | ExtendedFeatures::UNICAST_CONNECTIONLESS_DATA
+        }
+    }
+
+    fn local_fixed_channels(&self) -> FixedChannels {
+        let mut channels = FixedChannels::SIGNALING;
+        channels.set(FixedChannels::CONNECTIONLESS, !self.connectionless.is_empty());
+        channels.extend(self.fixed_channels.keys().filter_map(|&cid| FixedChannels::from_cid(cid)));
+        channels
+    }
+
+    // ([Vol 3] Part A, Section 4.11).
+    fn handle_information_response(&mut self, ctx: SignalingContext, mut data: Bytes) -> Result<(), RejectReason> {
+        let Ok((info_type, result)) = data.read_le::<(u16, u16)>() else {
+            warn!("Malformed information response");
+            return Ok(());
+        };
+        let Some(request) = self.pending_requests.remove(&(ctx.handle, ctx.id)) else {
+            debug!("Unexpected information response: id={}", ctx.id);
+            return Ok(());
+        };
+        // A device that doesn't understand the request doesn't support any of the features
+        let supported = result == INFO_SUCCESS;
+        match (info_type, request) {
+            (INFO_EXTENDED_FEATURES, PendingRequest::ExtendedFeatures(reply)) => {
+                let features: ExtendedFeatures = match supported {
+                    true => data.read_le().unwrap_or_default(),
+                    false => ExtendedFeatures::empty()
+                };
+                debug!("Remote features of 0x{:04X}: {:?}", ctx.handle, features);
+                if let Some(connection) = self.connections.get_mut(&ctx.handle) {
+                    connection.extended_features = Some(features);
+                }
+                let _ = reply.send(Ok(features));
+            }
+            (INFO_FIXED_CHANNELS, PendingRequest::FixedChannels(reply)) => {
+                let channels: FixedChannels = match supported {
+                    true => data.read_le().unwrap_or_default(),
+                    false => FixedChannels::empty()
+                };
+                debug!("Remote fixed channels of 0x{:04X}: {:?}", ctx.handle, channels);
+                if let Some(connection) = self.connections.get_mut(&ctx.handle) {
+                    connection.fixed_channels = Some(channels);
+                }
+                let _ = reply.send(Ok(channels));
+            }
+            (_, request) => {
+                warn!("Information response does not match the request: type={:04X}", info_type);
+                self.pending_requests.insert((ctx.handle, ctx.id), request);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Authenticates and encrypts the link before the channel is handed to `server` ([Vol 3] Part C, Section 5.2.2).
+async fn secure_channel(connection: Connection, required: SecurityLevel, server: Arc<dyn ProtocolHandler>, mut channel: Channel) {
+    match connection.secure(required).await {
+        Ok(()) => server.handle(channel),
+        result => {
+            debug!("Link 0x{:04X} does not reach {:?}: {:?}", connection.handle(), required, result);
+            channel
+                .refuse_connection(ConnectionResult::RefusedSecurityBlock)
+                .ignore();
+        }
+    }
 }
 
 // ([Vol 3] Part A, Section 4).
@@ -334,13 +484,50 @@ impl Instruct<LittleEndian> for Psm {
     }
 }
 
+bitflags! {
+    /// The optional features of an L2CAP implementation ([Vol 3] Part A, Section 4.12).
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[instructor(bitflags)]
+    pub struct ExtendedFeatures: u32 {
+        const FLOW_CONTROL = 1 << 0;
+        const RETRANSMISSION = 1 << 1;
+        const BIDIRECTIONAL_QOS = 1 << 2;
+        const ENHANCED_RETRANSMISSION = 1 << 3;
+        const STREAMING = 1 << 4;
+        const FCS = 1 << 5;
+        const EXTENDED_FLOW_SPECIFICATION = 1 << 6;
+        const FIXED_CHANNELS = 1 << 7;
+        const EXTENDED_WINDOW_SIZE = 1 << 8;
+        const UNICAST_CONNECTIONLESS_DATA = 1 << 9;
+        const ENHANCED_CREDIT_BASED_FLOW_CONTROL = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// The fixed channels an L2CAP implementation supports over BR/EDR ([Vol 3] Part A, Section 4.13).
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[instructor(bitflags)]
+    pub struct FixedChannels: u64 {
+        const SIGNALING = 1 << 1;
+        const CONNECTIONLESS = 1 << 2;
+        const SECURITY_MANAGER = 1 << 7;
+    }
+}
+
+impl FixedChannels {
+    /// Every fixed channel is represented by the bit of its channel id. Returns `None` for ids beyond the 64 bits of the mask.
+    pub fn from_cid(cid: u16) -> Option<Self> {
+        (cid < 64).then(|| Self::from_bits_retain(1 << cid))
+    }
+}
+
 // ([Vol 3] Part A, Section 4.1).
-#[allow(dead_code)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RejectReason {
     CommandNotUnderstood,
     SignalingMtuExceeded { actual_mtu: u16 },
-    InvalidCid { scid: u16, dcid: u16 }
+    /// The channel ids as seen by the device that sends the reject.
+    InvalidCid { local_cid: u16, remote_cid: u16 }
 }
 
 impl Instruct<LittleEndian> for RejectReason {
@@ -353,10 +540,10 @@ impl Instruct<LittleEndian> for RejectReason {
                 buffer.write_le(0x0001u16);
                 buffer.write_le(actual_mtu);
             }
-            RejectReason::InvalidCid { scid, dcid } => {
+            RejectReason::InvalidCid { local_cid, remote_cid } => {
                 buffer.write_le(0x0002u16);
-                buffer.write_le(scid);
-                buffer.write_le(dcid);
+                buffer.write_le(local_cid);
+                buffer.write_le(remote_cid);
             }
         }
     }
@@ -372,9 +559,9 @@ impl Exstruct<LittleEndian> for RejectReason {
                 Ok(RejectReason::SignalingMtuExceeded { actual_mtu })
             }
             0x0002 => {
-                let scid: u16 = buffer.read_le()?;
-                let dcid: u16 = buffer.read_le()?;
-                Ok(RejectReason::InvalidCid { scid, dcid })
+                let local_cid: u16 = buffer.read_le()?;
+                let remote_cid: u16 = buffer.read_le()?;
+                Ok(RejectReason::InvalidCid { local_cid, remote_cid })
             }
             _ => Err(instructor::Error::InvalidValue)
         }
@@ -2,7 +2,7 @@ mod uuid;
 
 use instructor::utils::Limit;
 use instructor::{BigEndian, Buffer, BufferMut, Error as InstructorError, Exstruct, Instruct};
-pub use uuid::Uuid;
+pub use uuid::{PackedUuid, Uuid};
 
 use crate::ensure;
 use crate::sdp::error::Error;
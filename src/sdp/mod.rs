@@ -9,7 +9,7 @@ use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 use bytes::{Bytes, BytesMut};
-pub use data_element::{DataElement, Uuid};
+pub use data_element::{DataElement, PackedUuid, Uuid};
 use instructor::utils::Length;
 use instructor::{BigEndian, Buffer, BufferMut, Exstruct, Instruct};
 pub use service::ServiceAttribute;
@@ -18,7 +18,9 @@ use tracing::{error, trace, warn};
 
 use crate::ensure;
 use crate::l2cap::channel::{Channel, Error as L2capError};
+use crate::l2cap::configuration::{ChannelConfig, Mtu};
 use crate::l2cap::{ProtocolHandler, SDP_PSM};
+use crate::sdp::ids::attributes::SERVICE_CLASS_ID_LIST_ID;
 use crate::sdp::error::{Error, SdpErrorCodes};
 use crate::sdp::service::Service;
 use crate::utils::{catch_error, LoggableResult};
@@ -42,6 +44,27 @@ impl SdpBuilder {
         self
     }
 
+    /// Collects the service classes of all registered records, e.g. to advertise them in the
+    /// [extended inquiry response](crate::hci::eir::ExtendedInquiryResponse::with_service_classes).
+    pub fn service_classes(&self) -> Vec<Uuid> {
+        let mut uuids = Vec::new();
+        let class_lists = self
+            .records
+            .values()
+            .flat_map(|service| service.as_ref())
+            .filter(|attribute| attribute.id == SERVICE_CLASS_ID_LIST_ID);
+        for attribute in class_lists {
+            for uuid in attribute.value.as_sequence().into_iter().flatten() {
+                match uuid.as_uuid() {
+                    Ok(uuid) if !uuids.contains(&uuid) => uuids.push(uuid),
+                    Ok(_) => {}
+                    Err(_) => warn!("Invalid service class id: {:?}", uuid)
+                }
+            }
+        }
+        uuids
+    }
+
     pub fn build(self) -> Sdp {
         Sdp {
             records: Arc::new(self.records)
@@ -60,7 +83,9 @@ impl ProtocolHandler for Sdp {
     }
 
     fn handle(&self, mut channel: Channel) {
-        if channel.accept_connection().log_err().is_err() {
+        // SDP PDUs are small and large responses are split using continuation states
+        let config = ChannelConfig::default().with_mtu(Mtu::DEFAULT_ACL_U.0);
+        if channel.set_config(config).log_err().is_err() || channel.accept_connection().log_err().is_err() {
             return;
         }
         let server = self.clone();